@@ -1,5 +1,6 @@
 use std::env;
 use std::path::Path;
+use std::process::Command;
 
 const LOCALES: &[&str] = &["zh", "en"];
 
@@ -15,4 +16,21 @@ fn main() {
     let out_file = Path::new(&env::var("OUT_DIR").unwrap()).join("ctl10n_macros.rs");
     let _ignore_error = std::fs::remove_file(&out_file);
     ctl10n::convert_strings_file(locale_file, out_file).expect("ctl10n failed");
+
+    // Shown by /version, builds from a tarball have no git
+    for path in [".git/HEAD", ".git/logs/HEAD"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=RSSBOT_GIT_HASH={}", git_hash);
 }
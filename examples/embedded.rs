@@ -0,0 +1,38 @@
+//! Run the bot as a part of another service, on its runtime and with its own settings.
+//! It stops gracefully on Ctrl-C, or when the service says so.
+//!
+//! ```sh
+//! RSSBOT_TOKEN=123456:ABC cargo run --example embedded
+//! ```
+
+use anyhow::Context;
+use rssbot::{Opt, RssBot};
+use tokio::sync::oneshot;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let token = std::env::var("RSSBOT_TOKEN").context("RSSBOT_TOKEN is not set")?;
+    let mut opt = Opt::new(&token);
+    opt.database = "./embedded-rssbot.json".into();
+    opt.min_interval = 600;
+
+    // Whatever the service uses to stop its parts
+    let (stop, stopped) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        let _ = tokio::signal::ctrl_c().await;
+        let _ = stop.send(());
+    });
+
+    let bot = RssBot::new(opt);
+    let run = bot.run();
+    tokio::pin!(run);
+    tokio::select! {
+        // Failed to start, f.e. the token is wrong
+        result = &mut run => return result,
+        _ = stopped => bot.shutdown(),
+    }
+    // Saves the database before returning
+    run.await?;
+    println!("Bot stopped");
+    Ok(())
+}
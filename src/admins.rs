@@ -0,0 +1,132 @@
+//! `--admin`, given as user IDs or @usernames
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use tbot::{
+    types::{chat::Kind, parameters::ChatId},
+    Bot,
+};
+
+// Usernames to the IDs of the users who had them last, only kept in memory,
+// people change their usernames
+static RESOLVED: RwLock<BTreeMap<String, i64>> = RwLock::new(BTreeMap::new());
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Admin {
+    Id(i64),
+    /// Lowercase, without the `@`
+    Username(String),
+}
+
+impl FromStr for Admin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix('@') {
+            Some(username) if is_username(username) => {
+                Ok(Admin::Username(username.to_lowercase()))
+            }
+            Some(_) => Err(format!("invalid username: {}", s)),
+            None => s
+                .parse()
+                .map(Admin::Id)
+                .map_err(|_| format!("must be a user ID or @username: {}", s)),
+        }
+    }
+}
+
+fn is_username(s: &str) -> bool {
+    (5..=32).contains(&s.len()) && s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+/// Whether the user is one of the `admins`.
+///
+/// `@username` admins are matched by the current username of the user,
+/// and then remembered by their ID until someone else shows up with it.
+pub fn is_admin(admins: &[Admin], id: i64, username: Option<&str>) -> bool {
+    let username = username.map(str::to_lowercase);
+    let mut resolved_to_others = false;
+    for admin in admins {
+        match admin {
+            Admin::Id(admin_id) if *admin_id == id => return true,
+            Admin::Id(_) => (),
+            Admin::Username(name) => {
+                if username.as_ref() == Some(name) {
+                    let previous = RESOLVED.write().unwrap().insert(name.clone(), id);
+                    if previous != Some(id) {
+                        eprintln!("Admin @{} resolved to {}", name, id);
+                    }
+                    return true;
+                }
+                resolved_to_others |= RESOLVED.read().unwrap().get(name) == Some(&id);
+            }
+        }
+    }
+    resolved_to_others
+}
+
+/// Log who the admins are, so a group ID passed by mistake is noticed
+pub async fn verify(bot: &Bot, admins: &[Admin]) {
+    for admin in admins {
+        let (chat_id, shown) = match admin {
+            Admin::Id(id) => (ChatId::Id((*id).into()), id.to_string()),
+            Admin::Username(name) => (ChatId::Username(name.clone()), format!("@{}", name)),
+        };
+        match bot.get_chat(chat_id).call().await {
+            Ok(chat) => match chat.kind {
+                Kind::Private { username, .. } => {
+                    let username = username.unwrap_or_default();
+                    eprintln!("Admin {}: user {} @{}", shown, chat.id.0, username);
+                    if let Admin::Username(name) = admin {
+                        RESOLVED.write().unwrap().insert(name.clone(), chat.id.0);
+                    }
+                }
+                _ => eprintln!(
+                    "WARNING: admin {} is a group or channel, not a user, \
+                     it will never be allowed to use the bot",
+                    shown
+                ),
+            },
+            // Users can't be looked up by username, and by ID only after they started the bot
+            Err(e) => match admin {
+                Admin::Username(_) => eprintln!(
+                    "Admin {} is resolved when they send a command to the bot",
+                    shown
+                ),
+                Admin::Id(_) => eprintln!(
+                    "WARNING: admin {} can't be verified, did they start the bot? {}",
+                    shown, e
+                ),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!("123".parse(), Ok(Admin::Id(123)));
+        assert_eq!("@Some_User".parse(), Ok(Admin::Username("some_user".into())));
+        assert!("@a".parse::<Admin>().is_err());
+        assert!("@some-user".parse::<Admin>().is_err());
+        assert!("some_user".parse::<Admin>().is_err());
+    }
+
+    #[test]
+    fn username_admins() {
+        let admins = ["1".parse().unwrap(), "@admin_test".parse().unwrap()];
+        assert!(is_admin(&admins, 1, None));
+        assert!(!is_admin(&admins, 2, None));
+        assert!(is_admin(&admins, 2, Some("Admin_Test")));
+        // Remembered after changing the username
+        assert!(is_admin(&admins, 2, Some("renamed")));
+        // Until someone else takes it
+        assert!(is_admin(&admins, 3, Some("admin_test")));
+        assert!(!is_admin(&admins, 2, Some("renamed")));
+    }
+}
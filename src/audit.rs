@@ -0,0 +1,169 @@
+//! `--audit-log`, a JSON line for every item sent to a chat, and for every failed attempt
+//!
+//! Records are queued and written in background, a slow disk never holds up delivery.
+//! The file is renamed to `<path>.<date>` when the day changes.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Local, NaiveDate};
+use serde::Serialize;
+use tbot::errors::MethodCall;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc,
+    time::{self, Duration},
+};
+
+use crate::data::chat_name;
+use crate::messages::html_links;
+
+// Records waiting to be written, beyond this they are dropped
+const QUEUE_SIZE: usize = 4096;
+
+static QUEUE: OnceLock<mpsc::Sender<Record>> = OnceLock::new();
+// Queued but not written yet
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+static DROP_REPORTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Serialize)]
+struct Record {
+    timestamp: String,
+    chat_id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chat_name: Option<String>,
+    feed_url: String,
+    item_link: Option<String>,
+    message_id: Option<i64>,
+    outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+pub fn init(path: PathBuf) {
+    // A second run keeps writing with the first writer
+    if QUEUE.get().is_some() {
+        return;
+    }
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    QUEUE.set(sender).expect("audit log already initialized");
+    tokio::spawn(write_records(path, receiver));
+}
+
+/// Record the result of sending `html` about the feed to the chat,
+/// a line for every link in it. Does nothing without `--audit-log`.
+pub fn record(chat_id: i64, feed_url: &str, html: &str, result: &Result<i64, MethodCall>) {
+    let queue = match QUEUE.get() {
+        Some(queue) => queue,
+        None => return,
+    };
+    let timestamp = Local::now().to_rfc3339();
+    let chat_name = chat_name(chat_id);
+    let (message_id, outcome, error) = match result {
+        Ok(message_id) => (Some(*message_id), "delivered", None),
+        Err(e) => (None, "failed", Some(e.to_string())),
+    };
+    let mut links: Vec<Option<String>> = html_links(html).into_iter().map(Some).collect();
+    if links.is_empty() {
+        links.push(None);
+    }
+    for item_link in links {
+        let record = Record {
+            timestamp: timestamp.clone(),
+            chat_id,
+            chat_name: chat_name.clone(),
+            feed_url: feed_url.to_string(),
+            item_link,
+            message_id,
+            outcome,
+            error: error.clone(),
+        };
+        PENDING.fetch_add(1, Ordering::SeqCst);
+        if queue.try_send(record).is_err() {
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+            if !DROP_REPORTED.swap(true, Ordering::Relaxed) {
+                eprintln!("Audit log can't keep up, dropping records");
+            }
+            return;
+        }
+    }
+}
+
+/// Wait for the queued records to be written, before exiting in `--once` mode
+pub async fn wait_written() {
+    while PENDING.load(Ordering::SeqCst) > 0 {
+        time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+async fn write_records(path: PathBuf, mut receiver: mpsc::Receiver<Record>) {
+    let mut log = AuditFile {
+        path,
+        file: None,
+        day: Local::now().date_naive(),
+        failing: false,
+    };
+    while let Some(record) = receiver.recv().await {
+        let mut records = vec![record];
+        while let Ok(record) = receiver.try_recv() {
+            records.push(record);
+        }
+        let written = records.len();
+        if let Err(e) = log.write(records).await {
+            log.file = None;
+            // Only once, until it works again
+            if !log.failing {
+                log.failing = true;
+                eprintln!("Failed to write the audit log: {}", e);
+            }
+        } else {
+            log.failing = false;
+        }
+        PENDING.fetch_sub(written, Ordering::SeqCst);
+    }
+}
+
+struct AuditFile {
+    path: PathBuf,
+    file: Option<BufWriter<File>>,
+    /// Of the lines in `path`
+    day: NaiveDate,
+    failing: bool,
+}
+
+impl AuditFile {
+    async fn write(&mut self, records: Vec<Record>) -> std::io::Result<()> {
+        let today = Local::now().date_naive();
+        if self.file.is_none() {
+            // Left by the last run, it may be from another day
+            if let Ok(modified) = fs::metadata(&self.path).await.and_then(|m| m.modified()) {
+                self.day = DateTime::<Local>::from(modified).date_naive();
+            }
+        }
+        if self.day != today {
+            self.file = None;
+            let rotated = format!("{}.{}", self.path.display(), self.day.format("%Y-%m-%d"));
+            match fs::rename(&self.path, rotated).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => self.day = today,
+            }
+        }
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await?;
+            self.file = Some(BufWriter::new(file));
+        }
+        let file = self.file.as_mut().unwrap();
+        for record in records {
+            let mut line = serde_json::to_vec(&record)?;
+            line.push(b'\n');
+            file.write_all(&line).await?;
+        }
+        file.flush().await
+    }
+}
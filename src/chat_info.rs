@@ -0,0 +1,193 @@
+//! `getChat`, `getChatAdministrators` and `getChatMember` for the commands, the delivery
+//! and the gardener
+//!
+//! A busy cycle asks about the same chats many times within seconds. Concurrent calls
+//! for the same chat share one request, and what it returned is kept for `TTL`.
+//! Failures are never kept.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tbot::{
+    errors::MethodCall,
+    types::{
+        chat::{self, member::Member, Chat},
+        user,
+    },
+    Bot,
+};
+use tokio::sync::watch;
+
+use crate::health::METRICS;
+
+// Changes made in Telegram are noticed this late at most
+const TTL: Duration = Duration::from_secs(30);
+
+static CHATS: Calls<i64, Chat> = Calls::new(TTL);
+static ADMINISTRATORS: Calls<i64, Vec<Member>> = Calls::new(TTL);
+static MEMBERS: Calls<(i64, i64), Member> = Calls::new(TTL);
+
+pub async fn get_chat(bot: &Bot, chat_id: chat::Id) -> Result<Chat, MethodCall> {
+    CHATS.get(chat_id.0, || bot.get_chat(chat_id).call()).await
+}
+
+pub async fn get_chat_administrators(
+    bot: &Bot,
+    chat_id: chat::Id,
+) -> Result<Vec<Member>, MethodCall> {
+    ADMINISTRATORS
+        .get(chat_id.0, || bot.get_chat_administrators(chat_id).call())
+        .await
+}
+
+pub async fn get_chat_member(
+    bot: &Bot,
+    chat_id: chat::Id,
+    user_id: user::Id,
+) -> Result<Member, MethodCall> {
+    MEMBERS
+        .get((chat_id.0, user_id.0), || bot.get_chat_member(chat_id, user_id).call())
+        .await
+}
+
+/// Telegram told about a change, f.e. the bot was promoted
+pub fn forget(chat_id: i64) {
+    CHATS.remove(|&chat| chat == chat_id);
+    ADMINISTRATORS.remove(|&chat| chat == chat_id);
+    MEMBERS.remove(|&(chat, _)| chat == chat_id);
+}
+
+struct Calls<K, V> {
+    ttl: Duration,
+    entries: Mutex<BTreeMap<K, Entry<V>>>,
+}
+
+enum Entry<V> {
+    /// Closed when the request finished, or was dropped
+    InFlight(watch::Receiver<()>),
+    Done(V, Instant),
+}
+
+impl<K: Ord + Clone, V: Clone> Calls<K, V> {
+    const fn new(ttl: Duration) -> Self {
+        Calls {
+            ttl,
+            entries: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The kept result, or the one of the request in flight, `call` is only made
+    /// if there is neither
+    async fn get<F, Fut, E>(&self, key: K, call: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        let done = loop {
+            let mut in_flight = {
+                let mut entries = self.entries.lock().unwrap();
+                match entries.get(&key) {
+                    Some(Entry::Done(value, at)) if at.elapsed() < self.ttl => {
+                        METRICS.chat_info(true);
+                        return Ok(value.clone());
+                    }
+                    // Not closed, so the request wasn't dropped
+                    Some(Entry::InFlight(in_flight)) if in_flight.has_changed().is_ok() => {
+                        in_flight.clone()
+                    }
+                    _ => {
+                        let ttl = self.ttl;
+                        entries.retain(|_, entry| match entry {
+                            Entry::Done(_, at) => at.elapsed() < ttl,
+                            Entry::InFlight(_) => true,
+                        });
+                        let (done, in_flight) = watch::channel(());
+                        entries.insert(key.clone(), Entry::InFlight(in_flight));
+                        break done;
+                    }
+                }
+            };
+            // Nothing is ever sent, only closed
+            let _ = in_flight.changed().await;
+        };
+        METRICS.chat_info(false);
+        let result = call().await;
+        let mut entries = self.entries.lock().unwrap();
+        match &result {
+            Ok(value) => entries.insert(key, Entry::Done(value.clone(), Instant::now())),
+            Err(_) => entries.remove(&key),
+        };
+        // The ones waiting find the result when they wake up
+        drop(entries);
+        drop(done);
+        result
+    }
+
+    fn remove(&self, mut f: impl FnMut(&K) -> bool) {
+        self.entries.lock().unwrap().retain(|key, _| !f(key));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future::{join_all, BoxFuture};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Takes a while, so the calls are concurrent
+    fn counted(
+        requests: &AtomicU32,
+        result: Result<u32, ()>,
+    ) -> impl Fn() -> BoxFuture<'_, Result<u32, ()>> {
+        move || {
+            Box::pin(async move {
+                requests.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                result
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn coalescing() {
+        let calls: Calls<i64, u32> = Calls::new(Duration::from_millis(200));
+        let requests = AtomicU32::new(0);
+        let call = counted(&requests, Ok(7));
+        let results = join_all((0..10).map(|_| calls.get(1, &call))).await;
+        assert!(results.iter().all(|result| *result == Ok(7)));
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+        // Another chat
+        assert_eq!(calls.get(2, &call).await, Ok(7));
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+        assert_eq!(calls.get(1, &call).await, Ok(7));
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(calls.get(1, &call).await, Ok(7));
+        assert_eq!(requests.load(Ordering::Relaxed), 3);
+        calls.remove(|&chat| chat == 1);
+        assert_eq!(calls.get(1, &call).await, Ok(7));
+        assert_eq!(requests.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn failures() {
+        let calls: Calls<i64, u32> = Calls::new(Duration::from_secs(30));
+        let requests = AtomicU32::new(0);
+        let failing = counted(&requests, Err(()));
+        assert_eq!(calls.get(1, &failing).await, Err(()));
+        assert_eq!(calls.get(1, &failing).await, Err(()));
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+
+        // The one waiting makes its own request when the first is dropped
+        let call = counted(&requests, Ok(7));
+        let dropped = tokio::time::timeout(Duration::from_millis(10), calls.get(1, &call));
+        let (dropped, waiting) = futures::join!(dropped, calls.get(1, &call));
+        assert!(dropped.is_err());
+        assert_eq!(waiting, Ok(7));
+        assert_eq!(requests.load(Ordering::Relaxed), 4);
+    }
+}
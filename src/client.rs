@@ -1,17 +1,91 @@
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
-use std::time::Duration;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, LazyLock, Mutex, RwLock,
+};
+use std::time::{Duration, Instant};
+
+use regex::Regex;
 
 use reqwest::{
     self,
-    header::{HeaderValue, CONTENT_TYPE},
+    dns::{Addrs, Name, Resolve, Resolving},
+    header::{
+        HeaderName, HeaderValue, ACCEPT, CONTENT_TYPE, COOKIE, ETAG, IF_MODIFIED_SINCE,
+        IF_NONE_MATCH, LAST_MODIFIED, LOCATION, RETRY_AFTER, USER_AGENT,
+    },
+    StatusCode,
 };
 use std::sync::OnceLock;
 use thiserror::Error;
+use tokio::sync::Semaphore;
 
-use crate::feed::Rss;
+use crate::data::{Quirks, Validators};
+use crate::feed::{Chunk, Chunks, ParseError, Rss, PARSE_BUDGET};
 
 static RESP_SIZE_LIMIT: OnceLock<u64> = OnceLock::new();
-static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+static FEED_CLIENTS: OnceLock<FeedClients> = OnceLock::new();
+// `--onion-proxy`, onion services are refused without it
+static ONION_PROXY: OnceLock<SocketAddr> = OnceLock::new();
+static PUBLIC_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+static DNS_CACHE: OnceLock<Arc<DnsCache>> = OnceLock::new();
+static RSSHUB_DEFAULT_BASE: OnceLock<String> = OnceLock::new();
+// Bodies of web pages and JSON Feeds are parsed at once, their buffers are kept
+static BUFFERS: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+// Each streaming parse holds a blocking thread until the body is downloaded
+static PARSERS: Semaphore = Semaphore::const_new(MAX_PARSERS);
+// Changed at runtime by /rsshub
+static RSSHUB_BASE: RwLock<Option<String>> = RwLock::new(None);
+
+pub const RSSHUB_SCHEME: &str = "rsshub://";
+
+// Link shorteners and the ones feeds wrap their links with
+const REDIRECTOR_HOSTS: &[&str] = &[
+    "feedproxy.google.com",
+    "feeds.feedburner.com",
+    "ad.doubleclick.net",
+    "t.co",
+    "bit.ly",
+];
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Redirector links resolved per minute at most
+const RESOLVE_RATE: u32 = 30;
+// Start of the current minute and how many were resolved in it
+static RESOLVE_BUDGET: Mutex<Option<(Instant, u32)>> = Mutex::new(None);
+
+const DNS_CACHE_CAPACITY: usize = 1024;
+// Challenge pages have their markers early on
+const CHALLENGE_PEEK_SIZE: usize = 64 * 1024;
+/// Tried once when the bot's own gets a challenge page
+const BROWSER_USER_AGENT: &str =
+    "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0";
+const FEED_ACCEPT: &str = "application/rss+xml, application/atom+xml, application/feed+json, \
+                           application/xml;q=0.9, text/xml;q=0.9, */*;q=0.8";
+// Read before deciding how to parse the body
+const SNIFF_SIZE: usize = 4 * 1024;
+// Downloaded ahead of the XML parser at most
+const STREAMED_CHUNKS: usize = 4;
+// Parsers running at once, the other downloads wait, well below the 512 blocking threads
+const MAX_PARSERS: usize = 32;
+const POOLED_BUFFERS: usize = 8;
+// Larger ones are rare, and would stay allocated
+const MAX_POOLED_BUFFER: usize = 4 * 1024 * 1024;
+// Keep this short, so typo'd subscriptions recover quickly after being fixed
+const DNS_NEGATIVE_TTL: Duration = Duration::from_secs(30);
+// Of `pull_feed_cached`, feeds can be large
+const PULLED_CAPACITY: usize = 32;
+const PULLED_TTL: Duration = Duration::from_secs(60 * 60);
+// Short, the site may be fixed any moment
+const PULL_FAILED_TTL: Duration = Duration::from_secs(5 * 60);
+static PULLED: PullCache = PullCache::new();
+/// Tor's SOCKS port, used by `--onion-proxy` without an address
+pub const DEFAULT_ONION_PROXY: SocketAddr =
+    SocketAddr::new(IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 9050);
 
 #[derive(Error, Debug)]
 pub enum FeedError {
@@ -19,62 +93,862 @@ pub enum FeedError {
     Network(#[from] reqwest::Error),
     #[error("feed parsing failed")]
     Parsing(#[from] quick_xml::Error),
+    #[error("feed parsing failed")]
+    JsonParsing(#[from] serde_json::Error),
     #[error("feed is too large")]
     TooLarge(u64),
+    /// Over one of the limits of the parser
+    #[error("feed rejected: too complex")]
+    TooComplex(&'static str),
+    /// With the feed the page links to, if any
+    #[error("the server returned a web page, not a feed")]
+    WebPage(reqwest::Url, Option<String>),
+    #[error("invalid feed URL")]
+    Url(#[from] UrlError),
+    /// An anti-bot challenge page, even with a browser's user agent
+    #[error("the site is blocking automated access")]
+    Blocked(reqwest::StatusCode),
+    /// An error response with a `Retry-After` in seconds
+    #[error("network error")]
+    RetryLater(reqwest::Error, u32),
+    /// Caught by the fetcher, with the message of the panic
+    #[error("feed parsing failed")]
+    Panicked(String),
+}
+
+#[derive(Error, Debug)]
+pub enum UrlError {
+    #[error("invalid URL")]
+    Invalid,
+    #[error("{0}:// is not supported, only http and https are")]
+    UnsupportedScheme(String),
+    #[error("no scheme in the URL")]
+    NoScheme,
+    #[error("failed to resolve the host: {0}")]
+    Resolve(#[from] io::Error),
+    #[error("not a public address")]
+    NotPublic,
+    #[error("onion services can only be fetched with --onion-proxy")]
+    NoOnionProxy,
 }
 
 impl FeedError {
+    /// HTTP status code if the server replied with an error
+    pub fn status(&self) -> Option<reqwest::StatusCode> {
+        match self {
+            Self::Network(source) | Self::RetryLater(source, _) => source.status(),
+            Self::Blocked(status) => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// The kind of the error, subscribers are told about each kind separately
+    pub fn class(&self) -> &'static str {
+        match self {
+            Self::Network(_) | Self::RetryLater(..) => "network",
+            Self::Parsing(_) | Self::JsonParsing(_) | Self::Panicked(_) => "parsing",
+            Self::TooLarge(_) => "too_large",
+            Self::TooComplex(_) => "too_complex",
+            Self::WebPage(..) => "web_page",
+            Self::Url(_) => "url",
+            Self::Blocked(_) => "blocked",
+        }
+    }
+
+    /// How long the server asked to wait before trying again, in seconds
+    pub fn retry_after(&self) -> Option<u32> {
+        match self {
+            Self::RetryLater(_, retry_after) => Some(*retry_after),
+            _ => None,
+        }
+    }
+
     pub fn to_user_friendly(&self) -> String {
         match self {
-            Self::Network(source) => tr!("network_error", source = source),
+            Self::Network(source) | Self::RetryLater(source, _) => {
+                tr!("network_error", source = source)
+            }
             Self::Parsing(source) => tr!("parsing_error", source = source),
+            Self::JsonParsing(source) => tr!("parsing_error", source = source),
+            Self::Panicked(message) => tr!("parsing_error", source = message),
             Self::TooLarge(limit) => {
                 tr!("rss_size_limit_exceeded", size = format_byte_size(*limit))
             }
+            Self::TooComplex(limit) => tr!("feed_too_complex", limit = limit),
+            Self::WebPage(url, _) => tr!("web_page_not_feed", url = url),
+            Self::Url(source) => tr!("invalid_feed_url", source = source),
+            Self::Blocked(_) => tr!("site_blocking_bots").to_string(),
         }
     }
 }
 
-pub async fn pull_feed(url: &str) -> Result<Rss, FeedError> {
-    let mut resp = CLIENT
-        .get()
-        .expect("CLIENT not initialized")
-        .get(url)
-        .send()
-        .await?
-        .error_for_status()?;
-    let size_limit = *RESP_SIZE_LIMIT
+impl From<ParseError> for FeedError {
+    fn from(e: ParseError) -> Self {
+        match e {
+            ParseError::Xml(e) => Self::Parsing(e),
+            ParseError::TooComplex(limit) => Self::TooComplex(limit),
+        }
+    }
+}
+
+pub async fn pull_feed(
+    url: &str,
+    cookie: Option<&str>,
+    max_size: Option<u64>,
+) -> Result<Rss, FeedError> {
+    fetch_feed(url, cookie, max_size, Quirks::default())
+        .await
+        .map(|fetched| fetched.rss)
+}
+
+/// Like `pull_feed`, but a web page is followed to the feed it links to.
+/// Return the URL of the feed with it
+pub async fn pull_feed_or_discover(url: &str) -> Result<(String, Rss), FeedError> {
+    match pull_feed(url, None, None).await {
+        Ok(rss) => Ok((url.to_string(), rss)),
+        Err(FeedError::WebPage(_, Some(feed_link))) => {
+            let rss = pull_feed(&feed_link, None, None).await?;
+            Ok((feed_link, rss))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// `pull_feed` for /sub. What it returned is remembered for the same URL, a feed for
+/// an hour, so subscribing to it in another chat is instant, and an error for five minutes,
+/// so retrying a broken URL doesn't hit the site each time. `force` pulls it again anyway
+pub async fn pull_feed_cached(url: &str, force: bool) -> Result<Rss, Arc<FeedError>> {
+    if !force {
+        if let Some(result) = PULLED.get(url, Instant::now()) {
+            return result;
+        }
+    }
+    let result = pull_feed(url, None, None).await.map_err(Arc::new);
+    PULLED.insert(url.to_string(), &result, Instant::now());
+    result
+}
+
+struct PullEntry {
+    result: Result<Rss, Arc<FeedError>>,
+    expires_at: Instant,
+}
+
+/// A bounded URL → feed cache, like `DnsCache`. Only in memory
+struct PullCache {
+    entries: Mutex<BTreeMap<String, PullEntry>>,
+}
+
+impl PullCache {
+    const fn new() -> Self {
+        PullCache {
+            entries: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn get(&self, url: &str, now: Instant) -> Option<Result<Rss, Arc<FeedError>>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(url) {
+            Some(entry) if entry.expires_at > now => Some(entry.result.clone()),
+            Some(_) => {
+                entries.remove(url);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, url: String, result: &Result<Rss, Arc<FeedError>>, now: Instant) {
+        let ttl = match result {
+            Ok(_) => PULLED_TTL,
+            Err(_) => PULL_FAILED_TTL,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= PULLED_CAPACITY && !entries.contains_key(&url) {
+            entries.retain(|_, entry| entry.expires_at > now);
+            if entries.len() >= PULLED_CAPACITY {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires_at)
+                    .map(|(url, _)| url.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(
+            url,
+            PullEntry {
+                result: result.clone(),
+                expires_at: now + ttl,
+            },
+        );
+    }
+}
+
+/// The first `<link rel="alternate">` to an RSS, Atom or JSON feed in the page
+fn discover_feed_link(html: &str, page_url: &reqwest::Url) -> Option<String> {
+    static LINK: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)<link\b[^>]*>").unwrap());
+    static ATTR: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r#"(?i)\b(rel|type|href)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#).unwrap()
+    });
+    const FEED_TYPES: [&str; 3] = [
+        "application/rss+xml",
+        "application/atom+xml",
+        "application/feed+json",
+    ];
+    LINK.find_iter(html).find_map(|tag| {
+        let (mut rel, mut kind, mut href) = ("", "", None);
+        for attr in ATTR.captures_iter(tag.as_str()) {
+            let value = attr
+                .get(2)
+                .or_else(|| attr.get(3))
+                .or_else(|| attr.get(4))
+                .map_or("", |m| m.as_str());
+            match &*attr[1].to_ascii_lowercase() {
+                "rel" => rel = value,
+                "type" => kind = value,
+                _ => href = Some(value.replace("&amp;", "&")),
+            }
+        }
+        let is_alternate = rel
+            .split_ascii_whitespace()
+            .any(|r| r.eq_ignore_ascii_case("alternate"));
+        let is_feed = FEED_TYPES.iter().any(|t| kind.trim().eq_ignore_ascii_case(t));
+        if !is_alternate || !is_feed {
+            return None;
+        }
+        page_url.join(&href?).ok().map(String::from)
+    })
+}
+
+async fn send_request(
+    url: &str,
+    cookie: Option<&str>,
+    user_agent: Option<&str>,
+    quirks: Quirks,
+    validators: &Validators,
+) -> Result<reqwest::Response, FeedError> {
+    let accept = if quirks.accept_any { "*/*" } else { FEED_ACCEPT };
+    let onion = reqwest::Url::parse(url)
+        .ok()
+        .is_some_and(|url| url.host_str().is_some_and(is_onion));
+    let client = FEED_CLIENTS
         .get()
-        .expect("RESP_SIZE_LIMIT not initialized");
+        .expect("FEED_CLIENTS not initialized")
+        .get(quirks, onion)
+        .ok_or(UrlError::NoOnionProxy)?;
+    let mut req = client.get(url).header(ACCEPT, accept);
+    if let Some(cookie) = cookie {
+        req = req.header(COOKIE, cookie);
+    }
+    if let Some(user_agent) = user_agent {
+        req = req.header(USER_AGENT, user_agent);
+    }
+    if let Some(etag) = &validators.etag {
+        req = req.header(IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        req = req.header(IF_MODIFIED_SINCE, last_modified);
+    }
+    let resp = req.send().await.map_err(|e| {
+        if e.is_connect() {
+            // The cached address may be stale, resolve it again next time
+            if let Some(host) = e.url().and_then(|url| url.host_str()) {
+                DNS_CACHE
+                    .get()
+                    .expect("DNS_CACHE not initialized")
+                    .invalidate(host);
+            }
+        }
+        e
+    })?;
+    let status = resp.status();
+    let error = match resp.error_for_status_ref().err() {
+        Some(e) => e,
+        None => return Ok(resp),
+    };
+    let retry_after = retry_after(&resp);
+    if is_challenge(resp).await {
+        Err(FeedError::Blocked(status))
+    } else if let Some(retry_after) = retry_after {
+        Err(FeedError::RetryLater(error, retry_after))
+    } else {
+        Err(error.into())
+    }
+}
+
+/// The workaround to try once after the request with `quirks` failed with `e`
+fn fallback_quirks(e: &FeedError, quirks: Quirks) -> Option<Quirks> {
+    match e {
+        // Broken content negotiation
+        _ if e.status() == Some(StatusCode::NOT_ACCEPTABLE) && !quirks.accept_any => Some(Quirks {
+            accept_any: true,
+            ..quirks
+        }),
+        FeedError::Network(e) if !quirks.http1_only && is_http2_error(e) => Some(Quirks {
+            http1_only: true,
+            ..quirks
+        }),
+        _ => None,
+    }
+}
+
+/// A protocol error of HTTP/2, not a timeout, a TLS failure or a broken connection
+fn is_http2_error(e: &reqwest::Error) -> bool {
+    if e.is_timeout() {
+        return false;
+    }
+    let mut source = std::error::Error::source(e);
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<h2::Error>() {
+            // HTTP/1.1 wouldn't get through a connection that failed either
+            return !error.is_io();
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// `Retry-After` of a 429 or 503 response, only in seconds, dates are rare
+fn retry_after(resp: &reqwest::Response) -> Option<u32> {
+    let status = resp.status();
+    if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE {
+        return None;
+    }
+    resp.headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Cloudflare and the like answer with a challenge page instead of the feed
+async fn is_challenge(mut resp: reqwest::Response) -> bool {
+    let status = resp.status();
+    if status != StatusCode::FORBIDDEN && status != StatusCode::SERVICE_UNAVAILABLE {
+        return false;
+    }
+    if resp.headers().contains_key("cf-mitigated") {
+        return true;
+    }
+    let mut body = Vec::new();
+    while let Ok(Some(bytes)) = resp.chunk().await {
+        body.extend_from_slice(&bytes);
+        if body.len() >= CHALLENGE_PEEK_SIZE {
+            break;
+        }
+    }
+    is_challenge_page(&body)
+}
+
+fn is_challenge_page(body: &[u8]) -> bool {
+    const MARKERS: [&[u8]; 4] = [
+        b"cf-chl",
+        b"Just a moment...",
+        b"challenge-platform",
+        b"Attention Required! | Cloudflare",
+    ];
+    MARKERS
+        .iter()
+        .any(|marker| body.windows(marker.len()).any(|w| w == *marker))
+}
+
+/// A feed and the response it came from
+pub struct Fetched {
+    /// Where the redirects ended
+    pub url: reqwest::Url,
+    pub status: reqwest::StatusCode,
+    pub bytes: usize,
+    pub rss: Rss,
+    /// The `quirks` it was fetched with, and the ones that turned out to be needed
+    pub quirks: Quirks,
+    /// Of the response, to make the next request conditional
+    pub validators: Validators,
+}
+
+/// `max_size` overrides `--max-feed-size` for this feed, 0 is unlimited.
+/// `quirks` are the ones remembered for the feed.
+pub async fn fetch_feed(
+    url: &str,
+    cookie: Option<&str>,
+    max_size: Option<u64>,
+    quirks: Quirks,
+) -> Result<Fetched, FeedError> {
+    let fetched = fetch_feed_if_modified(url, cookie, max_size, quirks, &Validators::default());
+    Ok(fetched.await?.expect("only a conditional request is not modified"))
+}
+
+/// Like `fetch_feed`, but `None` if the server answered 304 Not Modified to the
+/// `validators` of the last response
+pub async fn fetch_feed_if_modified(
+    url: &str,
+    cookie: Option<&str>,
+    max_size: Option<u64>,
+    mut quirks: Quirks,
+    validators: &Validators,
+) -> Result<Option<Fetched>, FeedError> {
+    let url = &*expand_url(url);
+    // Subscriptions from before the URLs were checked
+    check_feed_url(url)?;
+    let mut resp = match send_request(url, cookie, None, quirks, validators).await {
+        // Some challenges are only shown to clients that don't look like a browser
+        Err(FeedError::Blocked(_)) => {
+            let user_agent = Some(BROWSER_USER_AGENT);
+            send_request(url, cookie, user_agent, quirks, validators).await?
+        }
+        // Whichever works is used
+        Err(e) => match fallback_quirks(&e, quirks) {
+            Some(fallback) => match send_request(url, cookie, None, fallback, validators).await {
+                Ok(resp) => {
+                    quirks = fallback;
+                    resp
+                }
+                Err(_) => return Err(e),
+            },
+            None => return Err(e),
+        },
+        Ok(resp) => resp,
+    };
+    let status = resp.status();
+    // One that wasn't asked for fails like any other broken response
+    if status == StatusCode::NOT_MODIFIED && !validators.is_empty() {
+        return Ok(None);
+    }
+    let header = |name: HeaderName| {
+        let value = resp.headers().get(name)?.to_str().ok()?;
+        Some(value.to_string()).filter(|value| !value.is_empty())
+    };
+    let new_validators = Validators {
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    };
+    // Auth redirects are the usual reason for getting a web page
+    let final_url = resp.url().clone();
+    let size_limit = max_size.unwrap_or_else(|| {
+        *RESP_SIZE_LIMIT
+            .get()
+            .expect("RESP_SIZE_LIMIT not initialized")
+    });
     let unlimited = size_limit == 0;
-    if let Some(len) = resp.content_length() {
+    let content_length = resp.content_length();
+    if let Some(len) = content_length {
         if !unlimited && len > size_limit {
             return Err(FeedError::TooLarge(size_limit));
         }
     }
-
-    let feed = if url.ends_with(".json")
+    let is_json = url.ends_with(".json")
         || matches!(
             resp.headers().get(CONTENT_TYPE),
             Some(v) if content_type_is_json(v)
-        ) {
-        resp.json().await?
+        );
+
+    // Enough to tell a web page from a feed
+    let mut head = Vec::new();
+    while head.len() < SNIFF_SIZE {
+        match resp.chunk().await? {
+            Some(bytes) => head.extend_from_slice(&bytes),
+            None => break,
+        }
+    }
+    if !unlimited && head.len() as u64 > size_limit {
+        return Err(FeedError::TooLarge(size_limit));
+    }
+    let is_html = crate::feed::looks_like_html(&head);
+    let (feed, bytes) = if is_json || is_html {
+        // Only parsed at once, sized by the announced length but not trusting it too much
+        let limit = if unlimited {
+            MAX_POOLED_BUFFER as u64
+        } else {
+            size_limit
+        };
+        let mut buf = take_buffer(content_length.unwrap_or_default().min(limit) as usize);
+        buf.extend_from_slice(&head);
+        drop(head);
+        let feed = match read_body(&mut resp, &mut buf, size_limit).await {
+            Ok(()) if is_html => {
+                let feed_link = discover_feed_link(&String::from_utf8_lossy(&buf), &final_url);
+                Err(FeedError::WebPage(final_url.clone(), feed_link))
+            }
+            Ok(()) => serde_json::from_slice(&buf).map_err(FeedError::from),
+            Err(e) => Err(e),
+        };
+        let bytes = buf.len();
+        return_buffer(buf);
+        (feed?, bytes)
     } else {
-        let mut buf = Vec::new(); // TODO: capacity?
-        while let Some(bytes) = resp.chunk().await? {
-            if !unlimited && buf.len() + bytes.len() > size_limit as usize {
-                return Err(FeedError::TooLarge(size_limit));
+        parse_streaming(&mut resp, head, size_limit).await?
+    };
+
+    Ok(Some(Fetched {
+        url: final_url,
+        status,
+        bytes,
+        rss: crate::feed::inherit_language(crate::feed::fix_relative_url(feed, url)),
+        quirks,
+        validators: new_validators,
+    }))
+}
+
+/// Append the rest of the body to `buf`, `size_limit` 0 is unlimited
+async fn read_body(
+    resp: &mut reqwest::Response,
+    buf: &mut Vec<u8>,
+    size_limit: u64,
+) -> Result<(), FeedError> {
+    while let Some(bytes) = resp.chunk().await? {
+        if size_limit != 0 && (buf.len() + bytes.len()) as u64 > size_limit {
+            return Err(FeedError::TooLarge(size_limit));
+        }
+        buf.extend_from_slice(&bytes);
+    }
+    Ok(())
+}
+
+/// Parse the XML while it's downloaded, `head` is what was read already.
+/// Return the feed with the size of the body, as far as it was read.
+async fn parse_streaming(
+    resp: &mut reqwest::Response,
+    head: Vec<u8>,
+    size_limit: u64,
+) -> Result<(Rss, usize), FeedError> {
+    // Held by the blocking thread, it may outlive this after `PARSE_BUDGET`
+    let permit = PARSERS.acquire().await.unwrap();
+    let (sender, mut receiver) = tokio::sync::mpsc::channel::<Chunk>(STREAMED_CHUNKS);
+    let stop = Arc::new(AtomicBool::new(false));
+    let parser_stop = stop.clone();
+    let parser = crate::fetcher::spawn_blocking_in_feed(move || {
+        let _permit = permit;
+        let chunks = Chunks::new(std::iter::from_fn(move || receiver.blocking_recv()));
+        crate::feed::parse_until(chunks, &parser_stop)
+    });
+    let mut bytes = head.len();
+    let mut chunk: Option<Chunk> = Some(Box::new(head));
+    let downloaded = loop {
+        if let Some(chunk) = chunk.take() {
+            // The parser stops at the end of the feed, the rest isn't needed
+            if sender.send(chunk).await.is_err() {
+                break Ok(());
+            }
+        }
+        match resp.chunk().await {
+            Ok(Some(next)) => {
+                bytes += next.len();
+                if size_limit != 0 && bytes as u64 > size_limit {
+                    break Err(FeedError::TooLarge(size_limit));
+                }
+                chunk = Some(Box::new(next));
+            }
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(FeedError::from(e)),
+        }
+    };
+    // The parser sees the end of the body, or of what was read
+    drop(sender);
+    // The blocking thread can't be cancelled, it stops at its next check
+    let parsed = match tokio::time::timeout(PARSE_BUDGET, parser).await {
+        Ok(Ok(parsed)) => parsed,
+        // Raised again in this task, a panic in the feed's task only fails the feed
+        Ok(Err(e)) => match e.try_into_panic() {
+            Ok(panic) => std::panic::resume_unwind(panic),
+            Err(e) => return Err(FeedError::Panicked(e.to_string())),
+        },
+        Err(_) => {
+            stop.store(true, Ordering::Relaxed);
+            return Err(FeedError::TooComplex("took too long to parse"));
+        }
+    };
+    downloaded?;
+    Ok((parsed?, bytes))
+}
+
+fn take_buffer(capacity: usize) -> Vec<u8> {
+    let mut buf = BUFFERS.lock().unwrap().pop().unwrap_or_default();
+    buf.reserve(capacity);
+    buf
+}
+
+fn return_buffer(mut buf: Vec<u8>) {
+    if buf.capacity() > MAX_POOLED_BUFFER {
+        return;
+    }
+    buf.clear();
+    let mut buffers = BUFFERS.lock().unwrap();
+    if buffers.len() < POOLED_BUFFERS {
+        buffers.push(buf);
+    }
+}
+
+/// `default` is from `--rsshub-base`, `current` is what /rsshub set last time.
+/// A second run keeps the default of the first.
+pub fn init_rsshub(default: String, current: Option<String>) {
+    let _ = RSSHUB_DEFAULT_BASE.set(default);
+    set_rsshub_base(current);
+}
+
+/// `None` restores `--rsshub-base`
+pub fn set_rsshub_base(base: Option<String>) {
+    *RSSHUB_BASE.write().unwrap() = base;
+}
+
+pub fn rsshub_base() -> String {
+    RSSHUB_BASE.read().unwrap().clone().unwrap_or_else(|| {
+        RSSHUB_DEFAULT_BASE
+            .get()
+            .expect("RSSHUB_DEFAULT_BASE not initialized")
+            .clone()
+    })
+}
+
+/// Feeds are stored as `rsshub://route`, so moving the RSSHub instance doesn't
+/// require touching every subscription
+pub fn expand_url(url: &str) -> Cow<'_, str> {
+    match url.strip_prefix(RSSHUB_SCHEME) {
+        Some(route) => Cow::Owned(join_rsshub_route(&rsshub_base(), route)),
+        None => Cow::Borrowed(url),
+    }
+}
+
+fn join_rsshub_route(base: &str, route: &str) -> String {
+    format!(
+        "{}/{}",
+        base.trim_end_matches('/'),
+        route.trim_start_matches('/')
+    )
+}
+
+/// What a shorthand given to /sub stands for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Shorthand {
+    Feed(String),
+    /// The user picks one, with the localization keys of their names
+    Choice(Vec<(&'static str, String)>),
+}
+
+type ShorthandResolver = fn(&regex::Captures) -> Shorthand;
+
+/// Matched against the whole argument of /sub, the first match wins.
+/// What they resolve to is stored, never the shorthand.
+static SHORTHANDS: LazyLock<Vec<(Regex, ShorthandResolver)>> = LazyLock::new(|| {
+    let shorthands: [(&str, ShorthandResolver); 3] = [
+        (
+            r"^(?:/?r/|(?:https?://)?(?:www\.|old\.)?reddit\.com/r/)(\w{2,21})/?$",
+            |caps| Shorthand::Feed(format!("https://www.reddit.com/r/{}/.rss", &caps[1])),
+        ),
+        (
+            r"^(?:https?://)?(?:www\.)?github\.com/([\w-]+)/([\w.-]+?)(?:\.git)?/?$",
+            |caps| {
+                let repo = format!("https://github.com/{}/{}", &caps[1], &caps[2]);
+                Shorthand::Choice(vec![
+                    ("github_releases", format!("{}/releases.atom", repo)),
+                    ("github_commits", format!("{}/commits.atom", repo)),
+                    ("github_tags", format!("{}/tags.atom", repo)),
+                ])
+            },
+        ),
+        (
+            r"^(?:https?://)?(?:www\.)?github\.com/([\w-]+)/?$",
+            |caps| Shorthand::Feed(format!("https://github.com/{}.atom", &caps[1])),
+        ),
+    ];
+    shorthands
+        .into_iter()
+        .map(|(pattern, resolve)| (Regex::new(pattern).unwrap(), resolve))
+        .collect()
+});
+
+/// `None` if it's not a shorthand, it's taken as a feed URL then
+pub fn resolve_shorthand(input: &str) -> Option<Shorthand> {
+    SHORTHANDS
+        .iter()
+        .find_map(|(pattern, resolve)| pattern.captures(input.trim()).map(|caps| resolve(&caps)))
+}
+
+/// Feeds are only fetched over HTTP, and from RSSHub
+pub fn check_feed_url(url: &str) -> Result<(), UrlError> {
+    if url.starts_with(RSSHUB_SCHEME) {
+        return Ok(());
+    }
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => match parsed.host_str() {
+            Some(host) if is_onion(host) && ONION_PROXY.get().is_none() => {
+                Err(UrlError::NoOnionProxy)
             }
-            buf.extend_from_slice(&bytes);
+            Some(_) => Ok(()),
+            None => Err(UrlError::Invalid),
+        },
+        // `example.com:8080/feed` parses with `example.com` as the scheme
+        Ok(parsed) if !parsed.scheme().contains('.') => {
+            Err(UrlError::UnsupportedScheme(parsed.scheme().into()))
+        }
+        _ if !url.contains("://") && reqwest::Url::parse(&format!("https://{}", url)).is_ok() => {
+            Err(UrlError::NoScheme)
         }
+        _ => Err(UrlError::Invalid),
+    }
+}
 
-        crate::feed::parse(std::io::Cursor::new(buf))?
+/// Make sure a user-supplied URL doesn't point into the network the bot is running in
+pub async fn check_public_url(url: &str) -> Result<reqwest::Url, UrlError> {
+    let url = reqwest::Url::parse(url).map_err(|_| UrlError::Invalid)?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(UrlError::UnsupportedScheme(url.scheme().into()));
+    }
+    let host = url.host_str().ok_or(UrlError::Invalid)?;
+    // Never looked up, and not reachable from the bot anyway
+    if is_onion(host) {
+        return Err(UrlError::NotPublic);
+    }
+    let ips: Vec<IpAddr> = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => vec![ip],
+        Err(_) => tokio::net::lookup_host((host, 0))
+            .await?
+            .map(|addr| addr.ip())
+            .collect(),
     };
+    if ips.is_empty() || !ips.into_iter().all(is_public) {
+        return Err(UrlError::NotPublic);
+    }
+    Ok(url)
+}
+
+/// Up to 5 redirects. Off the onion clients, a redirect to an onion service is an error:
+/// a proxy from the environment would look it up, or fetch it in the clear
+fn redirect_policy(onion: bool) -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= 5 {
+            attempt.error("too many redirects")
+        } else if !onion && attempt.url().host_str().is_some_and(is_onion) {
+            attempt.error(UrlError::NoOnionProxy)
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// Hosts of Tor onion services, they only resolve inside the Tor network
+fn is_onion(host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    host.len() > ".onion".len()
+        && host[host.len() - ".onion".len()..].eq_ignore_ascii_case(".onion")
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // "this network" and reserved
+                || a == 0
+                || a >= 240
+                // shared address space, 100.64.0.0/10
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(ip.into());
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // unique local, fc00::/7
+                || first & 0xfe00 == 0xfc00
+                // link local, fe80::/10
+                || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+/// Where a link of a known redirector leads to, with a single `HEAD` request.
+/// `None` if it's not a redirector link, or on any problem.
+pub async fn resolve_redirect(link: &str) -> Option<String> {
+    let url = reqwest::Url::parse(link).ok()?;
+    if !is_redirector(&url) || !take_resolve_budget() {
+        return None;
+    }
+    let resp = public_client()
+        .head(url.clone())
+        .timeout(RESOLVE_TIMEOUT)
+        .send()
+        .await
+        .ok()?;
+    if !resp.status().is_redirection() {
+        return None;
+    }
+    let location = resp.headers().get(LOCATION)?.to_str().ok()?;
+    let target = url.join(location).ok()?;
+    matches!(target.scheme(), "http" | "https").then(|| target.into())
+}
+
+fn is_redirector(url: &reqwest::Url) -> bool {
+    url.host_str()
+        .is_some_and(|host| REDIRECTOR_HOSTS.contains(&host))
+}
+
+fn take_resolve_budget() -> bool {
+    let mut budget = RESOLVE_BUDGET.lock().unwrap();
+    let now = Instant::now();
+    match &mut *budget {
+        Some((start, used)) if now.duration_since(*start) < Duration::from_secs(60) => {
+            if *used >= RESOLVE_RATE {
+                return false;
+            }
+            *used += 1;
+        }
+        _ => *budget = Some((now, 1)),
+    }
+    true
+}
 
-    Ok(crate::feed::fix_relative_url(feed, url))
+/// Only connects to public addresses and never follows redirects,
+/// for the URLs chosen by users or feeds
+pub fn public_client() -> &'static reqwest::Client {
+    PUBLIC_CLIENT.get().expect("PUBLIC_CLIENT not initialized")
 }
 
-pub fn init_client(bot_name: &str, insecue: bool, max_feed_size: u64) {
+/// The clients for every combination of the quirks that need their own,
+/// and the ones that go through `--onion-proxy`
+struct FeedClients {
+    /// By `http1_only` and `insecure`
+    clearnet: [[reqwest::Client; 2]; 2],
+    onion: Option<[[reqwest::Client; 2]; 2]>,
+}
+
+impl FeedClients {
+    fn get(&self, quirks: Quirks, onion: bool) -> Option<&reqwest::Client> {
+        let clients = if onion {
+            self.onion.as_ref()?
+        } else {
+            &self.clearnet
+        };
+        Some(&clients[quirks.http1_only as usize][quirks.insecure as usize])
+    }
+}
+
+/// With `prefer_ipv4`, IPv4 addresses are tried first, for hosts with broken IPv6 routes.
+/// With `http1_only`, HTTP/2 is never used for the feeds.
+/// Onion services are fetched through the SOCKS5 proxy `onion_proxy`.
+pub fn init_client(
+    bot_name: &str,
+    insecue: bool,
+    max_feed_size: u64,
+    dns_cache_ttl: u64,
+    prefer_ipv4: bool,
+    http1_only: bool,
+    onion_proxy: Option<SocketAddr>,
+) {
+    // A second run keeps the clients of the first
+    if FEED_CLIENTS.get().is_some() {
+        return;
+    }
     let mut headers = reqwest::header::HeaderMap::new();
     let ua = format!(
         concat!(
@@ -89,27 +963,226 @@ pub fn init_client(bot_name: &str, insecue: bool, max_feed_size: u64) {
         reqwest::header::USER_AGENT,
         reqwest::header::HeaderValue::from_str(&ua).unwrap(),
     );
-    let mut client_builder = reqwest::Client::builder()
-        .timeout(Duration::from_secs(10))
-        .default_headers(headers)
-        .danger_accept_invalid_certs(insecue)
-        .redirect(reqwest::redirect::Policy::limited(5));
-
-    if env::var("RSSBOT_DONT_PROXY_FEEDS")
+    let dns_cache = Arc::new(DnsCache::new(Duration::from_secs(dns_cache_ttl)));
+    let resolver = CachingResolver {
+        cache: dns_cache.clone(),
+        prefer_ipv4,
+    };
+    let dont_proxy = env::var("RSSBOT_DONT_PROXY_FEEDS")
         .or_else(|_| env::var("rssbot_dont_proxy_feeds"))
-        .is_ok()
-    {
-        client_builder = client_builder.no_proxy();
+        .is_ok();
+    // The connector tries the family of the first address, and races the other family
+    // if that doesn't connect in 300ms. Both count against the same timeout,
+    // a host that is down still fails in time.
+    let feed_client = |onion_proxy: Option<SocketAddr>, http1: bool, insecure: bool| {
+        let mut client_builder = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .dns_resolver(Arc::new(resolver.clone()))
+            .default_headers(headers.clone())
+            .danger_accept_invalid_certs(insecue || insecure)
+            .redirect(redirect_policy(onion_proxy.is_some()));
+        if let Some(proxy) = onion_proxy {
+            // socks5h, the proxy resolves the names, the resolver never sees them.
+            // It replaces the proxies from the environment
+            let proxy = reqwest::Proxy::all(format!("socks5h://{}", proxy)).unwrap();
+            client_builder = client_builder.proxy(proxy);
+        } else if dont_proxy {
+            client_builder = client_builder.no_proxy();
+        }
+        if http1_only || http1 {
+            client_builder = client_builder.http1_only();
+        }
+        client_builder.build().unwrap()
+    };
+    let clients = |onion_proxy| {
+        [false, true].map(|http1| {
+            [false, true].map(|insecure| feed_client(onion_proxy, http1, insecure))
+        })
+    };
+    let feed_clients = FeedClients {
+        clearnet: clients(None),
+        onion: onion_proxy.map(|proxy| clients(Some(proxy))),
+    };
+    if FEED_CLIENTS.set(feed_clients).is_err() {
+        panic!("FEED_CLIENTS already initialized");
+    }
+    if let Some(proxy) = onion_proxy {
+        ONION_PROXY.set(proxy).expect("ONION_PROXY already initialized");
     }
 
-    let client = client_builder.build().unwrap();
-
-    CLIENT.set(client).expect("CLIENT already initialized");
+    // Redirects could lead anywhere, and the resolver drops the non-public addresses
+    let mut public_client_builder = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .dns_resolver(Arc::new(PublicResolver(resolver)))
+        .default_headers(headers)
+        .redirect(reqwest::redirect::Policy::none());
+    if dont_proxy {
+        public_client_builder = public_client_builder.no_proxy();
+    }
+    PUBLIC_CLIENT
+        .set(public_client_builder.build().unwrap())
+        .expect("PUBLIC_CLIENT already initialized");
+    DNS_CACHE
+        .set(dns_cache)
+        .unwrap_or_else(|_| panic!("DNS_CACHE already initialized"));
     RESP_SIZE_LIMIT
         .set(max_feed_size)
         .expect("RESP_SIZE_LIMIT already initialized");
 }
 
+struct DnsEntry {
+    // `io::Error` is not `Clone`, so only keep what we need to rebuild it
+    addrs: Result<Vec<SocketAddr>, (io::ErrorKind, String)>,
+    expires_at: Instant,
+}
+
+/// A bounded host → addresses cache, lookups of names that don't exist are cached too
+pub struct DnsCache {
+    max_ttl: Duration,
+    entries: Mutex<HashMap<String, DnsEntry>>,
+}
+
+impl DnsCache {
+    fn new(max_ttl: Duration) -> Self {
+        DnsCache {
+            max_ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, host: &str, now: Instant) -> Option<io::Result<Vec<SocketAddr>>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(host) {
+            Some(entry) if entry.expires_at > now => Some(
+                entry
+                    .addrs
+                    .clone()
+                    .map_err(|(kind, msg)| io::Error::new(kind, msg)),
+            ),
+            Some(_) => {
+                entries.remove(host);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, host: String, addrs: &io::Result<Vec<SocketAddr>>, now: Instant) {
+        let (addrs, ttl) = match addrs {
+            Ok(addrs) => (Ok(addrs.clone()), self.max_ttl),
+            Err(e) if name_not_found(e) => (
+                Err((e.kind(), e.to_string())),
+                cmp::min(self.max_ttl, DNS_NEGATIVE_TTL),
+            ),
+            // A timeout or a failing DNS server, the next lookup may succeed
+            Err(_) => return,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= DNS_CACHE_CAPACITY && !entries.contains_key(&host) {
+            entries.retain(|_, entry| entry.expires_at > now);
+            if entries.len() >= DNS_CACHE_CAPACITY {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires_at)
+                    .map(|(host, _)| host.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(
+            host,
+            DnsEntry {
+                addrs,
+                expires_at: now + ttl,
+            },
+        );
+    }
+
+    pub fn invalidate(&self, host: &str) {
+        self.entries.lock().unwrap().remove(host);
+    }
+}
+
+/// The system resolver only tells it apart in its message, see `getaddrinfo(3)`,
+/// WSAHOST_NOT_FOUND and WSANO_DATA on Windows
+fn name_not_found(e: &io::Error) -> bool {
+    const NOT_FOUND: &[&str] = &[
+        "Name or service not known",
+        "No address associated with hostname",
+        "nodename nor servname provided, or not known",
+        "Name does not resolve",
+    ];
+    matches!(e.raw_os_error(), Some(11001 | 11004))
+        || NOT_FOUND.iter().any(|message| e.to_string().contains(message))
+}
+
+#[derive(Clone)]
+struct CachingResolver {
+    cache: Arc<DnsCache>,
+    prefer_ipv4: bool,
+}
+
+impl Resolve for CachingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let cache = self.cache.clone();
+        let prefer_ipv4 = self.prefer_ipv4;
+        Box::pin(async move {
+            let host = name.as_str().to_owned();
+            // Looking them up would tell the DNS servers which onion services are used,
+            // f.e. after a redirect from a clearnet feed
+            if is_onion(&host) {
+                let e = io::Error::new(io::ErrorKind::PermissionDenied, UrlError::NoOnionProxy);
+                return Err(e.into());
+            }
+            let addrs = match cache.get(&host, Instant::now()) {
+                Some(addrs) => addrs,
+                None => {
+                    // The port will be replaced by the connector
+                    let addrs = tokio::net::lookup_host((host.as_str(), 0))
+                        .await
+                        .map(|addrs| addrs.collect::<Vec<_>>());
+                    cache.insert(host, &addrs, Instant::now());
+                    addrs
+                }
+            };
+            addrs
+                .map(|mut addrs| {
+                    if prefer_ipv4 {
+                        sort_ipv4_first(&mut addrs);
+                    }
+                    Box::new(addrs.into_iter()) as Addrs
+                })
+                .map_err(|e| e.into())
+        })
+    }
+}
+
+/// Keeps the order of the system resolver otherwise
+fn sort_ipv4_first(addrs: &mut [SocketAddr]) {
+    addrs.sort_by_key(|addr| !addr.is_ipv4());
+}
+
+/// `CachingResolver` that only returns public addresses
+struct PublicResolver(CachingResolver);
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolving = self.0.resolve(name);
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = resolving
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                let e = io::Error::new(io::ErrorKind::PermissionDenied, UrlError::NotPublic);
+                return Err(e.into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
 fn content_type_is_json(value: &HeaderValue) -> bool {
     value
         .to_str()
@@ -123,7 +1196,7 @@ fn content_type_is_json(value: &HeaderValue) -> bool {
 }
 
 /// About the "kiB" not "KiB": https://en.wikipedia.org/wiki/Metric_prefix#List_of_SI_prefixes
-fn format_byte_size(bytes: u64) -> String {
+pub fn format_byte_size(bytes: u64) -> String {
     const SIZES: [&str; 7] = ["B", "kiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
     const BASE: f64 = 1024.0;
 
@@ -150,11 +1223,65 @@ fn format_byte_size(bytes: u64) -> String {
 mod test {
     use super::*;
 
+    #[test]
+    fn shorthands() {
+        let feed = |url: &str| Some(Shorthand::Feed(url.into()));
+        assert_eq!(resolve_shorthand("r/rust"), feed("https://www.reddit.com/r/rust/.rss"));
+        assert_eq!(
+            resolve_shorthand("https://old.reddit.com/r/rust/"),
+            feed("https://www.reddit.com/r/rust/.rss")
+        );
+        assert_eq!(resolve_shorthand("github.com/iovxw"), feed("https://github.com/iovxw.atom"));
+        match resolve_shorthand("https://github.com/iovxw/rssbot.git") {
+            Some(Shorthand::Choice(choices)) => assert_eq!(
+                choices.iter().map(|(_, url)| &**url).collect::<Vec<_>>(),
+                [
+                    "https://github.com/iovxw/rssbot/releases.atom",
+                    "https://github.com/iovxw/rssbot/commits.atom",
+                    "https://github.com/iovxw/rssbot/tags.atom",
+                ]
+            ),
+            other => panic!("{:?}", other),
+        }
+        // Feed URLs on these sites are used as they are
+        for url in [
+            "https://www.reddit.com/r/rust/.rss",
+            "https://github.com/iovxw/rssbot/releases.atom",
+            "https://example.com/feed.xml",
+            "r/",
+        ] {
+            assert_eq!(resolve_shorthand(url), None, "{}", url);
+        }
+    }
+
     #[test]
     fn max_format_byte_size() {
         assert_eq!(format_byte_size(u64::MAX), "16EiB");
     }
 
+    #[test]
+    fn feed_discovery() {
+        let page = reqwest::Url::parse("https://example.com/blog/").unwrap();
+        let html = r#"<html><head>
+            <link rel="stylesheet" type="text/css" href="/style.css">
+            <link rel="alternate" type="text/html" hreflang="en" href="/en/">
+            <LINK REL='Alternate' TYPE='application/atom+xml' HREF='feed.xml?a=1&amp;b=2'>
+            <link rel="alternate" type="application/rss+xml" href="/rss.xml">
+            </head></html>"#;
+        assert_eq!(
+            discover_feed_link(html, &page).as_deref(),
+            Some("https://example.com/blog/feed.xml?a=1&b=2")
+        );
+        let html = r#"<link href="https://feeds.example.com/rss" rel="alternate home"
+            type="application/rss+xml" />"#;
+        assert_eq!(
+            discover_feed_link(html, &page).as_deref(),
+            Some("https://feeds.example.com/rss")
+        );
+        assert_eq!(discover_feed_link(r#"<link rel="alternate" href="/en/">"#, &page), None);
+        assert_eq!(discover_feed_link("<p>no feeds here</p>", &page), None);
+    }
+
     #[test]
     fn format_byte_size_zero() {
         assert_eq!(format_byte_size(0), "0B");
@@ -171,4 +1298,446 @@ mod test {
 
         assert_eq!(format_byte_size(1024 + 10), "1kiB");
     }
+
+    fn addrs(port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok(vec![SocketAddr::from(([127, 0, 0, 1], port))])
+    }
+
+    #[test]
+    fn dns_cache_expiry() {
+        let cache = DnsCache::new(Duration::from_secs(300));
+        let now = Instant::now();
+        cache.insert("example.com".into(), &addrs(1), now);
+        assert_eq!(
+            cache.get("example.com", now).unwrap().unwrap(),
+            addrs(1).unwrap()
+        );
+        assert!(cache.get("example.com", now + Duration::from_secs(301)).is_none());
+    }
+
+    #[test]
+    fn dns_cache_negative_ttl() {
+        let cache = DnsCache::new(Duration::from_secs(300));
+        let now = Instant::now();
+        let lookup_error = |detail| {
+            let message = format!("failed to lookup address information: {}", detail);
+            Err(io::Error::new(io::ErrorKind::Other, message))
+        };
+        let err = lookup_error("Name or service not known");
+        cache.insert("typo.example".into(), &err, now);
+        assert!(cache.get("typo.example", now).unwrap().is_err());
+        assert!(cache
+            .get("typo.example", now + DNS_NEGATIVE_TTL + Duration::from_secs(1))
+            .is_none());
+        // Looked up again right away
+        let err = lookup_error("Temporary failure in name resolution");
+        cache.insert("example.com".into(), &err, now);
+        assert!(cache.get("example.com", now).is_none());
+        let err = Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+        cache.insert("example.com".into(), &err, now);
+        assert!(cache.get("example.com", now).is_none());
+    }
+
+    #[test]
+    fn dns_cache_bounded() {
+        let cache = DnsCache::new(Duration::from_secs(300));
+        let now = Instant::now();
+        for i in 0..DNS_CACHE_CAPACITY + 10 {
+            let t = now + Duration::from_millis(i as u64);
+            cache.insert(format!("{}.example", i), &addrs(1), t);
+        }
+        assert_eq!(cache.entries.lock().unwrap().len(), DNS_CACHE_CAPACITY);
+        // The entries expiring first are evicted first
+        assert!(cache.get("0.example", now).is_none());
+        assert!(cache
+            .get(&format!("{}.example", DNS_CACHE_CAPACITY + 9), now)
+            .is_some());
+    }
+
+    #[test]
+    fn rsshub_route() {
+        let expected = "http://localhost:1200/github/issue/iovxw/rssbot";
+        for base in ["http://localhost:1200", "http://localhost:1200/"] {
+            for route in ["github/issue/iovxw/rssbot", "/github/issue/iovxw/rssbot"] {
+                assert_eq!(join_rsshub_route(base, route), expected);
+            }
+        }
+        assert_eq!(
+            join_rsshub_route("https://example.com/rsshub/", "telegram/channel/x"),
+            "https://example.com/rsshub/telegram/channel/x"
+        );
+        assert_eq!(expand_url("https://example.com/feed.xml"), "https://example.com/feed.xml");
+    }
+
+    #[test]
+    fn public_addresses() {
+        for ip in ["1.1.1.1", "2606:4700:4700::1111", "100.128.0.1"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn check_url() {
+        assert!(matches!(
+            check_public_url("ftp://1.1.1.1/").await,
+            Err(UrlError::UnsupportedScheme(scheme)) if scheme == "ftp"
+        ));
+        assert!(matches!(
+            check_public_url("http://[::1]:8080/hook").await,
+            Err(UrlError::NotPublic)
+        ));
+        assert!(matches!(
+            check_public_url("http://localhost/hook").await,
+            Err(UrlError::NotPublic)
+        ));
+        assert!(check_public_url("https://1.1.1.1/hook").await.is_ok());
+    }
+
+    #[test]
+    fn feed_urls() {
+        for url in [
+            "http://example.com/feed.xml",
+            "https://example.com:8443/feed.xml?a=1",
+            "rsshub://telegram/channel/x",
+        ] {
+            assert!(check_feed_url(url).is_ok(), "{}", url);
+        }
+        for (url, scheme) in [
+            ("gopher://example.com/1/feed", "gopher"),
+            ("ftp://example.com/feed.xml", "ftp"),
+            ("file:///etc/passwd", "file"),
+            ("javascript:alert(1)", "javascript"),
+        ] {
+            match check_feed_url(url) {
+                Err(UrlError::UnsupportedScheme(s)) => assert_eq!(s, scheme),
+                other => panic!("{}: {:?}", url, other),
+            }
+        }
+        for url in ["example.com", "example.com/feed.xml", "example.com:8080/feed.xml"] {
+            assert!(matches!(check_feed_url(url), Err(UrlError::NoScheme)), "{}", url);
+        }
+        assert!(matches!(check_feed_url("http://"), Err(UrlError::Invalid)));
+    }
+
+    #[tokio::test]
+    async fn onion_services() {
+        for host in ["example.onion", "abc.example.ONION", "example.onion."] {
+            assert!(is_onion(host), "{}", host);
+        }
+        for host in ["onion", ".onion", "example.onion.com", "example.com"] {
+            assert!(!is_onion(host), "{}", host);
+        }
+        // The tests don't set --onion-proxy
+        assert!(matches!(
+            check_feed_url("http://example.onion/feed.xml"),
+            Err(UrlError::NoOnionProxy)
+        ));
+        assert!(matches!(
+            check_public_url("http://example.onion/hook").await,
+            Err(UrlError::NotPublic)
+        ));
+        // Not even looked up
+        let cache = Arc::new(DnsCache::new(Duration::from_secs(60)));
+        let resolver = CachingResolver {
+            cache: cache.clone(),
+            prefer_ipv4: false,
+        };
+        let name = "example.onion".parse().unwrap();
+        assert!(resolver.resolve(name).await.is_err());
+        assert!(cache.entries.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn old_unsupported_urls() {
+        let result = pull_feed("ftp://example.com/feed.xml", None, None).await;
+        assert!(matches!(result, Err(FeedError::Url(UrlError::UnsupportedScheme(_)))));
+        assert!(result.unwrap_err().to_user_friendly().contains("ftp://"));
+    }
+
+    #[tokio::test]
+    async fn feed_size_override() {
+        use crate::testing::{rss, FeedServer, Response};
+        crate::testing::init();
+        let server = FeedServer::start().await;
+        server.script("/feed.xml", vec![Response::ok(rss(0..5))]);
+        let url = server.url("/feed.xml");
+        assert!(fetch_feed(&url, None, None, Quirks::default()).await.is_ok());
+        let result = fetch_feed(&url, None, Some(100), Quirks::default()).await;
+        assert!(matches!(result, Err(FeedError::TooLarge(100))));
+        assert!(fetch_feed(&url, None, Some(0), Quirks::default()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn challenge_pages() {
+        use crate::testing::{rss, FeedServer, Response};
+        crate::testing::init();
+        let challenge = || Response::error_page(503, "<title>Just a moment...</title>");
+        let server = FeedServer::start().await;
+        server.script("/blocked.xml", vec![challenge()]);
+        let result = fetch_feed(&server.url("/blocked.xml"), None, None, Quirks::default()).await;
+        assert!(matches!(result, Err(FeedError::Blocked(StatusCode::SERVICE_UNAVAILABLE))));
+        // Passed with the browser's user agent
+        server.script("/feed.xml", vec![challenge(), Response::ok(rss(0..5))]);
+        assert!(fetch_feed(&server.url("/feed.xml"), None, None, Quirks::default()).await.is_ok());
+        // Just down
+        server.script("/down.xml", vec![Response::error_page(503, "Service Unavailable")]);
+        let result = fetch_feed(&server.url("/down.xml"), None, None, Quirks::default()).await;
+        assert!(matches!(result, Err(FeedError::Network(_))));
+        assert!(!is_challenge_page(b"<rss version=\"2.0\"></rss>"));
+    }
+
+    #[tokio::test]
+    async fn retry_after_header() {
+        use crate::testing::{FeedServer, Response};
+        crate::testing::init();
+        let server = FeedServer::start().await;
+        let throttled = Response::status(429).header("Retry-After", "3600");
+        server.script("/throttled.xml", vec![throttled]);
+        let result = fetch_feed(&server.url("/throttled.xml"), None, None, Quirks::default()).await;
+        assert_eq!(result.err().and_then(|e| e.retry_after()), Some(3600));
+        let dated = Response::status(503).header("Retry-After", "Wed, 21 Oct 2015 07:28:00 GMT");
+        server.script("/dated.xml", vec![dated]);
+        let result = fetch_feed(&server.url("/dated.xml"), None, None, Quirks::default()).await;
+        assert!(matches!(result, Err(FeedError::Network(_))));
+    }
+
+    #[tokio::test]
+    async fn not_acceptable() {
+        use crate::testing::{rss, FeedServer, Response};
+        crate::testing::init();
+        let server = FeedServer::start().await;
+        server.script("/feed.xml", vec![Response::status(406), Response::ok(rss(0..5))]);
+        let fetched = fetch_feed(&server.url("/feed.xml"), None, None, Quirks::default())
+            .await
+            .unwrap();
+        assert!(fetched.quirks.accept_any);
+        let requests = server.take_requests();
+        assert!(requests[0].contains("accept: application/rss+xml"));
+        assert!(requests[1].contains("accept: */*"));
+        // Remembered, a single request
+        let fetched = fetch_feed(&server.url("/feed.xml"), None, None, fetched.quirks)
+            .await
+            .unwrap();
+        assert!(fetched.quirks.accept_any);
+        assert_eq!(server.take_requests().len(), 1);
+        // Neither works
+        server.script("/broken.xml", vec![Response::status(406)]);
+        let result = fetch_feed(&server.url("/broken.xml"), None, None, Quirks::default()).await;
+        assert_eq!(result.err().and_then(|e| e.status()), Some(StatusCode::NOT_ACCEPTABLE));
+    }
+
+    #[tokio::test]
+    async fn conditional_requests() {
+        use crate::testing::{rss, FeedServer, Response};
+        crate::testing::init();
+        let server = FeedServer::start().await;
+        let ok = Response::ok(rss(0..5))
+            .header("ETag", "\"v1\"")
+            .header("Last-Modified", "Wed, 21 Oct 2015 07:28:00 GMT");
+        server.script("/feed.xml", vec![ok, Response::status(304)]);
+        let url = server.url("/feed.xml");
+        let fetched = fetch_feed(&url, None, None, Quirks::default()).await.unwrap();
+        assert_eq!(fetched.validators.etag.as_deref(), Some("\"v1\""));
+        let validators = fetched.validators;
+        let result = fetch_feed_if_modified(&url, None, None, Quirks::default(), &validators);
+        assert!(result.await.unwrap().is_none());
+        let requests = server.take_requests();
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains("if-none-match: \"v1\""));
+        assert!(requests[1].contains("if-modified-since: wed, 21 oct 2015 07:28:00 gmt"));
+        // Not asked for
+        assert!(fetch_feed(&url, None, None, Quirks::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn http1_only_quirk() {
+        use crate::testing::{rss, FeedServer, Response};
+        crate::testing::init();
+        let server = FeedServer::start().await;
+        server.script("/feed.xml", vec![Response::ok(rss(0..5))]);
+        let quirks = Quirks {
+            http1_only: true,
+            ..Default::default()
+        };
+        let fetched = fetch_feed(&server.url("/feed.xml"), None, None, quirks).await;
+        assert_eq!(fetched.unwrap().quirks, quirks);
+        // Only a 406 is retried with another `Accept`
+        server.script("/gone.xml", vec![Response::status(410)]);
+        let result = fetch_feed(&server.url("/gone.xml"), None, None, Quirks::default()).await;
+        assert!(fallback_quirks(&result.unwrap_err(), Quirks::default()).is_none());
+        assert_eq!(server.take_requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn http2_fallback() {
+        use crate::testing::FeedServer;
+        let server = FeedServer::start().await;
+        // It only speaks HTTP/1.1, the answer to the preface isn't a valid frame
+        let client = reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .no_proxy()
+            .build()
+            .unwrap();
+        let e = client.get(server.url("/feed.xml")).send().await.unwrap_err();
+        assert!(is_http2_error(&e));
+        let fallback = fallback_quirks(&FeedError::Network(e), Quirks::default());
+        assert_eq!(fallback.map(|quirks| quirks.http1_only), Some(true));
+        // Not a protocol error
+        let refused = client.get("http://127.0.0.1:1/").send().await.unwrap_err();
+        assert!(!is_http2_error(&refused));
+    }
+
+    #[tokio::test]
+    async fn onion_redirect() {
+        use crate::testing::{rss, FeedServer, Response};
+        let server = FeedServer::start().await;
+        server.script("/moved.xml", vec![Response::redirect("http://example.onion/feed.xml")]);
+        server.script("/old.xml", vec![Response::redirect("/feed.xml")]);
+        server.script("/feed.xml", vec![Response::ok(rss(0..1))]);
+        let client = reqwest::Client::builder()
+            .redirect(redirect_policy(false))
+            .no_proxy()
+            .build()
+            .unwrap();
+        // Stopped before connecting, not by the resolver
+        let e = client.get(server.url("/moved.xml")).send().await.unwrap_err();
+        assert!(e.is_redirect(), "{:?}", e);
+        let response = client.get(server.url("/old.xml")).send().await.unwrap();
+        assert!(response.status().is_success());
+    }
+
+    #[test]
+    fn ipv4_first() {
+        let v6 = |i: u16| SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, i], 0));
+        let v4 = |i: u8| SocketAddr::from(([192, 0, 2, i], 0));
+        let mut addrs = [v6(1), v4(1), v6(2), v4(2)];
+        sort_ipv4_first(&mut addrs);
+        assert_eq!(addrs, [v4(1), v4(2), v6(1), v6(2)]);
+    }
+
+    #[test]
+    fn pull_cache_expiry() {
+        let cache = PullCache::new();
+        let now = Instant::now();
+        let feed = Rss {
+            meta: crate::feed::FeedMeta {
+                title: "feed".into(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        cache.insert("ok".into(), &Ok(feed.clone()), now);
+        let failed = Err(Arc::new(FeedError::TooLarge(100)));
+        cache.insert("failed".into(), &failed, now);
+        let later = now + Duration::from_secs(5 * 60);
+        assert!(cache.get("failed", later).is_none());
+        assert_eq!(cache.get("ok", later).unwrap().unwrap(), feed);
+        assert!(cache.get("ok", now + Duration::from_secs(60 * 60)).is_none());
+
+        // The first to expire makes room
+        cache.insert("failed".into(), &failed, now);
+        for i in 1..PULLED_CAPACITY {
+            cache.insert(format!("{}", i), &Ok(feed.clone()), now);
+        }
+        cache.insert("new".into(), &Ok(feed.clone()), now);
+        assert!(cache.get("failed", now).is_none());
+        assert!(cache.get("new", now).is_some());
+        assert!(cache.get("1", now).is_some());
+    }
+
+    #[tokio::test]
+    async fn pull_cache_force() {
+        use crate::testing::{rss, FeedServer, Response};
+        crate::testing::init();
+        let server = FeedServer::start().await;
+        server.script("/feed.xml", vec![Response::status(500), Response::ok(rss(0..5))]);
+        let url = server.url("/feed.xml");
+        let error = pull_feed_cached(&url, false).await.unwrap_err();
+        assert_eq!(error.status(), Some(StatusCode::INTERNAL_SERVER_ERROR));
+        // The same error again, without asking the site
+        let replayed = pull_feed_cached(&url, false).await.unwrap_err();
+        assert!(Arc::ptr_eq(&error, &replayed));
+        assert_eq!(server.take_requests().len(), 1);
+
+        // Fixed, and remembered
+        assert_eq!(pull_feed_cached(&url, true).await.unwrap().items.len(), 5);
+        assert_eq!(pull_feed_cached(&url, false).await.unwrap().items.len(), 5);
+        assert_eq!(server.take_requests().len(), 1);
+    }
+
+    /// Peak memory of a cycle of 500 feeds, with the bodies buffered before parsing
+    /// like before, and with the streaming parser. Linux only, run it on its own:
+    /// `cargo test --release fetch_cycle_memory -- --ignored --nocapture`
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    #[ignore]
+    async fn fetch_cycle_memory() {
+        use crate::testing::{rss, FeedServer, Response};
+        const FEEDS: usize = 500;
+        fn peak_rss_kib() -> u64 {
+            let status = std::fs::read_to_string("/proc/self/status").unwrap();
+            let line = status.lines().find(|line| line.starts_with("VmHWM:")).unwrap();
+            line.split_whitespace().nth(1).unwrap().parse().unwrap()
+        }
+        fn reset_peak_rss() {
+            std::fs::write("/proc/self/clear_refs", "5").unwrap();
+        }
+
+        crate::testing::init();
+        let server = FeedServer::start().await;
+        // Some 400 KiB each
+        server.script("/feed.xml", vec![Response::ok(rss(0..4000))]);
+        let url = server.url("/feed.xml");
+        let client = reqwest::Client::new();
+
+        reset_peak_rss();
+        let base = peak_rss_kib();
+        let buffered = (0..FEEDS).map(|_| async {
+            let body = client.get(&url).send().await?.bytes().await?;
+            let parsed = tokio::task::spawn_blocking(move || crate::feed::parse(&body[..]));
+            Ok::<_, FeedError>(parsed.await.unwrap()?.items.len())
+        });
+        for items in futures::future::join_all(buffered).await {
+            assert_eq!(items.unwrap(), 4000);
+        }
+        let buffered_peak = peak_rss_kib() - base;
+
+        reset_peak_rss();
+        let base = peak_rss_kib();
+        let streamed = (0..FEEDS).map(|_| fetch_feed(&url, None, None, Quirks::default()));
+        for fetched in futures::future::join_all(streamed).await {
+            assert_eq!(fetched.unwrap().rss.items.len(), 4000);
+        }
+        let streamed_peak = peak_rss_kib() - base;
+        println!(
+            "peak RSS of {} feeds: buffered {} KiB, streamed {} KiB",
+            FEEDS, buffered_peak, streamed_peak
+        );
+        assert!(streamed_peak < buffered_peak);
+    }
+
+    #[test]
+    fn dns_cache_invalidate() {
+        let cache = DnsCache::new(Duration::from_secs(300));
+        let now = Instant::now();
+        cache.insert("example.com".into(), &addrs(1), now);
+        cache.invalidate("example.com");
+        assert!(cache.get("example.com", now).is_none());
+    }
 }
@@ -1,18 +1,57 @@
-use std::sync::Arc;
+use std::borrow::Cow;
+use std::sync::{Arc, OnceLock};
 
 use tbot::{contexts::Command, types::parameters, Bot};
-use tokio::sync::Mutex;
 
-use crate::data::Database;
+use crate::admins::{is_admin, Admin};
+use crate::chat_info;
+use crate::data::{chat_display, remember_chat_name, Database};
+use crate::messages::{fit_html, is_hidden, wait_send_turn, Limit, Priority};
 
+mod cookie;
+mod dedupe_feeds;
+mod digest;
 mod export;
+mod fetchnow;
+mod filter;
+mod footer;
+mod gardener;
+mod import;
+mod import_list;
+mod inline;
+mod insecure;
+mod inspect;
+mod latest;
+mod maxsize;
+mod my_chat_member;
+mod note;
+mod preview;
+mod queue;
+mod refresh;
 mod rss;
+mod rsshub;
+mod settings;
 mod start;
+mod status;
 mod sub;
+mod unfilter;
 mod unsub;
+mod version;
+mod webhook;
+
+// Set with `--restricted-strict`, the bot admins who can change any subscription
+static STRICT_ADMINS: OnceLock<Vec<Admin>> = OnceLock::new();
+// The `--admin`s, for the commands that do more for them
+static BOT_ADMINS: OnceLock<Vec<Admin>> = OnceLock::new();
 
 macro_rules! add_handlers {
     ($event_loop: ident, $opt: ident, $env: ident, [$( $cmd: ident),*]) => {
+        add_handlers!($event_loop, $opt, $env, check_command, [$($cmd),*])
+    };
+    ($event_loop: ident, $opt: ident, $env: ident, $check: ident, [$( $cmd: ident),*]) => {
+        add_handlers!($event_loop, $opt, $env, $check, command, [$($cmd),*])
+    };
+    ($event_loop: ident, $opt: ident, $env: ident, $check: ident, $register: ident, [$( $cmd: ident),*]) => {
         $({
             let env = $env.clone();
             let opt = $opt.clone();
@@ -20,14 +59,15 @@ macro_rules! add_handlers {
                 let env = env.clone();
                 let opt = opt.clone();
                 async move {
-                    if check_command(&opt, &cmd).await {
+                    crate::health::HEARTBEATS.event_loop();
+                    if $check(&opt, &cmd).await {
                         if let Err(e) = self::$cmd::$cmd(env, cmd).await {
                             crate::print_error(e);
                         }
                     }
                 }
             };
-            $event_loop.command(stringify!($cmd), h);
+            $event_loop.$register(stringify!($cmd), h);
         })*
     };
 }
@@ -35,9 +75,79 @@ macro_rules! add_handlers {
 pub fn register_commands(
     event_loop: &mut tbot::EventLoop,
     opt: Arc<crate::Opt>,
-    db: Arc<Mutex<Database>>,
+    db: Arc<Database>,
 ) {
-    add_handlers!(event_loop, opt, db, [start, rss, sub, unsub, export]);
+    // Always the same, a second run keeps the first
+    let _ = BOT_ADMINS.set(opt.admin.clone());
+    if opt.restricted_strict {
+        let _ = STRICT_ADMINS.set(opt.admin.clone());
+    }
+    add_handlers!(event_loop, opt, db, [start, rss, latest, preview, sub, unsub, export, import, import_list, note, filter, unfilter, digest, settings, status, webhook, version]);
+    add_handlers!(event_loop, opt, db, check_admin_command, [rsshub, cookie, maxsize, fetchnow, refresh, gardener, footer, dedupe_feeds, inspect, insecure, queue]);
+    // Typo'd URLs are often fixed by editing the message
+    add_handlers!(event_loop, opt, db, check_edited_command, edited_command, [rss, sub, unsub]);
+
+    let callback_db = db.clone();
+    event_loop.data_callback(move |ctx| {
+        let db = callback_db.clone();
+        async move {
+            crate::health::HEARTBEATS.event_loop();
+            if let Err(e) = self::sub::choose(db, ctx).await {
+                crate::print_error(e);
+            }
+        }
+    });
+
+    let member_db = db.clone();
+    let member_opt = opt.clone();
+    event_loop.my_chat_member(move |ctx| {
+        let db = member_db.clone();
+        let opt = member_opt.clone();
+        async move {
+            crate::health::HEARTBEATS.event_loop();
+            if let Err(e) = self::my_chat_member::my_chat_member(db, opt, ctx).await {
+                crate::print_error(e);
+            }
+        }
+    });
+
+    event_loop.inline(move |query| {
+        let db = db.clone();
+        let opt = opt.clone();
+        async move {
+            crate::health::HEARTBEATS.event_loop();
+            if let Err(e) = self::inline::inline(db, opt, query).await {
+                crate::print_error(e);
+            }
+        }
+    });
+}
+
+/// How long after sending a command editing it runs the command again, in seconds
+const EDIT_WINDOW: i64 = 5 * 60;
+
+/// Editing an old message shouldn't run it again
+pub async fn check_edited_command(opt: &crate::Opt, cmd: &Command) -> bool {
+    let age = chrono::Utc::now().timestamp() - cmd.date;
+    age <= EDIT_WINDOW && check_command(opt, cmd).await
+}
+
+/// Commands that change the bot itself, only for the `--admin`s
+pub async fn check_admin_command(opt: &crate::Opt, cmd: &Command) -> bool {
+    if !check_command(opt, cmd).await {
+        return false;
+    }
+    if !is_from_bot_admin(cmd, &opt.admin) {
+        let reply_target = &mut MsgTarget::new(cmd.chat.id, cmd.message_id);
+        let _ignore_result = update_response(
+            &cmd.bot,
+            reply_target,
+            parameters::Text::with_plain(tr!("bot_admin_only_command")),
+        )
+        .await;
+        return false;
+    }
+    true
 }
 
 pub async fn check_command(opt: &crate::Opt, cmd: &Command) -> bool {
@@ -52,6 +162,8 @@ pub async fn check_command(opt: &crate::Opt, cmd: &Command) -> bool {
         );
         return false;
     }
+    // Only of the chats allowed to use the bot
+    remember_names(cmd);
 
     match cmd.chat.kind {
         Channel { .. } => {
@@ -61,7 +173,7 @@ pub async fn check_command(opt: &crate::Opt, cmd: &Command) -> bool {
             return false;
         }
         // Restrict mode: bot commands are only accessible to admins.
-        Group { .. } | Supergroup { .. } if opt.restricted => {
+        Group { .. } | Supergroup { .. } if opt.restricted || opt.restricted_strict => {
             let user_is_admin = is_from_chat_admin(cmd).await;
             if !user_is_admin {
                 let _ignore_result = update_response(
@@ -79,16 +191,39 @@ pub async fn check_command(opt: &crate::Opt, cmd: &Command) -> bool {
     true
 }
 
-fn is_from_bot_admin(cmd: &Command, admins: &[i64]) -> bool {
+/// For the admins, see `data::chat_display`
+fn remember_names(cmd: &Command) {
     use tbot::types::message::From;
-    match &cmd.from {
-        Some(from) => {
-            let id = match from {
-                From::User(user) => user.id.0,
-                From::Chat(chat) => chat.id.0,
-            };
-            admins.contains(&id)
+    remember_chat(&cmd.chat);
+    if let Some(From::User(user)) = &cmd.from {
+        remember_chat_name(user.id.0, Some(&user.first_name), user.username.as_deref());
+    }
+}
+
+pub fn remember_chat(chat: &tbot::types::Chat) {
+    use tbot::types::chat::Kind::*;
+    let (title, username) = match &chat.kind {
+        Private {
+            first_name,
+            username,
+            ..
+        } => (first_name, username),
+        Group { title, .. } => (title, &None),
+        Supergroup {
+            title, username, ..
         }
+        | Channel {
+            title, username, ..
+        } => (title, username),
+    };
+    remember_chat_name(chat.id.0, Some(title), username.as_deref());
+}
+
+fn is_from_bot_admin(cmd: &Command, admins: &[Admin]) -> bool {
+    use tbot::types::message::From;
+    match &cmd.from {
+        Some(From::User(user)) => is_admin(admins, user.id.0, user.username.as_deref()),
+        Some(From::Chat(chat)) => is_admin(admins, chat.id.0, None),
         None => false,
     }
 }
@@ -96,18 +231,23 @@ fn is_from_bot_admin(cmd: &Command, admins: &[i64]) -> bool {
 async fn is_from_chat_admin(cmd: &Command) -> bool {
     use tbot::types::message::From;
     match &cmd.from {
-        Some(From::User(user)) => {
-            let admins = match cmd.bot.get_chat_administrators(cmd.chat.id).call().await {
-                Ok(r) => r,
-                _ => return false,
-            };
-            admins.iter().any(|member| member.user.id == user.id)
-        }
+        Some(From::User(user)) => is_chat_admin(&cmd.bot, cmd.chat.id, user.id).await,
         Some(From::Chat(chat)) => chat.id == cmd.chat.id,
         None => false,
     }
 }
 
+async fn is_chat_admin(
+    bot: &Bot,
+    chat_id: tbot::types::chat::Id,
+    user: tbot::types::user::Id,
+) -> bool {
+    match chat_info::get_chat_administrators(bot, chat_id).await {
+        Ok(admins) => admins.iter().any(|member| member.user.id == user),
+        _ => false,
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 struct MsgTarget {
     chat_id: tbot::types::chat::Id,
@@ -123,6 +263,14 @@ impl MsgTarget {
             first_time: true,
         }
     }
+    /// Edit a message of the bot instead of replying
+    fn edit(chat_id: tbot::types::chat::Id, message_id: tbot::types::message::Id) -> Self {
+        MsgTarget {
+            chat_id,
+            message_id,
+            first_time: false,
+        }
+    }
     fn update(&mut self, message_id: tbot::types::message::Id) {
         self.message_id = message_id;
         self.first_time = false;
@@ -134,6 +282,7 @@ async fn update_response(
     target: &mut MsgTarget,
     message: parameters::Text,
 ) -> Result<(), tbot::errors::MethodCall> {
+    wait_send_turn(target.chat_id.0, Priority::Reply).await;
     let msg = if target.first_time {
         bot.send_message(target.chat_id, message)
             .in_reply_to(target.message_id)
@@ -150,6 +299,64 @@ async fn update_response(
     Ok(())
 }
 
+/// Send the messages generated by `format_large_msg`, each one replies to the previous
+async fn reply_large_msgs(
+    bot: &Bot,
+    target: &mut MsgTarget,
+    mut msgs: Vec<String>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let first_msg = msgs.remove(0);
+    let first_msg = fit_html(&first_msg, Limit::Text);
+    update_response(bot, target, parameters::Text::with_html(&first_msg)).await?;
+
+    let mut prev_msg = target.message_id;
+    for msg in msgs {
+        let msg = fit_html(&msg, Limit::Text);
+        let text = parameters::Text::with_html(&msg);
+        wait_send_turn(target.chat_id.0, Priority::Reply).await;
+        let msg = bot
+            .send_message(target.chat_id, text)
+            .in_reply_to(prev_msg)
+            .is_web_page_preview_disabled(true)
+            .call()
+            .await?;
+        prev_msg = msg.id;
+    }
+    Ok(())
+}
+
+/// The user who sent the command, `None` for anonymous admins and channels
+fn sender_id(cmd: &Command) -> Option<i64> {
+    use tbot::types::message::From;
+    match &cmd.from {
+        Some(From::User(user)) => Some(user.id.0),
+        _ => None,
+    }
+}
+
+/// Bots are often added to groups and channels without the permission to post
+async fn bot_can_post(
+    bot: &Bot,
+    chat_id: tbot::types::chat::Id,
+) -> Result<bool, tbot::errors::MethodCall> {
+    let me = chat_info::get_chat_member(bot, chat_id, *crate::BOT_ID.get().unwrap()).await?;
+    Ok(can_post(&me.status))
+}
+
+fn can_post(status: &tbot::types::chat::member::Status) -> bool {
+    use tbot::types::chat::member::Status;
+    match *status {
+        // Only set in channels
+        Status::Administrator {
+            can_post_messages, ..
+        } => can_post_messages != Some(false),
+        Status::Restricted {
+            can_send_messages, ..
+        } => can_send_messages,
+        ref status => !(status.is_left() || status.is_kicked()),
+    }
+}
+
 async fn check_channel_permission(
     cmd: &Command,
     channel: &str,
@@ -181,7 +388,7 @@ async fn check_channel_permission(
     )
     .await?;
 
-    let chat = match bot.get_chat(channel_id.clone()).call().await {
+    let chat = match bot.get_chat(channel_id).call().await {
         Err(MethodCall::RequestError {
             description,
             error_code: 400,
@@ -202,7 +409,8 @@ async fn check_channel_permission(
         .await?;
         return Ok(None);
     }
-    let admins = match bot.get_chat_administrators(channel_id).call().await {
+    remember_chat(&chat);
+    let admins = match chat_info::get_chat_administrators(bot, chat.id).await {
         Err(MethodCall::RequestError {
             description,
             error_code: 400,
@@ -238,3 +446,234 @@ async fn check_channel_permission(
     }
     Ok(Some(chat.id))
 }
+
+/// With `--restricted-strict` only the user who subscribed the chat to the feed, or a bot
+/// admin, can change or remove the subscription. Tells the sender who owns it otherwise
+async fn check_owner(
+    cmd: &Command,
+    db: &Database,
+    subscriber: tbot::types::chat::Id,
+    feed_url: &str,
+    target: &mut MsgTarget,
+) -> Result<bool, tbot::errors::MethodCall> {
+    use tbot::errors::MethodCall;
+    let admins = match STRICT_ADMINS.get() {
+        Some(admins) => admins,
+        None => return Ok(true),
+    };
+    let owner = match db.subscription_owner(subscriber.0, feed_url) {
+        Some(owner) => owner,
+        None => return Ok(true),
+    };
+    if sender_id(cmd) == Some(owner) || is_from_bot_admin(cmd, admins) {
+        return Ok(true);
+    }
+    // Only noticed now that it matters
+    let owner_id = tbot::types::user::Id(owner);
+    let left = match chat_info::get_chat_member(&cmd.bot, subscriber, owner_id).await {
+        Ok(member) => member.status.is_left() || member.status.is_kicked(),
+        // Deleted accounts, and users who were never in the chat
+        Err(MethodCall::RequestError {
+            description,
+            error_code: 400,
+            ..
+        }) if description.to_lowercase().contains("user not found") => true,
+        Err(MethodCall::RequestError { description, .. }) => {
+            let msg = tr!("unable_to_check_owner", desc = description);
+            update_response(&cmd.bot, target, parameters::Text::with_plain(&msg)).await?;
+            return Ok(false);
+        }
+        Err(e) => return Err(e),
+    };
+    if left {
+        db.disown_subscription(subscriber.0, feed_url);
+        return Ok(true);
+    }
+    let msg = tr!("subscription_owned_by", owner = chat_display(owner));
+    update_response(&cmd.bot, target, parameters::Text::with_plain(&msg)).await?;
+    Ok(false)
+}
+
+/// The URL in what was pasted as one: `<https://example.com/feed>`, `[feed](url)`,
+/// in quotes or with the period ending the sentence, with zero width spaces from
+/// a mobile keyboard. An IDN host is converted to punycode, like it's fetched.
+/// Anything else is returned without the invisible characters and the wrapping.
+fn clean_url(arg: &str) -> String {
+    let visible: String = arg
+        .chars()
+        .filter(|&c| !is_hidden(c) && !matches!(c, '\u{200C}' | '\u{200D}' | '\u{00AD}'))
+        .collect();
+    let mut url = visible.trim();
+    loop {
+        let unwrapped = markdown_link(url)
+            .or_else(|| unwrap_pair(url))
+            .or_else(|| strip_trailing_punctuation(url));
+        match unwrapped {
+            Some(inner) => url = inner.trim(),
+            None => break,
+        }
+    }
+    let range = match host_range(url) {
+        Some(range) if !url[range.clone()].is_ascii() => range,
+        _ => return url.to_string(),
+    };
+    match idna::domain_to_ascii(&url[range.clone()]) {
+        Ok(ascii) => format!("{}{}{}", &url[..range.start], ascii, &url[range.end..]),
+        Err(_) => url.to_string(),
+    }
+}
+
+/// The URL with an IDN host in Unicode, the way users know it
+fn display_url(url: &str) -> Cow<'_, str> {
+    match host_range(url) {
+        Some(range) if url[range.clone()].contains("xn--") => {
+            let (unicode, result) = idna::domain_to_unicode(&url[range.clone()]);
+            if result.is_err() {
+                return Cow::Borrowed(url);
+            }
+            Cow::Owned(format!("{}{}{}", &url[..range.start], unicode, &url[range.end..]))
+        }
+        _ => Cow::Borrowed(url),
+    }
+}
+
+/// Where the host is in `scheme://user@host:port/path`
+fn host_range(url: &str) -> Option<std::ops::Range<usize>> {
+    let start = url.find("://")? + 3;
+    let end = url[start..]
+        .find(['/', '?', '#'])
+        .map_or(url.len(), |i| start + i);
+    let start = url[start..end].rfind('@').map_or(start, |i| start + i + 1);
+    // Not the port, unless it's an IPv6 address
+    let end = match url[start..end].rfind(':') {
+        Some(i) if !url[start..end].ends_with(']') => start + i,
+        _ => end,
+    };
+    Some(start..end)
+}
+
+/// `[text](url)` of Markdown
+fn markdown_link(s: &str) -> Option<&str> {
+    let rest = s.strip_prefix('[')?.strip_suffix(')')?;
+    let (_, url) = rest.split_once("](")?;
+    Some(url)
+}
+
+fn unwrap_pair(s: &str) -> Option<&str> {
+    const PAIRS: [(char, char); 10] = [
+        ('<', '>'),
+        ('"', '"'),
+        ('\'', '\''),
+        ('`', '`'),
+        ('(', ')'),
+        ('[', ']'),
+        ('\u{201C}', '\u{201D}'),
+        ('\u{2018}', '\u{2019}'),
+        ('\u{00AB}', '\u{00BB}'),
+        ('\u{300C}', '\u{300D}'),
+    ];
+    PAIRS.iter().find_map(|&(open, close)| {
+        let inner = s.strip_prefix(open)?.strip_suffix(close)?;
+        Some(inner)
+    })
+}
+
+/// Punctuation ending a sentence isn't part of the URL, a closing parenthesis only
+/// if it has no opening one in the URL, like Wikipedia's have
+fn strip_trailing_punctuation(s: &str) -> Option<&str> {
+    let last = s.chars().next_back()?;
+    let unbalanced = |open, close| s.matches(open).count() < s.matches(close).count();
+    let strip = match last {
+        '.' | ',' | ';' | ':' | '!' | '?' | '\u{3002}' | '\u{FF0C}' | '\u{FF1B}' | '\u{FF1A}'
+        | '\u{FF01}' | '\u{FF1F}' | '\u{2026}' | '\u{3001}' => true,
+        // The other half of a pair, after the punctuation was stripped
+        '>' | '"' | '\'' | '\u{201D}' | '\u{2019}' | '\u{00BB}' | '\u{300D}' | '`' => true,
+        ')' => unbalanced('(', ')'),
+        ']' => unbalanced('[', ']'),
+        _ => false,
+    };
+    strip.then(|| &s[..s.len() - last.len_utf8()])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pasted_urls() {
+        let url = "https://example.com/feed.xml";
+        let pasted = [
+            "https://example.com/feed.xml",
+            "  https://example.com/feed.xml\n",
+            "<https://example.com/feed.xml>",
+            "\"https://example.com/feed.xml\"",
+            "'https://example.com/feed.xml'",
+            "`https://example.com/feed.xml`",
+            "“https://example.com/feed.xml”",
+            "「https://example.com/feed.xml」",
+            "(https://example.com/feed.xml)",
+            "https://example.com/feed.xml.",
+            "https://example.com/feed.xml,",
+            "https://example.com/feed.xml!)",
+            "https://example.com/feed.xml。",
+            "https://example.com/feed.xml…",
+            "<https://example.com/feed.xml>.",
+            "\"https://example.com/feed.xml\".",
+            "https://example.com/feed.xml\u{200B}",
+            "\u{FEFF}https://exam\u{200B}ple.com/feed.xml",
+            "\u{202E}https://example.com/feed.xml\u{202C}",
+            "https://example.com/\u{00AD}feed.xml",
+            "[feed](https://example.com/feed.xml)",
+            "[https://example.com/feed.xml](https://example.com/feed.xml)",
+            "<[feed](https://example.com/feed.xml)>",
+        ];
+        for pasted in pasted {
+            assert_eq!(clean_url(pasted), url, "{:?}", pasted);
+        }
+        // Part of the URL
+        let kept = [
+            "https://en.wikipedia.org/wiki/Rust_(programming_language)",
+            "https://example.com/feed?format=rss&page=1",
+            "https://example.com/feed/",
+            "https://example.com:8080/feed#top",
+            "rsshub://github/issue/iovxw/rssbot",
+            "example.com/feed",
+        ];
+        for kept in kept {
+            assert_eq!(clean_url(kept), kept);
+        }
+        assert_eq!(
+            clean_url("https://en.wikipedia.org/wiki/Rust_(programming_language))."),
+            "https://en.wikipedia.org/wiki/Rust_(programming_language)"
+        );
+        // Not a URL, only the junk is gone
+        assert_eq!(clean_url("<not a url>"), "not a url");
+        assert_eq!(clean_url("\u{200B}"), "");
+    }
+
+    #[test]
+    fn idn_hosts() {
+        let pasted = [
+            ("https://例子.测试/feed.xml", "https://xn--fsqu00a.xn--0zwm56d/feed.xml"),
+            ("<https://Bücher.example/rss>.", "https://xn--bcher-kva.example/rss"),
+            (
+                "http://user@bücher.example:8080/?a=ü",
+                "http://user@xn--bcher-kva.example:8080/?a=ü",
+            ),
+            ("https://xn--bcher-kva.example/rss", "https://xn--bcher-kva.example/rss"),
+        ];
+        for (pasted, fetched) in pasted {
+            assert_eq!(clean_url(pasted), fetched);
+        }
+        assert_eq!(
+            display_url("https://xn--fsqu00a.xn--0zwm56d/feed.xml"),
+            "https://例子.测试/feed.xml"
+        );
+        assert_eq!(
+            display_url("http://user@xn--bcher-kva.example:8080/"),
+            "http://user@bücher.example:8080/"
+        );
+        assert_eq!(display_url("https://example.com/xn--"), "https://example.com/xn--");
+        assert_eq!(display_url("not a url"), "not a url");
+    }
+}
@@ -0,0 +1,40 @@
+use std::sync::Arc;
+
+use reqwest::header::HeaderValue;
+use tbot::{contexts::Command, types::parameters};
+
+use crate::data::Database;
+
+use super::{update_response, MsgTarget};
+
+pub async fn cookie(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let text = cmd.text.value.trim();
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+
+    // Cookie values may contain spaces, take everything after the URL
+    let msg = match text.split_once(char::is_whitespace) {
+        Some((feed_url, "clear")) => {
+            if db.set_cookie(chat_id.0, feed_url, None) {
+                tr!("cookie_cleared")
+            } else {
+                tr!("cookie_not_owned")
+            }
+        }
+        Some((feed_url, value)) if HeaderValue::from_str(value.trim()).is_ok() => {
+            // Never echo the value back, the chat history may be shared later
+            if db.set_cookie(chat_id.0, feed_url, Some(value.trim().to_string())) {
+                tr!("cookie_set")
+            } else {
+                tr!("cookie_not_owned")
+            }
+        }
+        Some(_) => tr!("cookie_invalid"),
+        None => tr!("cookie_how_to_use"),
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+    Ok(())
+}
@@ -0,0 +1,128 @@
+use std::sync::{Arc, Mutex};
+
+use futures::future::join_all;
+use tbot::{contexts::Command, types::parameters};
+
+use crate::client::{fetch_feed, Fetched};
+use crate::data::{normalize_feed_link, Database, Feed};
+
+use super::{update_response, MsgTarget};
+
+// The plan of the last /dedupe_feeds, with the chat it was shown in
+static PLAN: Mutex<Option<(i64, Vec<Merge>)>> = Mutex::new(None);
+
+#[derive(Debug, Clone)]
+struct Merge {
+    canonical: String,
+    duplicates: Vec<String>,
+}
+
+pub async fn dedupe_feeds(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let target = &mut MsgTarget::new(cmd.chat.id, cmd.message_id);
+    let msg = match cmd.text.value.trim() {
+        "" => {
+            update_response(
+                &cmd.bot,
+                target,
+                parameters::Text::with_plain(tr!("processing_please_wait")),
+            )
+            .await?;
+            let mut plan = Vec::new();
+            for group in db.feed_variants() {
+                plan.extend(plan_merge(group).await);
+            }
+            let msg = if plan.is_empty() {
+                tr!("dedupe_feeds_none").to_string()
+            } else {
+                let mut msg = tr!("dedupe_feeds_plan", count = plan.len());
+                for merge in &plan {
+                    msg.push_str("\n\n");
+                    msg.push_str(&merge.canonical);
+                    for duplicate in &merge.duplicates {
+                        msg.push_str("\n  ← ");
+                        msg.push_str(duplicate);
+                    }
+                }
+                msg
+            };
+            *PLAN.lock().unwrap() = Some((cmd.chat.id.0, plan));
+            msg
+        }
+        "confirm" => {
+            let plan = match PLAN.lock().unwrap().take() {
+                Some((chat, plan)) if chat == cmd.chat.id.0 && !plan.is_empty() => plan,
+                _ => Vec::new(),
+            };
+            if plan.is_empty() {
+                tr!("dedupe_feeds_no_plan").to_string()
+            } else {
+                let feeds: usize = plan.iter().map(|merge| merge.duplicates.len()).sum();
+                let subscriptions: usize = plan
+                    .iter()
+                    .map(|merge| db.merge_feeds(&merge.canonical, &merge.duplicates))
+                    .sum();
+                tr!("dedupe_feeds_merged", feeds = feeds, subscriptions = subscriptions)
+            }
+        }
+        _ => tr!("dedupe_feeds_how_to_use").to_string(),
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_plain(&msg)).await?;
+    Ok(())
+}
+
+/// Only merge what surely is the same feed: the same site, and either a redirect
+/// between them or the same newest item
+async fn plan_merge(group: Vec<Feed>) -> Option<Merge> {
+    let fetched: Vec<Option<Fetched>> = join_all(
+        group
+            .iter()
+            .map(|feed| fetch_feed(&feed.link, feed.cookie(), feed.max_size, feed.quirks)),
+    )
+    .await
+    .into_iter()
+    .map(Result::ok)
+    .collect();
+    let redirected_to = |i: usize| {
+        fetched[i]
+            .as_ref()
+            .map(|f| normalize_feed_link(f.url.as_str()).into_owned())
+    };
+    let is_link = |i: usize, link: &Option<String>| {
+        link.as_deref() == Some(&*normalize_feed_link(&group[i].link))
+    };
+    // Where the others redirect to, then https, then the most subscribed
+    let canonical = (0..group.len())
+        .find(|&i| (0..group.len()).any(|j| j != i && is_link(i, &redirected_to(j))))
+        .or_else(|| {
+            (0..group.len()).max_by_key(|&i| {
+                (group[i].link.starts_with("https://"), group[i].subscribers.len())
+            })
+        })?;
+    let site = |fetched: &Fetched| normalize_feed_link(fetched.rss.meta.link.trim()).into_owned();
+    let newest_id = |fetched: &Fetched| fetched.rss.items.first().and_then(|item| item.id.clone());
+    let canonical_fetched = fetched[canonical].as_ref()?;
+    let duplicates: Vec<String> = (0..group.len())
+        .filter(|&i| i != canonical)
+        .filter(|&i| match &fetched[i] {
+            Some(other) => {
+                let same_site = !canonical_fetched.rss.meta.link.trim().is_empty()
+                    && site(other) == site(canonical_fetched);
+                let same_newest = newest_id(other).is_some()
+                    && newest_id(other) == newest_id(canonical_fetched);
+                same_site && (is_link(canonical, &redirected_to(i)) || same_newest)
+            }
+            None => false,
+        })
+        .map(|i| group[i].link.clone())
+        .collect();
+    if duplicates.is_empty() {
+        return None;
+    }
+    Some(Merge {
+        canonical: group[canonical].link.clone(),
+        duplicates,
+    })
+}
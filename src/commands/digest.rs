@@ -0,0 +1,120 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+
+use crate::data::{Database, Digest, DigestSchedule};
+use crate::messages::{Escape, Title};
+
+use super::note::next_arg;
+use super::{check_channel_permission, check_owner, clean_url, update_response, MsgTarget};
+
+pub async fn digest(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+
+    let (first, rest) = next_arg(cmd.text.value.trim());
+    let (channel, feed_url, value) = if first.contains("://") {
+        (None, first, rest)
+    } else {
+        let (url, rest) = next_arg(rest);
+        (Some(first), url, rest)
+    };
+    if !feed_url.contains("://") || value.split_whitespace().count() > 1 {
+        let msg = tr!("digest_how_to_use");
+        update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+        return Ok(());
+    }
+    if let Some(channel) = channel {
+        match check_channel_permission(&cmd, channel, target).await? {
+            Some(channel_id) => target_id = channel_id,
+            None => return Ok(()),
+        }
+    }
+
+    let feed_url = &clean_url(feed_url);
+    let feed = match db.subscribed_feed(target_id.0, feed_url) {
+        Some(feed) => feed,
+        None => {
+            let msg = tr!("feed_not_found");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+    let link = Escape(&feed.link);
+    let title = Title(&feed.title);
+
+    if value.is_empty() {
+        let msg = match feed.subscription(target_id.0).digest {
+            Some(digest) => tr!(
+                "digest_show",
+                link = link,
+                title = title,
+                schedule = format_schedule(digest.schedule),
+                count = feed.digest_items(target_id.0)
+            ),
+            None => tr!("digest_none", link = link, title = title),
+        };
+        update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+        return Ok(());
+    }
+    let schedule = match parse_schedule(value) {
+        Some(schedule) => schedule,
+        None => {
+            let msg = tr!("digest_invalid");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+    if !check_owner(&cmd, &db, target_id, feed_url, target).await? {
+        return Ok(());
+    }
+    // From now on, a time that already passed today is only sent tomorrow
+    let now = chrono::Utc::now().timestamp();
+    db.update_subscription(target_id.0, feed_url, |s| {
+        s.digest = schedule.map(|schedule| Digest {
+            schedule,
+            last_sent: now,
+        })
+    });
+    let msg = match schedule {
+        Some(schedule) => tr!(
+            "digest_set",
+            link = link,
+            title = title,
+            schedule = format_schedule(schedule)
+        ),
+        // The items kept for it are sent with the next check
+        None => tr!("digest_off", link = link, title = title),
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+    Ok(())
+}
+
+/// `daily` at 09:00 like the weekly report, `hourly`, `18:30`, or `off` for `None`
+fn parse_schedule(value: &str) -> Option<Option<DigestSchedule>> {
+    let schedule = match value {
+        "off" => return Some(None),
+        "hourly" => DigestSchedule::Hourly,
+        "daily" => DigestSchedule::Daily(9 * 60),
+        _ => {
+            let (hour, minute) = value.split_once(':')?;
+            let (hour, minute) = (hour.parse::<u32>().ok()?, minute.parse::<u32>().ok()?);
+            if hour > 23 || minute > 59 {
+                return None;
+            }
+            DigestSchedule::Daily(hour * 60 + minute)
+        }
+    };
+    Some(Some(schedule))
+}
+
+fn format_schedule(schedule: DigestSchedule) -> String {
+    match schedule {
+        DigestSchedule::Hourly => tr!("digest_hourly").into(),
+        DigestSchedule::Daily(_) => tr!("digest_daily", time = schedule),
+    }
+}
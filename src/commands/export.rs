@@ -4,47 +4,99 @@ use tbot::{
     contexts::Command,
     types::{input_file, parameters},
 };
-use tokio::sync::Mutex;
 
-use crate::data::Database;
-use crate::opml::into_opml;
+use crate::data::{chat_title, Database};
+use crate::export::Export;
+use crate::opml::{export_filename, into_opml};
 
-use super::{check_channel_permission, update_response, MsgTarget};
+use super::{
+    check_channel_permission, is_from_bot_admin, update_response, MsgTarget, BOT_ADMINS,
+};
 
 pub async fn export(
-    db: Arc<Mutex<Database>>,
+    db: Arc<Database>,
     cmd: Arc<Command>,
 ) -> Result<(), tbot::errors::MethodCall> {
     let chat_id = cmd.chat.id;
-    let channel = &cmd.text.value;
+    let mut args = cmd.text.value.split_whitespace().collect::<Vec<_>>();
     let mut target_id = chat_id;
     let target = &mut MsgTarget::new(chat_id, cmd.message_id);
 
-    if !channel.is_empty() {
-        let channel_id = check_channel_permission(&cmd, channel, target).await?;
-        if channel_id.is_none() {
+    // Only when asked for, the cookies are secrets set by the bot admins
+    let cookies = args.last() == Some(&"cookies");
+    if cookies {
+        args.pop();
+    }
+    // The seen items make the file much larger, only for moving to another instance
+    let full = args.last() == Some(&"full");
+    if full {
+        args.pop();
+    }
+    let json = args.last() == Some(&"json");
+    if json {
+        args.pop();
+    }
+    if (full || cookies) && !json {
+        let msg = tr!("export_how_to_use");
+        update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+        return Ok(());
+    }
+    match &*args {
+        [] => (),
+        [channel] => {
+            let channel_id = check_channel_permission(&cmd, channel, target).await?;
+            if channel_id.is_none() {
+                return Ok(());
+            }
+            target_id = channel_id.unwrap();
+        }
+        [..] => {
+            let msg = tr!("export_how_to_use");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
             return Ok(());
         }
-        target_id = channel_id.unwrap();
     }
 
-    let feeds = db.lock().await.subscribed_feeds(target_id.0);
-    if feeds.is_none() {
-        update_response(
-            &cmd.bot,
-            target,
-            parameters::Text::with_plain(tr!("subscription_list_empty")),
-        )
-        .await?;
-        return Ok(());
-    }
-    let opml = into_opml(feeds.unwrap());
+    let feeds = match db.subscribed_feeds(target_id.0) {
+        Some(feeds) => feeds,
+        None => {
+            update_response(
+                &cmd.bot,
+                target,
+                parameters::Text::with_plain(tr!("subscription_list_empty")),
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+    // Remembered from the command, or when checking the channel
+    let chat = chat_title(target_id.0).unwrap_or_else(|| target_id.0.to_string());
+    let now = chrono::Local::now();
+    let caption = tr!(
+        "export_caption",
+        count = feeds.len(),
+        time = now.format("%Y-%m-%d %H:%M")
+    );
+    let (extension, file) = if json {
+        // Group members could download the file
+        let admins = BOT_ADMINS.get().map_or(&[][..], Vec::as_slice);
+        let with_secrets = cookies && cmd.chat.kind.is_private() && is_from_bot_admin(&cmd, admins);
+        let defaults = db.chat(target_id.0).defaults;
+        let mut export = Export::new(&feeds, target_id.0, with_secrets).with_defaults(defaults);
+        if full {
+            export = export.with_seen_hashes(|link| db.seen_hashes(link));
+        }
+        let json = serde_json::to_vec_pretty(&export).expect("unreachable");
+        ("json", json)
+    } else {
+        let opml = into_opml(feeds, target_id.0, &chat);
+        ("opml", opml.into_bytes())
+    };
+    let name = export_filename(&chat, now.date_naive(), extension);
 
     cmd.bot
-        .send_document(
-            chat_id,
-            input_file::Document::with_bytes("feeds.opml", opml.as_bytes()),
-        )
+        .send_document(chat_id, input_file::Document::with_bytes(&name, &file))
+        .caption(parameters::Text::with_plain(&caption))
         .in_reply_to(cmd.message_id)
         .call()
         .await?;
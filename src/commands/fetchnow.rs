@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Local};
+use futures::future::join_all;
+use reqwest::StatusCode;
+use tbot::{contexts::Command, types::parameters};
+
+use crate::client::format_byte_size;
+use crate::data::Database;
+use crate::fetcher::{fetch, next_fetch, FetchOutcome};
+use crate::messages::{Escape, Title};
+
+use super::{update_response, MsgTarget};
+
+pub async fn fetchnow(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let feed_url = cmd.text.value.trim();
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+
+    update_response(
+        &cmd.bot,
+        target,
+        parameters::Text::with_plain(tr!("processing_please_wait")),
+    )
+    .await?;
+    let msg = if feed_url.is_empty() {
+        let feeds = db.subscribed_feeds(chat_id.0).unwrap_or_default();
+        if feeds.is_empty() {
+            tr!("subscription_list_empty").to_string()
+        } else {
+            let fetches = feeds
+                .into_iter()
+                .map(|feed| fetch(&cmd.bot, db.clone(), feed));
+            let (mut fetched, mut failed, mut busy, mut delivered) = (0, 0, 0, 0);
+            for result in join_all(fetches).await {
+                match result {
+                    Ok(Some(outcome)) => {
+                        if outcome.result.is_ok() {
+                            fetched += 1;
+                        } else {
+                            failed += 1;
+                        }
+                        delivered += outcome.delivered;
+                    }
+                    Ok(None) => busy += 1,
+                    Err(e) => {
+                        // Fetched, but some deliveries failed
+                        fetched += 1;
+                        crate::print_error(e);
+                    }
+                }
+            }
+            tr!(
+                "fetchnow_cycle",
+                fetched = fetched,
+                failed = failed,
+                busy = busy,
+                delivered = delivered
+            )
+        }
+    } else {
+        match db.all_feeds().into_iter().find(|feed| feed.link == feed_url) {
+            Some(feed) => match fetch(&cmd.bot, db.clone(), feed).await? {
+                Some(outcome) => format_outcome(&db, feed_url, &outcome),
+                None => tr!("fetchnow_busy").to_string(),
+            },
+            None => tr!("feed_not_found").to_string(),
+        }
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+    Ok(())
+}
+
+pub(super) fn format_outcome(db: &Database, feed_url: &str, outcome: &FetchOutcome) -> String {
+    let feed = db.all_feeds().into_iter().find(|feed| feed.link == feed_url);
+    let (title, time) = match &feed {
+        Some(feed) => (
+            feed.title.as_str(),
+            DateTime::<Local>::from(next_fetch(feed))
+                .format("%Y-%m-%d %H:%M")
+                .to_string(),
+        ),
+        // unsubscribed while fetching
+        None => (feed_url, "-".to_string()),
+    };
+    let result = match &outcome.result {
+        Ok(count) if outcome.skipped => tr!("fetchnow_truncated", count = count),
+        Ok(count) if outcome.status == Some(StatusCode::NOT_MODIFIED) => {
+            tr!("fetchnow_not_modified", count = count)
+        }
+        Ok(count) => tr!("fetchnow_items", count = count),
+        Err(e) => Escape(&e.to_user_friendly()).to_string(),
+    };
+    tr!(
+        "fetchnow_result",
+        link = Escape(feed_url),
+        title = Title(title),
+        status = outcome
+            .status
+            .map(|status| status.to_string())
+            .unwrap_or_else(|| "-".to_string()),
+        size = outcome
+            .bytes
+            .map(|bytes| format_byte_size(bytes as u64))
+            .unwrap_or_else(|| "-".to_string()),
+        result = result,
+        delivered = outcome.delivered,
+        time = time
+    )
+}
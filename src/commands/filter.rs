@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+
+use crate::data::{Database, ItemFilter};
+use crate::fetcher::filter_regex;
+use crate::messages::{Escape, Title};
+
+use super::note::next_arg;
+use super::{check_channel_permission, check_owner, clean_url, update_response, MsgTarget};
+
+/// In characters, longer patterns are rarely what was meant
+const MAX_PATTERN_CHARS: usize = 200;
+
+pub async fn filter(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+
+    // Patterns may contain spaces, like notes
+    let (first, rest) = next_arg(cmd.text.value.trim());
+    let (channel, feed_url, rest) = if first.contains("://") {
+        (None, first, rest)
+    } else {
+        let (url, rest) = next_arg(rest);
+        (Some(first), url, rest)
+    };
+    if !feed_url.contains("://") {
+        let msg = tr!("filter_how_to_use");
+        update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+        return Ok(());
+    }
+    let (exclude, pattern) = match next_arg(rest) {
+        ("exclude", pattern) => (true, pattern),
+        _ => (false, rest),
+    };
+    if let Some(channel) = channel {
+        match check_channel_permission(&cmd, channel, target).await? {
+            Some(channel_id) => target_id = channel_id,
+            None => return Ok(()),
+        }
+    }
+
+    let feed_url = &clean_url(feed_url);
+    let feed = match db.subscribed_feed(target_id.0, feed_url) {
+        Some(feed) => feed,
+        None => {
+            let msg = tr!("feed_not_found");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+    let link = Escape(&feed.link);
+    let title = Title(&feed.title);
+
+    if pattern.is_empty() {
+        let current = feed.subscription(target_id.0).filter;
+        let msg = if current.is_empty() {
+            tr!("filter_none", link = link, title = title)
+        } else {
+            format!(
+                "{}{}",
+                tr!("filter_show", link = link, title = title),
+                format_filter(&current)
+            )
+        };
+        update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+        return Ok(());
+    }
+    if !check_owner(&cmd, &db, target_id, feed_url, target).await? {
+        return Ok(());
+    }
+    let msg = if let Some(error) = pattern_error(pattern) {
+        error
+    } else {
+        let pattern = Some(pattern.to_string());
+        db.update_subscription(target_id.0, feed_url, |s| {
+            if exclude {
+                s.filter.exclude = pattern;
+            } else {
+                s.filter.include = pattern;
+            }
+        });
+        tr!("filter_set", link = link, title = title)
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+    Ok(())
+}
+
+/// Why the pattern can't be a filter, the ones of /import are checked the same way
+pub(super) fn pattern_error(pattern: &str) -> Option<String> {
+    if pattern.chars().count() > MAX_PATTERN_CHARS {
+        return Some(tr!("filter_too_long", max = MAX_PATTERN_CHARS));
+    }
+    let error = filter_regex(pattern).err()?;
+    Some(tr!("filter_invalid", error = Escape(&error.to_string())))
+}
+
+fn format_filter(filter: &ItemFilter) -> String {
+    let mut msg = String::new();
+    if let Some(include) = &filter.include {
+        msg.push('\n');
+        msg.push_str(&tr!("filter_include", pattern = Escape(include)));
+    }
+    if let Some(exclude) = &filter.exclude {
+        msg.push('\n');
+        msg.push_str(&tr!("filter_exclude", pattern = Escape(exclude)));
+    }
+    msg
+}
@@ -0,0 +1,38 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+
+use crate::data::Database;
+use crate::messages::{footer, sanitize_title, set_footer, Escape, FOOTER_UNITS};
+
+use super::{update_response, MsgTarget};
+
+pub async fn footer(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let text = cmd.text.value.trim();
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+
+    let msg = match text {
+        "" => match footer() {
+            Some(footer) => tr!("footer_current", footer = Escape(&footer)),
+            None => Escape(&tr!("footer_how_to_use")).to_string(),
+        },
+        "off" => {
+            db.update_global(|global| global.footer = None);
+            set_footer(None);
+            tr!("footer_removed").to_string()
+        }
+        text => {
+            // A single line, whatever was pasted
+            let text = sanitize_title(text, FOOTER_UNITS).into_owned();
+            db.update_global(|global| global.footer = Some(text.clone()));
+            set_footer(Some(text.clone()));
+            tr!("footer_changed", footer = Escape(&text))
+        }
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+    Ok(())
+}
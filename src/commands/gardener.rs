@@ -0,0 +1,89 @@
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use chrono::{DateTime, Local};
+use tbot::{contexts::Command, types::parameters};
+
+use crate::data::{chat_display, Database};
+use crate::gardener::{dry_run, in_grace_period, schedule, sweep_now, sweeps};
+use crate::messages::format_duration;
+
+use super::{update_response, MsgTarget};
+
+// Of the chats in the grace period, or that a dry run would remove
+const LISTED_CHATS: usize = 10;
+
+pub async fn gardener(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let target = &mut MsgTarget::new(cmd.chat.id, cmd.message_id);
+    let msg = match cmd.text.value.trim() {
+        "" => {
+            let sweeps = sweeps();
+            let schedule = schedule();
+            let last = match sweeps.last {
+                Some((time, removed)) => {
+                    tr!("gardener_last_sweep", time = format_time(time), removed = removed)
+                }
+                None => "-".to_string(),
+            };
+            let next = if sweeps.running {
+                tr!("gardener_sweeping").to_string()
+            } else {
+                sweeps.next.map(format_time).unwrap_or_else(|| "-".to_string())
+            };
+            let (interval, grace) = schedule
+                .map(|s| (s.interval.as_secs(), s.grace.as_secs()))
+                .unwrap_or_default();
+            let in_grace = in_grace_period();
+            let mut msg = tr!(
+                "gardener_status",
+                last = last,
+                next = next,
+                in_grace = in_grace.len(),
+                interval = format_duration(interval),
+                grace = format_duration(grace)
+            );
+            for &chat in in_grace.iter().take(LISTED_CHATS) {
+                msg.push_str("\n  ");
+                msg.push_str(&chat_display(chat));
+                if let Some(removed) = db.chat(chat).bot_removed {
+                    msg.push_str(&tr!("gardener_bot_removed", time = format_time(removed)));
+                }
+            }
+            msg
+        }
+        "dry" => {
+            update_response(
+                &cmd.bot,
+                target,
+                parameters::Text::with_plain(tr!("processing_please_wait")),
+            )
+            .await?;
+            let removed = dry_run(&cmd.bot, &db).await;
+            let subscriptions: usize = removed.iter().map(|(_, count)| count).sum();
+            let mut msg = tr!(
+                "gardener_dry_run",
+                count = removed.len(),
+                subscriptions = subscriptions
+            );
+            for &(chat, _) in removed.iter().take(LISTED_CHATS) {
+                msg.push_str("\n  ");
+                msg.push_str(&chat_display(chat));
+            }
+            msg
+        }
+        "now" if sweep_now() => tr!("gardener_sweep_started").to_string(),
+        "now" => tr!("gardener_sweeping").to_string(),
+        _ => tr!("gardener_how_to_use").to_string(),
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_plain(&msg)).await?;
+    Ok(())
+}
+
+fn format_time(time: SystemTime) -> String {
+    DateTime::<Local>::from(time)
+        .format("%Y-%m-%d %H:%M")
+        .to_string()
+}
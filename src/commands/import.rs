@@ -0,0 +1,212 @@
+use std::sync::Arc;
+
+use futures::stream::{self, StreamExt};
+use tbot::{
+    contexts::Command,
+    types::{message::Kind, parameters},
+};
+
+use crate::client::pull_feed;
+use crate::data::{Database, Settings, SubscribeResult, Subscription, HASH_VERSION};
+use crate::export::Export;
+use crate::messages::Escape;
+use crate::opml::parse_opml;
+
+use super::filter::pattern_error;
+use super::note::clean_note;
+use super::{check_channel_permission, sender_id, update_response, MsgTarget};
+
+const CONCURRENT_FETCHES: usize = 8;
+
+/// The link, the settings and the hashes of the items already seen
+type ImportedFeed = (String, Subscription, Vec<u64>);
+
+/// What was in the file besides the feeds
+#[derive(Default)]
+struct Ignored {
+    unknown_fields: usize,
+    cookies: usize,
+    /// Seen items of the feeds, hashed differently by the other instance
+    seen_lists: usize,
+    /// Patterns /filter wouldn't take
+    filters: usize,
+}
+
+pub async fn import(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let channel = &cmd.text.value;
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+
+    let document = match cmd.reply_to.as_ref().map(|message| &message.kind) {
+        Some(Kind::Document(document, _)) => document.clone(),
+        _ => {
+            let msg = tr!("import_how_to_use");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+    if !channel.is_empty() {
+        let channel_id = check_channel_permission(&cmd, channel, target).await?;
+        if channel_id.is_none() {
+            return Ok(());
+        }
+        target_id = channel_id.unwrap();
+    }
+
+    update_response(
+        &cmd.bot,
+        target,
+        parameters::Text::with_plain(tr!("processing_please_wait")),
+    )
+    .await?;
+    let file = cmd.bot.get_file(document.file_id.clone()).call().await?;
+    let content = match cmd.bot.download_file(&file).await {
+        Ok(content) => content,
+        Err(_) => {
+            let msg = tr!("import_download_failed");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+    let (feeds, defaults, ignored) = match parse_file(&content, target_id.0) {
+        Ok(r) => r,
+        Err(e) => {
+            let msg = tr!("import_invalid_file", error = Escape(&e));
+            update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+            return Ok(());
+        }
+    };
+
+    // The chat may have its own already
+    if !defaults.is_empty() {
+        db.update_chat(target_id.0, |chat| {
+            if chat.defaults.is_empty() {
+                chat.defaults = defaults;
+            }
+        });
+    }
+    let user_id = sender_id(&cmd);
+    let results = stream::iter(feeds)
+        .map(|(link, settings, seen)| {
+            let db = db.clone();
+            async move {
+                if db.is_subscribed(target_id.0, &link) {
+                    return Ok(false);
+                }
+                // Seen items are marked like /sub does, or the whole feed would be delivered
+                let rss = pull_feed(&link, None, None).await.map_err(|_| link.clone())?;
+                if db.subscribe(target_id.0, &link, &rss) == SubscribeResult::AlreadySubscribed {
+                    return Ok(false);
+                }
+                if settings != Subscription::default() {
+                    db.update_subscription(target_id.0, &link, |s| *s = settings);
+                }
+                if let Some(user_id) = user_id {
+                    db.set_subscribed_by(target_id.0, &link, user_id);
+                }
+                // From `/export json full`, the items the other instance delivered already
+                if !seen.is_empty() {
+                    db.import_seen_hashes(target_id.0, &link, &seen);
+                }
+                Ok::<_, String>(true)
+            }
+        })
+        .buffer_unordered(CONCURRENT_FETCHES)
+        .collect::<Vec<_>>()
+        .await;
+
+    let subscribed = results.iter().filter(|r| matches!(r, Ok(true))).count();
+    let existing = results.iter().filter(|r| matches!(r, Ok(false))).count();
+    let failed: Vec<&str> = results
+        .iter()
+        .filter_map(|r| r.as_ref().err())
+        .map(String::as_str)
+        .collect();
+    let mut msg = tr!(
+        "import_result",
+        subscribed = subscribed,
+        existing = existing,
+        failed = failed.len()
+    );
+    for link in failed {
+        msg.push_str(&format!("\n<code>{}</code>", Escape(link)));
+    }
+    if ignored.unknown_fields > 0 {
+        msg.push('\n');
+        msg.push_str(&tr!("import_unknown_fields", count = ignored.unknown_fields));
+    }
+    if ignored.cookies > 0 {
+        msg.push('\n');
+        msg.push_str(&tr!("import_cookies_ignored", count = ignored.cookies));
+    }
+    if ignored.seen_lists > 0 {
+        msg.push('\n');
+        msg.push_str(&tr!("import_seen_ignored", count = ignored.seen_lists));
+    }
+    if ignored.filters > 0 {
+        msg.push('\n');
+        msg.push_str(&tr!("import_filters_ignored", count = ignored.filters));
+    }
+    update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+    Ok(())
+}
+
+/// Accept both `/export` and `/export json`, with the chat defaults of the latter
+fn parse_file(
+    content: &[u8],
+    chat_id: i64,
+) -> Result<(Vec<ImportedFeed>, Settings, Ignored), String> {
+    let is_json = content.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{');
+    if !is_json {
+        let outlines = parse_opml(content).map_err(|e| e.to_string())?;
+        let feeds = outlines
+            .into_iter()
+            .map(|outline| (outline.xml_url, Subscription::default(), Vec::new()))
+            .collect();
+        return Ok((feeds, Settings::default(), Ignored::default()));
+    }
+
+    let export: Export = serde_json::from_slice(content).map_err(|e| e.to_string())?;
+    let mut ignored = Ignored {
+        unknown_fields: export.unknown_fields(),
+        cookies: 0,
+        seen_lists: 0,
+        filters: 0,
+    };
+    let same_hashes = export.hash_version == HASH_VERSION;
+    let same_chat = export.chat == chat_id;
+    let feeds = export
+        .feeds
+        .into_iter()
+        .map(|mut feed| {
+            // Only /cookie may set them, it's for bot admins
+            if feed.cookie.is_some() {
+                ignored.cookies += 1;
+            }
+            // The discussion group belongs to the exported channel
+            if !same_chat {
+                feed.discussion = None;
+            }
+            // Edited by hand maybe, they are checked like the ones set by /note
+            feed.note = feed.note.as_deref().and_then(clean_note);
+            for pattern in [&mut feed.filter.include, &mut feed.filter.exclude] {
+                if pattern.as_deref().is_some_and(|p| pattern_error(p).is_some()) {
+                    ignored.filters += 1;
+                    *pattern = None;
+                }
+            }
+            // None of them would match, the items are marked seen only like /sub does
+            if !same_hashes && !feed.seen.is_empty() {
+                ignored.seen_lists += 1;
+                feed.seen.clear();
+            }
+            let subscription = feed.subscription();
+            (feed.link, subscription, feed.seen)
+        })
+        .collect();
+    Ok((feeds, export.defaults, ignored))
+}
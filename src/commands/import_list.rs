@@ -0,0 +1,131 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use futures::stream::{self, StreamExt};
+use tbot::{
+    contexts::Command,
+    types::{
+        message::{
+            text::{EntityKind, Text},
+            Kind,
+        },
+        parameters,
+    },
+};
+
+use crate::client::{check_feed_url, pull_feed_or_discover};
+use crate::data::{Database, SubscribeResult};
+use crate::messages::{find_urls, Escape};
+
+use super::{check_channel_permission, sender_id, update_response, MsgTarget};
+
+const CONCURRENT_FETCHES: usize = 8;
+// Links looked at per command, so the reply and the fetches stay reasonable
+const MAX_URLS: usize = 50;
+
+/// Subscribe to the feeds in a /rss listing of another bot,
+/// pasted after the command or in the message it replies to
+pub async fn import_list(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+
+    let text = cmd.text.value.trim_start();
+    let (channel, list) = match text.split_once(char::is_whitespace) {
+        Some((first, rest)) if is_channel(first) => (Some(first), rest),
+        None if is_channel(text) => (Some(text), ""),
+        _ => (None, text),
+    };
+    let mut urls = find_urls(list);
+    urls.extend(text_links(&cmd.text));
+    if let Some(Kind::Text(replied)) = cmd.reply_to.as_ref().map(|message| &message.kind) {
+        urls.extend(find_urls(&replied.value));
+        urls.extend(text_links(replied));
+    }
+    let mut seen = HashSet::new();
+    urls.retain(|url| seen.insert(url.clone()));
+    if urls.is_empty() {
+        let msg = tr!("import_list_how_to_use");
+        update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+        return Ok(());
+    }
+    if let Some(channel) = channel {
+        let channel_id = check_channel_permission(&cmd, channel, target).await?;
+        if channel_id.is_none() {
+            return Ok(());
+        }
+        target_id = channel_id.unwrap();
+    }
+    let skipped = urls.len().saturating_sub(MAX_URLS);
+    urls.truncate(MAX_URLS);
+
+    update_response(
+        &cmd.bot,
+        target,
+        parameters::Text::with_plain(tr!("processing_please_wait")),
+    )
+    .await?;
+    let user_id = sender_id(&cmd);
+    let results = stream::iter(urls)
+        .map(|url| {
+            let db = db.clone();
+            async move {
+                if check_feed_url(&url).is_err() {
+                    return Err(url);
+                }
+                if db.is_subscribed(target_id.0, &url) {
+                    return Ok(false);
+                }
+                let (link, rss) = pull_feed_or_discover(&url).await.map_err(|_| url.clone())?;
+                if db.subscribe(target_id.0, &link, &rss) == SubscribeResult::AlreadySubscribed {
+                    return Ok(false);
+                }
+                if let Some(user_id) = user_id {
+                    db.set_subscribed_by(target_id.0, &link, user_id);
+                }
+                Ok(true)
+            }
+        })
+        .buffer_unordered(CONCURRENT_FETCHES)
+        .collect::<Vec<_>>()
+        .await;
+
+    let subscribed = results.iter().filter(|r| matches!(r, Ok(true))).count();
+    let existing = results.iter().filter(|r| matches!(r, Ok(false))).count();
+    let failed: Vec<&str> = results
+        .iter()
+        .filter_map(|r| r.as_ref().err())
+        .map(String::as_str)
+        .collect();
+    let mut msg = tr!(
+        "import_result",
+        subscribed = subscribed,
+        existing = existing,
+        failed = failed.len()
+    );
+    for link in failed {
+        msg.push_str(&format!("\n<code>{}</code>", Escape(link)));
+    }
+    if skipped > 0 {
+        msg.push('\n');
+        msg.push_str(&tr!("import_list_skipped", count = skipped, max = MAX_URLS));
+    }
+    update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+    Ok(())
+}
+
+/// `@channel` or a chat ID, anything else starts the list
+fn is_channel(arg: &str) -> bool {
+    arg.starts_with('@') || arg.parse::<i64>().is_ok()
+}
+
+/// Forwarded listings keep their links in the entities, not the text
+fn text_links(text: &Text) -> impl Iterator<Item = String> + '_ {
+    text.entities.iter().filter_map(|entity| match &entity.kind {
+        EntityKind::TextLink(url) if url.starts_with("http") => Some(url.clone()),
+        _ => None,
+    })
+}
@@ -0,0 +1,49 @@
+use std::sync::Arc;
+
+use tbot::{
+    contexts::Inline,
+    types::{inline_query, input_message_content, parameters},
+};
+
+use crate::admins::is_admin;
+use crate::data::Database;
+
+/// Telegram doesn't show more than this anyway
+const MAX_RESULTS: usize = 20;
+/// In seconds, new items keep coming in
+const CACHE_TIME: u64 = 60;
+
+/// Search the recent items of the feeds subscribed in the private chat of the user
+pub async fn inline(
+    db: Arc<Database>,
+    opt: Arc<crate::Opt>,
+    query: Arc<Inline>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let user_id = query.from.id.0;
+    // Private mode
+    if !opt.admin.is_empty() && !is_admin(&opt.admin, user_id, query.from.username.as_deref()) {
+        return Ok(());
+    }
+
+    // The private chat with a user has the same ID as the user,
+    // so the subscriptions of other chats are never searched
+    let items = db.search_recent_items(user_id, &query.query, MAX_RESULTS);
+    let results = items
+        .into_iter()
+        .enumerate()
+        .map(|(i, (feed_title, item))| {
+            let content =
+                input_message_content::Text::new(parameters::Text::with_plain(&item.link));
+            let article =
+                inline_query::result::Article::new(item.title, content).description(feed_title);
+            inline_query::Result::new(i.to_string(), article)
+        })
+        .collect::<Vec<_>>();
+    query
+        .answer(results)
+        .cache_time(CACHE_TIME)
+        .is_personal(true)
+        .call()
+        .await?;
+    Ok(())
+}
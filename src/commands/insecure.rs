@@ -0,0 +1,28 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+
+use crate::data::Database;
+
+use super::{update_response, MsgTarget};
+
+pub async fn insecure(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let target = &mut MsgTarget::new(cmd.chat.id, cmd.message_id);
+    let args = cmd.text.value.split_whitespace().collect::<Vec<_>>();
+    let msg = match &*args {
+        [feed_url, value @ ("on" | "off")] => {
+            let insecure = *value == "on";
+            match db.set_insecure(feed_url, insecure) {
+                true if insecure => tr!("insecure_on"),
+                true => tr!("insecure_off"),
+                false => tr!("feed_not_found"),
+            }
+        }
+        _ => tr!("insecure_how_to_use"),
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+    Ok(())
+}
@@ -0,0 +1,32 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+
+use crate::data::Database;
+use crate::messages::{format_large_msg, Escape};
+
+use super::{reply_large_msgs, update_response, MsgTarget};
+
+pub async fn inspect(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let target = &mut MsgTarget::new(cmd.chat.id, cmd.message_id);
+    let msg = match cmd.text.value.split_whitespace().collect::<Vec<_>>()[..] {
+        [feed_url] => match db.feed_debug_info(feed_url) {
+            Some(info) => {
+                // A feed with many subscribers doesn't fit in one message
+                let info = info.to_string();
+                let mut lines = info.lines();
+                let head = Escape(lines.next().unwrap_or_default()).to_string();
+                let lines: Vec<&str> = lines.collect();
+                let msgs = format_large_msg(head, &lines, |line| Escape(line).to_string());
+                return reply_large_msgs(&cmd.bot, target, msgs).await;
+            }
+            None => tr!("feed_not_found").to_string(),
+        },
+        _ => tr!("inspect_how_to_use").to_string(),
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_plain(&msg)).await?;
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+
+use crate::client::format_byte_size;
+use crate::data::Database;
+use crate::messages::Escape;
+
+use super::{update_response, MsgTarget};
+
+pub async fn maxsize(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let target = &mut MsgTarget::new(cmd.chat.id, cmd.message_id);
+    let args = cmd.text.value.split_whitespace().collect::<Vec<_>>();
+    let msg = match &*args {
+        [feed_url, "default"] => {
+            if db.set_max_size(feed_url, None) {
+                tr!("maxsize_default").to_string()
+            } else {
+                tr!("feed_not_found").to_string()
+            }
+        }
+        [feed_url, size] => match crate::parse_human_size(size) {
+            Ok(size) if db.set_max_size(feed_url, Some(size)) => {
+                tr!("maxsize_set", size = format_max_size(size))
+            }
+            Ok(_) => tr!("feed_not_found").to_string(),
+            Err(e) => tr!("maxsize_invalid", error = Escape(&e.to_string())),
+        },
+        _ => tr!("maxsize_how_to_use").to_string(),
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_plain(&msg)).await?;
+    Ok(())
+}
+
+/// Like `--max-feed-size`, 0 is unlimited
+pub fn format_max_size(size: u64) -> String {
+    if size == 0 {
+        tr!("unlimited").to_string()
+    } else {
+        format_byte_size(size)
+    }
+}
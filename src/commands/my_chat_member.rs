@@ -0,0 +1,68 @@
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use tbot::{
+    contexts::MyChatMember,
+    types::{chat::Kind, parameters},
+};
+
+use crate::data::Database;
+use crate::gardener;
+use crate::messages::{wait_send_turn, Priority};
+
+use super::{can_post, remember_chat};
+
+/// The bot was added to or removed from a chat, or its permissions changed
+pub async fn my_chat_member(
+    db: Arc<Database>,
+    opt: Arc<crate::Opt>,
+    ctx: Arc<MyChatMember>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = ctx.chat.id;
+    remember_chat(&ctx.chat);
+    crate::chat_info::forget(chat_id.0);
+    let old_status = &ctx.old_chat_member.status;
+    match (can_post(old_status), can_post(&ctx.new_chat_member.status)) {
+        (true, false) => {
+            // Removed by the gardener once the grace period is over, unless it's added back
+            gardener::mark_unavailable(chat_id.0);
+            if db.subscribed_feeds(chat_id.0).is_some() {
+                db.update_chat(chat_id.0, |chat| chat.bot_removed = Some(SystemTime::now()));
+            }
+        }
+        (false, true) => {
+            gardener::mark_available(chat_id.0);
+            if db.chat(chat_id.0).bot_removed.is_some() {
+                db.update_chat(chat_id.0, |chat| chat.bot_removed = None);
+            }
+            // Not when it's only allowed to post again
+            if old_status.is_left() || old_status.is_kicked() {
+                greet(&ctx, &opt).await?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// How to subscribe the chat, and who can
+async fn greet(ctx: &MyChatMember, opt: &crate::Opt) -> Result<(), tbot::errors::MethodCall> {
+    let msg = match ctx.chat.kind {
+        Kind::Channel { .. } => tr!("greeting_channel", channel = ctx.chat.id.0),
+        Kind::Group { .. } | Kind::Supergroup { .. } => {
+            let mode = if !opt.admin.is_empty() {
+                tr!("greeting_mode_private")
+            } else if opt.restricted || opt.restricted_strict {
+                tr!("greeting_mode_restricted")
+            } else {
+                tr!("greeting_mode_open")
+            };
+            tr!("greeting_group", mode = mode)
+        }
+        Kind::Private { .. } => return Ok(()),
+    };
+    wait_send_turn(ctx.chat.id.0, Priority::Reply).await;
+    let msg = parameters::Text::with_plain(&msg);
+    ctx.bot.send_message(ctx.chat.id, msg).call().await?;
+    Ok(())
+}
@@ -0,0 +1,96 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+
+use crate::data::Database;
+use crate::messages::{sanitize_title, Escape, Title};
+
+use super::{check_channel_permission, check_owner, clean_url, update_response, MsgTarget};
+
+/// In characters, after the whitespace is collapsed
+pub(super) const MAX_NOTE_CHARS: usize = 500;
+
+pub async fn note(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+
+    // Notes may contain spaces, take everything after the URL.
+    // Channel IDs never look like a URL
+    let (first, rest) = next_arg(cmd.text.value.trim());
+    let (channel, feed_url, text) = if first.contains("://") {
+        (None, first, rest)
+    } else {
+        let (url, rest) = next_arg(rest);
+        (Some(first), url, rest)
+    };
+    if !feed_url.contains("://") {
+        let msg = tr!("note_how_to_use");
+        update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+        return Ok(());
+    }
+    if let Some(channel) = channel {
+        match check_channel_permission(&cmd, channel, target).await? {
+            Some(channel_id) => target_id = channel_id,
+            None => return Ok(()),
+        }
+    }
+
+    let feed_url = &clean_url(feed_url);
+    let feed = match db.subscribed_feed(target_id.0, feed_url) {
+        Some(feed) => feed,
+        None => {
+            let msg = tr!("feed_not_found");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+    let link = Escape(&feed.link);
+    let title = Title(&feed.title);
+
+    // Showing it is fine, like the settings
+    if text.is_empty() {
+        let msg = match feed.subscription(target_id.0).note {
+            Some(note) => tr!("note_show", link = link, title = title, note = Escape(&note)),
+            None => tr!("note_none", link = link, title = title),
+        };
+        update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+        return Ok(());
+    }
+    if !check_owner(&cmd, &db, target_id, feed_url, target).await? {
+        return Ok(());
+    }
+    let msg = match text {
+        "clear" => {
+            db.update_subscription(target_id.0, feed_url, |s| s.note = None);
+            tr!("note_cleared", link = link, title = title)
+        }
+        _ => match clean_note(text) {
+            Some(note) => {
+                db.update_subscription(target_id.0, feed_url, |s| s.note = Some(note));
+                tr!("note_set", link = link, title = title)
+            }
+            None => tr!("note_too_long", max = MAX_NOTE_CHARS),
+        },
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+    Ok(())
+}
+
+/// Plain text on a single line, `None` if it's empty or too long
+pub(super) fn clean_note(text: &str) -> Option<String> {
+    let note = sanitize_title(text, usize::MAX);
+    let len = note.chars().count();
+    (len > 0 && len <= MAX_NOTE_CHARS).then(|| note.into_owned())
+}
+
+/// The first word, and the rest without the whitespace in between
+pub(super) fn next_arg(text: &str) -> (&str, &str) {
+    match text.split_once(char::is_whitespace) {
+        Some((arg, rest)) => (arg, rest.trim_start()),
+        None => (text, ""),
+    }
+}
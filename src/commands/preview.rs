@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+
+use crate::client::fetch_feed;
+use crate::data::Database;
+use crate::feed::Item;
+use crate::fetcher::send_preview;
+use crate::messages::Escape;
+
+use super::rss::{sort_feeds, Order};
+use super::{check_channel_permission, update_response, MsgTarget};
+
+pub async fn preview(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let args = cmd.text.value.split_whitespace().collect::<Vec<_>>();
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+
+    let feed_arg = match &*args {
+        [feed] => feed,
+        [channel, feed] => {
+            let channel_id = check_channel_permission(&cmd, channel, target).await?;
+            if channel_id.is_none() {
+                return Ok(());
+            }
+            target_id = channel_id.unwrap();
+            feed
+        }
+        [..] => {
+            let msg = tr!("preview_how_to_use");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+
+    let mut feeds = db.subscribed_feeds(target_id.0).unwrap_or_default();
+    // Numbered as listed by /rss
+    sort_feeds(&mut feeds, Order::Default, target_id.0);
+    let feed = match feed_arg.parse::<usize>() {
+        Ok(index) => index.checked_sub(1).and_then(|i| feeds.get(i)),
+        Err(_) => feeds.iter().find(|feed| feed.link == *feed_arg),
+    };
+    let feed = match feed {
+        Some(feed) => feed,
+        None => {
+            let msg = tr!("feed_not_found");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+
+    let mut replied = false;
+    let item = match db.latest_item(&feed.link) {
+        Some(item) => Ok(Some(Item {
+            title: Some(item.title),
+            link: Some(item.link),
+            published: item
+                .published
+                .and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+                .map(|t| t.fixed_offset()),
+            ..Default::default()
+        })),
+        // Not fetched since starting, the item is still new to the fetcher
+        None => {
+            update_response(
+                &cmd.bot,
+                target,
+                parameters::Text::with_plain(tr!("processing_please_wait")),
+            )
+            .await?;
+            replied = true;
+            let fetched = fetch_feed(&feed.link, feed.cookie(), feed.max_size, feed.quirks).await;
+            fetched.map(|fetched| fetched.rss.items.into_iter().next())
+        }
+    };
+    let msg = match item {
+        Ok(Some(item)) => match send_preview(&cmd.bot, &db, feed, target_id.0, item).await? {
+            // It's the reply in the chat itself
+            true if target_id == chat_id && !replied => return Ok(()),
+            true => tr!("preview_sent").to_string(),
+            false => tr!("preview_failed").to_string(),
+        },
+        Ok(None) => tr!("latest_no_items").to_string(),
+        Err(e) => tr!("latest_failed", error = Escape(&e.to_user_friendly())),
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use chrono::{DateTime, Local};
+use tbot::{contexts::Command, types::parameters};
+
+use crate::data::{chat_display, Database, QueueSnapshot};
+use crate::fetcher::next_fetch;
+use crate::messages::{format_duration, queued_deliveries, sanitize_title};
+
+use super::{update_response, MsgTarget};
+
+// Of the items queued for a chat
+const LISTED_ITEMS: usize = 10;
+// In UTF-16 code units, like the titles of the items
+const MAX_TITLE: usize = 100;
+
+pub async fn queue(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let target = &mut MsgTarget::new(cmd.chat.id, cmd.message_id);
+    let chat = match cmd.text.value.trim() {
+        "" => None,
+        arg => match arg.parse::<i64>() {
+            Ok(chat) => Some(chat),
+            Err(_) => {
+                let msg = tr!("queue_how_to_use");
+                update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+                return Ok(());
+            }
+        },
+    };
+
+    let sending = queued_deliveries(chat);
+    let (future_dated, pending_links) = db.held_items(chat, next_fetch);
+    let now = SystemTime::now();
+    let mut msg = match chat {
+        Some(chat) => tr!("queue_chat", chat = chat_display(chat)) + "\n",
+        None => String::new(),
+    };
+    msg.push_str(&tr!(
+        "queue_status",
+        sending = format_queue(&sending, now),
+        future_dated = format_queue(&future_dated, now),
+        pending_links = format_queue(&pending_links, now)
+    ));
+    if chat.is_some() {
+        for title in future_dated.titles.iter().take(LISTED_ITEMS) {
+            msg.push_str("\n  ");
+            msg.push_str(&sanitize_title(title, MAX_TITLE));
+        }
+    }
+    update_response(&cmd.bot, target, parameters::Text::with_plain(&msg)).await?;
+    Ok(())
+}
+
+fn format_queue(queue: &QueueSnapshot, now: SystemTime) -> String {
+    let mut line = tr!("queue_count", count = queue.queued);
+    if let Some(oldest) = queue.oldest {
+        let age = now.duration_since(oldest).unwrap_or_default().as_secs();
+        line.push_str(&tr!("queue_oldest", age = format_duration(age)));
+    }
+    if let Some(next) = queue.next_flush {
+        line.push_str(&tr!("queue_next", time = format_time(next.max(now))));
+    }
+    line
+}
+
+fn format_time(time: SystemTime) -> String {
+    DateTime::<Local>::from(time)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
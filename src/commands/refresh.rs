@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+
+use crate::data::Database;
+use crate::fetcher::fetch;
+
+use super::fetchnow::format_outcome;
+use super::{update_response, MsgTarget};
+
+pub async fn refresh(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let feed_url = cmd.text.value.trim();
+    let target = &mut MsgTarget::new(cmd.chat.id, cmd.message_id);
+
+    if feed_url.is_empty() {
+        let msg = tr!("refresh_how_to_use");
+        update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+        return Ok(());
+    }
+    // Looked up after, the fetch uses the quirks of this copy
+    let forgotten = db.forget_responses(feed_url);
+    let feed = db.all_feeds().into_iter().find(|feed| feed.link == feed_url);
+    let feed = match feed {
+        Some(feed) if forgotten => feed,
+        _ => {
+            let msg = tr!("feed_not_found");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+    update_response(
+        &cmd.bot,
+        target,
+        parameters::Text::with_plain(tr!("processing_please_wait")),
+    )
+    .await?;
+    let msg = match fetch(&cmd.bot, db.clone(), feed).await? {
+        Some(outcome) => format!(
+            "{}\n{}",
+            tr!("refresh_forgotten"),
+            format_outcome(&db, feed_url, &outcome)
+        ),
+        None => tr!("fetchnow_busy").to_string(),
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+    Ok(())
+}
@@ -1,35 +1,99 @@
+use std::cmp::Reverse;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use either::Either;
 use pinyin::{Pinyin, ToPinyin};
 use tbot::{contexts::Command, types::parameters};
-use tokio::sync::Mutex;
 
-use crate::data::Database;
-use crate::messages::{format_large_msg, Escape};
+use crate::client::{expand_url, RSSHUB_SCHEME};
+use crate::data::{Activity, Database, Feed};
+use crate::messages::{format_duration, format_large_msg, Escape, Title};
 
-use super::{check_channel_permission, update_response, MsgTarget};
+use super::settings::format_lang;
+use super::{check_channel_permission, reply_large_msgs, update_response, MsgTarget};
 
 pub async fn rss(
-    db: Arc<Mutex<Database>>,
+    db: Arc<Database>,
     cmd: Arc<Command>,
 ) -> Result<(), tbot::errors::MethodCall> {
     let chat_id = cmd.chat.id;
-    let channel = &cmd.text.value;
+    let mut args = cmd.text.value.split_whitespace().collect::<Vec<_>>();
     let mut target_id = chat_id;
     let target = &mut MsgTarget::new(chat_id, cmd.message_id);
 
-    if !channel.is_empty() {
-        let channel_id = check_channel_permission(&cmd, channel, target).await?;
-        if channel_id.is_none() {
+    let order = match args.last() {
+        Some(&"az") => Order::Alphabetical,
+        Some(&"recent") => Order::Recent,
+        _ => Order::Default,
+    };
+    if order != Order::Default {
+        args.pop();
+    }
+    match &*args {
+        [] => (),
+        [channel] => {
+            let channel_id = check_channel_permission(&cmd, channel, target).await?;
+            if channel_id.is_none() {
+                return Ok(());
+            }
+            target_id = channel_id.unwrap();
+        }
+        [..] => {
+            let msg = tr!("rss_how_to_use");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
             return Ok(());
         }
-        target_id = channel_id.unwrap();
     }
 
-    let feeds = db.lock().await.subscribed_feeds(target_id.0);
-    let mut msgs = if let Some(mut feeds) = feeds {
-        feeds.sort_by_cached_key(|feed| {
+    let feeds = db.subscribed_feeds(target_id.0);
+    let msgs = if let Some(mut feeds) = feeds {
+        // Sorted before splitting into messages, so the boundaries are stable
+        sort_feeds(&mut feeds, order, target_id.0);
+        let now = SystemTime::now();
+        let defaults = db.chat(target_id.0).defaults;
+        format_large_msg(tr!("subscription_list").to_string(), &feeds, |feed| {
+            let mut line = format!(
+                "<a href=\"{}\">{}</a>",
+                Escape(&expand_url(&feed.link)),
+                Title(&feed.title)
+            );
+            if feed.link.starts_with(RSSHUB_SCHEME) {
+                line.push_str(&format!(" <code>{}</code>", Escape(&feed.link)));
+            }
+            line.push_str(", ");
+            line.push_str(&format_last_delivered(feed.activity(target_id.0), now));
+            let subscription = feed.subscription(target_id.0);
+            let settings = subscription.settings.resolve(&defaults);
+            if let Some(filter) = &settings.lang {
+                let languages = format_lang(Some(filter));
+                line.push_str(&tr!("rss_lang_filter", languages = languages));
+            }
+            if let Some(note) = &subscription.note {
+                line.push_str(&tr!("rss_note", note = Escape(note)));
+            }
+            line
+        })
+    } else {
+        vec![tr!("subscription_list_empty").to_string()]
+    };
+
+    reply_large_msgs(&cmd.bot, target, msgs).await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Order {
+    /// By title, Chinese characters by their pinyin
+    Default,
+    /// By title, case-insensitive
+    Alphabetical,
+    /// The most recently delivered first
+    Recent,
+}
+
+pub(super) fn sort_feeds(feeds: &mut [Feed], order: Order, subscriber: i64) {
+    match order {
+        Order::Default => feeds.sort_by_cached_key(|feed| {
             feed.title
                 .chars()
                 .map(|c| {
@@ -39,32 +103,37 @@ pub async fn rss(
                         .unwrap_or_else(|| Either::Left(c))
                 })
                 .collect::<Vec<Either<char, &str>>>()
-        });
-        format_large_msg(tr!("subscription_list").to_string(), &feeds, |feed| {
-            format!(
-                "<a href=\"{}\">{}</a>",
-                Escape(&feed.link),
-                Escape(&feed.title)
-            )
-        })
-    } else {
-        vec![tr!("subscription_list_empty").to_string()]
-    };
-
-    let first_msg = msgs.remove(0);
-    update_response(&cmd.bot, target, parameters::Text::with_html(&first_msg)).await?;
+        }),
+        Order::Alphabetical => {
+            feeds.sort_by_cached_key(|feed| (feed.title.to_lowercase(), feed.title.clone()))
+        }
+        Order::Recent => {
+            sort_feeds(feeds, Order::Default, subscriber);
+            // Stable, so the feeds without deliveries stay sorted by title
+            feeds.sort_by_key(|feed| {
+                Reverse(
+                    feed.activity(subscriber)
+                        .and_then(|activity| activity.last_delivered),
+                )
+            });
+        }
+    }
+}
 
-    let mut prev_msg = target.message_id;
-    for msg in msgs {
-        let text = parameters::Text::with_html(&msg);
-        let msg = cmd
-            .bot
-            .send_message(chat_id, text)
-            .in_reply_to(prev_msg)
-            .is_web_page_preview_disabled(true)
-            .call()
-            .await?;
-        prev_msg = msg.id;
+fn format_last_delivered(activity: Option<Activity>, now: SystemTime) -> String {
+    match activity {
+        Some(Activity {
+            last_delivered: Some(time),
+            ..
+        }) => {
+            let ago = now.duration_since(time).unwrap_or_default().as_secs();
+            // Only the most significant unit, the list is long enough already
+            let ago = format_duration(ago);
+            let ago = ago.split(' ').next().unwrap_or_default();
+            tr!("last_item_ago", time = ago)
+        }
+        Some(_) => tr!("last_item_never").to_string(),
+        // Subscribed before the deliveries were recorded
+        None => tr!("last_item_unknown").to_string(),
     }
-    Ok(())
 }
@@ -0,0 +1,38 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+
+use crate::client::{rsshub_base, set_rsshub_base};
+use crate::data::Database;
+use crate::messages::Escape;
+
+use super::{update_response, MsgTarget};
+
+pub async fn rsshub(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let text = cmd.text.value.trim();
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+
+    let msg = match text {
+        "" => tr!("rsshub_base", base = Escape(&rsshub_base())),
+        "default" => {
+            db.update_global(|global| global.rsshub_base = None);
+            set_rsshub_base(None);
+            tr!("rsshub_base_changed", base = Escape(&rsshub_base()))
+        }
+        base => match reqwest::Url::parse(base) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {
+                let base = base.to_string();
+                db.update_global(|global| global.rsshub_base = Some(base.clone()));
+                set_rsshub_base(Some(base));
+                tr!("rsshub_base_changed", base = Escape(&rsshub_base()))
+            }
+            _ => tr!("rsshub_how_to_use").to_string(),
+        },
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+    Ok(())
+}
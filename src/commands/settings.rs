@@ -0,0 +1,561 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+
+use crate::data::{
+    Chat, Database, Discussion, FeedReset, LanguageFilter, LinkCleaning, MinWords, ReplyTo,
+    Settings, Subscription, WeeklyReport,
+};
+use crate::messages::{date, format_duration, parse_duration, Escape, Title};
+use crate::translate;
+
+use super::{check_channel_permission, check_owner, update_response, MsgTarget};
+
+/// Settings of a subscription that are also defaults of the chat, see `Settings`
+const KEYS: &[&str] = &[
+    "max_items",
+    "clean_links",
+    "digest_above",
+    "translate",
+    "min_words",
+    "lang",
+    "date",
+    "max_queue_age",
+    "reset",
+    "reset_history",
+];
+/// Only for subscriptions
+const SUBSCRIPTION_KEYS: &[&str] = &["discussion"];
+/// Settings of the chat, for all of its subscriptions
+const CHAT_KEYS: &[&str] = &["crossdedup", "reply_to", "footer", "weekly_report"];
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+pub async fn settings(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let text = &cmd.text.value;
+    let args = text.split_whitespace().collect::<Vec<_>>();
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+
+    // Channel IDs and setting names never look like a URL
+    let is_feed = |arg: &&str| arg.contains("://");
+    let (channel, feed_url, setting) = match &*args {
+        [] => return chat_settings(&db, &cmd, None, None, target).await,
+        [url] if is_feed(url) => (None, *url, None),
+        [channel] => return chat_settings(&db, &cmd, Some(*channel), None, target).await,
+        [url, key, value] if is_feed(url) => (None, *url, Some((*key, *value))),
+        [channel, url] if is_feed(url) => (Some(*channel), *url, None),
+        [key, value] => {
+            return chat_settings(&db, &cmd, None, Some((*key, *value)), target).await
+        }
+        [channel, url, key, value] => (Some(*channel), *url, Some((*key, *value))),
+        [channel, key, value] => {
+            let setting = Some((*key, *value));
+            return chat_settings(&db, &cmd, Some(*channel), setting, target).await;
+        }
+        [..] => {
+            let msg = tr!("settings_how_to_use");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+    if let Some(channel) = channel {
+        let channel_id = check_channel_permission(&cmd, channel, target).await?;
+        if channel_id.is_none() {
+            return Ok(());
+        }
+        target_id = channel_id.unwrap();
+    }
+
+    let feed = db
+        .subscribed_feeds(target_id.0)
+        .and_then(|feeds| feeds.into_iter().find(|feed| feed.link == feed_url));
+    let feed = match feed {
+        Some(feed) => feed,
+        None => {
+            let msg = tr!("feed_not_found");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+
+    // Showing them is fine, and chat settings belong to nobody
+    let changes_subscription = setting.is_some_and(|(key, _)| !CHAT_KEYS.contains(&key));
+    if changes_subscription && !check_owner(&cmd, &db, target_id, feed_url, target).await? {
+        return Ok(());
+    }
+
+    let updated = tr!(
+        "settings_updated",
+        link = Escape(&feed.link),
+        title = Title(&feed.title)
+    );
+    let msg = match setting {
+        None => format_settings(&feed.subscription(target_id.0), &db.chat(target_id.0)),
+        Some(("discussion", "on")) => {
+            if channel.is_none() {
+                tr!("discussion_for_channels_only").to_string()
+            } else if let Some(discussion) = resolve_discussion(&cmd, target_id).await? {
+                db.update_subscription(target_id.0, feed_url, |s| {
+                    s.discussion = Some(discussion)
+                });
+                updated
+            } else {
+                tr!("no_linked_discussion").to_string()
+            }
+        }
+        Some(("discussion", "off")) => {
+            db.update_subscription(target_id.0, feed_url, |s| s.discussion = None);
+            updated
+        }
+        // There's only one per chat
+        Some((key, value)) if CHAT_KEYS.contains(&key) => match apply_chat_setting(key, value) {
+            Ok(apply) => {
+                db.update_chat(target_id.0, apply);
+                tr!("chat_settings_updated").to_string()
+            }
+            Err(msg) => msg,
+        },
+        Some((key, value)) => match apply_setting(key, value) {
+            Ok(apply) => {
+                db.update_subscription(target_id.0, feed_url, |s| apply(&mut s.settings));
+                updated
+            }
+            Err(msg) => msg,
+        },
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+    Ok(())
+}
+
+/// Show or change the settings of the chat, and the defaults of its subscriptions
+async fn chat_settings(
+    db: &Database,
+    cmd: &Command,
+    channel: Option<&str>,
+    setting: Option<(&str, &str)>,
+    target: &mut MsgTarget,
+) -> Result<(), tbot::errors::MethodCall> {
+    let mut target_id = cmd.chat.id;
+    if let Some(channel) = channel {
+        match check_channel_permission(cmd, channel, target).await? {
+            Some(channel_id) => target_id = channel_id,
+            None => return Ok(()),
+        }
+    }
+    let updated = || tr!("chat_settings_updated").to_string();
+    let msg = match setting {
+        None => format_chat_settings(&db.chat(target_id.0)),
+        Some((key, value)) if CHAT_KEYS.contains(&key) => match apply_chat_setting(key, value) {
+            Ok(apply) => {
+                db.update_chat(target_id.0, apply);
+                updated()
+            }
+            Err(msg) => msg,
+        },
+        Some((key, _)) if SUBSCRIPTION_KEYS.contains(&key) => unknown_key(key),
+        Some((key, value)) => match apply_setting(key, value) {
+            Ok(apply) => {
+                db.update_chat(target_id.0, |chat| apply(&mut chat.defaults));
+                updated()
+            }
+            Err(msg) => msg,
+        },
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+    Ok(())
+}
+
+/// Find the linked discussion group of the channel
+async fn resolve_discussion(
+    cmd: &Command,
+    channel_id: tbot::types::chat::Id,
+) -> Result<Option<Discussion>, tbot::errors::MethodCall> {
+    use tbot::types::{chat::Kind, message::From};
+    let enabled_by = match &cmd.from {
+        Some(From::User(user)) => user.id.0,
+        _ => return Ok(None),
+    };
+    let chat = crate::chat_info::get_chat(&cmd.bot, channel_id).await?;
+    Ok(match chat.kind {
+        Kind::Channel {
+            linked_chat_id: Some(chat_id),
+            ..
+        } => Some(Discussion {
+            chat_id: chat_id.0,
+            enabled_by,
+        }),
+        _ => None,
+    })
+}
+
+/// The settings in effect, marked when they come from the defaults of the chat
+fn format_settings(subscription: &Subscription, chat: &Chat) -> String {
+    let own = &subscription.settings;
+    let defaults = &chat.defaults;
+    let resolved = own.resolve(defaults);
+    let source = |own: bool, default: bool| if !own && default { " (chat default)" } else { "" };
+    let discussion = if subscription.discussion.is_some() {
+        "on"
+    } else {
+        "off"
+    };
+    format!(
+        "max_items: {}{}\ndiscussion: {}\nclean_links: {}{}\ndigest_above: {}{}\n\
+         translate: {}{}\nmin_words: {}{}\nlang: {}{}\ndate: {}{}\nmax_queue_age: {}{}\n\
+         reset: {}{}\nreset_history: {}{}\n{}",
+        format_max_items(resolved.max_items),
+        source(own.max_items.is_some(), defaults.max_items.is_some()),
+        discussion,
+        format_clean_links(resolved.clean_links),
+        source(own.clean_links.is_some(), defaults.clean_links.is_some()),
+        format_digest_above(resolved.digest_above),
+        source(own.digest_above.is_some(), defaults.digest_above.is_some()),
+        format_translate(resolved.translate.as_deref()),
+        source(own.translate.is_some(), defaults.translate.is_some()),
+        format_min_words(resolved.min_words),
+        source(own.min_words.is_some(), defaults.min_words.is_some()),
+        format_lang(resolved.lang.as_ref()),
+        source(own.lang.is_some(), defaults.lang.is_some()),
+        Escape(format_date(resolved.date.as_deref())),
+        source(own.date.is_some(), defaults.date.is_some()),
+        format_max_queue_age(resolved.max_queue_age),
+        source(own.max_queue_age.is_some(), defaults.max_queue_age.is_some()),
+        format_reset(resolved.reset_policy()),
+        source(own.reset.is_some(), defaults.reset.is_some()),
+        resolved.reset_threshold(),
+        source(own.reset_history.is_some(), defaults.reset_history.is_some()),
+        format_chat_only(chat, " (chat)")
+    )
+}
+
+fn format_chat_settings(chat: &Chat) -> String {
+    let defaults = chat.defaults.resolve(&Settings::default());
+    format!(
+        "{}\nmax_items: {}\nclean_links: {}\ndigest_above: {}\ntranslate: {}\nmin_words: {}\n\
+         lang: {}\ndate: {}\nmax_queue_age: {}\nreset: {}\nreset_history: {}\n{}",
+        tr!("chat_settings"),
+        format_max_items(defaults.max_items),
+        format_clean_links(defaults.clean_links),
+        format_digest_above(defaults.digest_above),
+        format_translate(defaults.translate.as_deref()),
+        format_min_words(defaults.min_words),
+        format_lang(defaults.lang.as_ref()),
+        Escape(format_date(defaults.date.as_deref())),
+        format_max_queue_age(defaults.max_queue_age),
+        format_reset(defaults.reset_policy()),
+        defaults.reset_threshold(),
+        format_chat_only(chat, "")
+    )
+}
+
+/// The settings in `CHAT_KEYS`
+fn format_chat_only(chat: &Chat, suffix: &str) -> String {
+    let cross_dedup = if chat.cross_dedup { "on" } else { "off" };
+    let reply_to = match chat.reply_to {
+        None => "off".to_string(),
+        Some(ReplyTo::Message(message_id)) => message_id.to_string(),
+        Some(ReplyTo::Pinned) => "pin".to_string(),
+    };
+    let footer = if chat.hide_footer { "off" } else { "on" };
+    let weekly_report = match chat.weekly_report {
+        None => "off".to_string(),
+        Some(report) => format!(
+            "{},{:02}:{:02}",
+            WEEKDAYS[report.weekday as usize],
+            report.minute / 60,
+            report.minute % 60
+        ),
+    };
+    format!(
+        "crossdedup{}: {}\nreply_to{}: {}\nfooter{}: {}\nweekly_report{}: {}",
+        suffix, cross_dedup, suffix, reply_to, suffix, footer, suffix, weekly_report
+    )
+}
+
+fn format_max_items(max_items: Option<usize>) -> String {
+    max_items
+        .map(|n| n.to_string())
+        .unwrap_or_else(|| tr!("unlimited").to_string())
+}
+
+fn format_clean_links(clean_links: Option<LinkCleaning>) -> &'static str {
+    match clean_links {
+        None | Some(LinkCleaning::Off) => "off",
+        Some(LinkCleaning::Strip) => "on",
+        Some(LinkCleaning::Resolve) => "resolve",
+    }
+}
+
+fn format_digest_above(digest_above: Option<usize>) -> String {
+    digest_above
+        .map(|n| n.to_string())
+        .unwrap_or_else(|| "off".to_string())
+}
+
+fn format_translate(language: Option<&str>) -> &str {
+    language.unwrap_or("off")
+}
+
+fn format_min_words(min_words: Option<MinWords>) -> String {
+    match min_words {
+        None | Some(MinWords::Off) => "off".to_string(),
+        Some(MinWords::Links) => "links".to_string(),
+        Some(MinWords::Count(n)) => n.to_string(),
+    }
+}
+
+fn format_date(format: Option<&str>) -> &str {
+    format.unwrap_or("off")
+}
+
+fn format_max_queue_age(max_queue_age: Option<u64>) -> String {
+    max_queue_age
+        .map(format_duration)
+        .unwrap_or_else(|| tr!("unlimited").to_string())
+}
+
+fn format_reset(reset: FeedReset) -> String {
+    match reset {
+        FeedReset::All => "all".to_string(),
+        FeedReset::Resync => "none".to_string(),
+        FeedReset::Latest(n) => n.to_string(),
+    }
+}
+
+/// Like the value of `/settings lang`, `strict` when the untagged items are skipped
+pub(super) fn format_lang(filter: Option<&LanguageFilter>) -> String {
+    match filter {
+        Some(filter) if !filter.languages.is_empty() => {
+            let languages = filter.languages.join(",");
+            if filter.untagged {
+                languages
+            } else {
+                languages + ",strict"
+            }
+        }
+        _ => "off".to_string(),
+    }
+}
+
+/// `en,zh`, with `strict` to also skip the items without a language
+fn parse_lang(value: &str) -> Option<LanguageFilter> {
+    let mut filter = LanguageFilter {
+        languages: Vec::new(),
+        untagged: true,
+    };
+    for code in value.split(',') {
+        if code == "strict" {
+            filter.untagged = false;
+            continue;
+        }
+        // Only the primary subtag is compared, `en-US` is the same as `en`
+        let primary = code.split(['-', '_']).next().unwrap_or_default();
+        let is_code = (2..=3).contains(&primary.len())
+            && primary.chars().all(|c| c.is_ascii_alphabetic());
+        if !is_code {
+            return None;
+        }
+        let primary = primary.to_ascii_lowercase();
+        if !filter.languages.contains(&primary) {
+            filter.languages.push(primary);
+        }
+    }
+    Some(filter).filter(|filter| !filter.languages.is_empty())
+}
+
+fn unknown_key(key: &str) -> String {
+    let keys: Vec<&str> = KEYS
+        .iter()
+        .chain(SUBSCRIPTION_KEYS)
+        .chain(CHAT_KEYS)
+        .copied()
+        .collect();
+    tr!("settings_unknown_key", key = Escape(key), keys = keys.join(", "))
+}
+
+/// Validate `value` for `key`, return how to apply it, or the error message.
+/// `default` inherits the setting again, and 0 in `Settings` is off.
+fn apply_setting(key: &str, value: &str) -> Result<Box<dyn FnOnce(&mut Settings)>, String> {
+    let invalid = || tr!("settings_invalid_value", key = key, value = Escape(value));
+    let count = |off: &str| match value {
+        "default" => Ok(None),
+        _ if value == off => Ok(Some(0)),
+        _ => match value.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(Some(n)),
+            _ => Err(invalid()),
+        },
+    };
+    match key {
+        "max_items" => {
+            let max_items = count("unlimited")?;
+            Ok(Box::new(move |s: &mut Settings| s.max_items = max_items))
+        }
+        "digest_above" => {
+            let digest_above = count("off")?;
+            Ok(Box::new(move |s: &mut Settings| {
+                s.digest_above = digest_above
+            }))
+        }
+        "clean_links" => {
+            let clean_links = match value {
+                "default" => None,
+                "off" => Some(LinkCleaning::Off),
+                "on" => Some(LinkCleaning::Strip),
+                "resolve" => Some(LinkCleaning::Resolve),
+                _ => return Err(invalid()),
+            };
+            Ok(Box::new(move |s: &mut Settings| {
+                s.clean_links = clean_links
+            }))
+        }
+        "translate" => {
+            let language = match value {
+                "default" => None,
+                "off" => Some(value.to_string()),
+                _ if !translate::enabled() => {
+                    return Err(tr!("translate_not_configured").to_string())
+                }
+                _ if translate::is_language_code(value) => Some(value.to_string()),
+                _ => return Err(invalid()),
+            };
+            Ok(Box::new(move |s: &mut Settings| s.translate = language))
+        }
+        "min_words" => {
+            let min_words = match value {
+                "default" => None,
+                "links" => Some(MinWords::Links),
+                _ => count("off")?.map(|n| match n {
+                    0 => MinWords::Off,
+                    n => MinWords::Count(n),
+                }),
+            };
+            Ok(Box::new(move |s: &mut Settings| s.min_words = min_words))
+        }
+        "date" => {
+            let format = match value {
+                "default" => None,
+                "off" => Some(value.to_string()),
+                _ if date::is_valid(value) => Some(value.to_string()),
+                _ => return Err(tr!("settings_invalid_date", value = Escape(value))),
+            };
+            Ok(Box::new(move |s: &mut Settings| s.date = format))
+        }
+        "lang" => {
+            let lang = match value {
+                "default" => None,
+                "off" => Some(LanguageFilter::default()),
+                _ => Some(parse_lang(value).ok_or_else(invalid)?),
+            };
+            Ok(Box::new(move |s: &mut Settings| s.lang = lang))
+        }
+        "max_queue_age" => {
+            let max_queue_age = match value {
+                "default" => None,
+                "unlimited" => Some(0),
+                // 0 is `unlimited`
+                _ => match parse_duration(value) {
+                    Ok(secs) if secs > 0 => Some(secs),
+                    _ => {
+                        let value = Escape(value);
+                        return Err(tr!("settings_invalid_duration", key = key, value = value));
+                    }
+                },
+            };
+            Ok(Box::new(move |s: &mut Settings| {
+                s.max_queue_age = max_queue_age
+            }))
+        }
+        "reset" => {
+            let reset = match value {
+                "default" => None,
+                "all" => Some(FeedReset::All),
+                "none" => Some(FeedReset::Resync),
+                _ => count("none")?.map(|n| match n {
+                    0 => FeedReset::Resync,
+                    n => FeedReset::Latest(n),
+                }),
+            };
+            Ok(Box::new(move |s: &mut Settings| s.reset = reset))
+        }
+        // 0 treats every feed that had items as reset
+        "reset_history" => {
+            let reset_history = match value {
+                "default" => None,
+                _ => Some(value.parse::<usize>().map_err(|_| invalid())?),
+            };
+            Ok(Box::new(move |s: &mut Settings| {
+                s.reset_history = reset_history
+            }))
+        }
+        _ => Err(unknown_key(key)),
+    }
+}
+
+/// `mon` or `mon,18:30`, at 09:00 if there's no time
+fn parse_weekly_report(value: &str) -> Option<(u32, u32)> {
+    let (weekday, time) = value.split_once(',').unwrap_or((value, "09:00"));
+    let weekday = WEEKDAYS.iter().position(|&day| day == weekday)? as u32;
+    let (hour, minute) = time.split_once(':')?;
+    let (hour, minute) = (hour.parse::<u32>().ok()?, minute.parse::<u32>().ok()?);
+    if hour > 23 || minute > 59 {
+        return None;
+    }
+    Some((weekday, hour * 60 + minute))
+}
+
+fn apply_chat_setting(key: &str, value: &str) -> Result<Box<dyn FnOnce(&mut Chat)>, String> {
+    let invalid = || tr!("settings_invalid_value", key = key, value = Escape(value));
+    match key {
+        "crossdedup" => {
+            let enabled = match value {
+                "on" => true,
+                "off" => false,
+                _ => return Err(invalid()),
+            };
+            Ok(Box::new(move |chat: &mut Chat| {
+                chat.set_cross_dedup(enabled)
+            }))
+        }
+        "reply_to" => {
+            let reply_to = match value {
+                "off" => None,
+                "pin" => Some(ReplyTo::Pinned),
+                _ => match value.parse::<u32>() {
+                    Ok(message_id) if message_id > 0 => Some(ReplyTo::Message(message_id.into())),
+                    _ => return Err(invalid()),
+                },
+            };
+            Ok(Box::new(move |chat: &mut Chat| chat.reply_to = reply_to))
+        }
+        "footer" => {
+            let hide_footer = match value {
+                "on" => false,
+                "off" => true,
+                _ => return Err(invalid()),
+            };
+            Ok(Box::new(move |chat: &mut Chat| {
+                chat.hide_footer = hide_footer
+            }))
+        }
+        "weekly_report" => {
+            let report = match value {
+                "off" => None,
+                "on" => Some((0, 9 * 60)),
+                _ => Some(parse_weekly_report(value).ok_or_else(invalid)?),
+            };
+            let now = chrono::Utc::now().timestamp();
+            Ok(Box::new(move |chat: &mut Chat| {
+                chat.weekly_report = report.map(|(weekday, minute)| WeeklyReport {
+                    weekday,
+                    minute,
+                    last_sent: now,
+                })
+            }))
+        }
+        _ => unreachable!("not in CHAT_KEYS"),
+    }
+}
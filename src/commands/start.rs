@@ -1,12 +1,11 @@
 use std::sync::Arc;
 
 use tbot::{contexts::Command, types::parameters};
-use tokio::sync::Mutex;
 
 use super::{update_response, Database, MsgTarget};
 
 pub async fn start(
-    _db: Arc<Mutex<Database>>,
+    _db: Arc<Database>,
     cmd: Arc<Command>,
 ) -> Result<(), tbot::errors::MethodCall> {
     let target = &mut MsgTarget::new(cmd.chat.id, cmd.message_id);
@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Local};
+use tbot::contexts::Command;
+
+use crate::data::{Database, Quirks};
+use crate::fetcher::feed_interval;
+use crate::health::METRICS;
+use crate::messages::{format_duration, format_large_msg, Escape, Title};
+
+use super::maxsize::format_max_size;
+use super::{check_channel_permission, reply_large_msgs, MsgTarget};
+
+pub async fn status(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let channel = &cmd.text.value;
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+
+    if !channel.is_empty() {
+        let channel_id = check_channel_permission(&cmd, channel, target).await?;
+        if channel_id.is_none() {
+            return Ok(());
+        }
+        target_id = channel_id.unwrap();
+    }
+
+    let feeds = db.subscribed_feeds(target_id.0);
+    let msgs = if let Some(mut feeds) = feeds {
+        feeds.sort_by(|a, b| a.title.cmp(&b.title));
+        format_large_msg(tr!("feed_status").to_string(), &feeds, |feed| {
+            let interval = format_duration(feed_interval(feed) as u64);
+            let median_gap = feed
+                .median_gap()
+                .map(|gap| format_duration(gap as u64))
+                .unwrap_or_else(|| tr!("not_enough_dated_items").to_string());
+            let mut line = tr!(
+                "feed_status_line",
+                link = Escape(&feed.link),
+                title = Title(&feed.title),
+                interval = interval,
+                median_gap = median_gap
+            );
+            if let Some((median, p95)) = METRICS.delivery_latency(&feed.link) {
+                line.push_str(&tr!(
+                    "feed_latency",
+                    median = format_duration(median),
+                    p95 = format_duration(p95)
+                ));
+            }
+            if let Some(max_size) = feed.max_size {
+                line.push_str(&tr!("feed_max_size", size = format_max_size(max_size)));
+            }
+            if feed.blocked {
+                line.push_str(&tr!("feed_blocked"));
+            }
+            if !feed.quirks.is_empty() {
+                line.push_str(&tr!("feed_quirks", quirks = format_quirks(feed.quirks)));
+            }
+            if let Some(next_fetch) = feed.next_fetch {
+                let time = DateTime::<Local>::from(next_fetch).format("%Y-%m-%d %H:%M");
+                line.push_str(&tr!(
+                    "feed_backing_off",
+                    failures = feed.failures,
+                    time = time
+                ));
+            }
+            line
+        })
+    } else {
+        vec![tr!("subscription_list_empty").to_string()]
+    };
+
+    reply_large_msgs(&cmd.bot, target, msgs).await
+}
+
+/// Why the feed is fetched differently from the others
+fn format_quirks(quirks: Quirks) -> String {
+    let mut names = Vec::new();
+    if quirks.http1_only {
+        names.push(tr!("quirk_http1_only"));
+    }
+    if quirks.accept_any {
+        names.push(tr!("quirk_accept_any"));
+    }
+    if quirks.insecure {
+        names.push(tr!("quirk_insecure"));
+    }
+    names.join(", ")
+}
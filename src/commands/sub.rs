@@ -1,28 +1,79 @@
-use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use tbot::{contexts::Command, types::parameters};
-use tokio::sync::Mutex;
+use tbot::{
+    contexts::{methods::Callback, Command, DataCallback},
+    types::{
+        callback::Origin,
+        chat,
+        keyboard::inline::{Button, ButtonKind},
+        parameters,
+    },
+    Bot,
+};
 
-use crate::client::pull_feed;
-use crate::data::Database;
-use crate::messages::Escape;
+use crate::client::{check_feed_url, pull_feed_cached, resolve_shorthand, Shorthand, UrlError};
+use crate::data::{Database, SubscribeResult};
+use crate::feed::Rss;
+use crate::messages::{wait_send_turn, Escape, Priority, Title};
 
-use super::{check_channel_permission, update_response, MsgTarget};
+use super::{
+    bot_can_post, check_channel_permission, clean_url, display_url, is_chat_admin, sender_id,
+    update_response, MsgTarget,
+};
+
+// Prefix of the callback data of the buttons offering the feeds of a shorthand
+const CHOICE_DATA_PREFIX: &str = "sub ";
+// The oldest offers can't be picked anymore after this many
+const MAX_CHOICES: usize = 100;
+
+// Repeating the command later than this warns again
+const EMPTY_FEED_WARNING_TTL: Duration = Duration::from_secs(60 * 60);
+
+// (chat, feed) pairs that were warned about subscribing to an empty feed, and when,
+// repeating the command subscribes anyway
+static EMPTY_FEED_WARNED: Mutex<BTreeMap<(i64, String), Instant>> = Mutex::new(BTreeMap::new());
+static CHOICES: Mutex<BTreeMap<u64, Choice>> = Mutex::new(BTreeMap::new());
+
+/// Who subscribes, and how
+#[derive(Debug, Clone, Copy)]
+struct Request {
+    target_id: chat::Id,
+    is_private: bool,
+    /// Only they can pick from a `Choice`, any admin of the chat for anonymous admins
+    user: Option<i64>,
+    /// Pull the feed again, even if it was pulled recently
+    force: bool,
+}
+
+/// The feeds of a shorthand offered to a user
+struct Choice {
+    request: Request,
+    feeds: Vec<String>,
+}
 
 pub async fn sub(
-    db: Arc<Mutex<Database>>,
+    db: Arc<Database>,
     cmd: Arc<Command>,
 ) -> Result<(), tbot::errors::MethodCall> {
     let chat_id = cmd.chat.id;
     let text = &cmd.text.value;
-    let args = text.split_whitespace().collect::<Vec<_>>();
+    let mut args = text.split_whitespace().collect::<Vec<_>>();
+    // Not what was remembered of the URL, the site may have been fixed since
+    let force = args.len() > 1 && args.last() == Some(&"force");
+    if force {
+        args.pop();
+    }
     let mut target_id = chat_id;
     let target = &mut MsgTarget::new(chat_id, cmd.message_id);
     let feed_url;
+    let mut channel_arg = None;
 
     match &*args {
         [url] => feed_url = url,
         [channel, url] => {
+            channel_arg = Some(*channel);
             let channel_id = check_channel_permission(&cmd, channel, target).await?;
             if channel_id.is_none() {
                 return Ok(());
@@ -36,9 +87,163 @@ pub async fn sub(
             return Ok(());
         }
     };
-    if db.lock().await.is_subscribed(target_id.0, feed_url) {
+    let request = Request {
+        target_id,
+        is_private: cmd.chat.kind.is_private() && target_id == chat_id,
+        user: sender_id(&cmd),
+        force,
+    };
+    let cleaned = clean_url(feed_url);
+    let feed_url = &cleaned;
+    let resolved;
+    let feed_url: &str = match resolve_shorthand(feed_url) {
+        Some(Shorthand::Feed(url)) => {
+            resolved = url;
+            &resolved
+        }
+        Some(Shorthand::Choice(feeds)) => {
+            let (names, feeds) = feeds.into_iter().unzip();
+            let choice = Choice { request, feeds };
+            return offer_choice(&cmd.bot, target, feed_url, choice, names).await;
+        }
+        None => feed_url,
+    };
+    if let Err(e) = check_feed_url(feed_url) {
+        let msg = match e {
+            UrlError::UnsupportedScheme(scheme) => tr!("unsupported_scheme", scheme = scheme),
+            UrlError::NoOnionProxy => tr!("onion_proxy_required").to_string(),
+            UrlError::NoScheme => {
+                let feed_url = display_url(feed_url);
+                let command = match channel_arg {
+                    Some(channel) => format!("/sub {} https://{}", channel, feed_url),
+                    None => format!("/sub https://{}", feed_url),
+                };
+                tr!("url_without_scheme", command = command)
+            }
+            e => tr!("sub_invalid_url", source = e, url = display_url(feed_url)),
+        };
+        update_response(&cmd.bot, target, parameters::Text::with_plain(&msg)).await?;
+        return Ok(());
+    }
+    subscribe(&cmd.bot, &db, target, request, feed_url).await
+}
+
+/// The feed of a shorthand was picked from `offer_choice`
+pub async fn choose(
+    db: Arc<Database>,
+    ctx: Arc<DataCallback>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let id = match ctx
+        .data
+        .strip_prefix(CHOICE_DATA_PREFIX)
+        .and_then(|data| data.split_once(' '))
+        .and_then(|(id, index)| Some((id.parse::<u64>().ok()?, index.parse::<usize>().ok()?)))
+    {
+        Some(id) => id,
+        None => return Ok(()),
+    };
+    let message = match &ctx.origin {
+        Origin::Message(message) => message,
+        _ => return Ok(()),
+    };
+    let user = CHOICES
+        .lock()
+        .unwrap()
+        .get(&id.0)
+        .map(|choice| choice.request.user);
+    let (allowed, refusal) = match user {
+        Some(Some(user)) => (user == ctx.from.id.0, tr!("sub_choice_not_yours")),
+        Some(None) => {
+            let is_admin = is_chat_admin(&ctx.bot, message.chat.id, ctx.from.id).await;
+            (is_admin, tr!("sub_choice_admins_only"))
+        }
+        None => (true, ""),
+    };
+    if !allowed {
+        ctx.notify(refusal).call().await?;
+        return Ok(());
+    }
+    // Gone if it was picked while the admins were looked up
+    let choice = match CHOICES.lock().unwrap().remove(&id.0) {
+        Some(choice) => choice,
+        None => {
+            ctx.notify(tr!("sub_choice_expired")).call().await?;
+            return Ok(());
+        }
+    };
+    ctx.ignore().call().await?;
+    let feed_url = match choice.feeds.get(id.1) {
+        Some(feed_url) => feed_url,
+        None => return Ok(()),
+    };
+    // The offer is replaced by the result
+    let target = &mut MsgTarget::edit(message.chat.id, message.id);
+    subscribe(&ctx.bot, &db, target, choice.request, feed_url).await
+}
+
+/// Ask which of the feeds of `shorthand` to subscribe to, with a button for each.
+/// `names` are the localization keys of the feeds.
+async fn offer_choice(
+    bot: &Bot,
+    target: &MsgTarget,
+    shorthand: &str,
+    choice: Choice,
+    names: Vec<&'static str>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let id = {
+        let mut choices = CHOICES.lock().unwrap();
+        let id = choices.keys().next_back().map_or(0, |last| last + 1);
+        choices.insert(id, choice);
+        while choices.len() > MAX_CHOICES {
+            choices.pop_first();
+        }
+        id
+    };
+    let labels: Vec<(&str, String)> = names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (feed_name(name), format!("{}{} {}", CHOICE_DATA_PREFIX, id, i)))
+        .collect();
+    let buttons: Vec<[Button; 1]> = labels
+        .iter()
+        .map(|(label, data)| [Button::new(label, ButtonKind::CallbackData(data))])
+        .collect();
+    let keyboard: Vec<&[Button]> = buttons.iter().map(|row| &row[..]).collect();
+    let msg = tr!("sub_choose_feed", shorthand = shorthand);
+    wait_send_turn(target.chat_id.0, Priority::Reply).await;
+    bot.send_message(target.chat_id, parameters::Text::with_plain(&msg))
+        .in_reply_to(target.message_id)
+        .reply_markup(&keyboard[..])
+        .call()
+        .await?;
+    Ok(())
+}
+
+fn feed_name(name: &str) -> &str {
+    match name {
+        "github_releases" => tr!("github_releases"),
+        "github_commits" => tr!("github_commits"),
+        "github_tags" => tr!("github_tags"),
+        _ => name,
+    }
+}
+
+async fn subscribe(
+    bot: &Bot,
+    db: &Database,
+    target: &mut MsgTarget,
+    request: Request,
+    feed_url: &str,
+) -> Result<(), tbot::errors::MethodCall> {
+    let Request {
+        target_id,
+        is_private,
+        user,
+        force,
+    } = request;
+    if db.is_subscribed(target_id.0, feed_url) {
         update_response(
-            &cmd.bot,
+            bot,
             target,
             parameters::Text::with_plain(tr!("subscribed_to_rss")),
         )
@@ -46,31 +251,63 @@ pub async fn sub(
         return Ok(());
     }
 
-    if cfg!(feature = "hosted-by-iovxw") && db.lock().await.all_feeds().len() >= 1500 {
+    if cfg!(feature = "hosted-by-iovxw") && db.all_feeds().len() >= 1500 {
         let msg = tr!("subscription_rate_limit");
-        update_response(&cmd.bot, target, parameters::Text::with_markdown(msg)).await?;
+        update_response(bot, target, parameters::Text::with_markdown(msg)).await?;
         return Ok(());
     }
     update_response(
-        &cmd.bot,
+        bot,
         target,
         parameters::Text::with_plain(tr!("processing_please_wait")),
     )
     .await?;
-    let msg = match pull_feed(feed_url).await {
+    let msg = match pull_feed_cached(feed_url, force).await {
+        Ok(feed) if is_empty(&feed) && warn_empty(target_id.0, feed_url) => {
+            tr!("empty_feed_warning").into()
+        }
         Ok(feed) => {
-            if db.lock().await.subscribe(target_id.0, feed_url, &feed) {
-                tr!(
+            if db.subscribe(target_id.0, feed_url, &feed) == SubscribeResult::Subscribed {
+                if let Some(user_id) = user {
+                    db.set_subscribed_by(target_id.0, feed_url, user_id);
+                }
+                let mut msg = tr!(
                     "subscription_succeeded",
-                    link = Escape(&feed.link),
-                    title = Escape(&feed.title)
-                )
+                    link = Escape(&feed.meta.link),
+                    title = Title(&feed.meta.title)
+                );
+                // Kept anyway, the permission may be granted later
+                if !is_private && matches!(bot_can_post(bot, target_id).await, Ok(false)) {
+                    msg.push('\n');
+                    msg.push_str(&tr!("bot_cannot_post"));
+                }
+                msg
             } else {
                 tr!("subscribed_to_rss").into()
             }
         }
         Err(e) => tr!("subscription_failed", error = Escape(&e.to_user_friendly())),
     };
-    update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+    update_response(bot, target, parameters::Text::with_html(&msg)).await?;
     Ok(())
 }
+
+/// A valid feed without title or items is usually a placeholder served to
+/// logged-out visitors
+fn is_empty(feed: &Rss) -> bool {
+    feed.items.is_empty() && feed.meta.title.trim().is_empty()
+}
+
+/// Returns false if the user has been warned already
+fn warn_empty(chat_id: i64, feed_url: &str) -> bool {
+    let mut warned = EMPTY_FEED_WARNED.lock().unwrap();
+    // The ones never repeated would be kept forever
+    warned.retain(|_, at| at.elapsed() < EMPTY_FEED_WARNING_TTL);
+    let key = (chat_id, feed_url.to_string());
+    if warned.remove(&key).is_some() {
+        false
+    } else {
+        warned.insert(key, Instant::now());
+        true
+    }
+}
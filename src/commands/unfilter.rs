@@ -0,0 +1,54 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+
+use crate::data::{Database, ItemFilter};
+use crate::messages::{Escape, Title};
+
+use super::{check_channel_permission, check_owner, clean_url, update_response, MsgTarget};
+
+pub async fn unfilter(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let text = &cmd.text.value;
+    let args = text.split_whitespace().collect::<Vec<_>>();
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+    let feed_url;
+
+    match &*args {
+        [url] => feed_url = url,
+        [channel, url] => {
+            let channel_id = check_channel_permission(&cmd, channel, target).await?;
+            if channel_id.is_none() {
+                return Ok(());
+            }
+            target_id = channel_id.unwrap();
+            feed_url = url;
+        }
+        [..] => {
+            let msg = tr!("unfilter_how_to_use");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+    let feed_url = &clean_url(feed_url);
+    if !check_owner(&cmd, &db, target_id, feed_url, target).await? {
+        return Ok(());
+    }
+    let msg = match db.subscribed_feed(target_id.0, feed_url) {
+        Some(feed) => {
+            db.update_subscription(target_id.0, feed_url, |s| s.filter = ItemFilter::default());
+            tr!(
+                "filter_removed",
+                link = Escape(&feed.link),
+                title = Title(&feed.title)
+            )
+        }
+        None => tr!("feed_not_found").into(),
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+    Ok(())
+}
@@ -1,15 +1,14 @@
 use std::sync::Arc;
 
 use tbot::{contexts::Command, types::parameters};
-use tokio::sync::Mutex;
 
 use crate::data::Database;
-use crate::messages::Escape;
+use crate::messages::{Escape, Title};
 
-use super::{check_channel_permission, update_response, MsgTarget};
+use super::{check_channel_permission, check_owner, clean_url, update_response, MsgTarget};
 
 pub async fn unsub(
-    db: Arc<Mutex<Database>>,
+    db: Arc<Database>,
     cmd: Arc<Command>,
 ) -> Result<(), tbot::errors::MethodCall> {
     let chat_id = cmd.chat.id;
@@ -35,11 +34,15 @@ pub async fn unsub(
             return Ok(());
         }
     };
-    let msg = if let Some(feed) = db.lock().await.unsubscribe(target_id.0, feed_url) {
+    let feed_url = &clean_url(feed_url);
+    if !check_owner(&cmd, &db, target_id, feed_url, target).await? {
+        return Ok(());
+    }
+    let msg = if let Some(feed) = db.unsubscribe(target_id.0, feed_url) {
         tr!(
             "unsubscription_succeeded",
             link = Escape(&feed.link),
-            title = Escape(&feed.title)
+            title = Title(&feed.title)
         )
     } else {
         tr!("unsubscribed_from_rss").into()
@@ -0,0 +1,43 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+
+use crate::data::Database;
+use crate::fetcher::interval_bounds;
+use crate::health::HEARTBEATS;
+use crate::messages::format_duration;
+
+use super::{update_response, MsgTarget};
+
+const FEATURES: &[(&str, bool)] = &[
+    ("rustls", cfg!(feature = "rustls")),
+    ("native-tls", cfg!(feature = "native-tls")),
+    ("hosted-by-iovxw", cfg!(feature = "hosted-by-iovxw")),
+];
+
+pub async fn version(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let target = &mut MsgTarget::new(cmd.chat.id, cmd.message_id);
+    let features: Vec<&str> = FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect();
+    let (min_interval, max_interval) = interval_bounds();
+    let (feeds, chats) = db.counts();
+    let msg = tr!(
+        "version_info",
+        version = env!("CARGO_PKG_VERSION"),
+        commit = env!("RSSBOT_GIT_HASH"),
+        features = features.join(", "),
+        min_interval = format_duration(min_interval as u64),
+        max_interval = format_duration(max_interval as u64),
+        uptime = format_duration(HEARTBEATS.uptime()),
+        feeds = feeds,
+        chats = chats
+    );
+    update_response(&cmd.bot, target, parameters::Text::with_plain(&msg)).await?;
+    Ok(())
+}
@@ -0,0 +1,96 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+
+use crate::client::check_public_url;
+use crate::data::{Database, Webhook};
+use crate::messages::Escape;
+use crate::webhook::{gen_secret, SIGNATURE_HEADER};
+
+use super::{check_channel_permission, is_from_chat_admin, update_response, MsgTarget};
+
+pub async fn webhook(
+    db: Arc<Database>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let text = &cmd.text.value;
+    let args = text.split_whitespace().collect::<Vec<_>>();
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+
+    let (channel, url) = match &*args {
+        [] => (None, None),
+        [url] if *url == "off" || url.contains("://") => (None, Some(*url)),
+        [channel] => (Some(*channel), None),
+        [channel, url] => (Some(*channel), Some(*url)),
+        [..] => {
+            let msg = tr!("webhook_how_to_use");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+    if let Some(channel) = channel {
+        let channel_id = check_channel_permission(&cmd, channel, target).await?;
+        if channel_id.is_none() {
+            return Ok(());
+        }
+        target_id = channel_id.unwrap();
+    } else if !cmd.chat.kind.is_private() && !is_from_chat_admin(&cmd).await {
+        // Group members could read the deliveries of the group anyway,
+        // but only admins decide where they go
+        let msg = tr!("group_admin_only_command");
+        update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+        return Ok(());
+    }
+
+    let msg = match url {
+        None => match db.chat(target_id.0).webhook {
+            Some(webhook) => tr!("webhook_current", url = Escape(&webhook.url)),
+            None => tr!("webhook_not_set").to_string(),
+        },
+        Some("off") => {
+            db.update_chat(target_id.0, |chat| chat.webhook = None);
+            tr!("webhook_removed").to_string()
+        }
+        Some(url) => match check_public_url(url).await {
+            Ok(url) => {
+                let webhook = Webhook {
+                    url: url.to_string(),
+                    secret: gen_secret(),
+                };
+                let msg = tr!(
+                    "webhook_set",
+                    url = Escape(&webhook.url),
+                    header = SIGNATURE_HEADER,
+                    secret = &webhook.secret
+                );
+                if cmd.chat.kind.is_private() {
+                    db.update_chat(target_id.0, |chat| chat.webhook = Some(webhook));
+                    msg
+                } else if send_privately(&cmd, &msg).await {
+                    // Everyone in the group could sign the requests with the secret
+                    db.update_chat(target_id.0, |chat| chat.webhook = Some(webhook));
+                    tr!("webhook_secret_sent_privately").to_string()
+                } else {
+                    tr!("webhook_secret_not_sent").to_string()
+                }
+            }
+            Err(e) => tr!("webhook_rejected", error = Escape(&e.to_string())),
+        },
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+    Ok(())
+}
+
+/// To the user who sent `cmd`, fails if they never started the bot
+async fn send_privately(cmd: &Command, msg: &str) -> bool {
+    use tbot::types::message::From;
+    let user_id = match &cmd.from {
+        Some(From::User(user)) => tbot::types::chat::Id(user.id.0),
+        _ => return false,
+    };
+    let text = parameters::Text::with_html(msg);
+    let result = cmd.bot.send_message(user_id, text).call().await;
+    result.is_ok()
+}
@@ -1,14 +1,25 @@
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::{hash_map::Entry, BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
 use std::hash::{BuildHasherDefault, Hash, Hasher};
-use std::path::PathBuf;
-use std::time::{Duration, SystemTime};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use atomicwrites::{AtomicFile, OverwriteBehavior};
-use serde::{Deserialize, Serialize};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
 use thiserror::Error;
 
 use crate::feed;
+use crate::health::{HEARTBEATS, METRICS};
+use crate::interval;
+use crate::messages::{format_duration, strip_tracking_params};
+
+mod sqlite;
 
 #[derive(Error, Debug)]
 pub enum DataError {
@@ -16,343 +27,4804 @@ pub enum DataError {
     Io(#[from] std::io::Error),
     #[error("json error")]
     Json(#[from] serde_json::Error),
+    #[error("sqlite error")]
+    Sqlite(#[from] rusqlite::Error),
+    /// Two bots on one database would deliver everything twice, and overwrite each other
+    #[error("the database is already used by {0}, only one bot can run on it")]
+    Locked(String),
+    /// Opened without migrating, see `Database::open_exclusive`
+    #[error("the database needs migrating ({0}), it's not migrated with --no-migrate")]
+    MigrationsPending(String),
+    /// See `Database::import_json`
+    #[error("the database already has feeds or chats, only an empty one can be imported into")]
+    NotEmpty,
+    /// Stored by a newer version of the bot, none of the items would be known
+    #[error("the item hashes were made by a newer version of the bot ({0})")]
+    HashVersion(u32),
 }
 
+/// How the stored item hashes are made, bumped when `gen_hash` changes.
+/// 1: SipHash-1-3 with zero keys, what `DefaultHasher` used before it was fixed
+pub const HASH_VERSION: u32 = 1;
+
+/// The hashes are stored and exported, unlike `DefaultHasher` this one never changes
 fn gen_hash<T: Hash>(t: &T) -> u64 {
-    let mut hasher = std::collections::hash_map::DefaultHasher::default();
+    let mut hasher = siphasher::sip::SipHasher13::new();
     t.hash(&mut hasher);
     hasher.finish()
 }
 
+/// Feeds are found by their normalized link,
+/// `HTTPS://Example.com/feed.xml ` is the same feed as `https://example.com/feed.xml`
+fn feed_key(rss_link: &str) -> FeedId {
+    gen_hash(&normalize_feed_link(rss_link))
+}
+
+/// Links that only differ in `http` or `https` and a `www.` may be the same feed
+fn variant_key(link: &str) -> Option<String> {
+    let link = normalize_feed_link(link);
+    let rest = link
+        .strip_prefix("https://")
+        .or_else(|| link.strip_prefix("http://"))?;
+    Some(rest.strip_prefix("www.").unwrap_or(rest).to_string())
+}
+
+/// Trim it and lowercase the scheme and host of http(s) links,
+/// the rest of the URL may be case sensitive
+pub fn normalize_feed_link(link: &str) -> Cow<'_, str> {
+    let link = link.trim();
+    let scheme_end = match link.find("://") {
+        Some(i) if ["http", "https"].iter().any(|s| link[..i].eq_ignore_ascii_case(s)) => i + 3,
+        _ => return Cow::Borrowed(link),
+    };
+    let host_end = link[scheme_end..]
+        .find(['/', '?', '#'])
+        .map_or(link.len(), |i| scheme_end + i);
+    let (origin, rest) = link.split_at(host_end);
+    if origin.bytes().any(|b| b.is_ascii_uppercase()) {
+        Cow::Owned(origin.to_ascii_lowercase() + rest)
+    } else {
+        Cow::Borrowed(link)
+    }
+}
+
 type FeedId = u64;
 type SubscriberId = i64;
 
+const HASH_LIST_SHARDS: usize = 16;
+const ITEM_DATES_HISTORY: usize = 20;
+/// Days the counts of new items are kept for, enough for the weekly report
+const DAILY_ITEMS_HISTORY: i64 = 7;
+const DAY: i64 = 24 * 60 * 60;
+const RECENT_ITEMS_PER_FEED: usize = 20;
+/// Delivered links remembered per chat for `cross_dedup`
+const CROSS_DEDUP_LINKS: usize = 500;
+/// Hashes kept per item of the last response, unless `--max-hashes-per-feed` is set
+const HASH_SAFETY_FACTOR: usize = 2;
+/// Items delivered of a feed that was reset, unless `Settings::reset` says otherwise
+const DEFAULT_RESET_LATEST: usize = 3;
+/// Items a feed needs to have had for a response without any of them to be a reset
+const DEFAULT_RESET_HISTORY: usize = 5;
+/// Some sites A/B test their titles, tell the subscribers about each title once in this long
+const TITLE_NOTICE_DAMPING: i64 = 30 * 24 * 60 * 60;
+/// Feeds failing for longer than this get a notice when they work again
+const RECOVERY_NOTICE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+/// Names of chats and users kept at most, the least recently seen are forgotten first
+const MAX_CHAT_NAMES: usize = 10_000;
+/// Names not seen for this long may have changed, the ID is shown instead
+const CHAT_NAME_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Items held back per feed until their link appears, the others are delivered as they are
+const MAX_PENDING_ITEMS: usize = 50;
+/// Items dated this far ahead are only a clock off, they are delivered right away
+const FUTURE_DATE_SKEW: i64 = 5 * 60;
+/// Items dated further ahead are delivered right away, some feeds date them year 3000
+const MAX_EMBARGO: i64 = 7 * 24 * 60 * 60;
+/// Items kept per subscription for its digest, the oldest are dropped first
+const MAX_DIGEST_ITEMS: usize = 100;
+
+// Titles and usernames of the chats and users, to show them to the admins.
+// Only kept in memory, they are seen again soon enough
+static CHAT_NAMES: RwLock<BTreeMap<SubscriberId, ChatName>> = RwLock::new(BTreeMap::new());
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Feed {
     pub link: String,
     pub title: String,
+    /// The website of the feed, from its `<link>`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub home_page: String,
     pub down_time: Option<SystemTime>,
     pub subscribers: HashSet<SubscriberId, Size64>,
     pub ttl: Option<u32>,
-    hash_list: Vec<u64>,
+    /// Only subscriptions with non-default settings are stored
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        deserialize_with = "deserialize_id_map"
+    )]
+    pub subscriptions: HashMap<SubscriberId, Subscription, Size64>,
+    /// Missing for the subscriptions from before it was recorded
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        deserialize_with = "deserialize_id_map"
+    )]
+    pub activity: HashMap<SubscriberId, Activity, Size64>,
+    /// Publish dates of the recent items in unix seconds, newest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    item_dates: Vec<i64>,
+    /// New items found per day, days since the epoch and the count, newest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    daily_items: Vec<(i64, u32)>,
+    /// When new items were last found, unix seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_new_items: Option<i64>,
+    /// Consecutive fetch failures
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub failures: u32,
+    /// Set while backing off from failures
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_fetch: Option<SystemTime>,
+    /// The first of the consecutive failures, unlike `down_time`
+    /// it's not reset by the notice about them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    failing_since: Option<SystemTime>,
+    /// The last failure was an anti-bot challenge page
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub blocked: bool,
+    /// Items in the last accepted response
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub item_count: usize,
+    /// The last response looked truncated and was skipped
+    #[serde(skip)]
+    suspect: bool,
+    /// Normalized titles the subscribers were told about recently, with unix seconds
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    title_notices: Vec<(String, i64)>,
+    /// The sites of the home pages they were told about recently, like `title_notices`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    home_page_notices: Vec<(String, i64)>,
+    /// Sent as the `Cookie` header, only kept while the feed has a single subscriber
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cookie: Option<String>,
+    /// Overrides `--max-feed-size`, set by /maxsize
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+    /// Hashes of the new items without a real link yet, with the fetches they were held for
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pending: Vec<(u64, u32)>,
+    /// Hashes of the links with the titles of the recent items, newest first,
+    /// to notice the items republished with a new GUID
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    recent_keys: Vec<u64>,
+    /// The last error notices of the subscribers, cleared when a fetch works
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    error_notices: Vec<ErrorNotice>,
+    /// How the server has to be fetched, learned by the fetcher
+    #[serde(default, skip_serializing_if = "Quirks::is_empty")]
+    pub quirks: Quirks,
+    #[serde(default, skip_serializing_if = "Validators::is_empty")]
+    pub validators: Validators,
+    /// From the last accepted response, see `feed::FeedMeta`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generator: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hubs: Vec<String>,
+    #[serde(default, skip_serializing_if = "feed::SkipHints::is_empty")]
+    pub skip: feed::SkipHints,
+    /// Items waiting for the digest of the subscribers, newest first
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        deserialize_with = "deserialize_id_map"
+    )]
+    digest_items: HashMap<SubscriberId, Vec<DigestItem>, Size64>,
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
-pub struct Hub {
-    pub callback: String,
-    pub secret: String,
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ErrorNotice {
+    subscriber: SubscriberId,
+    /// `FeedError::class`
+    class: String,
+    /// Unix seconds
+    sent_at: i64,
+    /// Notices not sent since then
+    suppressed: u32,
+    /// Sent since the feed failed, each one waits twice as long as the one before
+    #[serde(default)]
+    sent: u32,
 }
 
-#[derive(Debug)]
-pub struct Database {
-    path: PathBuf,
-    feeds: HashMap<FeedId, Feed, Size64>,
-    subscribers: HashMap<SubscriberId, HashSet<FeedId, Size64>, Size64>,
+impl ErrorNotice {
+    /// Seconds until the next notice, `window` at first
+    fn wait(&self, window: u64) -> i64 {
+        // Some 180 years with the default window, it doesn't go further
+        const MAX_DOUBLINGS: u32 = 16;
+        let doublings = self.sent.saturating_sub(1).min(MAX_DOUBLINGS);
+        i64::try_from(window.saturating_mul(1 << doublings)).unwrap_or(i64::MAX)
+    }
 }
 
-impl Database {
-    pub fn create(path: PathBuf) -> Result<Database, DataError> {
-        let result = Database {
-            path,
-            feeds: HashMap::with_hasher(Size64::default()),
-            subscribers: HashMap::with_hasher(Size64::default()),
+fn normalize_title(title: &str) -> String {
+    title
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// The host of `link` without `www.`, the parts of the home page that identify the site
+fn home_page_site(link: &str) -> String {
+    match variant_key(link) {
+        Some(rest) => rest.split(['/', '?', '#']).next().unwrap_or_default().to_string(),
+        None => link.trim().to_string(),
+    }
+}
+
+/// Record the change from `old` to `new` in `notices`, return `true` if it's worth telling
+/// the subscribers. Each value is told at most once in `TITLE_NOTICE_DAMPING`, and an empty
+/// one never.
+fn note_change(notices: &mut Vec<(String, i64)>, old: String, new: String, now: i64) -> bool {
+    if old == new {
+        return false;
+    }
+    notices.retain(|(_, time)| now - time < TITLE_NOTICE_DAMPING);
+    // Flapping back to the old value isn't worth another notice either
+    if !old.is_empty() && !notices.iter().any(|(value, _)| *value == old) {
+        notices.push((old, now));
+    }
+    if new.is_empty() || notices.iter().any(|(value, _)| *value == new) {
+        return false;
+    }
+    notices.push((new, now));
+    true
+}
+
+fn is_zero<T: Default + PartialEq>(n: &T) -> bool {
+    *n == T::default()
+}
+
+impl Feed {
+    /// The requests for the feed changed, what was learned from the responses to the old
+    /// ones may not apply anymore. `insecure` is set by the bot admins, it's kept
+    fn forget_responses(&mut self) {
+        self.quirks = Quirks {
+            insecure: self.quirks.insecure,
+            ..Quirks::default()
         };
+        self.validators = Validators::default();
+        self.suspect = false;
+    }
 
-        result.save()?;
+    /// Median gap between the recent items, `None` if there are fewer than 3 dated items
+    pub fn median_gap(&self) -> Option<u32> {
+        interval::median_gap(&self.item_dates)
+    }
 
-        Ok(result)
+    /// Publication times of the recent items, Unix timestamps from the newest
+    pub fn item_dates(&self) -> &[i64] {
+        &self.item_dates
     }
 
-    pub fn open(path: PathBuf) -> Result<Database, DataError> {
-        if path.exists() {
-            let f = File::open(&path)?;
-            let feeds_list: Vec<Feed> = serde_json::from_reader(&f)?;
+    /// New items found in the last `DAILY_ITEMS_HISTORY` days, dated or not
+    pub fn items_this_week(&self, now: i64) -> u32 {
+        let today = now.div_euclid(DAY);
+        self.daily_items
+            .iter()
+            .filter(|(day, _)| today - day < DAILY_ITEMS_HISTORY)
+            .map(|(_, count)| count)
+            .sum()
+    }
 
-            let mut feeds = HashMap::with_capacity_and_hasher(feeds_list.len(), Size64::default());
-            let mut subscribers = HashMap::with_hasher(Size64::default());
+    /// When the feed last had new items, falls back to the newest publish date
+    /// for the feeds that had none since this was recorded
+    pub fn last_new_items(&self) -> Option<i64> {
+        self.last_new_items.or_else(|| self.item_dates.first().copied())
+    }
 
-            for feed in feeds_list {
-                let feed_id = gen_hash(&feed.link);
-                for subscriber in &feed.subscribers {
-                    let subscribed_feeds = subscribers
-                        .entry(subscriber.to_owned())
-                        .or_insert_with(HashSet::default);
-                    subscribed_feeds.insert(feed_id);
+    fn record_new_items(&mut self, count: usize, now: i64) {
+        let today = now.div_euclid(DAY);
+        let count = count as u32;
+        match self.daily_items.first_mut() {
+            Some((day, n)) if *day == today => *n += count,
+            _ => self.daily_items.insert(0, (today, count)),
+        }
+        self.daily_items
+            .retain(|(day, _)| today - day < DAILY_ITEMS_HISTORY);
+        self.last_new_items = Some(now);
+    }
+
+    /// Take the subscribers of the same feed stored under another link
+    /// Return how many subscriptions were dropped, the chats had their own in both
+    fn merge(&mut self, other: Feed) -> usize {
+        let mut dropped = 0;
+        let gained = other.subscribers.difference(&self.subscribers).next().is_some();
+        if gained {
+            // Whoever set the cookie didn't agree to share their access,
+            // and /insecure was decided for the chats subscribed then
+            self.quirks.insecure = false;
+            if self.cookie.take().is_some() {
+                self.forget_responses();
+            }
+        }
+        self.subscribers.extend(other.subscribers);
+        for (subscriber, subscription) in other.subscriptions {
+            match self.subscriptions.entry(subscriber) {
+                Entry::Occupied(_) => dropped += 1,
+                Entry::Vacant(entry) => {
+                    entry.insert(subscription);
                 }
-                feeds.insert(feed_id, feed);
             }
-
-            Ok(Database {
-                path,
-                feeds,
-                subscribers,
-            })
-        } else {
-            Database::create(path)
         }
+        for (subscriber, activity) in other.activity {
+            self.activity.entry(subscriber).or_insert(activity);
+        }
+        for (subscriber, items) in other.digest_items {
+            let queued = self.digest_items.entry(subscriber).or_default();
+            // Newest first like `queue_digest_items`, so the oldest are the ones dropped.
+            // Without all the dates, the other ones are taken as the newer
+            queued.splice(0..0, items);
+            if queued.iter().all(|item| item.published.is_some()) {
+                queued.sort_by_key(|item| cmp::Reverse(item.published_at()));
+            }
+            queued.truncate(MAX_DIGEST_ITEMS);
+        }
+        dropped
     }
 
-    pub fn all_feeds(&self) -> Vec<Feed> {
-        self.feeds.values().cloned().collect()
+    /// Merge the dates of `items` into the history, return `true` if changed
+    fn record_item_dates(&mut self, items: &[feed::Item]) -> bool {
+        let old_len = self.item_dates.len();
+        let newest = self.item_dates.first().copied();
+        self.item_dates
+            .extend(items.iter().filter_map(|item| item.published).map(|t| t.timestamp()));
+        self.item_dates.sort_unstable_by(|a, b| b.cmp(a));
+        self.item_dates.dedup();
+        self.item_dates.truncate(ITEM_DATES_HISTORY);
+        self.item_dates.len() != old_len || self.item_dates.first().copied() != newest
     }
 
-    pub fn all_subscribers(&self) -> Vec<SubscriberId> {
-        self.subscribers.keys().copied().collect()
+    /// The metadata kept apart from the title, the home page and the TTL,
+    /// return `true` if any of it changed
+    fn record_meta(&mut self, meta: &feed::FeedMeta) -> bool {
+        let changed = self.language != meta.language
+            || self.generator != meta.generator
+            || self.hubs != meta.hubs
+            || self.skip != meta.skip;
+        if changed {
+            self.language = meta.language.clone();
+            self.generator = meta.generator.clone();
+            self.hubs = meta.hubs.clone();
+            self.skip = meta.skip.clone();
+        }
+        changed
     }
 
-    pub fn subscribed_feeds(&self, subscriber: SubscriberId) -> Option<Vec<Feed>> {
-        self.subscribers.get(&subscriber).map(|feeds| {
-            feeds
-                .iter()
-                .map(|feed_id| &self.feeds[feed_id])
-                .cloned()
-                .collect()
-        })
+    /// Return `true` if the subscribers should be told about the new title.
+    /// Changes in case, whitespace or punctuation don't count.
+    fn note_title_change(&mut self, new_title: &str, now: i64) -> bool {
+        let old = normalize_title(&self.title);
+        let new = normalize_title(new_title);
+        // An empty title is more likely a broken response than a new identity
+        note_change(&mut self.title_notices, old, new, now)
     }
 
-    /// Return `None` if feed not found
-    pub fn get_or_update_down_time(&mut self, rss_link: &str) -> Option<Duration> {
-        let feed_id = gen_hash(&rss_link);
-        let feed = self.feeds.get_mut(&feed_id)?;
-        let now = SystemTime::now();
-        if let Some(t) = feed.down_time {
-            Some(now.duration_since(t).unwrap_or_default())
-        } else {
-            feed.down_time = Some(now);
-            Some(Duration::default())
+    /// Return `true` if the subscribers should be told about the new home page.
+    /// Only moving to another site counts, the first one learned doesn't.
+    fn note_home_page_change(&mut self, new_home_page: &str, now: i64) -> bool {
+        let old = home_page_site(&self.home_page);
+        if old.is_empty() && self.home_page_notices.is_empty() {
+            return false;
         }
+        let new = home_page_site(new_home_page);
+        note_change(&mut self.home_page_notices, old, new, now)
     }
 
-    pub fn reset_down_time(&mut self, rss_link: &str) -> bool {
-        let feed_id = gen_hash(&rss_link);
-        self.feeds
-            .get_mut(&feed_id)
-            .map(|feed| {
-                feed.down_time = None;
-            })
-            .is_some()
+    /// Never show this to users, it grants access to whatever the cookie's owner can read
+    pub fn cookie(&self) -> Option<&str> {
+        self.cookie.as_deref()
     }
 
-    pub fn is_subscribed(&self, subscriber: SubscriberId, rss_link: &str) -> bool {
-        self.subscribers
+    /// `None` if the subscription is from before the activity was recorded
+    pub fn activity(&self, subscriber: SubscriberId) -> Option<Activity> {
+        self.activity.get(&subscriber).copied()
+    }
+
+    pub fn subscription(&self, subscriber: SubscriberId) -> Subscription {
+        self.subscriptions
             .get(&subscriber)
-            .map(|feeds| feeds.contains(&gen_hash(&rss_link)))
-            .unwrap_or(false)
+            .cloned()
+            .unwrap_or_default()
     }
 
-    pub fn subscribe(&mut self, subscriber: SubscriberId, rss_link: &str, rss: &feed::Rss) -> bool {
-        let feed_id = gen_hash(&rss_link);
-        {
-            let subscribed_feeds = self.subscribers.entry(subscriber).or_default();
-            if !subscribed_feeds.insert(feed_id) {
-                return false;
+    /// Items waiting for the digest of the subscriber
+    pub fn digest_items(&self, subscriber: SubscriberId) -> usize {
+        self.digest_items.get(&subscriber).map_or(0, Vec::len)
+    }
+
+    /// Keep the items for the digest of the subscribers, newest first like the feeds
+    fn queue_digest_items(&mut self, subscribers: &[SubscriberId], items: &[feed::Item]) {
+        for subscriber in subscribers {
+            if !self.subscribers.contains(subscriber) {
+                continue;
             }
+            let queued = self.digest_items.entry(*subscriber).or_default();
+            queued.splice(0..0, items.iter().map(DigestItem::from));
+            queued.truncate(MAX_DIGEST_ITEMS);
         }
-        {
-            let feed = self.feeds.entry(feed_id).or_insert_with(|| Feed {
-                link: rss_link.to_owned(),
-                title: rss.title.to_owned(),
-                down_time: None,
-                ttl: rss.ttl,
-                hash_list: rss.items.iter().map(gen_item_hash).collect(),
-                subscribers: HashSet::default(),
-            });
-            feed.subscribers.insert(subscriber);
-        }
-        self.save().unwrap_or_default();
-        true
     }
+}
 
-    pub fn unsubscribe(&mut self, subscriber: SubscriberId, rss_link: &str) -> Option<Feed> {
-        let feed_id = gen_hash(&rss_link);
+/// What a digest needs of an item, the filters were applied when it was kept.
+/// A digest only lists the titles and links, like the ones of `digest_above`,
+/// so the settings of the content don't apply to it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct DigestItem {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    link: Option<String>,
+    /// RFC 3339, with the offset of the feed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    published: Option<String>,
+}
 
-        let clear_subscriber;
-        if let Some(subscribed_feeds) = self.subscribers.get_mut(&subscriber) {
-            if subscribed_feeds.remove(&feed_id) {
-                clear_subscriber = subscribed_feeds.is_empty();
-            } else {
-                return None;
-            }
-        } else {
-            return None;
+impl From<&feed::Item> for DigestItem {
+    fn from(item: &feed::Item) -> Self {
+        DigestItem {
+            title: item.title.clone(),
+            link: item.link.clone(),
+            published: item.published.map(|published| published.to_rfc3339()),
         }
-        if clear_subscriber {
-            self.subscribers.remove(&subscriber);
+    }
+}
+
+impl DigestItem {
+    fn published_at(&self) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+        let published = self.published.as_deref()?;
+        chrono::DateTime::parse_from_rfc3339(published).ok()
+    }
+
+    fn into_item(self) -> feed::Item {
+        feed::Item {
+            title: self.title,
+            link: self.link,
+            published: self
+                .published
+                .and_then(|published| chrono::DateTime::parse_from_rfc3339(&published).ok()),
+            ..Default::default()
         }
+    }
+}
 
-        let result;
-        let clear_feed;
-        if let Some(feed) = self.feeds.get_mut(&feed_id) {
-            if feed.subscribers.remove(&subscriber) {
-                clear_feed = feed.subscribers.is_empty();
-                result = feed.clone();
-            } else {
-                return None;
-            }
-        } else {
-            return None;
-        };
-        if clear_feed {
-            self.feeds.remove(&feed_id);
+/// Per-subscription settings
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Subscription {
+    /// Overrides the defaults of the chat
+    #[serde(flatten)]
+    pub settings: Settings,
+    /// Also deliver to the linked discussion group of the channel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discussion: Option<Discussion>,
+    /// Why the chat subscribed, plain text set with /note
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    #[serde(skip_serializing_if = "ItemFilter::is_empty")]
+    pub filter: ItemFilter,
+    /// Send the items together on a schedule, set with /digest
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<Digest>,
+}
+
+/// How the items are delivered, the defaults of a chat in `Chat`,
+/// and the overrides of a subscription in `Subscription`.
+///
+/// `None` is inherited, and after `resolve` it's off.
+/// `0` and `LinkCleaning::Off` turn a setting off when the chat default is on.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Maximum items delivered per fetch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_items: Option<usize>,
+    /// Clean the links of the items before sending them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clean_links: Option<LinkCleaning>,
+    /// Send a single digest instead when a fetch has more new items than this
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest_above: Option<usize>,
+    /// Append the translation to this language to the item titles, `off` overrides
+    /// a chat default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub translate: Option<String>,
+    /// Skip the items with a shorter description
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_words: Option<MinWords>,
+    /// Only deliver the items in these languages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lang: Option<LanguageFilter>,
+    /// Show the publish dates in this format, see `messages::date`. `off` overrides
+    /// a chat default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    /// Seconds since publication, or since they were found for undated items, after which
+    /// the items waiting for delivery are dropped. 0 overrides a chat default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_queue_age: Option<u64>,
+    /// What is delivered of a feed that was reset, see `reset_policy`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reset: Option<FeedReset>,
+    /// Items the feed needs to have had to be considered reset, see `reset_threshold`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reset_history: Option<usize>,
+}
+
+impl Settings {
+    /// The settings in effect: `self`, then the chat `defaults`, then the built-in defaults
+    pub fn resolve(&self, defaults: &Settings) -> Settings {
+        let count = |own: Option<usize>, default| own.or(default).filter(|&n| n > 0);
+        Settings {
+            max_items: count(self.max_items, defaults.max_items),
+            clean_links: self
+                .clean_links
+                .or(defaults.clean_links)
+                .filter(|&cleaning| cleaning != LinkCleaning::Off),
+            digest_above: count(self.digest_above, defaults.digest_above),
+            translate: self
+                .translate
+                .as_ref()
+                .or(defaults.translate.as_ref())
+                .filter(|&lang| lang != "off")
+                .cloned(),
+            min_words: self
+                .min_words
+                .or(defaults.min_words)
+                .filter(|&min_words| min_words != MinWords::Off),
+            lang: self
+                .lang
+                .as_ref()
+                .or(defaults.lang.as_ref())
+                .filter(|filter| !filter.languages.is_empty())
+                .cloned(),
+            date: self
+                .date
+                .as_ref()
+                .or(defaults.date.as_ref())
+                .filter(|&format| format != "off")
+                .cloned(),
+            max_queue_age: self
+                .max_queue_age
+                .or(defaults.max_queue_age)
+                .filter(|&secs| secs > 0),
+            reset: self.reset.or(defaults.reset),
+            reset_history: self.reset_history.or(defaults.reset_history),
         }
-        self.save().unwrap_or_default();
-        Some(result)
     }
 
-    pub fn delete_subscriber(&mut self, subscriber: SubscriberId) -> bool {
-        self.subscribed_feeds(subscriber)
-            .map(|feeds| {
-                for feed in feeds {
-                    let _ = self.unsubscribe(subscriber, &feed.link);
-                }
-            })
-            .is_some()
+    /// `reset` of the resolved settings, which is never off
+    pub fn reset_policy(&self) -> FeedReset {
+        self.reset.unwrap_or(FeedReset::Latest(DEFAULT_RESET_LATEST))
     }
 
-    pub fn update_subscriber(&mut self, from: SubscriberId, to: SubscriberId) -> bool {
-        self.subscribers
-            .remove(&from)
-            .map(|feeds| {
-                for feed_id in &feeds {
-                    let feed = self.feeds.get_mut(feed_id).unwrap();
-                    feed.subscribers.remove(&from);
-                    feed.subscribers.insert(to);
-                }
-                self.subscribers.insert(to, feeds);
-            })
-            .is_some()
+    /// `reset_history` of the resolved settings
+    pub fn reset_threshold(&self) -> usize {
+        self.reset_history.unwrap_or(DEFAULT_RESET_HISTORY)
     }
 
-    /// Update the feed in database, return updates
-    pub fn update(&mut self, rss_link: &str, new_feed: feed::Rss) -> Vec<FeedUpdate> {
-        let feed_id = gen_hash(&rss_link);
-        if !self.feeds.contains_key(&feed_id) {
-            return Vec::new();
-        }
+    pub fn is_empty(&self) -> bool {
+        *self == Settings::default()
+    }
+}
 
-        self.reset_down_time(rss_link);
-        let feed = self.feeds.get_mut(&feed_id).unwrap();
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkCleaning {
+    /// Keep the links as they are, overrides a chat default
+    Off,
+    /// Drop the tracking parameters
+    Strip,
+    /// Also follow a known redirector once, see `client::resolve_redirect`
+    Resolve,
+}
 
-        let mut updates = Vec::new();
-        let mut new_items = Vec::new();
-        let mut new_hash_list = Vec::new();
-        let items_len = new_feed.items.len();
-        for item in new_feed.items {
-            let hash = gen_item_hash(&item);
-            if !feed.hash_list.contains(&hash) {
-                new_hash_list.push(hash);
-                new_items.push(item);
+/// When none of the items of a feed were seen before, like when the site moved to another
+/// system and the feed started over, there's no telling which of them are really new
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedReset {
+    /// Deliver them like any other new items
+    All,
+    /// Only mark them as seen
+    Resync,
+    /// Only deliver this many of them, saying why
+    Latest(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MinWords {
+    /// Overrides a chat default
+    Off,
+    /// Only skip the items that are nothing but a link
+    Links,
+    Count(usize),
+}
+
+/// Workarounds for servers that don't handle the usual requests
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Quirks {
+    /// Send `Accept: */*`, the server answers the feed types with 406
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub accept_any: bool,
+    /// Never use HTTP/2, the server or a proxy in front of it mishandles it
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub http1_only: bool,
+    /// Accept invalid TLS certificates, only set by /insecure.
+    /// Onion services often have self-signed ones
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub insecure: bool,
+}
+
+impl Quirks {
+    pub fn is_empty(&self) -> bool {
+        *self == Quirks::default()
+    }
+}
+
+/// `ETag` and `Last-Modified` of the last accepted response, sent back as
+/// `If-None-Match` and `If-Modified-Since`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Validators {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/// Everything stored about a feed, dumped by /inspect
+#[derive(Debug, Clone)]
+pub struct FeedDebugInfo {
+    pub feed: Feed,
+    /// Hashes of the items already seen
+    pub seen_hashes: usize,
+}
+
+impl fmt::Display for FeedDebugInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let feed = &self.feed;
+        let time = |time: Option<SystemTime>| match time {
+            Some(time) => chrono::DateTime::<chrono::Local>::from(time)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+            None => "-".to_string(),
+        };
+        let or_none = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+        writeln!(f, "link: {}", feed.link)?;
+        writeln!(f, "title: {}", feed.title)?;
+        if !feed.home_page.is_empty() {
+            writeln!(f, "home_page: {}", feed.home_page)?;
+        }
+        let mut subscribers: Vec<_> = feed.subscribers.iter().copied().collect();
+        subscribers.sort_unstable();
+        writeln!(f, "subscribers: {}", subscribers.len())?;
+        for subscriber in subscribers {
+            writeln!(f, "  {}", chat_display(subscriber))?;
+            let subscription = feed.subscription(subscriber);
+            if let Some(note) = &subscription.note {
+                writeln!(f, "    note: {}", note)?;
+            }
+            if let Some(include) = &subscription.filter.include {
+                writeln!(f, "    filter include: {}", include)?;
+            }
+            if let Some(exclude) = &subscription.filter.exclude {
+                writeln!(f, "    filter exclude: {}", exclude)?;
+            }
+            if let Some(digest) = &subscription.digest {
+                writeln!(f, "    digest: {}", digest.schedule)?;
+            }
+            if feed.digest_items(subscriber) > 0 {
+                writeln!(f, "    digest items: {}", feed.digest_items(subscriber))?;
             }
         }
-        if !new_items.is_empty() {
-            updates.push(FeedUpdate::Items(new_items));
+        writeln!(f, "own_settings: {}", feed.subscriptions.len())?;
 
-            let max_size = items_len * 2;
-            let mut append: Vec<u64> = feed
-                .hash_list
-                .iter()
-                .take(max_size - new_hash_list.len())
-                .cloned()
-                .collect();
-            new_hash_list.append(&mut append);
-            feed.hash_list = new_hash_list;
+        writeln!(f, "ttl: {}", or_none(feed.ttl.map(|ttl| ttl.to_string())))?;
+        writeln!(f, "language: {}", or_none(feed.language.clone()))?;
+        writeln!(f, "generator: {}", or_none(feed.generator.clone()))?;
+        for hub in &feed.hubs {
+            writeln!(f, "hub: {}", hub)?;
         }
-        if new_feed.title != feed.title {
-            updates.push(FeedUpdate::Title(new_feed.title.clone()));
-            feed.title = new_feed.title;
+        if !feed.skip.is_empty() {
+            writeln!(f, "skip_hours: {:?}", feed.skip.hours)?;
+            writeln!(f, "skip_days: {:?}", feed.skip.days)?;
         }
-        feed.ttl = new_feed.ttl;
-        if !updates.is_empty() {
-            self.save().unwrap_or_default();
+        let median_gap = feed.median_gap().map(|gap| format_duration(gap as u64));
+        writeln!(f, "median_gap: {}", or_none(median_gap))?;
+        writeln!(f, "next_fetch: {}", time(feed.next_fetch))?;
+        writeln!(f, "failures: {}", feed.failures)?;
+        writeln!(f, "failing_since: {}", time(feed.failing_since))?;
+        writeln!(f, "down_time: {}", time(feed.down_time))?;
+        writeln!(f, "blocked: {}", feed.blocked)?;
+        writeln!(f, "suspect: {}", feed.suspect)?;
+        writeln!(f, "error_notices: {}", feed.error_notices.len())?;
+
+        let max_size = feed.max_size.map(|size| size.to_string());
+        writeln!(f, "max_size: {}", or_none(max_size))?;
+        // Never shown, the chat history may be shared later
+        let cookie = feed.cookie.as_ref().map(|_| "<redacted>".to_string());
+        writeln!(f, "cookie: {}", or_none(cookie))?;
+        let mut quirks = Vec::new();
+        if feed.quirks.accept_any {
+            quirks.push("accept_any");
         }
-        updates
-    }
+        if feed.quirks.http1_only {
+            quirks.push("http1_only");
+        }
+        if feed.quirks.insecure {
+            quirks.push("insecure");
+        }
+        let quirks = Some(quirks.join(", ")).filter(|quirks| !quirks.is_empty());
+        writeln!(f, "quirks: {}", or_none(quirks))?;
+        writeln!(f, "etag: {}", or_none(feed.validators.etag.clone()))?;
+        writeln!(f, "last_modified: {}", or_none(feed.validators.last_modified.clone()))?;
 
-    pub fn save(&self) -> Result<(), DataError> {
-        let feeds_list: Vec<&Feed> = self.feeds.values().collect();
-        let file = AtomicFile::new(&self.path, OverwriteBehavior::AllowOverwrite);
-        file.write(|file| serde_json::to_writer(file, &feeds_list))
-            .map_err(|e| match e {
-                atomicwrites::Error::Internal(e) => DataError::Io(e),
-                atomicwrites::Error::User(e) => {
-                    assert!(!e.is_io(), "unreachable code");
-                    DataError::Io(e.into())
-                }
-            })?;
-        Ok(())
+        writeln!(f, "item_count: {}", feed.item_count)?;
+        writeln!(f, "pending: {}", feed.pending.len())?;
+        writeln!(f, "recent_keys: {}", feed.recent_keys.len())?;
+        write!(f, "seen_hashes: {}", self.seen_hashes)
     }
 }
 
-pub enum FeedUpdate {
-    Items(Vec<feed::Item>),
-    Title(String),
+/// Matched by the primary subtag, `en` also matches `en-US`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LanguageFilter {
+    /// Lowercase, empty overrides a chat default
+    pub languages: Vec<String>,
+    /// Also deliver the items whose feed doesn't tell their language
+    pub untagged: bool,
 }
 
-fn gen_item_hash(item: &feed::Item) -> u64 {
-    item.id.as_ref().map(|id| gen_hash(&id)).unwrap_or_else(|| {
-        let title = item.title.as_deref().unwrap_or_default();
-        let link = item.link.as_deref().unwrap_or_default();
-        gen_hash(&format!("{}{}", title, link))
-    })
+/// Set by /filter, regexes matched against the title and the text of the content,
+/// ignoring case. Checked when they are set
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ItemFilter {
+    /// Only deliver the items that match
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include: Option<String>,
+    /// Never deliver the items that match, even if they match `include`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exclude: Option<String>,
 }
 
-pub type Size64 = BuildHasherDefault<Size64Hasher>;
+impl ItemFilter {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude.is_none()
+    }
+}
 
-/// A specialized hasher for u64 and i64
-///
-/// WARNING: Do not use it for user-controlled input
-#[derive(Default)]
-pub struct Size64Hasher {
-    finished: bool,
-    value: u64,
+/// When the digest of a subscription is sent, in the local time of the bot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Digest {
+    pub schedule: DigestSchedule,
+    /// Unix seconds, set when it's enabled so a past time isn't sent right away
+    pub last_sent: i64,
 }
 
-impl Hasher for Size64Hasher {
-    fn finish(&self) -> u64 {
-        self.value
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestSchedule {
+    /// On the hour
+    Hourly,
+    /// Minutes from midnight
+    Daily(u32),
+}
 
-    fn write(&mut self, _bytes: &[u8]) {
-        panic!("only support u64 and i64");
+impl Digest {
+    /// Whether the time of the digest came since the last one was sent
+    pub fn is_due<Tz: chrono::TimeZone>(&self, now: &chrono::DateTime<Tz>) -> bool {
+        use chrono::{Days, NaiveTime, Timelike};
+        let local = now.naive_local();
+        let scheduled = match self.schedule {
+            DigestSchedule::Hourly => local.date().and_hms_opt(local.hour(), 0, 0),
+            DigestSchedule::Daily(minute) => {
+                NaiveTime::from_num_seconds_from_midnight_opt(minute * 60, 0).map(|time| {
+                    let scheduled = local.date().and_time(time);
+                    if scheduled > local {
+                        scheduled - Days::new(1)
+                    } else {
+                        scheduled
+                    }
+                })
+            }
+        };
+        let scheduled = match scheduled {
+            Some(scheduled) => scheduled,
+            None => return false,
+        };
+        match now.timezone().timestamp_opt(self.last_sent, 0).single() {
+            Some(last_sent) => last_sent.naive_local() < scheduled,
+            None => false,
+        }
     }
+}
 
-    fn write_u64(&mut self, i: u64) {
-        assert!(
-            !self.finished,
-            "this is a special hasher, do not write twice"
-        );
-        self.value = i;
-        self.finished = true;
+impl fmt::Display for DigestSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DigestSchedule::Hourly => write!(f, "hourly"),
+            DigestSchedule::Daily(minute) => write!(f, "{:02}:{:02}", minute / 60, minute % 60),
+        }
     }
+}
 
-    fn write_i64(&mut self, i: i64) {
-        self.write_u64(i as u64);
-    }
+/// Per-subscription history, unlike `Subscription` it's never set by users
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Activity {
+    pub subscribed_at: Option<SystemTime>,
+    /// When an item was last sent to the chat successfully
+    pub last_delivered: Option<SystemTime>,
+    /// The user who subscribed the group or channel, told when the bot can't post there
+    pub subscribed_by: Option<SubscriberId>,
+    /// Items dropped by `Settings::max_queue_age` since the last delivery, told with the next
+    #[serde(skip_serializing_if = "is_zero")]
+    pub stale_skipped: u32,
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Discussion {
+    /// Resolved from `linked_chat_id` of the channel when enabled
+    pub chat_id: SubscriberId,
+    /// Who gets notified when the mirroring stops working
+    pub enabled_by: i64,
+}
 
-    #[test]
-    fn size64hasher() {
-        let mut h = Size64Hasher::default();
-        h.write_i64(-42);
-        assert_eq!(h.finish(), -42i64 as u64);
+/// Per-chat settings, independent of the subscriptions
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Chat {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<Webhook>,
+    /// Skip the items another feed delivered to the chat recently
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub cross_dedup: bool,
+    /// Newest first, only kept while `cross_dedup` is on. Shared with the copies of the
+    /// chat, they are taken for every delivery
+    #[serde(skip_serializing_if = "has_no_links")]
+    recent_links: Arc<Vec<DeliveredLink>>,
+    /// Send the items as replies to this message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<ReplyTo>,
+    /// For the subscriptions that don't override them
+    #[serde(skip_serializing_if = "Settings::is_empty")]
+    pub defaults: Settings,
+    /// Opted out of the footer set by the bot admins
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub hide_footer: bool,
+    /// When the bot was removed from the chat, cleared when it's added back
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bot_removed: Option<SystemTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weekly_report: Option<WeeklyReport>,
+}
+
+/// When to send the summary of the week, in the local time of the bot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WeeklyReport {
+    /// Days from Monday
+    pub weekday: u32,
+    /// Minutes from midnight
+    pub minute: u32,
+    /// Unix seconds, set when it's enabled so a past time isn't sent right away
+    pub last_sent: i64,
+}
+
+impl WeeklyReport {
+    /// Whether the time of the report came since the last one was sent
+    pub fn is_due<Tz: chrono::TimeZone>(&self, now: &chrono::DateTime<Tz>) -> bool {
+        use chrono::{Datelike, Days, NaiveTime};
+        let days_back = (now.weekday().num_days_from_monday() + 7 - self.weekday) % 7;
+        let time = NaiveTime::from_num_seconds_from_midnight_opt(self.minute * 60, 0)
+            .unwrap_or_default();
+        let local = now.naive_local();
+        let mut scheduled = (local.date() - Days::new(days_back.into())).and_time(time);
+        if scheduled > local {
+            scheduled = scheduled - Days::new(7);
+        }
+        match now.timezone().timestamp_opt(self.last_sent, 0).single() {
+            Some(last_sent) => last_sent.naive_local() < scheduled,
+            None => false,
+        }
     }
+}
 
-    #[test]
-    #[should_panic(expected = "this is a special hasher, do not write twice")]
-    fn size64hasher_write_twice() {
-        let mut h = Size64Hasher::default();
-        h.write_u64(42);
-        h.write_u64(42);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplyTo {
+    Message(i64),
+    /// Whatever is pinned when the items are sent
+    Pinned,
+}
+
+/// Hash of the link without the tracking parameters, the feed that delivered it and when
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct DeliveredLink(u64, FeedId, i64);
+
+fn has_no_links(links: &Arc<Vec<DeliveredLink>>) -> bool {
+    links.is_empty()
+}
+
+impl Chat {
+    pub fn set_cross_dedup(&mut self, enabled: bool) {
+        self.cross_dedup = enabled;
+        if !enabled {
+            self.recent_links = Arc::default();
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    /// Key of the HMAC signature
+    pub secret: String,
+}
+
+/// Settings of the bot itself, changed by the bot admins
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Global {
+    /// Overrides `--rsshub-base`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rsshub_base: Option<String>,
+    /// Plain text appended to the items delivered to every chat, see `Chat::hide_footer`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub footer: Option<String>,
+}
+
+impl Global {
+    fn is_default(&self) -> bool {
+        *self == Global::default()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Hub {
+    pub callback: String,
+    pub secret: String,
+}
+
+/// On-disk form of a feed, the hash list is stored aside from `Feed` in memory
+#[derive(Serialize)]
+struct FeedRecordRef<'a> {
+    #[serde(flatten)]
+    feed: &'a Feed,
+    hash_list: &'a [u64],
+    hash_version: u32,
+}
+
+impl<'a> FeedRecordRef<'a> {
+    fn new(feed: &'a Feed, hash_list: &'a [u64]) -> Self {
+        FeedRecordRef {
+            feed,
+            hash_list,
+            hash_version: HASH_VERSION,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct FeedRecord {
+    #[serde(flatten)]
+    feed: Feed,
+    #[serde(default)]
+    hash_list: Vec<u64>,
+    /// See `HASH_VERSION`, the records stored without it have the same hashes as 1
+    #[serde(default = "first_hash_version")]
+    hash_version: u32,
+}
+
+fn first_hash_version() -> u32 {
+    1
+}
+
+/// Not compared with hashes made differently, all the items would be delivered again
+fn check_hash_versions(feeds: &[FeedRecord]) -> Result<(), DataError> {
+    match feeds.iter().find(|record| record.hash_version != HASH_VERSION) {
+        Some(record) => Err(DataError::HashVersion(record.hash_version)),
+        None => Ok(()),
+    }
+}
+
+#[derive(Serialize)]
+struct DatabaseFileRef<'a> {
+    feeds: Vec<FeedRecordRef<'a>>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    chats: &'a HashMap<SubscriberId, Chat, Size64>,
+    #[serde(skip_serializing_if = "Global::is_default")]
+    global: &'a Global,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DatabaseFile {
+    Current {
+        feeds: Vec<FeedRecord>,
+        #[serde(default, deserialize_with = "deserialize_id_map")]
+        chats: HashMap<SubscriberId, Chat, Size64>,
+        #[serde(default)]
+        global: Global,
+    },
+    /// Only the feeds were stored before chat settings
+    Legacy(Vec<FeedRecord>),
+}
+
+/// JSON object keys are strings, serde can't read them as integers
+/// once `flatten` or `untagged` buffered the input
+fn deserialize_id_map<'de, D, V>(
+    deserializer: D,
+) -> Result<HashMap<SubscriberId, V, Size64>, D::Error>
+where
+    D: Deserializer<'de>,
+    V: Deserialize<'de>,
+{
+    HashMap::<String, V>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(id, v)| id.parse().map(|id| (id, v)).map_err(D::Error::custom))
+        .collect()
+}
+
+#[derive(Debug, Default)]
+struct State {
+    feeds: HashMap<FeedId, Feed, Size64>,
+    subscribers: HashMap<SubscriberId, HashSet<FeedId, Size64>, Size64>,
+    chats: HashMap<SubscriberId, Chat, Size64>,
+    global: Global,
+    /// The digests taken by `take_due_digests` and not sent yet. Only in memory,
+    /// their items are still kept until they are sent
+    sending_digests: HashSet<(FeedId, SubscriberId)>,
+}
+
+type HashLists = HashMap<FeedId, SeenHashes, Size64>;
+
+/// The feeds, chats and global settings as they are stored
+type Contents = (Vec<FeedRecord>, HashMap<SubscriberId, Chat, Size64>, Global);
+
+impl State {
+    /// The stored feeds with their subscribers indexed, and their hash lists aside.
+    /// Feeds stored twice by links that differed only in case are merged
+    fn build(
+        feeds_list: Vec<FeedRecord>,
+        chats: HashMap<SubscriberId, Chat, Size64>,
+        global: Global,
+        report: &mut MigrationReport,
+    ) -> (State, Vec<(FeedId, Vec<u64>)>) {
+        let mut state = State {
+            feeds: HashMap::with_capacity_and_hasher(feeds_list.len(), Size64::default()),
+            subscribers: HashMap::with_hasher(Size64::default()),
+            chats,
+            global,
+            sending_digests: HashSet::new(),
+        };
+        let mut hash_lists = Vec::with_capacity(feeds_list.len());
+        let started = Instant::now();
+        let stored = feeds_list.len();
+        let (mut merged, mut dropped) = (0, 0);
+
+        for FeedRecord { feed, hash_list, .. } in feeds_list {
+            let feed_id = feed_key(&feed.link);
+            for subscriber in &feed.subscribers {
+                let subscribed_feeds = state
+                    .subscribers
+                    .entry(subscriber.to_owned())
+                    .or_insert_with(HashSet::default);
+                subscribed_feeds.insert(feed_id);
+            }
+            match state.feeds.entry(feed_id) {
+                // Subscribed twice by links that differed only in case
+                Entry::Occupied(mut existing) => {
+                    merged += 1;
+                    dropped += existing.get_mut().merge(feed);
+                    let merged = hash_lists.iter_mut().find(|(id, _)| *id == feed_id);
+                    if let Some((_, list)) = merged {
+                        list.extend(hash_list);
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(feed);
+                    hash_lists.push((feed_id, hash_list));
+                }
+            }
+        }
+
+        if merged > 0 {
+            report.migrations.push(Migration {
+                name: NORMALIZED_LINKS,
+                touched: stored,
+                merged,
+                dropped,
+                elapsed: started.elapsed(),
+            });
+        }
+        (state, hash_lists)
+    }
+}
+
+/// An item from the last response of a feed, searched by inline queries
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecentItem {
+    pub title: String,
+    pub link: String,
+    pub published: Option<i64>,
+}
+
+impl RecentItem {
+    /// `None` without a title or link
+    pub fn from_item(item: &feed::Item) -> Option<Self> {
+        Some(RecentItem {
+            title: item.title.clone()?,
+            link: item.link.clone()?,
+            published: item.published.map(|t| t.timestamp()),
+        })
+    }
+}
+
+/// Items or messages waiting to be delivered, shown by /queue
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueueSnapshot {
+    pub queued: usize,
+    /// When the one waiting longest was queued, if that's known
+    pub oldest: Option<SystemTime>,
+    /// When the next of them may go out
+    pub next_flush: Option<SystemTime>,
+    /// Of the items, in the order they go out, if they are known
+    pub titles: Vec<String>,
+}
+
+/// The item published last, undated items are usually newest first
+pub fn newest_item(items: &[RecentItem]) -> Option<&RecentItem> {
+    items
+        .iter()
+        .enumerate()
+        .max_by_key(|(i, item)| (item.published, cmp::Reverse(*i)))
+        .map(|(_, item)| item)
+}
+
+/// What changed in memory since the last save to SQLite, by row
+#[derive(Debug, Default)]
+struct Dirty {
+    feeds: HashSet<FeedId, Size64>,
+    chats: HashSet<SubscriberId, Size64>,
+    global: bool,
+    /// Every row is compared, for the changes not tracked by row
+    all: bool,
+}
+
+impl Dirty {
+    fn merge(&mut self, other: Dirty) {
+        self.feeds.extend(other.feeds);
+        self.chats.extend(other.chats);
+        self.global |= other.global;
+        self.all |= other.all;
+    }
+}
+
+/// Lock order: `saving` first, then `state`, then the shard of `hash_lists`.
+/// `save` takes `saving` before copying the snapshot, so nothing may call it while
+/// holding `state` or a shard. `recent_items` and `embargoed` are taken last, after
+/// any of them, and `dirty` after all of them, nothing is taken under it.
+/// None of them is held across an `.await`.
+#[derive(Debug)]
+pub struct Database {
+    path: PathBuf,
+    /// With `sqlite:`, else the database is the JSON file at `path`
+    sqlite: Option<sqlite::Store>,
+    state: RwLock<State>,
+    hash_lists: [Mutex<HashLists>; HASH_LIST_SHARDS],
+    // Only in memory, filled again by the fetches after restarting
+    recent_items: RwLock<HashMap<FeedId, Vec<RecentItem>, Size64>>,
+    // Items dated in the future, not seen until their time comes, with when they were
+    // first held. Only in memory too, the fetches find them again after restarting
+    embargoed: RwLock<HashMap<FeedId, Vec<(feed::Item, SystemTime)>, Size64>>,
+    // Makes sure an older snapshot never overwrites a newer one
+    saving: Mutex<()>,
+    /// With `sqlite:`, the rows changed since the last save
+    dirty: Mutex<Dirty>,
+    /// `--max-hashes-per-feed`
+    max_hashes: Option<usize>,
+    /// `--pending-cycles`
+    pending_cycles: u32,
+    /// Held by `open_exclusive` until the database is dropped
+    _lock: Option<File>,
+}
+
+impl Database {
+    pub fn create(path: PathBuf) -> Result<Database, DataError> {
+        let result = Database::with_state(path, None, State::default(), Vec::new());
+
+        result.save()?;
+
+        Ok(result)
+    }
+
+    pub fn open(path: PathBuf) -> Result<Database, DataError> {
+        Ok(Database::open_migrating(Location::Json(path), Execution::Apply)?.0)
+    }
+
+    /// Migrations are reported on stderr and in `<database>.migrations.log`,
+    /// with the copy of the database made before the first of them
+    fn open_migrating(
+        location: Location,
+        migrations: Execution,
+    ) -> Result<(Database, MigrationReport), DataError> {
+        let mut report = MigrationReport::default();
+        let (path, store, (feeds_list, chats, global)) = match location {
+            Location::Json(path) if !path.exists() => {
+                return Ok((Database::create(path)?, report));
+            }
+            Location::Json(path) => {
+                let f = File::open(&path)?;
+                let file: DatabaseFile = serde_json::from_reader(&f)?;
+                let pending = file.pending_migrations();
+                if !pending.is_empty() {
+                    if migrations == Execution::DryRun {
+                        return Err(DataError::MigrationsPending(pending.join(", ")));
+                    }
+                    report.backup = Some(backup_database(&path)?);
+                }
+                let contents = file.into_contents(&mut report);
+                (path, None, contents)
+            }
+            // Created empty if it doesn't exist
+            Location::Sqlite(path) => {
+                let store = sqlite::Store::open(&path)?;
+                let contents = store.load()?;
+                if has_duplicate_links(&contents.0) {
+                    if migrations == Execution::DryRun {
+                        let pending = NORMALIZED_LINKS.to_string();
+                        return Err(DataError::MigrationsPending(pending));
+                    }
+                    report.backup = Some(store.backup(&path)?);
+                }
+                (path, Some(store), contents)
+            }
+        };
+        check_hash_versions(&feeds_list)?;
+        let (state, hash_lists) = State::build(feeds_list, chats, global, &mut report);
+
+        let db = Database::with_state(path, store, state, hash_lists);
+        if !report.migrations.is_empty() {
+            // Not migrated again by the next start
+            db.save()?;
+            eprint!("{}", report);
+            if let Err(e) = db.append_migration_log(&report) {
+                eprintln!("Failed to write the migration report: {}", e);
+            }
+        }
+        Ok((db, report))
+    }
+
+    /// Like `open`, but fail if another process opened it this way.
+    /// `Execution::DryRun` fails if the database needs migrating, for `--no-migrate`
+    pub fn open_exclusive(
+        location: Location,
+        migrations: Execution,
+    ) -> Result<Database, DataError> {
+        let lock = lock_database(location.path())?;
+        let (mut db, _) = Database::open_migrating(location, migrations)?;
+        db._lock = Some(lock);
+        Ok(db)
+    }
+
+    /// Fill an empty database with the contents of a JSON database, for `--import-json`.
+    /// The JSON file is only read, its hash lists are capped like the loaded ones.
+    /// Returns how many feeds and chats were imported
+    pub fn import_json(&self, path: &Path) -> Result<(usize, usize), DataError> {
+        let file: DatabaseFile = serde_json::from_reader(File::open(path)?)?;
+        let mut report = MigrationReport::default();
+        let (feeds_list, chats, global) = file.into_contents(&mut report);
+        check_hash_versions(&feeds_list)?;
+        let (imported, hash_lists) = State::build(feeds_list, chats, global, &mut report);
+        // The chats with subscriptions and the ones with only settings
+        let chat_ids: HashSet<SubscriberId, Size64> = imported
+            .subscribers
+            .keys()
+            .chain(imported.chats.keys())
+            .copied()
+            .collect();
+        let counts = (imported.feeds.len(), chat_ids.len());
+        {
+            let mut state = self.write();
+            if !state.feeds.is_empty() || !state.chats.is_empty() {
+                return Err(DataError::NotEmpty);
+            }
+            for (feed_id, hash_list) in hash_lists {
+                let mut hash_list = SeenHashes::from_list(hash_list);
+                // Unknown for the feeds not fetched since upgrading, as in `with_max_hashes`
+                let item_count = imported.feeds.get(&feed_id).map_or(0, |feed| feed.item_count);
+                if item_count > 0 || self.max_hashes.is_some() {
+                    hash_list.evict(self.hash_capacity(item_count), &HashSet::default());
+                }
+                self.hash_list_shard(feed_id).insert(feed_id, hash_list);
+            }
+            *state = imported;
+        }
+        self.save()?;
+        eprint!("{}", report);
+        Ok(counts)
+    }
+
+    fn append_migration_log(&self, report: &MigrationReport) -> std::io::Result<()> {
+        let mut log_path = self.path.as_os_str().to_owned();
+        log_path.push(".migrations.log");
+        let mut log = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path)?;
+        let now = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+        write!(log, "{}\n{}", now, report)
+    }
+
+    fn with_state(
+        path: PathBuf,
+        sqlite: Option<sqlite::Store>,
+        state: State,
+        hash_lists: Vec<(FeedId, Vec<u64>)>,
+    ) -> Self {
+        let db = Database {
+            path,
+            sqlite,
+            state: RwLock::new(state),
+            hash_lists: std::array::from_fn(|_| Mutex::new(HashMap::default())),
+            recent_items: RwLock::new(HashMap::default()),
+            embargoed: RwLock::new(HashMap::default()),
+            saving: Mutex::new(()),
+            // What was loaded may differ from the rows after the migrations
+            dirty: Mutex::new(Dirty {
+                all: true,
+                ..Dirty::default()
+            }),
+            max_hashes: None,
+            pending_cycles: 0,
+            _lock: None,
+        };
+        for (feed_id, hash_list) in hash_lists {
+            db.hash_list_shard(feed_id).insert(feed_id, SeenHashes::from_list(hash_list));
+        }
+        db
+    }
+
+    /// Cap the seen hashes of every feed, and trim the lists that are already longer,
+    /// oldest first
+    pub fn with_max_hashes(mut self, max_hashes: Option<usize>) -> Result<Self, DataError> {
+        self.max_hashes = max_hashes;
+        let item_counts: Vec<(FeedId, usize)> = self
+            .read()
+            .feeds
+            .iter()
+            .map(|(feed_id, feed)| (*feed_id, feed.item_count))
+            .collect();
+        let mut trimmed = (0, 0);
+        for (feed_id, item_count) in item_counts {
+            // Unknown for the feeds not fetched since upgrading
+            if item_count == 0 && max_hashes.is_none() {
+                continue;
+            }
+            let capacity = self.hash_capacity(item_count);
+            if let Some(hash_list) = self.hash_list_shard(feed_id).get_mut(&feed_id) {
+                if hash_list.len() > capacity {
+                    trimmed.0 += 1;
+                    trimmed.1 += hash_list.len() - capacity;
+                    hash_list.evict(capacity, &HashSet::default());
+                }
+            }
+        }
+        if trimmed.0 > 0 {
+            eprintln!(
+                "Trimmed {} seen item hashes of {} oversized feeds",
+                trimmed.1, trimmed.0
+            );
+            self.save()?;
+        }
+        Ok(self)
+    }
+
+    /// Hold back new items without a real link for up to `cycles` fetches,
+    /// some sites publish the item first and fill it in later. 0 delivers them at once
+    pub fn with_pending_cycles(mut self, cycles: u32) -> Self {
+        self.pending_cycles = cycles;
+        self
+    }
+
+    /// How many hashes are kept for a feed with `item_count` items,
+    /// never less than the items, or they would be delivered again
+    fn hash_capacity(&self, item_count: usize) -> usize {
+        let limit = self.max_hashes.unwrap_or(item_count * HASH_SAFETY_FACTOR);
+        cmp::max(limit, item_count)
+    }
+
+    /// Feeds stored under links that only differ in the scheme or a `www.`,
+    /// in groups sorted by link
+    pub fn feed_variants(&self) -> Vec<Vec<Feed>> {
+        let mut groups: BTreeMap<String, Vec<Feed>> = BTreeMap::new();
+        for feed in self.read().feeds.values() {
+            if let Some(key) = variant_key(&feed.link) {
+                groups.entry(key).or_default().push(feed.clone());
+            }
+        }
+        groups
+            .into_values()
+            .filter(|group| group.len() > 1)
+            .map(|mut group| {
+                group.sort_by(|a, b| a.link.cmp(&b.link));
+                group
+            })
+            .collect()
+    }
+
+    /// Move the subscribers and the seen items of the `duplicates` to `canonical`,
+    /// every subscription keeps its settings. Return how many subscriptions were moved,
+    /// not counting the chats that were subscribed to both
+    pub fn merge_feeds(&self, canonical: &str, duplicates: &[String]) -> usize {
+        let canonical_id = feed_key(canonical);
+        let mut moved = 0;
+        {
+            let mut state = self.write();
+            if !state.feeds.contains_key(&canonical_id) {
+                return 0;
+            }
+            for duplicate in duplicates {
+                let feed_id = feed_key(duplicate);
+                if feed_id == canonical_id {
+                    continue;
+                }
+                let feed = match state.feeds.remove(&feed_id) {
+                    Some(feed) => feed,
+                    None => continue,
+                };
+                for subscriber in &feed.subscribers {
+                    if let Some(feeds) = state.subscribers.get_mut(subscriber) {
+                        feeds.remove(&feed_id);
+                        if feeds.insert(canonical_id) {
+                            moved += 1;
+                        }
+                    }
+                }
+                for chat in state.chats.values_mut() {
+                    let links = Arc::make_mut(&mut chat.recent_links);
+                    for DeliveredLink(_, delivered_by, _) in links {
+                        if *delivered_by == feed_id {
+                            *delivered_by = canonical_id;
+                        }
+                    }
+                }
+                state.feeds.get_mut(&canonical_id).unwrap().merge(feed);
+                // Never both shards at once, they may be the same
+                let hashes = self.hash_list_shard(feed_id).remove(&feed_id);
+                if let Some(hashes) = hashes {
+                    if let Some(list) = self.hash_list_shard(canonical_id).get_mut(&canonical_id) {
+                        for hash in hashes.to_list().into_iter().rev() {
+                            list.insert(hash);
+                        }
+                    }
+                }
+                self.recent_items.write().unwrap().remove(&feed_id);
+                self.embargoed.write().unwrap().remove(&feed_id);
+            }
+        }
+        self.save().unwrap_or_default();
+        moved
+    }
+
+    /// Drop what's not in `State` of a removed feed
+    fn forget_feed(&self, feed_id: FeedId, rss_link: &str) {
+        self.hash_list_shard(feed_id).remove(&feed_id);
+        METRICS.forget_feed(rss_link);
+        self.recent_items.write().unwrap().remove(&feed_id);
+        self.embargoed.write().unwrap().remove(&feed_id);
+    }
+
+    fn remember_items(&self, feed_id: FeedId, items: &[feed::Item]) {
+        let recent = items
+            .iter()
+            .filter_map(RecentItem::from_item)
+            .take(RECENT_ITEMS_PER_FEED)
+            .collect();
+        self.recent_items.write().unwrap().insert(feed_id, recent);
+    }
+
+    /// Recent items of the feeds subscribed by `subscriber` with all the words of `query`
+    /// in their titles, newest first, with the titles of their feeds
+    pub fn search_recent_items(
+        &self,
+        subscriber: SubscriberId,
+        query: &str,
+        limit: usize,
+    ) -> Vec<(String, RecentItem)> {
+        let feeds: Vec<(FeedId, String)> = {
+            let state = self.read();
+            match state.subscribers.get(&subscriber) {
+                Some(feed_ids) => feed_ids
+                    .iter()
+                    .filter_map(|id| state.feeds.get(id).map(|feed| (*id, feed.title.clone())))
+                    .collect(),
+                None => return Vec::new(),
+            }
+        };
+        let words: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+        let recent_items = self.recent_items.read().unwrap();
+        let mut results: Vec<(String, RecentItem)> = feeds
+            .into_iter()
+            .flat_map(|(id, feed_title)| {
+                recent_items
+                    .get(&id)
+                    .into_iter()
+                    .flatten()
+                    .filter(|item| {
+                        let title = item.title.to_lowercase();
+                        words.iter().all(|word| title.contains(word))
+                    })
+                    .map(move |item| (feed_title.clone(), item.clone()))
+            })
+            .collect();
+        results.sort_by(|(_, a), (_, b)| b.published.cmp(&a.published));
+        results.truncate(limit);
+        results
+    }
+
+    /// The newest item of the last response of the feed, if it was fetched since starting
+    pub fn latest_item(&self, rss_link: &str) -> Option<RecentItem> {
+        let recent_items = self.recent_items.read().unwrap();
+        newest_item(recent_items.get(&feed_key(rss_link))?).cloned()
+    }
+
+    /// A panic happened while holding one of the locks
+    pub fn is_poisoned(&self) -> bool {
+        self.state.is_poisoned()
+            || self.hash_lists.iter().any(|shard| shard.is_poisoned())
+            || self.recent_items.is_poisoned()
+            || self.embargoed.is_poisoned()
+            || self.saving.is_poisoned()
+            || self.dirty.is_poisoned()
+    }
+
+    fn hash_list_shard(&self, feed_id: FeedId) -> MutexGuard<'_, HashLists> {
+        self.hash_lists[(feed_id % HASH_LIST_SHARDS as u64) as usize]
+            .lock()
+            .unwrap()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read().unwrap()
+    }
+
+    /// For changes that may touch any row, the next save compares all of them
+    fn write(&self) -> RwLockWriteGuard<'_, State> {
+        self.write_marking(|dirty| dirty.all = true)
+    }
+
+    fn write_feed(&self, feed_id: FeedId) -> RwLockWriteGuard<'_, State> {
+        self.write_marking(|dirty| {
+            dirty.feeds.insert(feed_id);
+        })
+    }
+
+    fn write_chat(&self, chat_id: SubscriberId) -> RwLockWriteGuard<'_, State> {
+        self.write_marking(|dirty| {
+            dirty.chats.insert(chat_id);
+        })
+    }
+
+    /// Marked while `state` is held, a save copying it afterwards sees the change
+    /// or leaves the mark for the next one
+    fn write_marking(&self, mark: impl FnOnce(&mut Dirty)) -> RwLockWriteGuard<'_, State> {
+        let state = self.state.write().unwrap();
+        if self.sqlite.is_some() {
+            mark(&mut self.dirty.lock().unwrap());
+        }
+        state
+    }
+
+    /// The shard for changing the seen hashes of the feed
+    fn hash_list_shard_mut(&self, feed_id: FeedId) -> MutexGuard<'_, HashLists> {
+        let shard = self.hash_list_shard(feed_id);
+        if self.sqlite.is_some() {
+            self.dirty.lock().unwrap().feeds.insert(feed_id);
+        }
+        shard
+    }
+
+    pub fn all_feeds(&self) -> Vec<Feed> {
+        self.read().feeds.values().cloned().collect()
+    }
+
+    /// How many feeds and subscribers there are, without copying them
+    pub fn counts(&self) -> (usize, usize) {
+        let state = self.read();
+        (state.feeds.len(), state.subscribers.len())
+    }
+
+    pub fn all_subscribers(&self) -> Vec<SubscriberId> {
+        self.read().subscribers.keys().copied().collect()
+    }
+
+    pub fn subscribed_feeds(&self, subscriber: SubscriberId) -> Option<Vec<Feed>> {
+        let state = self.read();
+        state.subscribers.get(&subscriber).map(|feeds| {
+            feeds
+                .iter()
+                .map(|feed_id| &state.feeds[feed_id])
+                .cloned()
+                .collect()
+        })
+    }
+
+    /// The chats that opted in to the weekly report
+    pub fn weekly_reports(&self) -> Vec<(SubscriberId, WeeklyReport)> {
+        self.read()
+            .chats
+            .iter()
+            .filter_map(|(&chat_id, chat)| Some((chat_id, chat.weekly_report?)))
+            .collect()
+    }
+
+    /// Call before sending the reports, so they aren't sent twice
+    pub fn record_weekly_reports(&self, chats: &[SubscriberId], time: i64) {
+        {
+            let mut state = self.write_marking(|dirty| dirty.chats.extend(chats));
+            for chat_id in chats {
+                let chat = state.chats.get_mut(chat_id);
+                if let Some(report) = chat.and_then(|chat| chat.weekly_report.as_mut()) {
+                    report.last_sent = time;
+                }
+            }
+        }
+        self.save().unwrap_or_default();
+    }
+
+    pub fn chat(&self, chat_id: SubscriberId) -> Chat {
+        self.read().chats.get(&chat_id).cloned().unwrap_or_default()
+    }
+
+    pub fn update_chat<F: FnOnce(&mut Chat)>(&self, chat_id: SubscriberId, f: F) {
+        {
+            let mut state = self.write_chat(chat_id);
+            let chat = state.chats.entry(chat_id).or_default();
+            f(chat);
+            if *chat == Chat::default() {
+                state.chats.remove(&chat_id);
+            }
+        }
+        self.save().unwrap_or_default();
+    }
+
+    /// Drop the items whose links were delivered to the chat by another feed
+    /// in the last `window` seconds, see `record_delivery`.
+    /// Does nothing unless the chat has `cross_dedup` on.
+    pub fn filter_cross_duplicates(
+        &self,
+        chat_id: SubscriberId,
+        rss_link: &str,
+        items: &[feed::Item],
+        now: i64,
+        window: i64,
+    ) -> Vec<feed::Item> {
+        let feed_id = feed_key(rss_link);
+        let state = self.read();
+        let chat = match state.chats.get(&chat_id) {
+            Some(chat) if chat.cross_dedup => chat,
+            _ => return items.to_vec(),
+        };
+        let delivered: HashSet<u64, Size64> = chat
+            .recent_links
+            .iter()
+            .filter(|DeliveredLink(_, feed, at)| *feed != feed_id && now - at <= window)
+            .map(|DeliveredLink(hash, _, _)| *hash)
+            .collect();
+        items
+            .iter()
+            .filter(|item| match &item.link {
+                Some(link) => !delivered.contains(&gen_hash(&normalize_link(link))),
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub fn global(&self) -> Global {
+        self.read().global.clone()
+    }
+
+    pub fn update_global<F: FnOnce(&mut Global)>(&self, f: F) {
+        f(&mut self.write_marking(|dirty| dirty.global = true).global);
+        self.save().unwrap_or_default();
+    }
+
+    /// Return `None` if feed not found
+    pub fn get_or_update_down_time(&self, rss_link: &str) -> Option<Duration> {
+        let feed_id = feed_key(rss_link);
+        let mut state = self.write_feed(feed_id);
+        let feed = state.feeds.get_mut(&feed_id)?;
+        let now = SystemTime::now();
+        if let Some(t) = feed.down_time {
+            Some(now.duration_since(t).unwrap_or_default())
+        } else {
+            feed.down_time = Some(now);
+            Some(Duration::default())
+        }
+    }
+
+    /// Count a fetch failure, `next_fetch` gets the number of consecutive failures.
+    /// Return `None` if feed not found
+    pub fn record_failure<F>(&self, rss_link: &str, blocked: bool, next_fetch: F) -> Option<u32>
+    where
+        F: FnOnce(u32) -> SystemTime,
+    {
+        let feed_id = feed_key(rss_link);
+        let failures = {
+            let mut state = self.write_feed(feed_id);
+            let feed = state.feeds.get_mut(&feed_id)?;
+            feed.failures = feed.failures.saturating_add(1);
+            feed.next_fetch = Some(next_fetch(feed.failures));
+            feed.failing_since.get_or_insert_with(SystemTime::now);
+            feed.blocked = blocked;
+            feed.failures
+        };
+        // Keep backing off after restarting
+        self.save().unwrap_or_default();
+        Some(failures)
+    }
+
+    /// Return the item count of the last accepted response and how many of `items`
+    /// are already known, `None` if feed not found
+    pub fn compare_items(
+        &self,
+        rss_link: &str,
+        items: &[feed::Item],
+    ) -> Option<(usize, usize)> {
+        let feed_id = feed_key(rss_link);
+        let item_count = self.read().feeds.get(&feed_id)?.item_count;
+        let hash_lists = self.hash_list_shard(feed_id);
+        let hash_list = hash_lists.get(&feed_id)?;
+        let known = items
+            .iter()
+            .filter(|item| is_known(hash_list, item))
+            .count();
+        Some((item_count, known))
+    }
+
+    /// The item count of the last accepted response if the feed seems to have started
+    /// over: none of `items` were seen, by their hashes or their links, and some of them
+    /// are dated before the feed last had new items. Items that all turned over since are
+    /// simply new. `None` if it's not reset, or not found
+    pub fn seems_reset(&self, rss_link: &str, items: &[feed::Item]) -> Option<usize> {
+        let feed_id = feed_key(rss_link);
+        let (item_count, since, home_page, recent) = {
+            let state = self.read();
+            let feed = state.feeds.get(&feed_id)?;
+            let recent: HashSet<u64, Size64> = feed.recent_keys.iter().copied().collect();
+            (feed.item_count, feed.last_new_items(), feed.home_page.clone(), recent)
+        };
+        if item_count == 0 {
+            return None;
+        }
+        {
+            let hash_lists = self.hash_list_shard(feed_id);
+            let hash_list = hash_lists.get(&feed_id)?;
+            if items.iter().any(|item| is_known(hash_list, item)) {
+                return None;
+            }
+        }
+        let republished = items
+            .iter()
+            .flat_map(|item| secondary_keys(item, &home_page))
+            .any(|key| recent.contains(&key));
+        // Undated items may be old
+        let predating = |item: &feed::Item| match (item.published, since) {
+            (Some(published), Some(since)) => published.timestamp() < since,
+            _ => true,
+        };
+        (!republished && items.iter().any(predating)).then_some(item_count)
+    }
+
+    /// Mark the last response of the feed as suspect,
+    /// return `true` if the one before it was suspect too
+    pub fn mark_suspect(&self, rss_link: &str) -> bool {
+        let feed_id = feed_key(rss_link);
+        match self.write_feed(feed_id).feeds.get_mut(&feed_id) {
+            Some(feed) => std::mem::replace(&mut feed.suspect, true),
+            None => false,
+        }
+    }
+
+    /// The subscribers to tell about a `class` of errors of the feed now, with how many
+    /// notices they didn't get since the last one. Each gets at most one per `window`
+    /// seconds for each class, the window doubles with every notice sent.
+    pub fn error_notices(
+        &self,
+        rss_link: &str,
+        class: &str,
+        now: i64,
+        window: u64,
+    ) -> Vec<(SubscriberId, u32)> {
+        let feed_id = feed_key(rss_link);
+        let mut notify = Vec::new();
+        {
+            let mut state = self.write_feed(feed_id);
+            let feed = match state.feeds.get_mut(&feed_id) {
+                Some(feed) => feed,
+                None => return notify,
+            };
+            let subscribers = &feed.subscribers;
+            feed.error_notices.retain(|notice| subscribers.contains(&notice.subscriber));
+            for &subscriber in &feed.subscribers {
+                let notice = feed
+                    .error_notices
+                    .iter_mut()
+                    .find(|notice| notice.subscriber == subscriber && notice.class == class);
+                match notice {
+                    Some(notice) if now.saturating_sub(notice.sent_at) < notice.wait(window) => {
+                        notice.suppressed = notice.suppressed.saturating_add(1);
+                    }
+                    Some(notice) => {
+                        notify.push((subscriber, notice.suppressed));
+                        notice.sent_at = now;
+                        notice.suppressed = 0;
+                        notice.sent = notice.sent.saturating_add(1);
+                    }
+                    None => {
+                        notify.push((subscriber, 0));
+                        feed.error_notices.push(ErrorNotice {
+                            subscriber,
+                            class: class.to_string(),
+                            sent_at: now,
+                            suppressed: 0,
+                            sent: 1,
+                        });
+                    }
+                }
+            }
+        }
+        // Not alerting again after restarting, the suppressed ones aren't worth a save
+        if !notify.is_empty() {
+            self.save().unwrap_or_default();
+        }
+        notify
+    }
+
+    /// The feed of `rss_link` if the subscriber has it, the link is normalized like `subscribe`
+    pub fn subscribed_feed(&self, subscriber: SubscriberId, rss_link: &str) -> Option<Feed> {
+        let state = self.read();
+        let feed = state.feeds.get(&feed_key(rss_link))?;
+        feed.subscribers.contains(&subscriber).then(|| feed.clone())
+    }
+
+    pub fn is_subscribed(&self, subscriber: SubscriberId, rss_link: &str) -> bool {
+        self.read()
+            .subscribers
+            .get(&subscriber)
+            .map(|feeds| feeds.contains(&feed_key(rss_link)))
+            .unwrap_or(false)
+    }
+
+    /// Checked and inserted under the same lock, of concurrent calls for the same chat
+    /// and feed only one subscribes
+    pub fn subscribe(
+        &self,
+        subscriber: SubscriberId,
+        rss_link: &str,
+        rss: &feed::Rss,
+    ) -> SubscribeResult {
+        let feed_id = feed_key(rss_link);
+        {
+            let mut state = self.write_feed(feed_id);
+            let subscribed_feeds = state.subscribers.entry(subscriber).or_default();
+            if !subscribed_feeds.insert(feed_id) {
+                return SubscribeResult::AlreadySubscribed;
+            }
+            let feed = state.feeds.entry(feed_id).or_insert_with(|| {
+                let hashes = rss.items.iter().map(gen_item_hash).collect();
+                self.hash_list_shard(feed_id).insert(feed_id, SeenHashes::from_list(hashes));
+                let mut feed = Feed {
+                    link: normalize_feed_link(rss_link).into_owned(),
+                    title: rss.meta.title.to_owned(),
+                    home_page: rss.meta.link.to_owned(),
+                    down_time: None,
+                    ttl: rss.meta.ttl,
+                    subscribers: HashSet::default(),
+                    subscriptions: HashMap::default(),
+                    activity: HashMap::default(),
+                    item_dates: Vec::new(),
+                    daily_items: Vec::new(),
+                    last_new_items: None,
+                    failures: 0,
+                    next_fetch: None,
+                    failing_since: None,
+                    blocked: false,
+                    item_count: rss.items.len(),
+                    suspect: false,
+                    title_notices: Vec::new(),
+                    home_page_notices: Vec::new(),
+                    cookie: None,
+                    max_size: None,
+                    pending: Vec::new(),
+                    recent_keys: rss
+                        .items
+                        .iter()
+                        .flat_map(|item| secondary_keys(item, &rss.meta.link))
+                        .collect(),
+                    error_notices: Vec::new(),
+                    quirks: Quirks::default(),
+                    validators: Validators::default(),
+                    language: None,
+                    generator: None,
+                    hubs: Vec::new(),
+                    skip: feed::SkipHints::default(),
+                    digest_items: HashMap::default(),
+                };
+                feed.record_meta(&rss.meta);
+                feed.record_item_dates(&rss.items);
+                feed
+            });
+            feed.subscribers.insert(subscriber);
+            feed.activity.insert(
+                subscriber,
+                Activity {
+                    subscribed_at: Some(SystemTime::now()),
+                    ..Default::default()
+                },
+            );
+            // Whoever set the cookie didn't agree to share their access
+            if feed.cookie.take().is_some() {
+                feed.forget_responses();
+            }
+        }
+        self.remember_items(feed_id, &rss.items);
+        self.save().unwrap_or_default();
+        SubscribeResult::Subscribed
+    }
+
+    /// `None` removes the cookie, return `false` if `subscriber` isn't the only subscriber
+    pub fn set_cookie(
+        &self,
+        subscriber: SubscriberId,
+        rss_link: &str,
+        cookie: Option<String>,
+    ) -> bool {
+        let feed_id = feed_key(rss_link);
+        {
+            let mut state = self.write_feed(feed_id);
+            let feed = match state.feeds.get_mut(&feed_id) {
+                Some(feed)
+                    if feed.subscribers.len() == 1 && feed.subscribers.contains(&subscriber) =>
+                {
+                    feed
+                }
+                _ => return false,
+            };
+            if feed.cookie == cookie {
+                return true;
+            }
+            feed.cookie = cookie;
+            feed.forget_responses();
+        }
+        self.save().unwrap_or_default();
+        true
+    }
+
+    /// `None` goes back to `--max-feed-size`, return `false` if nobody subscribed the feed
+    pub fn set_max_size(&self, rss_link: &str, max_size: Option<u64>) -> bool {
+        let feed_id = feed_key(rss_link);
+        match self.write_feed(feed_id).feeds.get_mut(&feed_id) {
+            Some(feed) => feed.max_size = max_size,
+            None => return false,
+        }
+        self.save().unwrap_or_default();
+        true
+    }
+
+    /// Set by /insecure, unlike the other quirks it's never learned
+    pub fn set_insecure(&self, rss_link: &str, insecure: bool) -> bool {
+        let feed_id = feed_key(rss_link);
+        match self.write_feed(feed_id).feeds.get_mut(&feed_id) {
+            Some(feed) if feed.quirks.insecure == insecure => return true,
+            Some(feed) => {
+                feed.quirks.insecure = insecure;
+                feed.forget_responses();
+            }
+            None => return false,
+        }
+        self.save().unwrap_or_default();
+        true
+    }
+
+    /// Forget the workarounds learned for the feed, for /refresh.
+    /// Return `false` if nobody subscribed the feed
+    pub fn forget_responses(&self, rss_link: &str) -> bool {
+        let feed_id = feed_key(rss_link);
+        match self.write_feed(feed_id).feeds.get_mut(&feed_id) {
+            Some(feed) => feed.forget_responses(),
+            None => return false,
+        }
+        self.save().unwrap_or_default();
+        true
+    }
+
+    /// The feed answered 304 Not Modified, it's working again if it was failing.
+    /// Not suspect either, the validators are only of accepted responses. The `pending`
+    /// items aren't counted down, they are only seen again in a full response
+    pub fn record_not_modified(&self, rss_link: &str) {
+        let feed_id = feed_key(rss_link);
+        match self.write_feed(feed_id).feeds.get_mut(&feed_id) {
+            Some(feed) if feed.down_time.is_some() || feed.failures > 0 || feed.suspect => {
+                feed.down_time = None;
+                feed.failures = 0;
+                feed.next_fetch = None;
+                feed.failing_since = None;
+                feed.blocked = false;
+                feed.suspect = false;
+                feed.error_notices.clear();
+            }
+            _ => return,
+        }
+        self.save().unwrap_or_default();
+    }
+
+    /// Only saved when they changed
+    pub fn set_quirks(&self, rss_link: &str, quirks: Quirks) {
+        let feed_id = feed_key(rss_link);
+        match self.write_feed(feed_id).feeds.get_mut(&feed_id) {
+            Some(feed) if feed.quirks != quirks => feed.quirks = quirks,
+            _ => return,
+        }
+        self.save().unwrap_or_default();
+    }
+
+    /// Hashes of the items seen in the feed, newest first, for `/export json full`
+    pub fn seen_hashes(&self, rss_link: &str) -> Vec<u64> {
+        let feed_id = feed_key(rss_link);
+        self.hash_list_shard(feed_id)
+            .get(&feed_id)
+            .map(SeenHashes::to_list)
+            .unwrap_or_default()
+    }
+
+    /// Mark the items seen by another instance of the bot as seen, older than the ones
+    /// seen here. Only for feeds subscribed by this subscriber alone,
+    /// the others would miss the items.
+    pub fn import_seen_hashes(
+        &self,
+        subscriber: SubscriberId,
+        rss_link: &str,
+        hashes: &[u64],
+    ) -> bool {
+        let feed_id = feed_key(rss_link);
+        {
+            let state = self.read();
+            let item_count = match state.feeds.get(&feed_id) {
+                Some(feed)
+                    if feed.subscribers.len() == 1 && feed.subscribers.contains(&subscriber) =>
+                {
+                    feed.item_count
+                }
+                _ => return false,
+            };
+            let mut hash_lists = self.hash_list_shard_mut(feed_id);
+            let hash_list = hash_lists.entry(feed_id).or_default();
+            let mut list = hash_list.to_list();
+            list.extend(hashes.iter().filter(|&&hash| !hash_list.contains(hash)));
+            *hash_list = SeenHashes::from_list(list);
+            hash_list.evict(self.hash_capacity(item_count), &HashSet::default());
+        }
+        self.save().unwrap_or_default();
+        true
+    }
+
+    /// `None` if feed not found
+    pub fn feed_debug_info(&self, rss_link: &str) -> Option<FeedDebugInfo> {
+        let feed_id = feed_key(rss_link);
+        let feed = self.read().feeds.get(&feed_id)?.clone();
+        let seen_hashes = self
+            .hash_list_shard(feed_id)
+            .get(&feed_id)
+            .map_or(0, SeenHashes::len);
+        Some(FeedDebugInfo { feed, seen_hashes })
+    }
+
+    pub fn unsubscribe(&self, subscriber: SubscriberId, rss_link: &str) -> Option<Feed> {
+        let feed_id = feed_key(rss_link);
+        let result = {
+            let mut state = self.write_feed(feed_id);
+            Self::unsubscribe_locked(&mut state, subscriber, feed_id, |feed_id, link| {
+                self.forget_feed(feed_id, link)
+            })?
+        };
+        self.save().unwrap_or_default();
+        Some(result)
+    }
+
+    fn unsubscribe_locked<F: FnOnce(FeedId, &str)>(
+        state: &mut State,
+        subscriber: SubscriberId,
+        feed_id: FeedId,
+        on_feed_removed: F,
+    ) -> Option<Feed> {
+        let clear_subscriber;
+        if let Some(subscribed_feeds) = state.subscribers.get_mut(&subscriber) {
+            if subscribed_feeds.remove(&feed_id) {
+                clear_subscriber = subscribed_feeds.is_empty();
+            } else {
+                return None;
+            }
+        } else {
+            return None;
+        }
+        if clear_subscriber {
+            state.subscribers.remove(&subscriber);
+        }
+
+        let result;
+        let clear_feed;
+        if let Some(feed) = state.feeds.get_mut(&feed_id) {
+            if feed.subscribers.remove(&subscriber) {
+                feed.subscriptions.remove(&subscriber);
+                feed.activity.remove(&subscriber);
+                feed.digest_items.remove(&subscriber);
+                clear_feed = feed.subscribers.is_empty();
+                result = feed.clone();
+            } else {
+                return None;
+            }
+        } else {
+            return None;
+        };
+        if clear_feed {
+            state.feeds.remove(&feed_id);
+            on_feed_removed(feed_id, &result.link);
+        }
+        Some(result)
+    }
+
+    pub fn set_subscribed_by(&self, subscriber: SubscriberId, rss_link: &str, user_id: i64) {
+        self.replace_subscribed_by(subscriber, rss_link, Some(user_id));
+    }
+
+    /// Who subscribed the group or channel to the feed, `None` if nobody is known to
+    pub fn subscription_owner(&self, subscriber: SubscriberId, rss_link: &str) -> Option<i64> {
+        let state = self.read();
+        let activity = state.feeds.get(&feed_key(rss_link))?.activity.get(&subscriber)?;
+        activity.subscribed_by.filter(|&user| user != subscriber)
+    }
+
+    /// The owner left the chat, any of its admins can change the subscription now
+    pub fn disown_subscription(&self, subscriber: SubscriberId, rss_link: &str) {
+        self.replace_subscribed_by(subscriber, rss_link, None);
+    }
+
+    fn replace_subscribed_by(&self, subscriber: SubscriberId, rss_link: &str, user: Option<i64>) {
+        let feed_id = feed_key(rss_link);
+        {
+            let mut state = self.write_feed(feed_id);
+            let activity = state
+                .feeds
+                .get_mut(&feed_id)
+                .and_then(|feed| feed.activity.get_mut(&subscriber));
+            match activity {
+                Some(activity) => activity.subscribed_by = user,
+                None => return,
+            }
+        }
+        self.save().unwrap_or_default();
+    }
+
+    /// The users who subscribed feeds for the chat, other than the chat itself
+    pub fn subscribed_by(&self, subscriber: SubscriberId) -> Vec<i64> {
+        let state = self.read();
+        let mut users: Vec<i64> = state
+            .subscribers
+            .get(&subscriber)
+            .into_iter()
+            .flatten()
+            .filter_map(|feed_id| state.feeds.get(feed_id)?.activity.get(&subscriber))
+            .filter_map(|activity| activity.subscribed_by)
+            .filter(|&user| user != subscriber)
+            .collect();
+        users.sort_unstable();
+        users.dedup();
+        users
+    }
+
+    /// Remove the chat with its subscriptions, return how many subscriptions it had,
+    /// `None` if there was nothing to remove. A dry run only counts them
+    pub fn delete_subscriber(
+        &self,
+        subscriber: SubscriberId,
+        execution: Execution,
+    ) -> Option<usize> {
+        let subscriptions = {
+            let mut state = self.write();
+            let feeds = state.subscribers.get(&subscriber).cloned();
+            if feeds.is_none() && !state.chats.contains_key(&subscriber) {
+                return None;
+            }
+            let subscriptions = feeds.as_ref().map_or(0, |feeds| feeds.len());
+            if execution == Execution::DryRun {
+                return Some(subscriptions);
+            }
+            state.chats.remove(&subscriber);
+            for feed_id in feeds.into_iter().flatten() {
+                let _ = Self::unsubscribe_locked(&mut state, subscriber, feed_id, |id, link| {
+                    self.forget_feed(id, link)
+                });
+            }
+            subscriptions
+        };
+        self.save().unwrap_or_default();
+        Some(subscriptions)
+    }
+
+    pub fn update_subscriber(&self, from: SubscriberId, to: SubscriberId) -> bool {
+        let mut state = self.write();
+        let State {
+            feeds,
+            subscribers,
+            chats,
+            ..
+        } = &mut *state;
+        if let Some(chat) = chats.remove(&from) {
+            chats.insert(to, chat);
+        }
+        subscribers
+            .remove(&from)
+            .map(|subscribed_feeds| {
+                for feed_id in &subscribed_feeds {
+                    let feed = feeds.get_mut(feed_id).unwrap();
+                    feed.subscribers.remove(&from);
+                    feed.subscribers.insert(to);
+                    if let Some(subscription) = feed.subscriptions.remove(&from) {
+                        feed.subscriptions.insert(to, subscription);
+                    }
+                    if let Some(activity) = feed.activity.remove(&from) {
+                        feed.activity.insert(to, activity);
+                    }
+                    if let Some(items) = feed.digest_items.remove(&from) {
+                        feed.digest_items.insert(to, items);
+                    }
+                }
+                subscribers.insert(to, subscribed_feeds);
+            })
+            .is_some()
+    }
+
+    /// Return `false` if not subscribed
+    pub fn update_subscription<F>(&self, subscriber: SubscriberId, rss_link: &str, f: F) -> bool
+    where
+        F: FnOnce(&mut Subscription),
+    {
+        let feed_id = feed_key(rss_link);
+        {
+            let mut state = self.write_feed(feed_id);
+            let feed = match state.feeds.get_mut(&feed_id) {
+                Some(feed) if feed.subscribers.contains(&subscriber) => feed,
+                _ => return false,
+            };
+            let subscription = feed.subscriptions.entry(subscriber).or_default();
+            f(subscription);
+            if *subscription == Subscription::default() {
+                feed.subscriptions.remove(&subscriber);
+            }
+        }
+        self.save().unwrap_or_default();
+        true
+    }
+
+    /// Count the items dropped as too old for `subscribers`, until the next delivery
+    pub fn record_stale_items(&self, rss_link: &str, subscribers: &[SubscriberId], count: u32) {
+        let feed_id = feed_key(rss_link);
+        {
+            let mut state = self.write_feed(feed_id);
+            let feed = match state.feeds.get_mut(&feed_id) {
+                Some(feed) => feed,
+                None => return,
+            };
+            for subscriber in subscribers {
+                if feed.subscribers.contains(subscriber) {
+                    let activity = feed.activity.entry(*subscriber).or_default();
+                    activity.stale_skipped = activity.stale_skipped.saturating_add(count);
+                }
+            }
+        }
+        self.save().unwrap_or_default();
+    }
+
+    /// Call only after the `items` were sent to `subscribers`, the chats with
+    /// `cross_dedup` on remember their links
+    pub fn record_delivery(
+        &self,
+        rss_link: &str,
+        subscribers: &[SubscriberId],
+        items: &[feed::Item],
+        time: SystemTime,
+    ) {
+        let feed_id = feed_key(rss_link);
+        let at = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let hashes: Vec<u64> = items
+            .iter()
+            .filter_map(|item| item.link.as_deref())
+            .map(|link| gen_hash(&normalize_link(link)))
+            .collect();
+        {
+            let mut state = self.write_marking(|dirty| {
+                dirty.feeds.insert(feed_id);
+                dirty.chats.extend(subscribers);
+            });
+            let feed = match state.feeds.get_mut(&feed_id) {
+                Some(feed) => feed,
+                None => return,
+            };
+            // Unsubscribed while delivering
+            let subscribers: Vec<SubscriberId> = subscribers
+                .iter()
+                .copied()
+                .filter(|subscriber| feed.subscribers.contains(subscriber))
+                .collect();
+            for subscriber in &subscribers {
+                let activity = feed.activity.entry(*subscriber).or_default();
+                activity.last_delivered = Some(time);
+                activity.stale_skipped = 0;
+            }
+            for subscriber in &subscribers {
+                let chat = match state.chats.get_mut(subscriber) {
+                    Some(chat) if chat.cross_dedup && !hashes.is_empty() => chat,
+                    _ => continue,
+                };
+                let links = Arc::make_mut(&mut chat.recent_links);
+                links.retain(|DeliveredLink(hash, feed, _)| {
+                    *feed != feed_id || !hashes.contains(hash)
+                });
+                let delivered = hashes.iter().map(|&hash| DeliveredLink(hash, feed_id, at));
+                links.splice(0..0, delivered);
+                links.truncate(CROSS_DEDUP_LINKS);
+            }
+        }
+        self.save().unwrap_or_default();
+    }
+
+    /// Keep the items for the digests of `subscribers`, instead of sending them now
+    pub fn queue_digest_items(
+        &self,
+        rss_link: &str,
+        subscribers: &[SubscriberId],
+        items: &[feed::Item],
+    ) {
+        let feed_id = feed_key(rss_link);
+        {
+            let mut state = self.write_feed(feed_id);
+            match state.feeds.get_mut(&feed_id) {
+                Some(feed) => feed.queue_digest_items(subscribers, items),
+                None => return,
+            }
+        }
+        self.save().unwrap_or_default();
+    }
+
+    /// Take the items of the digests whose time has come, with their feed and subscriber.
+    /// They stay kept until `digest_sent`, a digest that failed, or that a restart cut
+    /// short, has them in the next one. Until then the digest isn't taken again.
+    /// The items kept for a digest that was turned off are due right away
+    pub fn take_due_digests<Tz: chrono::TimeZone>(
+        &self,
+        now: &chrono::DateTime<Tz>,
+    ) -> Vec<(Feed, SubscriberId, Vec<feed::Item>)> {
+        let due: Vec<(FeedId, SubscriberId)> = self
+            .read()
+            .feeds
+            .iter()
+            .flat_map(|(&feed_id, feed)| {
+                let digest = |subscriber: &SubscriberId| feed.subscriptions.get(subscriber)?.digest;
+                let scheduled = feed
+                    .subscriptions
+                    .iter()
+                    .filter(|(_, s)| s.digest.is_some_and(|digest| digest.is_due(now)))
+                    .map(|(&subscriber, _)| subscriber);
+                let unscheduled = feed
+                    .digest_items
+                    .keys()
+                    .copied()
+                    .filter(move |subscriber| digest(subscriber).is_none());
+                scheduled
+                    .chain(unscheduled)
+                    .map(move |subscriber| (feed_id, subscriber))
+            })
+            .collect();
+        if due.is_empty() {
+            return Vec::new();
+        }
+        let time = now.timestamp();
+        let mut result = Vec::new();
+        // Not saved, `digest_sent` saves the time with the items that are gone
+        let mut state = self.write_marking(|dirty| {
+            dirty.feeds.extend(due.iter().map(|(feed_id, _)| *feed_id));
+        });
+        let State {
+            feeds,
+            sending_digests,
+            ..
+        } = &mut *state;
+        for (feed_id, subscriber) in due {
+            let feed = match feeds.get_mut(&feed_id) {
+                Some(feed) => feed,
+                None => continue,
+            };
+            let subscription = feed.subscriptions.get_mut(&subscriber);
+            if let Some(digest) = subscription.and_then(|s| s.digest.as_mut()) {
+                digest.last_sent = time;
+            }
+            let items = match feed.digest_items.get(&subscriber) {
+                Some(items) if sending_digests.insert((feed_id, subscriber)) => items,
+                _ => continue,
+            };
+            let items = items.iter().cloned().map(DigestItem::into_item).collect();
+            result.push((feed.clone(), subscriber, items));
+        }
+        result
+    }
+
+    /// The digest taken by `take_due_digests` was sent with these `items`, or failed
+    /// with `None`, its items are then kept for the next one
+    pub fn digest_sent(
+        &self,
+        rss_link: &str,
+        subscriber: SubscriberId,
+        items: Option<&[feed::Item]>,
+    ) {
+        let feed_id = feed_key(rss_link);
+        {
+            let mut state = self.write_feed(feed_id);
+            state.sending_digests.remove(&(feed_id, subscriber));
+            let (feed, items) = match (state.feeds.get_mut(&feed_id), items) {
+                (Some(feed), Some(items)) => (feed, items),
+                _ => return,
+            };
+            if let Some(queued) = feed.digest_items.get_mut(&subscriber) {
+                // The ones taken are the oldest, the ones kept while it was sent are
+                // before them, even if an item that came again is equal to a sent one
+                queued.truncate(queued.len().saturating_sub(items.len()));
+                if queued.is_empty() {
+                    feed.digest_items.remove(&subscriber);
+                }
+            }
+        }
+        self.save().unwrap_or_default();
+    }
+
+    /// Update the feed in database, return updates. The validators stored are kept
+    pub fn update(&self, rss_link: &str, new_feed: feed::Rss) -> Vec<FeedUpdate> {
+        self.update_validated(rss_link, new_feed, None)
+    }
+
+    /// Like `update`, for an accepted response with its validators, or `None` to keep
+    /// the ones stored. Saved with the rest, a skipped response keeps the old ones
+    pub fn update_validated(
+        &self,
+        rss_link: &str,
+        new_feed: feed::Rss,
+        validators: Option<Validators>,
+    ) -> Vec<FeedUpdate> {
+        let feed_id = feed_key(rss_link);
+        let (
+            old_title,
+            old_home_page,
+            old_ttl,
+            was_down,
+            old_item_count,
+            was_suspect,
+            was_pending,
+            old_recent_keys,
+        ) = match self.read().feeds.get(&feed_id) {
+            Some(feed) => (
+                feed.title.clone(),
+                feed.home_page.clone(),
+                feed.ttl,
+                feed.down_time.is_some() || feed.failures > 0,
+                feed.item_count,
+                feed.suspect,
+                feed.pending.clone(),
+                feed.recent_keys.clone(),
+            ),
+            None => return Vec::new(),
+        };
+        let failing_since = self.read().feeds.get(&feed_id).and_then(|f| f.failing_since);
+        self.remember_items(feed_id, &new_feed.items);
+        let item_count = new_feed.items.len();
+        let item_count_changed = item_count != old_item_count;
+        let home_page_changed = new_feed.meta.link != old_home_page;
+
+        let mut updates = Vec::new();
+        let mut pending = Vec::new();
+        let mut embargoed = Vec::new();
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let mut dates_changed = false;
+        let new_count;
+        let mut recent_keys: Vec<u64>;
+        if new_feed.items.iter().any(|item| item.published.is_some()) {
+            if let Some(feed) = self.write_feed(feed_id).feeds.get_mut(&feed_id) {
+                dates_changed = feed.record_item_dates(&new_feed.items);
+            }
+        }
+        {
+            // Only this feed's shard is locked while comparing the items
+            let mut hash_lists = self.hash_list_shard_mut(feed_id);
+            let hash_list = match hash_lists.get_mut(&feed_id) {
+                Some(hash_list) => hash_list,
+                // user unsubscribed while fetching the feed
+                None => return Vec::new(),
+            };
+            let mut new_items = Vec::new();
+            let mut seen = Vec::with_capacity(new_feed.items.len());
+            let capacity = self.hash_capacity(new_feed.items.len());
+            let recent: HashSet<u64, Size64> = old_recent_keys.iter().copied().collect();
+            recent_keys = new_feed
+                .items
+                .iter()
+                .flat_map(|item| secondary_keys(item, &new_feed.meta.link))
+                .collect();
+            for item in new_feed.items {
+                let hash = gen_item_hash(&item);
+                if is_known(hash_list, &item) {
+                    seen.push(hash);
+                    continue;
+                }
+                // Without a GUID the hash is of the title and link already. Silently seen,
+                // there is no telling what changed
+                if item.id.is_some()
+                    && secondary_keys(&item, &new_feed.meta.link).any(|key| recent.contains(&key))
+                {
+                    seen.push(hash);
+                    continue;
+                }
+                // Delivered by `take_due_items` once it's time, or by a later fetch
+                if embargoed.len() < MAX_PENDING_ITEMS && is_embargoed(&item, now) {
+                    embargoed.push(item);
+                    continue;
+                }
+                // Not recorded as seen while pending, so it's still new once filled in
+                if self.pending_cycles > 0
+                    && pending.len() < MAX_PENDING_ITEMS
+                    && is_placeholder_link(&item, &new_feed.meta.link)
+                {
+                    let held = was_pending
+                        .iter()
+                        .find(|(pending_hash, _)| *pending_hash == hash)
+                        .map_or(0, |(_, held)| *held);
+                    if held < self.pending_cycles {
+                        pending.push((hash, held + 1));
+                        continue;
+                    }
+                }
+                seen.push(hash);
+                new_items.push(item);
+            }
+            hash_list.record(&seen, capacity);
+            if let Some(since) = failing_since {
+                let down_for = SystemTime::now().duration_since(since).unwrap_or_default();
+                // Undated items may have been published any time
+                let missed = new_items
+                    .iter()
+                    .filter(|item| item.published.is_none_or(|t| SystemTime::from(t) >= since))
+                    .count();
+                if down_for > RECOVERY_NOTICE_AFTER && missed > 0 {
+                    updates.push(FeedUpdate::Recovered { down_for, missed });
+                }
+            }
+            new_count = new_items.len();
+            if !new_items.is_empty() {
+                updates.push(FeedUpdate::Items(new_items));
+            }
+        }
+        if new_count > 0 {
+            if let Some(feed) = self.write_feed(feed_id).feeds.get_mut(&feed_id) {
+                feed.record_new_items(new_count, now);
+            }
+        }
+        {
+            let mut held = self.embargoed.write().unwrap();
+            let since: HashMap<u64, SystemTime> = held
+                .remove(&feed_id)
+                .into_iter()
+                .flatten()
+                .map(|(item, since)| (gen_item_hash(&item), since))
+                .collect();
+            if !embargoed.is_empty() {
+                let embargoed = embargoed
+                    .into_iter()
+                    .map(|item| {
+                        let since = since.get(&gen_item_hash(&item)).copied();
+                        (item, since.unwrap_or_else(SystemTime::now))
+                    })
+                    .collect();
+                held.insert(feed_id, embargoed);
+            }
+        }
+        let pending_changed = pending != was_pending;
+        if pending_changed {
+            if let Some(feed) = self.write_feed(feed_id).feeds.get_mut(&feed_id) {
+                feed.pending = pending;
+            }
+        }
+        // The ones of the items that left the feed are kept as long as there's room
+        let max_recent_keys = 2 * self.hash_capacity(item_count);
+        let kept: HashSet<u64, Size64> = recent_keys.iter().copied().collect();
+        recent_keys.extend(old_recent_keys.iter().filter(|key| !kept.contains(key)));
+        recent_keys.truncate(max_recent_keys);
+        let recent_keys_changed = recent_keys != old_recent_keys;
+        if recent_keys_changed {
+            if let Some(feed) = self.write_feed(feed_id).feeds.get_mut(&feed_id) {
+                feed.recent_keys = recent_keys;
+            }
+        }
+        let title_changed = new_feed.meta.title != old_title;
+        if was_down
+            || was_suspect
+            || item_count_changed
+            || home_page_changed
+            || title_changed
+            || new_feed.meta.ttl != old_ttl
+        {
+            if let Some(feed) = self.write_feed(feed_id).feeds.get_mut(&feed_id) {
+                feed.down_time = None;
+                feed.failures = 0;
+                feed.next_fetch = None;
+                feed.failing_since = None;
+                feed.blocked = false;
+                feed.suspect = false;
+                feed.error_notices.clear();
+                feed.item_count = item_count;
+                if title_changed {
+                    if feed.note_title_change(&new_feed.meta.title, now) {
+                        updates.push(FeedUpdate::Title(new_feed.meta.title.clone()));
+                    }
+                }
+                if home_page_changed && feed.note_home_page_change(&new_feed.meta.link, now) {
+                    updates.push(FeedUpdate::HomePage(new_feed.meta.link.clone()));
+                }
+                feed.title = new_feed.meta.title.clone();
+                feed.home_page = new_feed.meta.link.clone();
+                feed.ttl = new_feed.meta.ttl;
+            }
+        }
+        let meta_changed = self
+            .write_feed(feed_id)
+            .feeds
+            .get_mut(&feed_id)
+            .is_some_and(|feed| feed.record_meta(&new_feed.meta));
+        let mut state = self.write_feed(feed_id);
+        let validators_changed = match (state.feeds.get_mut(&feed_id), validators) {
+            (Some(feed), Some(validators)) if feed.validators != validators => {
+                feed.validators = validators;
+                true
+            }
+            _ => false,
+        };
+        drop(state);
+        if !updates.is_empty()
+            || dates_changed
+            || pending_changed
+            || recent_keys_changed
+            || was_down
+            || item_count_changed
+            || home_page_changed
+            || title_changed
+            || meta_changed
+            || validators_changed
+        {
+            self.save().unwrap_or_default();
+        }
+        updates
+    }
+
+    /// The items held for their publish date that are due at `now`, with their feeds.
+    /// They are recorded as seen, a fetch at the same time doesn't deliver them again.
+    pub fn take_due_items(&self, now: i64) -> Vec<(Feed, Vec<feed::Item>)> {
+        let due: Vec<(FeedId, Vec<feed::Item>)> = {
+            let mut embargoed = self.embargoed.write().unwrap();
+            let mut due = Vec::new();
+            for (feed_id, items) in embargoed.iter_mut() {
+                let (ready, waiting): (Vec<_>, Vec<_>) = std::mem::take(items)
+                    .into_iter()
+                    .partition(|(item, _)| !is_embargoed(item, now));
+                *items = waiting;
+                if !ready.is_empty() {
+                    due.push((*feed_id, ready.into_iter().map(|(item, _)| item).collect()));
+                }
+            }
+            embargoed.retain(|_, items| !items.is_empty());
+            due
+        };
+        let mut result = Vec::new();
+        for (feed_id, items) in due {
+            let feed = match self.read().feeds.get(&feed_id) {
+                Some(feed) => feed.clone(),
+                None => continue,
+            };
+            let new_items: Vec<_> = {
+                let mut hash_lists = self.hash_list_shard_mut(feed_id);
+                let hash_list = match hash_lists.get_mut(&feed_id) {
+                    Some(hash_list) => hash_list,
+                    None => continue,
+                };
+                items
+                    .into_iter()
+                    .filter(|item| {
+                        let new = !is_known(hash_list, item);
+                        hash_list.insert(gen_item_hash(item));
+                        new
+                    })
+                    .collect()
+            };
+            if !new_items.is_empty() {
+                result.push((feed, new_items));
+            }
+        }
+        if !result.is_empty() {
+            self.save().unwrap_or_default();
+        }
+        result
+    }
+
+    /// The items held back from the subscriber, or from everyone: the ones dated in
+    /// the future, and the ones waiting for a real link until `next_fetch` of their feed.
+    /// Taken under the same locks, so they agree with each other
+    pub fn held_items(
+        &self,
+        subscriber: Option<SubscriberId>,
+        next_fetch: impl Fn(&Feed) -> SystemTime,
+    ) -> (QueueSnapshot, QueueSnapshot) {
+        let state = self.read();
+        let feeds: Vec<(&FeedId, &Feed)> = match subscriber {
+            Some(subscriber) => state
+                .subscribers
+                .get(&subscriber)
+                .into_iter()
+                .flatten()
+                .filter_map(|feed_id| state.feeds.get_key_value(feed_id))
+                .collect(),
+            None => state.feeds.iter().collect(),
+        };
+
+        let mut dated = Vec::new();
+        {
+            let embargoed = self.embargoed.read().unwrap();
+            for (feed_id, _) in &feeds {
+                for (item, since) in embargoed.get(*feed_id).into_iter().flatten() {
+                    let published = item.published.map_or(0, |t| t.timestamp());
+                    let title = item.title.as_ref().or(item.link.as_ref());
+                    dated.push((published, *since, title.cloned().unwrap_or_default()));
+                }
+            }
+        }
+        dated.sort_by(|a, b| (a.0, &a.2).cmp(&(b.0, &b.2)));
+        let future_dated = QueueSnapshot {
+            queued: dated.len(),
+            oldest: dated.iter().map(|(_, since, _)| *since).min(),
+            // Due a little before their date, like a wrong clock
+            next_flush: dated.first().map(|(published, _, _)| {
+                UNIX_EPOCH + Duration::from_secs((published - FUTURE_DATE_SKEW).max(0) as u64)
+            }),
+            titles: dated.into_iter().map(|(_, _, title)| title).collect(),
+        };
+
+        // Only the hashes are kept, not the titles
+        let waiting: Vec<&Feed> = feeds
+            .iter()
+            .map(|(_, feed)| *feed)
+            .filter(|feed| !feed.pending.is_empty())
+            .collect();
+        let pending_links = QueueSnapshot {
+            queued: waiting.iter().map(|feed| feed.pending.len()).sum(),
+            oldest: None,
+            next_flush: waiting.iter().map(|feed| next_fetch(feed)).min(),
+            titles: Vec::new(),
+        };
+        (future_dated, pending_links)
+    }
+
+    pub fn save(&self) -> Result<(), DataError> {
+        let _saving = self.saving.lock().unwrap();
+        let result = match &self.sqlite {
+            Some(store) => self.save_rows(store),
+            None => self.save_file(),
+        };
+        HEARTBEATS.database_saved(result.is_ok());
+        result
+    }
+
+    /// Only the feeds and chats marked dirty are copied and serialized
+    fn save_rows(&self, store: &sqlite::Store) -> Result<(), DataError> {
+        let dirty = std::mem::take(&mut *self.dirty.lock().unwrap());
+        let rows = {
+            let state = self.read();
+            let feed_ids: Vec<FeedId> = if dirty.all {
+                state.feeds.keys().copied().collect()
+            } else {
+                dirty.feeds.iter().copied().collect()
+            };
+            let chat_ids: Vec<SubscriberId> = if dirty.all {
+                state.chats.keys().copied().collect()
+            } else {
+                dirty.chats.iter().copied().collect()
+            };
+            let feeds = feed_ids
+                .into_iter()
+                .map(|feed_id| {
+                    let feed = state.feeds.get(&feed_id).map(|feed| {
+                        let hash_list = self
+                            .hash_list_shard(feed_id)
+                            .get(&feed_id)
+                            .map(SeenHashes::to_list)
+                            .unwrap_or_default();
+                        (feed.clone(), hash_list)
+                    });
+                    (feed_id, feed)
+                })
+                .collect();
+            sqlite::Rows {
+                feeds,
+                chats: chat_ids
+                    .into_iter()
+                    .map(|chat_id| (chat_id, state.chats.get(&chat_id).cloned()))
+                    .collect(),
+                global: (dirty.all || dirty.global).then(|| state.global.clone()),
+                all: dirty.all,
+            }
+        };
+        let result = store.save(rows);
+        if result.is_err() {
+            // Tried again by the next save
+            self.dirty.lock().unwrap().merge(dirty);
+        }
+        result
+    }
+
+    fn save_file(&self) -> Result<(), DataError> {
+        // Copy a snapshot, so the locks are not held while serializing
+        let (snapshot, chats, global): (Vec<(Feed, Vec<u64>)>, _, _) = {
+            let state = self.read();
+            let feeds = state
+                .feeds
+                .iter()
+                .map(|(feed_id, feed)| {
+                    let hash_list = self
+                        .hash_list_shard(*feed_id)
+                        .get(feed_id)
+                        .map(SeenHashes::to_list)
+                        .unwrap_or_default();
+                    (feed.clone(), hash_list)
+                })
+                .collect();
+            (feeds, state.chats.clone(), state.global.clone())
+        };
+        let database_file = DatabaseFileRef {
+            feeds: snapshot
+                .iter()
+                .map(|(feed, hash_list)| FeedRecordRef::new(feed, hash_list))
+                .collect(),
+            chats: &chats,
+            global: &global,
+        };
+        let file = AtomicFile::new(&self.path, OverwriteBehavior::AllowOverwrite);
+        file.write(|file| serde_json::to_writer(file, &database_file))
+            .map_err(|e| match e {
+                atomicwrites::Error::Internal(e) => DataError::Io(e),
+                atomicwrites::Error::User(e) => {
+                    assert!(!e.is_io(), "unreachable code");
+                    DataError::Io(e.into())
+                }
+            })
+    }
+}
+
+/// A lock file next to the database, the database itself is replaced by every save.
+/// It has the PID of the process holding it, the lock is gone when that exits.
+fn lock_database(path: &Path) -> Result<File, DataError> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(lock_path)?;
+    match file.try_lock() {
+        Ok(()) => (),
+        Err(std::fs::TryLockError::WouldBlock) => {
+            let mut pid = String::new();
+            let holder = match file.read_to_string(&mut pid) {
+                Ok(_) if !pid.trim().is_empty() => format!("PID {}", pid.trim()),
+                _ => "another process".to_string(),
+            };
+            return Err(DataError::Locked(holder));
+        }
+        Err(std::fs::TryLockError::Error(e)) => return Err(e.into()),
+    }
+    file.set_len(0)?;
+    write!(file, "{}", std::process::id())?;
+    Ok(file)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ChatName {
+    title: Option<String>,
+    username: Option<String>,
+    seen: SystemTime,
+}
+
+/// Remember the names of a chat or user who sent a command, or that was looked up
+pub fn remember_chat_name(chat_id: SubscriberId, title: Option<&str>, username: Option<&str>) {
+    let title = title.map(str::trim).filter(|s| !s.is_empty()).map(String::from);
+    let username = username.filter(|s| !s.is_empty()).map(String::from);
+    let mut names = CHAT_NAMES.write().unwrap();
+    names.insert(
+        chat_id,
+        ChatName {
+            title,
+            username,
+            seen: SystemTime::now(),
+        },
+    );
+    if names.len() > MAX_CHAT_NAMES {
+        let oldest = names.iter().min_by_key(|(_, name)| name.seen).map(|(id, _)| *id);
+        if let Some(id) = oldest {
+            names.remove(&id);
+        }
+    }
+}
+
+/// `Title @username`, `None` if the names are unknown or stale
+pub fn chat_name(chat_id: SubscriberId) -> Option<String> {
+    let name = known_name(chat_id)?;
+    match (&name.title, &name.username) {
+        (Some(title), Some(username)) => Some(format!("{} @{}", title, username)),
+        (Some(title), None) => Some(title.clone()),
+        (None, Some(username)) => Some(format!("@{}", username)),
+        (None, None) => None,
+    }
+}
+
+/// The title of the chat or the first name of the user, else the username
+pub fn chat_title(chat_id: SubscriberId) -> Option<String> {
+    let name = known_name(chat_id)?;
+    name.title.or(name.username)
+}
+
+fn known_name(chat_id: SubscriberId) -> Option<ChatName> {
+    let names = CHAT_NAMES.read().unwrap();
+    names
+        .get(&chat_id)
+        .filter(|name| {
+            let age = SystemTime::now().duration_since(name.seen).unwrap_or_default();
+            age < CHAT_NAME_TTL
+        })
+        .cloned()
+}
+
+/// The name and ID of the chat for the admins, only the ID if the name isn't known
+pub fn chat_display(chat_id: SubscriberId) -> String {
+    match chat_name(chat_id) {
+        Some(name) => format!("{} ({})", name, chat_id),
+        None => chat_id.to_string(),
+    }
+}
+
+// The migrations `Database::open` applies, see `DatabaseFile::pending_migrations`
+const LEGACY_FORMAT: &str = "legacy_format";
+const NORMALIZED_LINKS: &str = "normalized_links";
+
+/// A migration applied by `Database::open`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Migration {
+    name: &'static str,
+    /// Feeds in the database
+    touched: usize,
+    /// Feeds merged into another one
+    merged: usize,
+    /// Subscriptions lost in the merges, the chats had their own settings for both
+    dropped: usize,
+    elapsed: Duration,
+}
+
+#[derive(Debug, Default)]
+struct MigrationReport {
+    migrations: Vec<Migration>,
+    /// The database as it was before them
+    backup: Option<PathBuf>,
+}
+
+impl fmt::Display for MigrationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(backup) = &self.backup {
+            writeln!(f, "Database backed up to {} before migrating", backup.display())?;
+        }
+        for migration in &self.migrations {
+            writeln!(
+                f,
+                "Migration {}: {} feeds, {} merged, {} subscriptions dropped, in {:?}",
+                migration.name,
+                migration.touched,
+                migration.merged,
+                migration.dropped,
+                migration.elapsed
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl DatabaseFile {
+    fn pending_migrations(&self) -> Vec<&'static str> {
+        let mut pending = Vec::new();
+        let feeds = match self {
+            DatabaseFile::Current { feeds, .. } => feeds,
+            DatabaseFile::Legacy(feeds) => {
+                pending.push(LEGACY_FORMAT);
+                feeds
+            }
+        };
+        if has_duplicate_links(feeds) {
+            pending.push(NORMALIZED_LINKS);
+        }
+        pending
+    }
+
+    fn into_contents(self, report: &mut MigrationReport) -> Contents {
+        match self {
+            DatabaseFile::Current {
+                feeds,
+                chats,
+                global,
+            } => (feeds, chats, global),
+            DatabaseFile::Legacy(feeds) => {
+                report.migrations.push(Migration {
+                    name: LEGACY_FORMAT,
+                    touched: feeds.len(),
+                    merged: 0,
+                    dropped: 0,
+                    elapsed: Duration::ZERO,
+                });
+                (feeds, HashMap::default(), Global::default())
+            }
+        }
+    }
+}
+
+/// Stored by links that differ only in case, `NORMALIZED_LINKS` merges them
+fn has_duplicate_links(feeds: &[FeedRecord]) -> bool {
+    let mut feed_ids = HashSet::with_capacity(feeds.len());
+    !feeds.iter().all(|record| feed_ids.insert(feed_key(&record.feed.link)))
+}
+
+/// Copy the database next to it, return the path of the copy
+fn backup_database(path: &Path) -> Result<PathBuf, DataError> {
+    let backup = backup_path(path);
+    std::fs::copy(path, &backup)?;
+    Ok(backup)
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".pre-migration-{}", now));
+    PathBuf::from(backup)
+}
+
+/// `--database`, a JSON file, or a SQLite database with `sqlite:path`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    Json(PathBuf),
+    Sqlite(PathBuf),
+}
+
+impl Location {
+    pub fn path(&self) -> &Path {
+        match self {
+            Location::Json(path) | Location::Sqlite(path) => path,
+        }
+    }
+}
+
+impl FromStr for Location {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("sqlite:") {
+            Some("") => Err("sqlite: needs the path of the database".into()),
+            Some(path) => Ok(Location::Sqlite(path.into())),
+            None => Ok(Location::Json(s.into())),
+        }
+    }
+}
+
+/// Whether the changes are made, or only reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Execution {
+    Apply,
+    DryRun,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscribeResult {
+    Subscribed,
+    AlreadySubscribed,
+}
+
+pub enum FeedUpdate {
+    /// Comes before the items, `missed` of them were published while the feed was failing
+    Recovered { down_for: Duration, missed: usize },
+    Items(Vec<feed::Item>),
+    Title(String),
+    /// The channel link of the feed moved to another site
+    HomePage(String),
+}
+
+/// Uses the link without tracking parameters,
+/// so cleaning the links or not doesn't change which items are new
+fn gen_item_hash(item: &feed::Item) -> u64 {
+    item.id.as_ref().map(|id| gen_hash(&id)).unwrap_or_else(|| {
+        let title = item.title.as_deref().unwrap_or_default();
+        let link = item.link.as_deref().unwrap_or_default();
+        gen_hash(&format!("{}{}", title, strip_tracking_params(link)))
+    })
+}
+
+/// Hashes of the items seen in a feed, in no particular order. Beyond the capacity
+/// the ones seen least recently are dropped, wherever their items are in the feed, so
+/// feeds that keep reordering the same items don't make them churn.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct SeenHashes {
+    /// To the order they were last seen in
+    inserted: HashMap<u64, u64, Size64>,
+    next: u64,
+}
+
+impl SeenHashes {
+    /// From the list stored on disk, last inserted first
+    fn from_list(list: Vec<u64>) -> Self {
+        let mut hashes = SeenHashes::default();
+        for hash in list.into_iter().rev() {
+            hashes.insert(hash);
+        }
+        hashes
+    }
+
+    fn to_list(&self) -> Vec<u64> {
+        let mut list: Vec<(u64, u64)> = self.inserted.iter().map(|(h, t)| (*t, *h)).collect();
+        list.sort_unstable_by(|a, b| b.cmp(a));
+        list.into_iter().map(|(_, hash)| hash).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.inserted.len()
+    }
+
+    fn contains(&self, hash: u64) -> bool {
+        self.inserted.contains_key(&hash)
+    }
+
+    /// Known hashes keep their place
+    fn insert(&mut self, hash: u64) {
+        if let Entry::Vacant(entry) = self.inserted.entry(hash) {
+            entry.insert(self.next);
+            self.next += 1;
+        }
+    }
+
+    /// Add the hashes of the last response, newest first like the feed, as the ones seen
+    /// last, then drop the oldest beyond `capacity`, never the ones of the last response.
+    /// They come first in `to_list`, so trimming a stored list to at least as many as the
+    /// response had keeps them too.
+    fn record(&mut self, seen: &[u64], capacity: usize) {
+        for &hash in seen.iter().rev() {
+            self.inserted.insert(hash, self.next);
+            self.next += 1;
+        }
+        let keep = seen.iter().copied().collect();
+        self.evict(capacity, &keep);
+    }
+
+    fn evict(&mut self, capacity: usize, keep: &HashSet<u64, Size64>) {
+        let excess = self.len().saturating_sub(capacity);
+        if excess == 0 {
+            return;
+        }
+        let mut oldest: Vec<(u64, u64)> = self
+            .inserted
+            .iter()
+            .filter(|(hash, _)| !keep.contains(*hash))
+            .map(|(hash, inserted)| (*inserted, *hash))
+            .collect();
+        oldest.sort_unstable();
+        for (_, hash) in oldest.into_iter().take(excess) {
+            self.inserted.remove(&hash);
+        }
+    }
+}
+
+/// The same post is often linked slightly differently by the feeds of a site
+pub fn normalize_link(link: &str) -> String {
+    let link = strip_tracking_params(link);
+    let link = link.split('#').next().unwrap_or_default();
+    link.trim_end_matches('/').to_string()
+}
+
+/// The hash of the normalized link together with the title of the item, if it has a link.
+/// Either alone is shared by different items: a link to the website, or titles like
+/// "Daily links". Items without a link of their own have none
+fn secondary_keys(item: &feed::Item, home_page: &str) -> impl Iterator<Item = u64> {
+    let title = item.title.as_deref().unwrap_or_default().trim();
+    item.link
+        .as_deref()
+        .filter(|_| !is_placeholder_link(item, home_page))
+        .map(|link| gen_hash(&("item", normalize_link(link.trim()), title)))
+        .into_iter()
+}
+
+/// The item has no link of its own yet, only the one of the website at most
+fn is_placeholder_link(item: &feed::Item, home_page: &str) -> bool {
+    let link = item.link.as_deref().unwrap_or_default().trim();
+    let home_page = home_page.trim().trim_end_matches('/');
+    link.is_empty() || (!home_page.is_empty() && link.trim_end_matches('/') == home_page)
+}
+
+/// Dated in the future, but not too far, at `now` in Unix seconds
+fn is_embargoed(item: &feed::Item, now: i64) -> bool {
+    item.published.is_some_and(|published| {
+        let ahead = published.timestamp() - now;
+        ahead > FUTURE_DATE_SKEW && ahead <= MAX_EMBARGO
+    })
+}
+
+/// Also accept the hash of the raw link, to not deliver items again
+/// whose hash was stored before the tracking parameters were stripped
+fn is_known(hash_list: &SeenHashes, item: &feed::Item) -> bool {
+    if hash_list.contains(gen_item_hash(item)) {
+        return true;
+    }
+    match (&item.id, &item.link) {
+        (None, Some(link)) if strip_tracking_params(link) != link.as_str() => {
+            let title = item.title.as_deref().unwrap_or_default();
+            hash_list.contains(gen_hash(&format!("{}{}", title, link)))
+        }
+        _ => false,
+    }
+}
+
+pub type Size64 = BuildHasherDefault<Size64Hasher>;
+
+/// A specialized hasher for u64 and i64
+///
+/// WARNING: Do not use it for user-controlled input
+#[derive(Default)]
+pub struct Size64Hasher {
+    finished: bool,
+    value: u64,
+}
+
+impl Hasher for Size64Hasher {
+    fn finish(&self) -> u64 {
+        self.value
+    }
+
+    fn write(&mut self, _bytes: &[u8]) {
+        panic!("only support u64 and i64");
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        assert!(
+            !self.finished,
+            "this is a special hasher, do not write twice"
+        );
+        self.value = i;
+        self.finished = true;
+    }
+
+    fn write_i64(&mut self, i: i64) {
+        self.write_u64(i as u64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::temp_database;
+
+    #[test]
+    fn size64hasher() {
+        let mut h = Size64Hasher::default();
+        h.write_i64(-42);
+        assert_eq!(h.finish(), -42i64 as u64);
+    }
+
+    #[test]
+    #[should_panic(expected = "this is a special hasher, do not write twice")]
+    fn size64hasher_write_twice() {
+        let mut h = Size64Hasher::default();
+        h.write_u64(42);
+        h.write_u64(42);
+    }
+
+    #[test]
+    #[should_panic(expected = "only support u64 and i64")]
+    fn size64hasher_other_types() {
+        let mut h = Size64Hasher::default();
+        h.write_u8(0);
+    }
+
+    #[test]
+    fn stable_hashes() {
+        // What `DefaultHasher` gave, the databases stored before can still be read
+        assert_eq!(gen_hash(&"https://example.com/feed.xml"), 5333986857289017617);
+        assert_eq!(feed_key("https://example.com/feed.xml"), 5333986857289017617);
+    }
+
+    #[test]
+    fn newer_hash_version() {
+        let (path, _) = temp_database("hash-version");
+        std::fs::write(
+            &path,
+            r#"{"feeds":[{"link":"http://example.com/feed.xml","title":"feed",
+                "subscribers":[1],"hash_list":[1,2,3],"hash_version":2}]}"#,
+        )
+        .unwrap();
+        match Database::open_exclusive(Location::Json(path.clone()), Execution::Apply) {
+            Err(DataError::HashVersion(2)) => (),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn rss_with_items(title: &str, items: std::ops::Range<usize>) -> feed::Rss {
+        feed::Rss {
+            meta: feed::FeedMeta {
+                title: title.into(),
+                ..Default::default()
+            },
+            items: items
+                .map(|i| feed::Item {
+                    title: Some(format!("item {}", i)),
+                    link: Some(format!("http://example.com/{}", i)),
+                    ..Default::default()
+                })
+                .collect(),
+        }
+    }
+
+    fn assert_consistent(db: &Database) {
+        let state = db.read();
+        for (subscriber, feeds) in &state.subscribers {
+            assert!(!feeds.is_empty());
+            for feed_id in feeds {
+                assert!(state.feeds[feed_id].subscribers.contains(subscriber));
+            }
+        }
+        for (feed_id, feed) in &state.feeds {
+            assert!(!feed.subscribers.is_empty());
+            for subscriber in &feed.subscribers {
+                assert!(state.subscribers[subscriber].contains(feed_id));
+            }
+            assert!(db.hash_list_shard(*feed_id).contains_key(feed_id));
+        }
+        let hash_lists: usize = db
+            .hash_lists
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum();
+        assert_eq!(hash_lists, state.feeds.len());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_access() {
+        const TASKS: i64 = 8;
+        const FEEDS: usize = 10;
+        let (path, db) = temp_database("concurrent");
+        let db = std::sync::Arc::new(db);
+
+        let mut tasks = Vec::new();
+        for subscriber in 0..TASKS {
+            let db = db.clone();
+            tasks.push(tokio::spawn(async move {
+                for round in 0..20 {
+                    for i in 0..FEEDS {
+                        let link = format!("http://example.com/{}.xml", i);
+                        let rss = rss_with_items(&link, 0..5);
+                        if (round + i) % 3 == 0 {
+                            db.unsubscribe(subscriber, &link);
+                        } else {
+                            db.subscribe(subscriber, &link, &rss);
+                        }
+                        db.update(&link, rss_with_items(&link, round..round + 5));
+                        tokio::task::yield_now().await;
+                    }
+                }
+                // Every task ends up subscribed to the even feeds only
+                for i in 0..FEEDS {
+                    let link = format!("http://example.com/{}.xml", i);
+                    if i % 2 == 0 {
+                        db.subscribe(subscriber, &link, &rss_with_items(&link, 0..5));
+                    } else {
+                        db.unsubscribe(subscriber, &link);
+                    }
+                }
+            }));
+        }
+        for task in tasks {
+            tokio::time::timeout(std::time::Duration::from_secs(60), task)
+                .await
+                .expect("deadlock")
+                .unwrap();
+        }
+
+        assert_consistent(&db);
+        assert_eq!(db.all_feeds().len(), FEEDS / 2);
+        assert_eq!(db.all_subscribers().len(), TASKS as usize);
+        for subscriber in 0..TASKS {
+            assert_eq!(db.subscribed_feeds(subscriber).unwrap().len(), FEEDS / 2);
+        }
+
+        // The saved file agrees with the memory
+        let reopened = Database::open(path.clone()).unwrap();
+        assert_consistent(&reopened);
+        assert_eq!(reopened.all_feeds().len(), FEEDS / 2);
+        std::fs::remove_file(path).unwrap();
     }
 
     #[test]
-    #[should_panic(expected = "only support u64 and i64")]
-    fn size64hasher_other_types() {
-        let mut h = Size64Hasher::default();
-        h.write_u8(0);
+    fn median_gap() {
+        let mut feed = Feed::default();
+        let items = |dates: &[&str]| -> Vec<feed::Item> {
+            dates
+                .iter()
+                .map(|date| feed::Item {
+                    published: feed::parse_date(date),
+                    ..Default::default()
+                })
+                .collect()
+        };
+        assert!(feed.record_item_dates(&items(&[
+            "2001-01-02T00:00:00Z",
+            "2001-01-01T00:00:00Z"
+        ])));
+        assert_eq!(feed.median_gap(), None);
+        assert!(!feed.record_item_dates(&items(&["2001-01-01T00:00:00Z"])));
+        assert!(feed.record_item_dates(&items(&[
+            "2001-01-04T00:00:00Z",
+            "2001-01-03T12:00:00Z"
+        ])));
+        // gaps: 12h, 12h, 1d
+        assert_eq!(feed.median_gap(), Some(12 * 60 * 60));
+
+        let many: Vec<String> = (0..30)
+            .map(|i| format!("2002-01-{:02}T00:00:00Z", i + 1))
+            .filter(|s| feed::parse_date(s).is_some())
+            .collect();
+        let many: Vec<&str> = many.iter().map(|s| s.as_str()).collect();
+        feed.record_item_dates(&items(&many));
+        assert_eq!(feed.item_dates.len(), ITEM_DATES_HISTORY);
+        assert_eq!(feed.median_gap(), Some(24 * 60 * 60));
+    }
+
+    #[test]
+    fn subscription_settings() {
+        let (path, db) = temp_database("subscription");
+        let link = "http://example.com/feed.xml";
+        assert!(!db.update_subscription(1, link, |s| s.settings.max_items = Some(1)));
+        db.subscribe(1, link, &rss_with_items("feed", 0..5));
+        db.subscribe(2, link, &rss_with_items("feed", 0..5));
+        assert!(db.update_subscription(1, link, |s| s.settings.max_items = Some(1)));
+
+        let reopened = Database::open(path.clone()).unwrap();
+        let feed = reopened.subscribed_feeds(1).unwrap().remove(0);
+        assert_eq!(feed.subscription(1).settings.max_items, Some(1));
+        assert_eq!(feed.subscription(2), Subscription::default());
+
+        // Default settings are not stored
+        reopened.update_subscription(1, link, |s| s.settings.max_items = None);
+        let feed = reopened.subscribed_feeds(1).unwrap().remove(0);
+        assert!(feed.subscriptions.is_empty());
+
+        db.update_subscription(1, link, |s| s.settings.max_items = Some(1));
+        db.unsubscribe(1, link);
+        db.subscribe(1, link, &rss_with_items("feed", 0..5));
+        let feed = db.subscribed_feeds(1).unwrap().remove(0);
+        assert_eq!(feed.subscription(1), Subscription::default());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn chat_and_global_settings() {
+        let (path, db) = temp_database("chat");
+        let webhook = Webhook {
+            url: "https://example.com/hook".into(),
+            secret: "secret".into(),
+        };
+        db.update_chat(-1, |chat| chat.webhook = Some(webhook.clone()));
+        db.subscribe(-1, "http://example.com/feed.xml", &rss_with_items("feed", 0..5));
+
+        db.update_global(|global| global.rsshub_base = Some("http://localhost:1200".into()));
+
+        let reopened = Database::open(path.clone()).unwrap();
+        assert_eq!(
+            reopened.global().rsshub_base.as_deref(),
+            Some("http://localhost:1200")
+        );
+        assert_eq!(reopened.chat(-1).webhook, Some(webhook.clone()));
+        assert_eq!(reopened.chat(-2), Chat::default());
+
+        reopened.update_subscriber(-1, -2);
+        assert_eq!(reopened.chat(-2).webhook, Some(webhook));
+        assert_eq!(reopened.delete_subscriber(-2, Execution::Apply), Some(1));
+        assert_eq!(reopened.chat(-2), Chat::default());
+        assert_eq!(reopened.delete_subscriber(-2, Execution::Apply), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn dry_run_deletion() {
+        let (path, db) = temp_database("dry-run");
+        db.subscribe(1, "http://example.com/a.xml", &rss_with_items("a", 0..5));
+        db.subscribe(1, "http://example.com/b.xml", &rss_with_items("b", 0..5));
+        db.subscribe(2, "http://example.com/b.xml", &rss_with_items("b", 0..5));
+        db.update_chat(3, |chat| chat.cross_dedup = true);
+        let before = std::fs::read(&path).unwrap();
+        assert_eq!(db.delete_subscriber(1, Execution::DryRun), Some(2));
+        assert_eq!(db.delete_subscriber(3, Execution::DryRun), Some(0));
+        assert_eq!(db.delete_subscriber(4, Execution::DryRun), None);
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        assert_eq!(db.subscribed_feeds(1).unwrap().len(), 2);
+        assert!(db.chat(3).cross_dedup);
+
+        // The real thing matches the preview
+        assert_eq!(db.delete_subscriber(1, Execution::Apply), Some(2));
+        assert_eq!(db.delete_subscriber(3, Execution::Apply), Some(0));
+        assert_ne!(std::fs::read(&path).unwrap(), before);
+        assert_eq!(db.all_feeds().len(), 1);
+        assert_consistent(&db);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn settings_layering() {
+        let (path, db) = temp_database("layering");
+        let link = "http://example.com/feed.xml";
+        db.subscribe(1, link, &rss_with_items("feed", 0..5));
+        let resolved = |db: &Database| {
+            let feed = db.subscribed_feeds(1).unwrap().remove(0);
+            feed.subscription(1).settings.resolve(&db.chat(1).defaults)
+        };
+        assert_eq!(resolved(&db), Settings::default());
+
+        db.update_chat(1, |chat| {
+            chat.defaults.max_items = Some(5);
+            chat.defaults.clean_links = Some(LinkCleaning::Strip);
+        });
+        db.update_subscription(1, link, |s| {
+            s.settings.max_items = Some(2);
+            s.settings.digest_above = Some(10);
+        });
+        let reopened = Database::open(path.clone()).unwrap();
+        let expected = Settings {
+            max_items: Some(2),
+            clean_links: Some(LinkCleaning::Strip),
+            digest_above: Some(10),
+            translate: None,
+            min_words: None,
+            lang: None,
+            date: None,
+            max_queue_age: None,
+            reset: None,
+            reset_history: None,
+        };
+        assert_eq!(resolved(&reopened), expected);
+
+        // Turned off for the subscription only
+        reopened.update_subscription(1, link, |s| {
+            s.settings.max_items = Some(0);
+            s.settings.clean_links = Some(LinkCleaning::Off);
+        });
+        let settings = resolved(&reopened);
+        assert_eq!((settings.max_items, settings.clean_links), (None, None));
+
+        // Inherited again
+        reopened.update_subscription(1, link, |s| s.settings = Settings::default());
+        assert_eq!(resolved(&reopened).max_items, Some(5));
+        reopened.update_chat(1, |chat| chat.defaults = Settings::default());
+        assert_eq!(reopened.chat(1), Chat::default());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn delivery_activity() {
+        let (path, db) = temp_database("activity");
+        let link = "http://example.com/feed.xml";
+        db.subscribe(1, link, &rss_with_items("feed", 0..5));
+        let activity = db.subscribed_feeds(1).unwrap()[0].activity(1).unwrap();
+        assert!(activity.subscribed_at.is_some());
+        assert_eq!(activity.last_delivered, None);
+
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        db.record_delivery(link, &[1, 2], &[], time);
+        let reopened = Database::open(path.clone()).unwrap();
+        let feed = reopened.subscribed_feeds(1).unwrap().remove(0);
+        assert_eq!(feed.activity(1).unwrap().last_delivered, Some(time));
+        // Not a subscriber
+        assert_eq!(feed.activity(2), None);
+
+        reopened.update_subscriber(1, 3);
+        let feed = reopened.subscribed_feeds(3).unwrap().remove(0);
+        assert_eq!(feed.activity(3).unwrap().last_delivered, Some(time));
+        reopened.unsubscribe(3, link);
+        reopened.subscribe(3, link, &rss_with_items("feed", 0..5));
+        let feed = reopened.subscribed_feeds(3).unwrap().remove(0);
+        assert_eq!(feed.activity(3).unwrap().last_delivered, None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn recent_items() {
+        let (path, db) = temp_database("recent");
+        db.subscribe(1, "http://example.com/a.xml", &rss_with_items("a", 0..3));
+        db.subscribe(2, "http://example.com/b.xml", &rss_with_items("b", 0..30));
+        let titles = |results: Vec<(String, RecentItem)>| {
+            results
+                .into_iter()
+                .map(|(feed, item)| format!("{}: {}", feed, item.title))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            titles(db.search_recent_items(1, "ITEM", 20)),
+            ["a: item 0", "a: item 1", "a: item 2"]
+        );
+        assert_eq!(titles(db.search_recent_items(1, "item 2", 20)), ["a: item 2"]);
+        assert_eq!(db.latest_item("http://example.com/a.xml").unwrap().title, "item 0");
+        // Only the subscriptions of the chat
+        assert!(db.search_recent_items(1, "item 10", 20).is_empty());
+        assert_eq!(db.search_recent_items(2, "", 100).len(), RECENT_ITEMS_PER_FEED);
+        assert_eq!(db.search_recent_items(2, "", 5).len(), 5);
+
+        db.update("http://example.com/a.xml", rss_with_items("a", 5..6));
+        assert_eq!(titles(db.search_recent_items(1, "", 20)), ["a: item 5"]);
+        db.unsubscribe(1, "http://example.com/a.xml");
+        assert!(db.search_recent_items(1, "", 20).is_empty());
+        assert_eq!(db.latest_item("http://example.com/a.xml"), None);
+        assert_eq!(db.recent_items.read().unwrap().len(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn republished_items() {
+        let (path, db) = temp_database("republished");
+        let link = "http://example.com/feed.xml";
+        let rss = |items: &[(&str, &str, &str)]| feed::Rss {
+            meta: feed::FeedMeta {
+                title: "feed".into(),
+                link: "http://example.com".into(),
+                ..Default::default()
+            },
+            items: items
+                .iter()
+                .map(|(id, title, link)| feed::Item {
+                    id: Some(id.to_string()),
+                    title: Some(title.to_string()),
+                    link: Some(link.to_string()),
+                    ..Default::default()
+                })
+                .collect(),
+        };
+        let new_titles = |updates: Vec<FeedUpdate>| -> Vec<String> {
+            updates
+                .into_iter()
+                .flat_map(|update| match update {
+                    FeedUpdate::Items(items) => items,
+                    _ => Vec::new(),
+                })
+                .map(|item| item.title.unwrap())
+                .collect()
+        };
+        db.subscribe(1, link, &rss(&[("1", "first", "http://example.com/1")]));
+        // Rebuilt with new GUIDs, the link only gained a tracking parameter
+        let updates = db.update(
+            link,
+            rss(&[
+                ("a", "second", "http://example.com/2"),
+                ("b", "first", "http://example.com/1?utm_source=rss"),
+            ]),
+        );
+        assert_eq!(new_titles(updates), ["second"]);
+
+        let reopened = Database::open(path.clone()).unwrap();
+        let updates = reopened.update(
+            link,
+            rss(&[
+                ("c", "second", "http://example.com/2#comments"),
+                // Only the title or only the link is the same, they are different items
+                ("d", "first", "http://example.com/1-again"),
+                ("e", "first (updated)", "http://example.com/1"),
+                // Linked to the website
+                ("f", "third", "http://example.com/"),
+                ("g", "fourth", "http://example.com"),
+            ]),
+        );
+        assert_eq!(new_titles(updates), ["first", "first (updated)", "third", "fourth"]);
+        assert_consistent(&reopened);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn recovery_notice() {
+        let (path, db) = temp_database("recovery");
+        let link = "http://example.com/feed.xml";
+        db.subscribe(1, link, &rss_with_items("feed", 0..5));
+        let fail_for = |secs| {
+            db.record_failure(link, false, |_| SystemTime::now());
+            let mut state = db.write();
+            let feed = state.feeds.values_mut().next().unwrap();
+            feed.failing_since = Some(SystemTime::now() - Duration::from_secs(secs));
+        };
+        let recovered = |updates: &[FeedUpdate]| match updates.first() {
+            Some(FeedUpdate::Recovered { missed, .. }) => Some(*missed),
+            _ => None,
+        };
+
+        // Nothing was missed
+        fail_for(3 * 24 * 60 * 60);
+        assert_eq!(recovered(&db.update(link, rss_with_items("feed", 0..5))), None);
+        // Not long enough
+        fail_for(60);
+        assert_eq!(recovered(&db.update(link, rss_with_items("feed", 5..7))), None);
+
+        fail_for(3 * 24 * 60 * 60);
+        let updates = db.update(link, rss_with_items("feed", 5..10));
+        assert_eq!(recovered(&updates), Some(3));
+        assert!(matches!(&updates[1], FeedUpdate::Items(items) if items.len() == 3));
+        // Only once
+        assert_eq!(recovered(&db.update(link, rss_with_items("feed", 10..12))), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn error_notice_window() {
+        let (path, db) = temp_database("error-notices");
+        let link = "http://example.com/feed.xml";
+        db.subscribe(1, link, &rss_with_items("feed", 0..5));
+        db.subscribe(2, link, &rss_with_items("feed", 0..5));
+        let notices = |db: &Database, class, now| {
+            let mut notices = db.error_notices(link, class, now, 100);
+            notices.sort_unstable();
+            notices
+        };
+        assert_eq!(notices(&db, "network", 0), [(1, 0), (2, 0)]);
+        assert!(notices(&db, "network", 50).is_empty());
+        assert!(notices(&db, "network", 60).is_empty());
+        // Another kind of error isn't held back
+        assert_eq!(notices(&db, "parsing", 60), [(1, 0), (2, 0)]);
+        assert_eq!(notices(&db, "network", 100), [(1, 2), (2, 2)]);
+
+        // Not again after restarting
+        let db = Database::open(path.clone()).unwrap();
+        assert!(notices(&db, "network", 150).is_empty());
+        // Twice as long after the second one
+        assert!(notices(&db, "network", 299).is_empty());
+        assert_eq!(notices(&db, "network", 300), [(1, 2), (2, 2)]);
+        assert!(notices(&db, "network", 699).is_empty());
+        assert_eq!(notices(&db, "network", 700), [(1, 1), (2, 1)]);
+        db.unsubscribe(2, link);
+        // A fetch that works starts over
+        db.record_failure(link, false, |_| SystemTime::now());
+        db.update(link, rss_with_items("feed", 0..5));
+        assert_eq!(notices(&db, "network", 710), [(1, 0)]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn merging_digest_items() {
+        let queue = |times: std::ops::Range<i64>| -> Vec<DigestItem> {
+            times
+                .rev()
+                .map(|at| {
+                    DigestItem::from(&feed::Item {
+                        link: Some(format!("http://example.com/{}", at)),
+                        published: chrono::DateTime::from_timestamp(at, 0)
+                            .map(|t| t.fixed_offset()),
+                        ..Default::default()
+                    })
+                })
+                .collect()
+        };
+        let mut feed = Feed::default();
+        feed.digest_items.insert(1, queue(0..MAX_DIGEST_ITEMS as i64));
+        let mut other = Feed::default();
+        other.digest_items.insert(1, queue(200..202));
+        feed.merge(other);
+        let links: Vec<_> = feed.digest_items[&1]
+            .iter()
+            .map(|item| item.link.as_deref().unwrap())
+            .collect();
+        assert_eq!(links.len(), MAX_DIGEST_ITEMS);
+        // The oldest are dropped
+        assert_eq!(
+            links[..3],
+            [
+                "http://example.com/201",
+                "http://example.com/200",
+                "http://example.com/99"
+            ]
+        );
+        assert_eq!(links.last(), Some(&"http://example.com/2"));
+    }
+
+    #[test]
+    fn merging_feed_variants() {
+        let (path, db) = temp_database("variants");
+        let canonical = "https://example.com/feed.xml";
+        let http = "http://example.com/feed.xml";
+        let www = "http://www.example.com/feed.xml";
+        db.subscribe(1, canonical, &rss_with_items("feed", 0..3));
+        db.subscribe(2, http, &rss_with_items("feed", 2..5));
+        db.subscribe(1, www, &rss_with_items("feed", 0..3));
+        db.subscribe(3, "https://example.com/other.xml", &rss_with_items("other", 0..3));
+        db.update_subscription(2, http, |s| s.settings.max_items = Some(2));
+        assert!(db.set_cookie(1, canonical, Some("session=1".into())));
+        db.set_insecure(canonical, true);
+        let links = |groups: Vec<Vec<Feed>>| -> Vec<Vec<String>> {
+            groups
+                .into_iter()
+                .map(|group| group.into_iter().map(|feed| feed.link).collect())
+                .collect()
+        };
+        assert_eq!(links(db.feed_variants()), [[http, www, canonical]]);
+
+        assert_eq!(db.merge_feeds(canonical, &[http.into(), www.into()]), 1);
+        assert!(db.feed_variants().is_empty());
+        let reopened = Database::open(path.clone()).unwrap();
+        let feeds = reopened.subscribed_feeds(2).unwrap();
+        assert_eq!(feeds.len(), 1);
+        assert_eq!(feeds[0].link, canonical);
+        assert_eq!(feeds[0].subscription(2).settings.max_items, Some(2));
+        assert_eq!(reopened.subscribed_feeds(1).unwrap().len(), 1);
+        // Chat 2 doesn't get to use them
+        assert_eq!(feeds[0].cookie(), None);
+        assert!(!feeds[0].quirks.insecure);
+        // What any of them saw is not new
+        assert!(reopened.update(canonical, rss_with_items("feed", 0..5)).is_empty());
+        assert_consistent(&reopened);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn feed_meta_saved() {
+        let (path, db) = temp_database("feed-meta");
+        let link = "http://example.com/feed.xml";
+        let mut rss = rss_with_items("feed", 0..2);
+        rss.meta.language = Some("en".into());
+        db.subscribe(1, link, &rss);
+        let feed = &db.subscribed_feeds(1).unwrap()[0];
+        assert_eq!(feed.language.as_deref(), Some("en"));
+        assert!(feed.hubs.is_empty());
+
+        rss.meta.hubs = vec!["http://hub.example.com/".into()];
+        rss.meta.skip.days = vec![5, 6];
+        rss.meta.generator = Some("generator".into());
+        assert!(db.update(link, rss).is_empty());
+        let reopened = Database::open(path.clone()).unwrap();
+        let feed = &reopened.subscribed_feeds(1).unwrap()[0];
+        assert_eq!(feed.hubs, ["http://hub.example.com/"]);
+        assert_eq!(feed.skip.days, [5, 6]);
+        assert_eq!(feed.generator.as_deref(), Some("generator"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn title_change_notices() {
+        let mut feed = Feed {
+            title: "Example Blog".into(),
+            ..Default::default()
+        };
+        let mut change = |title: &str, day: i64| {
+            let notify = feed.note_title_change(title, day * 24 * 60 * 60);
+            feed.title = title.into();
+            notify
+        };
+        assert!(!change("example  blog!", 0));
+        assert!(!change("", 0));
+        assert!(change("Cheap Pills", 1));
+        // A/B tested titles
+        assert!(!change("Example Blog", 2));
+        assert!(!change("Cheap Pills", 3));
+        assert!(change("Domain For Sale", 4));
+        assert!(change("Example Blog", 40));
+    }
+
+    #[test]
+    fn home_page_change_notices() {
+        let mut feed = Feed::default();
+        let mut change = |home_page: &str, day: i64| {
+            let notify = feed.note_home_page_change(home_page, day * 24 * 60 * 60);
+            feed.home_page = home_page.into();
+            notify
+        };
+        assert!(!change("http://example.com/", 0));
+        assert!(!change("https://www.example.com/blog", 1));
+        assert!(!change("", 2));
+        assert!(change("https://parked-domains.example/lander?id=1", 3));
+        assert!(!change("https://example.com/", 4));
+        assert!(!change("https://parked-domains.example/", 5));
+    }
+
+    #[test]
+    fn daily_item_counts() {
+        let mut feed = Feed::default();
+        assert_eq!(feed.last_new_items(), None);
+        feed.record_new_items(3, DAY / 2);
+        feed.record_new_items(2, DAY - 1);
+        feed.record_new_items(4, 3 * DAY);
+        assert_eq!(feed.daily_items, vec![(3, 4), (0, 5)]);
+        assert_eq!(feed.items_this_week(3 * DAY), 9);
+        assert_eq!(feed.items_this_week(7 * DAY), 4);
+        assert_eq!(feed.items_this_week(10 * DAY), 0);
+        feed.record_new_items(1, 8 * DAY);
+        assert_eq!(feed.daily_items, vec![(8, 1), (3, 4)]);
+        assert_eq!(feed.last_new_items(), Some(8 * DAY));
+    }
+
+    #[test]
+    fn weekly_report_due() {
+        use chrono::{DateTime, FixedOffset};
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap();
+        let timestamp = |s: &str| at(s).timestamp();
+        // Mondays at 09:00
+        let report = |last_sent: &str| WeeklyReport {
+            weekday: 0,
+            minute: 9 * 60,
+            last_sent: timestamp(last_sent),
+        };
+        // 2024-01-08 is a Monday
+        let enabled = report("2024-01-08T10:00:00+08:00");
+        assert!(!enabled.is_due(&at("2024-01-08T10:01:00+08:00")));
+        assert!(!enabled.is_due(&at("2024-01-15T08:59:00+08:00")));
+        assert!(enabled.is_due(&at("2024-01-15T09:00:00+08:00")));
+        assert!(enabled.is_due(&at("2024-01-17T00:00:00+08:00")));
+        let sent = report("2024-01-15T09:00:30+08:00");
+        assert!(!sent.is_due(&at("2024-01-15T09:01:00+08:00")));
+        assert!(!sent.is_due(&at("2024-01-21T23:59:00+08:00")));
+        // In the timezone of `now`
+        let local = FixedOffset::east_opt(8 * 60 * 60).unwrap();
+        assert!(!sent.is_due(&at("2024-01-22T00:59:00Z").with_timezone(&local)));
+        assert!(sent.is_due(&at("2024-01-22T01:00:00Z").with_timezone(&local)));
+    }
+
+    #[test]
+    fn digest_due() {
+        use chrono::DateTime;
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap();
+        let digest = |schedule, last_sent: &str| Digest {
+            schedule,
+            last_sent: at(last_sent).timestamp(),
+        };
+        let hourly = digest(DigestSchedule::Hourly, "2024-01-08T10:20:00+08:00");
+        assert!(!hourly.is_due(&at("2024-01-08T10:59:00+08:00")));
+        assert!(hourly.is_due(&at("2024-01-08T11:00:00+08:00")));
+        let daily = digest(DigestSchedule::Daily(18 * 60 + 30), "2024-01-08T10:20:00+08:00");
+        assert!(!daily.is_due(&at("2024-01-08T18:29:00+08:00")));
+        assert!(daily.is_due(&at("2024-01-08T18:30:00+08:00")));
+        assert!(daily.is_due(&at("2024-01-09T01:00:00+08:00")));
+        let sent = digest(DigestSchedule::Daily(18 * 60 + 30), "2024-01-08T18:30:10+08:00");
+        assert!(!sent.is_due(&at("2024-01-09T18:29:00+08:00")));
+        assert!(sent.is_due(&at("2024-01-09T18:30:00+08:00")));
+    }
+
+    #[test]
+    fn digest_items() {
+        use chrono::DateTime;
+        let (path, db) = temp_database("digest-items");
+        let link = "http://example.com/feed.xml";
+        db.subscribe(1, link, &rss_with_items("feed", 0..1));
+        db.subscribe(2, link, &rss_with_items("feed", 0..1));
+        let enabled = DateTime::parse_from_rfc3339("2024-01-08T10:00:00+08:00").unwrap();
+        db.update_subscription(1, link, |s| {
+            s.digest = Some(Digest {
+                schedule: DigestSchedule::Hourly,
+                last_sent: enabled.timestamp(),
+            })
+        });
+        let mut items = rss_with_items("feed", 1..3).items;
+        items[0].published = Some(enabled);
+        db.queue_digest_items(link, &[1], &items);
+        db.queue_digest_items(link, &[1, 3], &rss_with_items("feed", 3..4).items);
+        assert!(db.take_due_digests(&enabled).is_empty());
+        drop(db);
+
+        // Kept until it's time
+        let db = Database::open(path.clone()).unwrap();
+        assert_eq!(db.subscribed_feeds(1).unwrap()[0].digest_items(1), 3);
+        let later = DateTime::parse_from_rfc3339("2024-01-08T11:00:00+08:00").unwrap();
+        let due = db.take_due_digests(&later);
+        assert_eq!(due.len(), 1);
+        let (feed, subscriber, taken) = &due[0];
+        assert_eq!((&feed.link[..], *subscriber), (link, 1));
+        let titles: Vec<_> = taken.iter().map(|item| item.title.as_deref().unwrap()).collect();
+        assert_eq!(titles, ["item 3", "item 1", "item 2"]);
+        assert_eq!(taken[1].published, Some(enabled));
+        // Kept until they are sent, and not taken twice
+        assert_eq!(db.subscribed_feeds(1).unwrap()[0].digest_items(1), 3);
+        assert!(db.take_due_digests(&later).is_empty());
+        db.queue_digest_items(link, &[1], &rss_with_items("feed", 4..5).items);
+        // Equal to one that is sent, still new for the next one
+        db.queue_digest_items(link, &[1], &taken[0..1]);
+        db.digest_sent(link, 1, Some(&taken[..]));
+        assert_eq!(db.subscribed_feeds(1).unwrap()[0].digest_items(1), 2);
+        let at = |time| DateTime::parse_from_rfc3339(time).unwrap();
+        let (_, _, taken) = db.take_due_digests(&at("2024-01-08T12:00:00+08:00")).pop().unwrap();
+        let titles: Vec<_> = taken.iter().map(|item| item.title.as_deref().unwrap()).collect();
+        assert_eq!(titles, ["item 3", "item 4"]);
+        // Failed, sent with the next one
+        db.digest_sent(link, 1, None);
+        assert_eq!(db.subscribed_feeds(1).unwrap()[0].digest_items(1), 2);
+        let (_, _, taken) = db.take_due_digests(&at("2024-01-08T13:00:00+08:00")).pop().unwrap();
+        db.digest_sent(link, 1, Some(&taken[..]));
+        assert_eq!(db.subscribed_feeds(1).unwrap()[0].digest_items(1), 0);
+
+        // Turned off, the items kept are sent right away
+        db.queue_digest_items(link, &[1], &items);
+        db.update_subscription(1, link, |s| s.digest = None);
+        let (_, _, taken) = db.take_due_digests(&enabled).pop().unwrap();
+        db.digest_sent(link, 1, Some(&taken[..]));
+        db.queue_digest_items(link, &[2], &items);
+        db.unsubscribe(2, link);
+        assert!(db.take_due_digests(&enabled).is_empty());
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn tracking_params_dedup() {
+        let tracked = |source: &str| {
+            let mut rss = rss_with_items("feed", 0..2);
+            for item in &mut rss.items {
+                let link = item.link.take().unwrap();
+                item.link = Some(format!("{}?utm_source={}", link, source));
+            }
+            rss
+        };
+        let (path, db) = temp_database("tracking");
+        let link = "http://example.com/feed.xml";
+        db.subscribe(1, link, &tracked("rss"));
+        assert!(db.update(link, tracked("twitter")).is_empty());
+        assert!(db.update(link, rss_with_items("feed", 0..2)).is_empty());
+
+        // Hashes stored with the tracking parameters before they were stripped
+        let feed_id = gen_hash(&link);
+        let legacy = tracked("rss")
+            .items
+            .iter()
+            .map(|item| {
+                let title = item.title.as_deref().unwrap();
+                gen_hash(&format!("{}{}", title, item.link.as_deref().unwrap()))
+            })
+            .collect();
+        db.hash_list_shard(feed_id).insert(feed_id, SeenHashes::from_list(legacy));
+        assert!(db.update(link, tracked("rss")).is_empty());
+        assert_eq!(db.compare_items(link, &tracked("rss").items), Some((2, 2)));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reset_detection() {
+        let (path, db) = temp_database("reset-detection");
+        let link = "http://example.com/feed.xml";
+        db.subscribe(1, link, &rss_with_items("feed", 0..5));
+        let items = |range, published: Option<i64>| {
+            let mut items = rss_with_items("feed", range).items;
+            for item in &mut items {
+                item.published = published
+                    .and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+                    .map(|t| t.fixed_offset());
+            }
+            items
+        };
+        // Undated, there's no telling how old they are
+        assert_eq!(db.seems_reset(link, &items(10..15, None)), Some(5));
+        assert_eq!(db.seems_reset(link, &items(4..9, None)), None);
+        db.write().feeds.get_mut(&feed_key(link)).unwrap().last_new_items = Some(1000);
+        assert_eq!(db.seems_reset(link, &items(10..15, Some(999))), Some(5));
+        // All published since, the feed only turned over
+        assert_eq!(db.seems_reset(link, &items(10..15, Some(1000))), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn pending_items() {
+        let (path, db) = temp_database("pending");
+        let db = db.with_pending_cycles(2);
+        let link = "http://example.com/feed.xml";
+        let rss = |stub: feed::Item| {
+            let mut rss = rss_with_items("feed", 0..2);
+            rss.meta.link = "http://example.com/".into();
+            rss.items.push(stub);
+            rss.items.push(feed::Item {
+                title: Some("draft".into()),
+                ..Default::default()
+            });
+            rss
+        };
+        let stub = |link: &str| feed::Item {
+            id: Some("stub".into()),
+            link: Some(link.into()),
+            ..Default::default()
+        };
+        let delivered = |updates: Vec<FeedUpdate>| match updates.into_iter().next() {
+            Some(FeedUpdate::Items(items)) => items.into_iter().map(|item| item.link).collect(),
+            _ => Vec::new(),
+        };
+        db.subscribe(1, link, &rss_with_items("feed", 0..2));
+        assert!(db.update(link, rss(stub("http://example.com"))).is_empty());
+
+        let db = Database::open(path.clone()).unwrap().with_pending_cycles(2);
+        let feed_id = gen_hash(&link);
+        assert_eq!(db.read().feeds[&feed_id].pending.len(), 2);
+        // The link is there now
+        assert_eq!(
+            delivered(db.update(link, rss(stub("http://example.com/stub")))),
+            [Some("http://example.com/stub".to_string())]
+        );
+        // Delivered as it is after the cycles, just once
+        assert_eq!(delivered(db.update(link, rss(stub("http://example.com/stub")))), [None]);
+        assert!(db.update(link, rss(stub("http://example.com/stub"))).is_empty());
+        assert!(db.read().feeds[&feed_id].pending.is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn embargoed_items() {
+        let (path, db) = temp_database("embargoed");
+        let link = "http://example.com/feed.xml";
+        let now = chrono::Utc::now().timestamp();
+        let dated = |title: &str, at: i64| feed::Item {
+            title: Some(title.into()),
+            published: chrono::DateTime::from_timestamp(at, 0).map(|t| t.fixed_offset()),
+            ..Default::default()
+        };
+        let rss = |items: Vec<feed::Item>| feed::Rss {
+            meta: feed::FeedMeta {
+                title: "feed".into(),
+                ..Default::default()
+            },
+            items,
+        };
+        let titles = |items: &[feed::Item]| -> Vec<String> {
+            items.iter().filter_map(|item| item.title.clone()).collect()
+        };
+        db.subscribe(1, link, &rss(Vec::new()));
+        let items = vec![
+            dated("skewed", now + 60),
+            dated("tomorrow", now + 24 * 60 * 60),
+            dated("year 3000", now + 100 * MAX_EMBARGO),
+        ];
+        match &db.update(link, rss(items.clone()))[..] {
+            [FeedUpdate::Items(items)] => assert_eq!(titles(items), ["skewed", "year 3000"]),
+            updates => panic!("{:?}", updates),
+        }
+        // Still held on the next fetch, and not due yet
+        assert!(db.update(link, rss(items.clone())).is_empty());
+        assert!(db.take_due_items(now).is_empty());
+
+        let due = db.take_due_items(now + 24 * 60 * 60);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].0.link, link);
+        assert_eq!(titles(&due[0].1), ["tomorrow"]);
+        assert!(db.take_due_items(now + 24 * 60 * 60).is_empty());
+        // Seen once it was delivered
+        assert!(db.update(link, rss(items)).is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn held_items() {
+        let (path, db) = temp_database("held");
+        let db = db.with_pending_cycles(2);
+        let link = "http://example.com/feed.xml";
+        let now = chrono::Utc::now().timestamp();
+        let next_fetch = UNIX_EPOCH + Duration::from_secs(now as u64 + 600);
+        let dated = |title: &str, at: i64| feed::Item {
+            title: Some(title.into()),
+            link: Some(format!("http://example.com/{}", title)),
+            published: chrono::DateTime::from_timestamp(at, 0).map(|t| t.fixed_offset()),
+            ..Default::default()
+        };
+        let rss = feed::Rss {
+            meta: feed::FeedMeta {
+                title: "feed".into(),
+                link: "http://example.com/".into(),
+                ..Default::default()
+            },
+            items: vec![
+                dated("later", now + 2 * 60 * 60),
+                dated("soon", now + 60 * 60),
+                feed::Item {
+                    title: Some("draft".into()),
+                    ..Default::default()
+                },
+            ],
+        };
+        db.subscribe(1, link, &rss_with_items("feed", 0..0));
+        db.subscribe(2, "http://example.com/other.xml", &rss_with_items("other", 0..0));
+        assert!(db.update(link, rss.clone()).is_empty());
+        let (future_dated, pending_links) = db.held_items(None, |_| next_fetch);
+        assert_eq!(future_dated.queued, 2);
+        assert_eq!(future_dated.titles, ["soon", "later"]);
+        let due = UNIX_EPOCH + Duration::from_secs((now + 60 * 60 - FUTURE_DATE_SKEW) as u64);
+        assert_eq!(future_dated.next_flush, Some(due));
+        let since = future_dated.oldest.unwrap();
+        assert_eq!(pending_links.queued, 1);
+        assert_eq!(pending_links.next_flush, Some(next_fetch));
+        assert_eq!(pending_links.titles, Vec::<String>::new());
+
+        // Still held since the first fetch
+        assert!(db.update(link, rss).is_empty());
+        assert_eq!(db.held_items(Some(1), |_| next_fetch).0.oldest, Some(since));
+        let (future_dated, pending_links) = db.held_items(Some(2), |_| next_fetch);
+        assert_eq!(future_dated, QueueSnapshot::default());
+        assert_eq!(pending_links, QueueSnapshot::default());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn seen_hashes() {
+        let mut seen = SeenHashes::from_list(vec![5, 4, 3, 2, 1]);
+        seen.record(&[6, 1, 1], 4);
+        // 1 is in the last response, it stays even if it's the oldest
+        assert_eq!(seen.to_list(), [6, 1, 5, 4]);
+        // Reordering drops nothing
+        seen.record(&[1, 4, 6, 5], 4);
+        assert_eq!(seen.to_list(), [1, 4, 6, 5]);
+        // The last response is kept even if it has more items than the capacity
+        seen.record(&[7, 8, 9], 2);
+        assert_eq!(seen.to_list(), [7, 8, 9]);
+    }
+
+    #[test]
+    fn hash_list_cap() {
+        let (path, db) = temp_database("hash-cap");
+        let db = db.with_max_hashes(Some(4)).unwrap();
+        let link = "http://example.com/feed.xml";
+        // Item 0 stays in the feed, while the others rotate quickly
+        let rss = |new: std::ops::Range<usize>| {
+            let mut rss = rss_with_items("feed", new);
+            rss.items.push(rss_with_items("feed", 0..1).items.remove(0));
+            rss
+        };
+        db.subscribe(1, link, &rss(1..3));
+        for i in 1..10 {
+            let updates = db.update(link, rss(i * 3..i * 3 + 3));
+            match &updates[..] {
+                [FeedUpdate::Items(items)] => assert_eq!(items.len(), 3),
+                _ => panic!("expected only the new items"),
+            }
+        }
+        let feed_id = gen_hash(&link);
+        assert_eq!(db.hash_list_shard(feed_id)[&feed_id].len(), 4);
+
+        // Lists saved before the cap are trimmed, oldest first
+        db.hash_list_shard(feed_id).insert(feed_id, SeenHashes::from_list((0..10).collect()));
+        db.save().unwrap();
+        let reopened = Database::open(path.clone())
+            .unwrap()
+            .with_max_hashes(Some(6))
+            .unwrap();
+        assert_eq!(
+            reopened.hash_list_shard(feed_id)[&feed_id].to_list(),
+            [0, 1, 2, 3, 4, 5]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn hash_list_trim() {
+        let (path, db) = temp_database("hash-trim");
+        let link = "http://example.com/feed.xml";
+        let rss = |new: std::ops::Range<usize>| {
+            let mut rss = rss_with_items("feed", new);
+            rss.items.push(rss_with_items("feed", 0..1).items.remove(0));
+            rss
+        };
+        db.subscribe(1, link, &rss(1..4));
+        for i in 1..5 {
+            db.update(link, rss(i * 3 + 1..i * 3 + 4));
+        }
+        drop(db);
+        // Item 0 was seen first, but it's still in the feed
+        let reopened = Database::open(path.clone())
+            .unwrap()
+            .with_max_hashes(Some(4))
+            .unwrap();
+        let feed_id = gen_hash(&link);
+        assert_eq!(reopened.hash_list_shard(feed_id)[&feed_id].len(), 4);
+        assert!(reopened.update(link, rss(13..16)).is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn cross_feed_dedup() {
+        let (path, db) = temp_database("cross-dedup");
+        let items = |links: &[&str]| {
+            links
+                .iter()
+                .map(|link| feed::Item {
+                    link: Some(link.to_string()),
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>()
+        };
+        let kept = |items: Vec<feed::Item>| {
+            items
+                .into_iter()
+                .map(|item| item.link.unwrap())
+                .collect::<Vec<_>>()
+        };
+        let main = "http://example.com/feed.xml";
+        let category = "http://example.com/category/feed.xml";
+        for chat_id in [1, 2] {
+            db.subscribe(chat_id, main, &rss_with_items("main", 0..0));
+            db.subscribe(chat_id, category, &rss_with_items("category", 0..0));
+        }
+        let at = |secs: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let post = items(&["http://example.com/post/"]);
+        // Off by default
+        db.record_delivery(main, &[1], &post, at(0));
+        assert_eq!(db.filter_cross_duplicates(1, category, &post, 0, 100).len(), 1);
+
+        db.update_chat(1, |chat| chat.set_cross_dedup(true));
+        db.update_chat(2, |chat| chat.set_cross_dedup(true));
+        // Only once it was delivered
+        assert_eq!(db.filter_cross_duplicates(1, category, &post, 0, 100).len(), 1);
+        db.record_delivery(main, &[1], &post, at(0));
+        assert_eq!(db.filter_cross_duplicates(1, main, &post, 0, 100).len(), 1);
+        let tracked = items(&[
+            "http://example.com/post?utm_source=category#comments",
+            "http://example.com/other",
+        ]);
+        assert_eq!(
+            kept(db.filter_cross_duplicates(1, category, &tracked, 10, 100)),
+            ["http://example.com/other"]
+        );
+        // Per chat
+        assert_eq!(db.filter_cross_duplicates(2, category, &post, 10, 100).len(), 1);
+        // Only within the window
+        assert_eq!(db.filter_cross_duplicates(1, category, &post, 200, 100).len(), 1);
+
+        let reopened = Database::open(path.clone()).unwrap();
+        assert_eq!(reopened.chat(1).recent_links.len(), 1);
+        reopened.update_chat(1, |chat| chat.set_cross_dedup(false));
+        assert_eq!(reopened.chat(1), Chat::default());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn feed_cookie() {
+        let (path, db) = temp_database("cookie");
+        let link = "http://example.com/feed.xml";
+        assert!(!db.set_cookie(1, link, Some("session=1".into())));
+        db.subscribe(1, link, &rss_with_items("feed", 0..5));
+        assert!(db.set_cookie(1, link, Some("session=1".into())));
+
+        let reopened = Database::open(path.clone()).unwrap();
+        let feed = reopened.subscribed_feeds(1).unwrap().remove(0);
+        assert_eq!(feed.cookie(), Some("session=1"));
+
+        // A new subscriber drops the cookie, and it can't be set again
+        reopened.subscribe(2, link, &rss_with_items("feed", 0..5));
+        let feed = reopened.subscribed_feeds(1).unwrap().remove(0);
+        assert_eq!(feed.cookie(), None);
+        assert!(!reopened.set_cookie(1, link, Some("session=1".into())));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn feed_max_size() {
+        let (path, db) = temp_database("max-size");
+        let link = "http://example.com/feed.xml";
+        assert!(!db.set_max_size(link, Some(1024)));
+        db.subscribe(1, link, &rss_with_items("feed", 0..5));
+        assert!(db.set_max_size(link, Some(1024)));
+        let reopened = Database::open(path.clone()).unwrap();
+        assert_eq!(reopened.all_feeds()[0].max_size, Some(1024));
+        assert!(reopened.set_max_size(link, None));
+        assert_eq!(reopened.all_feeds()[0].max_size, None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn exclusive_open() {
+        let (path, db) = temp_database("exclusive");
+        drop(db);
+        let db = Database::open_exclusive(Location::Json(path.clone()), Execution::Apply).unwrap();
+        match Database::open_exclusive(Location::Json(path.clone()), Execution::Apply) {
+            Err(DataError::Locked(holder)) => {
+                assert_eq!(holder, format!("PID {}", std::process::id()))
+            }
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+        // Only the exclusive opens are refused
+        assert!(Database::open(path.clone()).is_ok());
+        drop(db);
+        assert!(Database::open_exclusive(Location::Json(path.clone()), Execution::Apply).is_ok());
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        std::fs::remove_file(lock_path).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn imported_seen_hashes() {
+        let (path, db) = temp_database("import-seen");
+        let link = "http://example.com/feed.xml";
+        db.subscribe(1, link, &rss_with_items("feed", 0..10));
+        let exported = db.seen_hashes(link);
+        assert_eq!(exported.len(), 10);
+
+        // The new instance only sees the last 5 items when subscribing
+        let (new_path, new_db) = temp_database("import-seen-new");
+        new_db.subscribe(2, link, &rss_with_items("feed", 5..10));
+        assert!(new_db.import_seen_hashes(2, link, &exported));
+        let updates = new_db.update(link, rss_with_items("feed", 0..10));
+        assert!(!updates.iter().any(|u| matches!(u, FeedUpdate::Items(_))));
+        assert_eq!(new_db.seen_hashes(link)[..5], exported[5..]);
+
+        // The other subscribers would miss items
+        new_db.subscribe(3, link, &rss_with_items("feed", 0..10));
+        assert!(!new_db.import_seen_hashes(2, link, &[1, 2, 3]));
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(new_path).unwrap();
+    }
+
+    #[test]
+    fn feed_debug_info() {
+        let (path, db) = temp_database("debug-info");
+        let link = "http://example.com/feed.xml";
+        assert!(db.feed_debug_info(link).is_none());
+        db.subscribe(1, link, &rss_with_items("feed", 0..5));
+        db.set_cookie(1, link, Some("session=secret".into()));
+        db.update_subscription(1, link, |s| s.note = Some("ask before removing".into()));
+        db.set_max_size(link, Some(1024));
+        db.set_quirks(
+            link,
+            Quirks {
+                accept_any: true,
+                http1_only: true,
+                insecure: true,
+            },
+        );
+        db.record_failure(link, true, |_| SystemTime::now());
+
+        let info = db.feed_debug_info(link).unwrap();
+        assert_eq!(info.seen_hashes, 5);
+        let dump = info.to_string();
+        for line in [
+            "subscribers: 1\n  1\n    note: ask before removing\n",
+            "failures: 1\n",
+            "blocked: true\n",
+            "max_size: 1024\n",
+            "cookie: <redacted>\n",
+            "quirks: accept_any, http1_only, insecure\n",
+            "seen_hashes: 5",
+        ] {
+            assert!(dump.contains(line), "{:?} not in {}", line, dump);
+        }
+        assert!(!dump.contains("secret"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn request_changes() {
+        let (path, db) = temp_database("request-changes");
+        let link = "http://example.com/feed.xml";
+        db.subscribe(1, link, &rss_with_items("feed", 0..5));
+        let quirks = |db: &Database| db.feed_debug_info(link).unwrap().feed.quirks;
+        let learned = Quirks {
+            accept_any: true,
+            http1_only: true,
+            insecure: false,
+        };
+        let insecure = Quirks {
+            insecure: true,
+            ..Quirks::default()
+        };
+
+        db.set_quirks(link, learned);
+        db.set_cookie(1, link, Some("session=1".into()));
+        assert_eq!(quirks(&db), Quirks::default());
+        db.set_quirks(link, learned);
+        // Nothing changed
+        db.set_cookie(1, link, Some("session=1".into()));
+        db.set_insecure(link, false);
+        assert_eq!(quirks(&db), learned);
+
+        db.set_insecure(link, true);
+        assert_eq!(quirks(&db), insecure);
+        db.set_quirks(link, Quirks { insecure: true, ..learned });
+        // Drops the cookie
+        db.subscribe(2, link, &rss_with_items("feed", 0..5));
+        assert_eq!(quirks(&db), insecure);
+        db.set_quirks(link, Quirks { insecure: true, ..learned });
+        db.subscribe(3, link, &rss_with_items("feed", 0..5));
+        assert_eq!(quirks(&db), Quirks { insecure: true, ..learned });
+
+        assert!(db.forget_responses(link));
+        assert_eq!(quirks(&db), insecure);
+        assert!(!db.forget_responses("http://example.com/other.xml"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_subscribe() {
+        const TASKS: usize = 32;
+        let (path, db) = temp_database("concurrent-subscribe");
+        let db = std::sync::Arc::new(db);
+        let barrier = std::sync::Arc::new(tokio::sync::Barrier::new(TASKS));
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|i| {
+                let db = db.clone();
+                let barrier = barrier.clone();
+                // Not everyone types the link the same way
+                let link = if i % 2 == 0 {
+                    "https://example.com/feed.xml"
+                } else {
+                    "HTTPS://Example.com/feed.xml "
+                };
+                tokio::spawn(async move {
+                    barrier.wait().await;
+                    db.subscribe(1, link, &rss_with_items("feed", 0..5))
+                })
+            })
+            .collect();
+        let mut subscribed = 0;
+        for task in tasks {
+            if task.await.unwrap() == SubscribeResult::Subscribed {
+                subscribed += 1;
+            }
+        }
+        assert_eq!(subscribed, 1);
+        let feeds = db.subscribed_feeds(1).unwrap();
+        assert_eq!(feeds.len(), 1);
+        assert_eq!(feeds[0].link, "https://example.com/feed.xml");
+        assert!(db.is_subscribed(1, "https://EXAMPLE.COM/feed.xml"));
+        let feed = db.subscribed_feed(1, "https://EXAMPLE.COM/feed.xml").unwrap();
+        assert_eq!(feed.link, "https://example.com/feed.xml");
+        assert!(db.subscribed_feed(2, "https://example.com/feed.xml").is_none());
+        assert_consistent(&db);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn feed_link_normalization() {
+        assert_eq!(
+            normalize_feed_link(" HTTP://Example.COM:8080/Feed.xml?Q=1 "),
+            "http://example.com:8080/Feed.xml?Q=1"
+        );
+        assert_eq!(normalize_feed_link("https://Example.com"), "https://example.com");
+        assert!(matches!(
+            normalize_feed_link("https://example.com/Feed.xml"),
+            Cow::Borrowed(_)
+        ));
+        // Routes may be case sensitive
+        assert_eq!(normalize_feed_link("rsshub://GitHub/repos"), "rsshub://GitHub/repos");
+
+        // Stored twice before the links were normalized
+        let (path, _) = temp_database("normalized-links");
+        std::fs::write(
+            &path,
+            r#"[{"link":"http://example.com/feed.xml","title":"feed","down_time":null,
+                "subscribers":[1],"ttl":null,"hash_list":[1,2]},
+                {"link":"http://EXAMPLE.com/feed.xml","title":"feed","down_time":null,
+                "subscribers":[2],"ttl":null,"hash_list":[3]}]"#,
+        )
+        .unwrap();
+        let (db, report) =
+            Database::open_migrating(Location::Json(path.clone()), Execution::Apply).unwrap();
+        assert_eq!(report.migrations.len(), 1);
+        assert_eq!(report.migrations[0].name, NORMALIZED_LINKS);
+        assert_eq!(report.migrations[0].merged, 1);
+        let feeds = db.all_feeds();
+        assert_eq!(feeds.len(), 1);
+        assert_eq!(feeds[0].subscribers.len(), 2);
+        assert_eq!(db.subscribed_feeds(2).unwrap()[0].link, "http://example.com/feed.xml");
+        let feed_id = feed_key(&feeds[0].link);
+        assert_eq!(db.hash_list_shard(feed_id)[&feed_id].len(), 3);
+        assert_consistent(&db);
+        remove_migration_files(&path, report);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn chat_names() {
+        assert_eq!(chat_display(-1001), "-1001");
+        remember_chat_name(-1001, Some("Some Group "), None);
+        assert_eq!(chat_display(-1001), "Some Group (-1001)");
+        remember_chat_name(-1001, Some("Renamed"), Some("some_group"));
+        assert_eq!(chat_display(-1001), "Renamed @some_group (-1001)");
+        remember_chat_name(1001, None, Some("someone"));
+        assert_eq!(chat_display(1001), "@someone (1001)");
+
+        CHAT_NAMES.write().unwrap().get_mut(&1001).unwrap().seen -= CHAT_NAME_TTL;
+        assert_eq!(chat_display(1001), "1001");
+    }
+
+    #[test]
+    fn subscription_owners() {
+        let (path, db) = temp_database("owners");
+        let link = "http://example.com/feed.xml";
+        db.subscribe(-1, link, &rss_with_items("feed", 0..5));
+        db.subscribe(1, link, &rss_with_items("feed", 0..5));
+        assert_eq!(db.subscription_owner(-1, link), None);
+        db.set_subscribed_by(-1, link, 5);
+        db.set_subscribed_by(1, link, 1);
+        assert_eq!(db.subscription_owner(-1, link), Some(5));
+        // Subscribed by the chat itself
+        assert_eq!(db.subscription_owner(1, link), None);
+        assert_eq!(db.subscription_owner(-1, "http://example.com/other.xml"), None);
+
+        db.disown_subscription(-1, link);
+        assert_eq!(db.subscription_owner(-1, link), None);
+        assert_eq!(db.subscribed_by(-1), Vec::<i64>::new());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn legacy_database() {
+        let (path, _) = temp_database("legacy");
+        std::fs::write(
+            &path,
+            r#"[{"link":"http://example.com/feed.xml","title":"feed","down_time":null,
+                "subscribers":[1],"ttl":null,"subscriptions":{"1":{"max_items":1}},
+                "hash_list":[1,2,3]}]"#,
+        )
+        .unwrap();
+        let legacy = std::fs::read(&path).unwrap();
+        match Database::open_exclusive(Location::Json(path.clone()), Execution::DryRun) {
+            Err(DataError::MigrationsPending(pending)) => assert_eq!(pending, LEGACY_FORMAT),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), legacy);
+
+        let (db, report) =
+            Database::open_migrating(Location::Json(path.clone()), Execution::Apply).unwrap();
+        let feed = db.subscribed_feeds(1).unwrap().remove(0);
+        assert_eq!(feed.subscription(1).settings.max_items, Some(1));
+        assert_eq!(feed.activity(1), None);
+        assert_consistent(&db);
+        let backup = report.backup.as_ref().unwrap();
+        assert_eq!(std::fs::read(backup).unwrap(), legacy);
+        let mut log_path = path.as_os_str().to_owned();
+        log_path.push(".migrations.log");
+        let log = std::fs::read_to_string(log_path).unwrap();
+        assert!(log.contains("Migration legacy_format: 1 feeds"), "{}", log);
+
+        // Saved migrated
+        drop(db);
+        let (_, again) =
+            Database::open_migrating(Location::Json(path.clone()), Execution::DryRun).unwrap();
+        assert!(again.migrations.is_empty() && again.backup.is_none());
+        remove_migration_files(&path, report);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn temp_sqlite(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "rssbot-test-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        remove_sqlite(&path);
+        path
+    }
+
+    fn remove_sqlite(path: &Path) {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.as_os_str().to_owned();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    #[test]
+    fn sqlite_database() {
+        let path = temp_sqlite("sqlite");
+        let location = Location::Sqlite(path.clone());
+        let (db, report) = Database::open_migrating(location.clone(), Execution::Apply).unwrap();
+        assert!(report.migrations.is_empty());
+        let link = "http://example.com/feed.xml";
+        let other = "http://example.com/other.xml";
+        db.subscribe(1, link, &rss_with_items("feed", 0..5));
+        db.subscribe(2, other, &rss_with_items("other", 0..2));
+        db.update_chat(1, |chat| chat.set_cross_dedup(true));
+        db.update_global(|global| global.footer = Some("footer".into()));
+        db.unsubscribe(2, other);
+        db.save().unwrap();
+        drop(db);
+
+        let (db, report) = Database::open_migrating(location.clone(), Execution::DryRun).unwrap();
+        assert!(report.migrations.is_empty());
+        let feeds = db.all_feeds();
+        assert_eq!(feeds.len(), 1);
+        assert_eq!(feeds[0].title, "feed");
+        assert_eq!(db.subscribed_feeds(1).unwrap()[0].link, link);
+        assert!(db.subscribed_feeds(2).unwrap_or_default().is_empty());
+        assert_eq!(db.seen_hashes(link).len(), 5);
+        assert!(db.chat(1).cross_dedup);
+        assert_eq!(db.global().footer.as_deref(), Some("footer"));
+        assert_consistent(&db);
+
+        // Back to the defaults, the row is deleted
+        db.update_chat(1, |chat| chat.set_cross_dedup(false));
+        drop(db);
+        let (db, _) = Database::open_migrating(location, Execution::DryRun).unwrap();
+        assert_eq!(db.chat(1), Chat::default());
+        assert_eq!(db.all_feeds().len(), 1);
+        drop(db);
+        remove_sqlite(&path);
+    }
+
+    #[test]
+    fn import_json() {
+        let (json, db) = temp_database("import-json");
+        db.subscribe(1, "http://example.com/feed.xml", &rss_with_items("feed", 0..3));
+        db.update("http://example.com/feed.xml", rss_with_items("feed", 3..6));
+        db.update_chat(1, |chat| chat.set_cross_dedup(true));
+        db.update_chat(2, |chat| chat.set_cross_dedup(true));
+        drop(db);
+        let path = temp_sqlite("import-json");
+        let location = Location::Sqlite(path.clone());
+        let (db, _) = Database::open_migrating(location.clone(), Execution::Apply).unwrap();
+        let db = db.with_max_hashes(Some(4)).unwrap();
+        // Chat 2 has only settings
+        assert_eq!(db.import_json(&json).unwrap(), (1, 2));
+        assert!(matches!(db.import_json(&json), Err(DataError::NotEmpty)));
+        drop(db);
+
+        let (db, _) = Database::open_migrating(location, Execution::DryRun).unwrap();
+        assert_eq!(db.all_feeds().len(), 1);
+        // Capped by `--max-hashes-per-feed` like the loaded ones
+        assert_eq!(db.seen_hashes("http://example.com/feed.xml").len(), 4);
+        assert!(db.chat(1).cross_dedup);
+        assert_consistent(&db);
+        drop(db);
+        remove_sqlite(&path);
+        std::fs::remove_file(json).unwrap();
+    }
+
+    fn remove_migration_files(path: &Path, report: MigrationReport) {
+        std::fs::remove_file(report.backup.unwrap()).unwrap();
+        let mut log_path = path.as_os_str().to_owned();
+        log_path.push(".migrations.log");
+        std::fs::remove_file(log_path).unwrap();
     }
 }
@@ -0,0 +1,217 @@
+//! `--database sqlite:path`, the database as rows instead of one JSON file
+//!
+//! Every feed and chat is a row holding the JSON it has in the file, the global settings
+//! are the only row of their table. A save only serializes the rows marked dirty since the
+//! last one, and writes the ones that really changed, all in one transaction.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, Transaction};
+
+use super::{
+    backup_path, gen_hash, Chat, Contents, DataError, Feed, FeedId, FeedRecord, FeedRecordRef,
+    Global, Size64, SubscriberId,
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS feeds (id INTEGER PRIMARY KEY, record TEXT NOT NULL);
+    CREATE TABLE IF NOT EXISTS chats (id INTEGER PRIMARY KEY, chat TEXT NOT NULL);
+    CREATE TABLE IF NOT EXISTS global (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        global TEXT NOT NULL
+    );
+";
+
+/// The rows to save, `None` for the ones removed since the last save
+pub(super) struct Rows {
+    pub(super) feeds: Vec<(FeedId, Option<(Feed, Vec<u64>)>)>,
+    pub(super) chats: Vec<(SubscriberId, Option<Chat>)>,
+    pub(super) global: Option<Global>,
+    /// Every feed and chat is in the rows, the ones missing are removed too
+    pub(super) all: bool,
+}
+
+#[derive(Debug)]
+pub(super) struct Store {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    connection: Connection,
+    /// Hashes of the rows as they were last written, by table
+    written: HashMap<&'static str, HashMap<i64, u64>>,
+}
+
+impl Store {
+    pub(super) fn open(path: &Path) -> Result<Store, DataError> {
+        let connection = Connection::open(path)?;
+        // Readers never wait for a save, and a crash never leaves half of one
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Store {
+            inner: Mutex::new(Inner {
+                connection,
+                written: HashMap::new(),
+            }),
+        })
+    }
+
+    pub(super) fn load(&self) -> Result<Contents, DataError> {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner {
+            connection,
+            written,
+        } = &mut *inner;
+        let feeds: Vec<FeedRecord> = read_rows(connection, "feeds", "record", written)?
+            .into_iter()
+            .map(|(_, record)| serde_json::from_str(&record))
+            .collect::<Result<_, _>>()?;
+        let chats: HashMap<SubscriberId, Chat, Size64> =
+            read_rows(connection, "chats", "chat", written)?
+                .into_iter()
+                .map(|(id, chat)| serde_json::from_str(&chat).map(|chat| (id, chat)))
+                .collect::<Result<_, _>>()?;
+        let global = read_rows(connection, "global", "global", written)?
+            .pop()
+            .map(|(_, global)| serde_json::from_str(&global))
+            .transpose()?
+            .unwrap_or_default();
+        Ok((feeds, chats, global))
+    }
+
+    pub(super) fn save(&self, rows: Rows) -> Result<(), DataError> {
+        let feeds = rows
+            .feeds
+            .into_iter()
+            .map(|(id, feed)| {
+                let record = feed
+                    .map(|(feed, hash_list)| {
+                        serde_json::to_string(&FeedRecordRef::new(&feed, &hash_list))
+                    })
+                    .transpose()?;
+                // The key of the feed in memory is `feed_key` of its link
+                Ok((id as i64, record))
+            })
+            .collect::<Result<Vec<_>, serde_json::Error>>()?;
+        let chats = rows
+            .chats
+            .into_iter()
+            .map(|(id, chat)| Ok((id, chat.as_ref().map(serde_json::to_string).transpose()?)))
+            .collect::<Result<Vec<_>, serde_json::Error>>()?;
+        let global = match rows.global {
+            Some(global) => vec![(0, Some(serde_json::to_string(&global)?))],
+            None => Vec::new(),
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        let Inner {
+            connection,
+            written,
+        } = &mut *inner;
+        let transaction = connection.transaction()?;
+        let none = HashMap::new();
+        let changes = [
+            ("feeds", "record", feeds),
+            ("chats", "chat", chats),
+            ("global", "global", global),
+        ]
+        .into_iter()
+        .map(|(table, column, rows_of_table)| {
+            let before = written.get(table).unwrap_or(&none);
+            write_rows(&transaction, table, column, rows_of_table, before, rows.all)
+                .map(|changes| (table, changes))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+        transaction.commit()?;
+        // Only once it's committed, a failed save is written again
+        for (table, changes) in changes {
+            let hashes = written.entry(table).or_default();
+            for (id, hash) in changes {
+                match hash {
+                    Some(hash) => hashes.insert(id, hash),
+                    None => hashes.remove(&id),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy the database next to it, return the path of the copy
+    pub(super) fn backup(&self, path: &Path) -> Result<PathBuf, DataError> {
+        let backup = backup_path(path);
+        let inner = self.inner.lock().unwrap();
+        // A plain copy would miss what is still in the WAL file
+        inner
+            .connection
+            .execute("VACUUM INTO ?1", params![backup.to_string_lossy().into_owned()])?;
+        Ok(backup)
+    }
+}
+
+/// Every row of the table, their hashes are remembered in `written`
+fn read_rows(
+    connection: &Connection,
+    table: &'static str,
+    column: &str,
+    written: &mut HashMap<&'static str, HashMap<i64, u64>>,
+) -> Result<Vec<(i64, String)>, rusqlite::Error> {
+    let mut statement = connection.prepare(&format!("SELECT id, {} FROM {}", column, table))?;
+    let rows = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<(i64, String)>, _>>()?;
+    let hashes = written.entry(table).or_default();
+    hashes.clear();
+    hashes.extend(rows.iter().map(|(id, value)| (*id, gen_hash(value))));
+    Ok(rows)
+}
+
+/// Write the rows that differ from `before`, and delete the ones that are gone.
+/// Returns the hashes of the rows written, `None` for the deleted ones
+fn write_rows(
+    transaction: &Transaction<'_>,
+    table: &str,
+    column: &str,
+    rows: Vec<(i64, Option<String>)>,
+    before: &HashMap<i64, u64>,
+    all: bool,
+) -> Result<Vec<(i64, Option<u64>)>, DataError> {
+    let mut upsert = transaction.prepare_cached(&format!(
+        "INSERT INTO {table} (id, {column}) VALUES (?1, ?2) \
+         ON CONFLICT (id) DO UPDATE SET {column} = excluded.{column}",
+        table = table,
+        column = column,
+    ))?;
+    let mut delete =
+        transaction.prepare_cached(&format!("DELETE FROM {} WHERE id = ?1", table))?;
+    let mut changes = Vec::new();
+    let mut kept = HashSet::with_capacity(if all { rows.len() } else { 0 });
+    for (id, value) in rows {
+        match value {
+            Some(value) => {
+                let hash = gen_hash(&value);
+                if before.get(&id) != Some(&hash) {
+                    upsert.execute(params![id, value])?;
+                    changes.push((id, Some(hash)));
+                }
+                if all {
+                    kept.insert(id);
+                }
+            }
+            None => {
+                delete.execute(params![id])?;
+                changes.push((id, None));
+            }
+        }
+    }
+    if all {
+        for &id in before.keys().filter(|id| !kept.contains(id)) {
+            delete.execute(params![id])?;
+            changes.push((id, None));
+        }
+    }
+    Ok(changes)
+}
@@ -0,0 +1,232 @@
+//! The JSON format of `/export json`, unlike OPML it keeps the settings of the subscriptions
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::data::{Digest, Discussion, Feed, ItemFilter, Settings, Subscription, HASH_VERSION};
+
+/// Bumped when the meaning of an existing field changes,
+/// new fields are simply ignored by older versions.
+/// 2: `clean_links` can be `off`, to override the chat defaults, which are exported too
+pub const VERSION: u32 = 2;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Export {
+    pub version: u32,
+    /// Discussion groups are only restored to the chat they were exported from
+    pub chat: i64,
+    /// Of the chat, for the subscriptions that don't override them
+    #[serde(default, skip_serializing_if = "Settings::is_empty")]
+    pub defaults: Settings,
+    pub feeds: Vec<ExportedFeed>,
+    /// How the `seen` hashes were made, see `data::HASH_VERSION`.
+    /// The exports without it have the same hashes as 1
+    #[serde(default = "first_hash_version")]
+    pub hash_version: u32,
+    #[serde(flatten, skip_serializing)]
+    unknown: BTreeMap<String, serde_json::Value>,
+}
+
+fn first_hash_version() -> u32 {
+    1
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExportedFeed {
+    pub link: String,
+    pub title: String,
+    // Not a flattened `Subscription`, it has a flattened field itself,
+    // and those don't take their fields away from `unknown`
+    #[serde(flatten)]
+    pub settings: Settings,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub discussion: Option<Discussion>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    #[serde(default, skip_serializing_if = "ItemFilter::is_empty")]
+    pub filter: ItemFilter,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<Digest>,
+    /// Only exported in private chats
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cookie: Option<String>,
+    /// Hashes of the items already seen, newest first, only in `/export json full`.
+    /// They only depend on the items, another instance of the bot can use them
+    /// if it makes them the same way, see `Export::hash_version`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub seen: Vec<u64>,
+    // Must stay after `settings`, it only gets the fields nobody else took
+    #[serde(flatten, skip_serializing)]
+    unknown: BTreeMap<String, serde_json::Value>,
+}
+
+impl ExportedFeed {
+    pub fn subscription(&self) -> Subscription {
+        Subscription {
+            settings: self.settings.clone(),
+            discussion: self.discussion,
+            note: self.note.clone(),
+            filter: self.filter.clone(),
+            digest: self.digest,
+        }
+    }
+}
+
+impl Export {
+    pub fn new(feeds: &[Feed], chat: i64, with_secrets: bool) -> Self {
+        let feeds = feeds
+            .iter()
+            .map(|feed| {
+                let subscription = feed.subscription(chat);
+                ExportedFeed {
+                    link: feed.link.clone(),
+                    title: feed.title.clone(),
+                    settings: subscription.settings,
+                    discussion: subscription.discussion,
+                    note: subscription.note,
+                    filter: subscription.filter,
+                    digest: subscription.digest,
+                    cookie: feed
+                        .cookie()
+                        .filter(|_| with_secrets)
+                        .map(ToString::to_string),
+                    seen: Vec::new(),
+                    unknown: BTreeMap::new(),
+                }
+            })
+            .collect();
+        Export {
+            version: VERSION,
+            chat,
+            defaults: Settings::default(),
+            feeds,
+            hash_version: HASH_VERSION,
+            unknown: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_defaults(mut self, defaults: Settings) -> Self {
+        self.defaults = defaults;
+        self
+    }
+
+    /// Include what the bot remembers of the items of every feed
+    pub fn with_seen_hashes<F>(mut self, mut seen_hashes: F) -> Self
+    where
+        F: FnMut(&str) -> Vec<u64>,
+    {
+        for feed in &mut self.feeds {
+            feed.seen = seen_hashes(&feed.link);
+        }
+        self
+    }
+
+    /// Fields from newer versions that were ignored
+    pub fn unknown_fields(&self) -> usize {
+        self.unknown.len()
+            + self
+                .feeds
+                .iter()
+                .map(|feed| feed.unknown.len())
+                .sum::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::data::{DigestSchedule, FeedReset, LanguageFilter, LinkCleaning, MinWords};
+
+    #[test]
+    fn round_trip() {
+        let mut feed = Feed {
+            link: "http://example.com/feed.xml".into(),
+            title: "feed".into(),
+            ..Default::default()
+        };
+        let subscription = Subscription {
+            settings: Settings {
+                max_items: Some(3),
+                clean_links: Some(LinkCleaning::Resolve),
+                digest_above: Some(10),
+                translate: Some("pt-BR".into()),
+                min_words: Some(MinWords::Links),
+                lang: Some(LanguageFilter {
+                    languages: vec!["en".into(), "zh".into()],
+                    untagged: false,
+                }),
+                date: Some("relative".into()),
+                max_queue_age: Some(6 * 60 * 60),
+                reset: Some(FeedReset::Latest(5)),
+                reset_history: Some(20),
+            },
+            discussion: Some(Discussion {
+                chat_id: -2,
+                enabled_by: 1,
+            }),
+            note: Some("ask @alice before removing".into()),
+            filter: ItemFilter {
+                include: Some("rust|go".into()),
+                exclude: Some("sponsored".into()),
+            },
+            digest: Some(Digest {
+                schedule: DigestSchedule::Daily(18 * 60),
+                last_sent: 1_700_000_000,
+            }),
+        };
+        feed.subscriptions.insert(-1, subscription.clone());
+        let defaults = Settings {
+            clean_links: Some(LinkCleaning::Strip),
+            ..Default::default()
+        };
+        let export = Export::new(&[feed], -1, false).with_defaults(defaults.clone());
+        let json = serde_json::to_string(&export).unwrap();
+        let imported: Export = serde_json::from_str(&json).unwrap();
+        assert_eq!(imported, export);
+        assert_eq!(imported.defaults, defaults);
+        assert_eq!(imported.feeds[0].subscription(), subscription);
+        assert_eq!(imported.unknown_fields(), 0);
+        assert!(!json.contains("seen"));
+    }
+
+    #[test]
+    fn seen_hashes() {
+        let feed = Feed {
+            link: "http://example.com/feed.xml".into(),
+            title: "feed".into(),
+            ..Default::default()
+        };
+        let export = Export::new(&[feed], 1, false).with_seen_hashes(|link| {
+            assert_eq!(link, "http://example.com/feed.xml");
+            vec![u64::MAX, 1]
+        });
+        let json = serde_json::to_string(&export).unwrap();
+        let imported: Export = serde_json::from_str(&json).unwrap();
+        assert_eq!(imported.feeds[0].seen, [u64::MAX, 1]);
+        assert_eq!(imported.hash_version, HASH_VERSION);
+
+        // Exported before the version was, by the same hashes
+        let json = r#"{"version": 2, "chat": 1, "feeds": []}"#;
+        let old: Export = serde_json::from_str(json).unwrap();
+        assert_eq!(old.hash_version, 1);
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let json = r#"{
+            "version": 3,
+            "chat": 1,
+            "exported_by": "rssbot 3",
+            "feeds": [
+                {"link": "http://example.com/feed.xml", "title": "feed", "max_items": 1,
+                 "paused": true, "tags": ["news"]},
+                {"link": "http://example.com/other.xml", "title": "other"}
+            ]
+        }"#;
+        let imported: Export = serde_json::from_str(json).unwrap();
+        assert_eq!(imported.feeds[0].settings.max_items, Some(1));
+        assert_eq!(imported.feeds[1].subscription(), Subscription::default());
+        assert_eq!(imported.unknown_fields(), 3);
+    }
+}
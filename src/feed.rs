@@ -1,43 +1,130 @@
 use std::borrow::Cow;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
 use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
+use chrono::{DateTime, Datelike, FixedOffset, Timelike, Utc};
 use quick_xml::events::attributes::Attributes;
 use quick_xml::events::BytesStart;
 use quick_xml::events::Event as XmlEvent;
 use quick_xml::Reader as XmlReader;
 use regex::Regex;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::sync::LazyLock;
+use thiserror::Error;
+
+// Limits for crafted feeds, far beyond what real ones need
+const MAX_DEPTH: usize = 64;
+const MAX_ATTRIBUTES: usize = 64;
+/// Items after these are ignored
+const MAX_ITEMS: usize = 1000;
+// How often `parse_until` checks if it should stop
+const STOP_CHECK_EVENTS: u64 = 1024;
+/// How long parsing may take after the download finished
+pub const PARSE_BUDGET: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum ParseError {
+    #[error(transparent)]
+    Xml(#[from] quick_xml::Error),
+    /// With the limit it's over
+    #[error("feed rejected: too complex, {0}")]
+    TooComplex(&'static str),
+}
 
 trait FromXml: Sized {
     fn from_xml<B: std::io::BufRead>(
-        bufs: &BufPool,
+        cx: &Context,
         reader: &mut XmlReader<B>,
         start: &BytesStart,
-    ) -> quick_xml::Result<Self>;
+    ) -> Result<Self, ParseError>;
 }
 
 #[derive(Debug, Eq, PartialEq)]
 enum AtomLink<'a> {
-    Alternate(String),
+    /// `html` if the type is text/html
+    Alternate { href: String, html: bool },
+    /// Without `rel`, which means alternate by default
+    Unspecified(String),
     Source(String),
     Hub(String),
+    Enclosure(String),
     Other(String, Cow<'a, str>),
 }
 
+impl AtomLink<'_> {
+    /// Lower is better as the link to the web page, `None` never points to one
+    fn priority(&self) -> Option<u8> {
+        match self {
+            AtomLink::Alternate { html: true, .. } => Some(0),
+            AtomLink::Unspecified(_) => Some(1),
+            AtomLink::Alternate { html: false, .. } => Some(2),
+            AtomLink::Enclosure(_) | AtomLink::Other(..) => Some(3),
+            AtomLink::Source(_) | AtomLink::Hub(_) => None,
+        }
+    }
+
+    fn into_href(self) -> String {
+        match self {
+            AtomLink::Alternate { href, .. }
+            | AtomLink::Unspecified(href)
+            | AtomLink::Source(href)
+            | AtomLink::Hub(href)
+            | AtomLink::Enclosure(href)
+            | AtomLink::Other(href, _) => href,
+        }
+    }
+}
+
+/// Feeds and entries may have many links, keep the best one.
+/// The first one wins if they are equally good.
+#[derive(Default)]
+struct LinkPicker {
+    best: Option<(u8, String)>,
+}
+
+impl LinkPicker {
+    /// The text of RSS `<link>` is always the web page
+    fn offer_text(&mut self, href: String) {
+        self.offer(0, href);
+    }
+
+    fn offer_atom(&mut self, link: AtomLink) {
+        if let Some(priority) = link.priority() {
+            self.offer(priority, link.into_href());
+        }
+    }
+
+    fn offer(&mut self, priority: u8, href: String) {
+        if self.best.as_ref().map_or(true, |(best, _)| priority < *best) {
+            self.best = Some((priority, href));
+        }
+    }
+
+    fn into_link(self) -> Option<String> {
+        self.best.map(|(_, href)| href)
+    }
+}
+
 fn parse_atom_link<'a, B: std::io::BufRead>(
     reader: &mut XmlReader<B>,
     attributes: Attributes<'a>,
 ) -> quick_xml::Result<Option<AtomLink<'a>>> {
     let mut href = None;
     let mut rel = None;
+    let mut html = false;
     for attribute in attributes {
         let attribute = attribute?;
         match &*reader.decode(attribute.key) {
             "href" => href = Some(attribute.unescape_and_decode_value(reader)?),
+            "type" => {
+                html = attribute
+                    .unescape_and_decode_value(reader)?
+                    .starts_with("text/html")
+            }
             "rel" => {
                 rel = Some(reader.decode(if let Cow::Borrowed(s) = attribute.value {
                     s
@@ -53,29 +140,44 @@ fn parse_atom_link<'a, B: std::io::BufRead>(
     Ok(href.map(move |href| {
         if let Some(rel) = rel {
             match &*rel {
-                "alternate" => AtomLink::Alternate(href),
+                "alternate" => AtomLink::Alternate { href, html },
                 "self" => AtomLink::Source(href),
                 "hub" => AtomLink::Hub(href),
+                "enclosure" => AtomLink::Enclosure(href),
                 _ => AtomLink::Other(href, rel),
             }
         } else {
-            AtomLink::Alternate(href)
+            AtomLink::Unspecified(href)
         }
     }))
 }
 
+fn attribute_value<B: std::io::BufRead>(
+    reader: &XmlReader<B>,
+    element: &BytesStart,
+    name: &str,
+) -> quick_xml::Result<Option<String>> {
+    for attribute in element.attributes() {
+        let attribute = attribute?;
+        if reader.decode(attribute.key) == name {
+            return Ok(Some(attribute.unescape_and_decode_value(reader)?));
+        }
+    }
+    Ok(None)
+}
+
 struct SkipThisElement;
 
 impl FromXml for SkipThisElement {
     fn from_xml<B: std::io::BufRead>(
-        bufs: &BufPool,
+        cx: &Context,
         reader: &mut XmlReader<B>,
         _start: &BytesStart,
-    ) -> quick_xml::Result<Self> {
-        let mut buf = bufs.pop();
+    ) -> Result<Self, ParseError> {
+        let mut buf = cx.bufs.pop();
         let mut depth = 1u64;
         loop {
-            match reader.read_event(&mut buf)? {
+            match cx.read_event(reader, &mut buf)? {
                 XmlEvent::Start(_) => depth += 1,
                 XmlEvent::End(_) if depth == 1 => break,
                 XmlEvent::End(_) => depth -= 1,
@@ -90,16 +192,16 @@ impl FromXml for SkipThisElement {
 
 impl FromXml for Option<u32> {
     fn from_xml<B: std::io::BufRead>(
-        bufs: &BufPool,
+        cx: &Context,
         reader: &mut XmlReader<B>,
         _start: &BytesStart,
-    ) -> quick_xml::Result<Self> {
-        let mut buf = bufs.pop();
+    ) -> Result<Self, ParseError> {
+        let mut buf = cx.bufs.pop();
         let mut output = None;
         loop {
-            match reader.read_event(&mut buf)? {
+            match cx.read_event(reader, &mut buf)? {
                 XmlEvent::Start(ref e) => {
-                    SkipThisElement::from_xml(bufs, reader, e)?;
+                    SkipThisElement::from_xml(cx, reader, e)?;
                 }
                 XmlEvent::Text(ref e) => {
                     let text = reader.decode(e);
@@ -116,16 +218,16 @@ impl FromXml for Option<u32> {
 
 impl FromXml for Option<String> {
     fn from_xml<B: std::io::BufRead>(
-        bufs: &BufPool,
+        cx: &Context,
         reader: &mut XmlReader<B>,
         _start: &BytesStart,
-    ) -> quick_xml::Result<Self> {
-        let mut buf = bufs.pop();
+    ) -> Result<Self, ParseError> {
+        let mut buf = cx.bufs.pop();
         let mut content: Option<String> = None;
         loop {
-            match reader.read_event(&mut buf)? {
+            match cx.read_event(reader, &mut buf)? {
                 XmlEvent::Start(ref e) => {
-                    SkipThisElement::from_xml(bufs, reader, e)?;
+                    SkipThisElement::from_xml(cx, reader, e)?;
                 }
                 XmlEvent::Text(ref e) => {
                     let text = e.unescape_and_decode(reader)?;
@@ -146,37 +248,161 @@ impl FromXml for Option<String> {
 
 #[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
 pub struct Rss {
+    #[serde(flatten)]
+    pub meta: FeedMeta,
+    pub items: Vec<Item>,
+}
+
+/// What the feed tells about itself, apart from its items
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct FeedMeta {
     pub title: String,
     #[serde(rename = "home_page_url", default)]
     pub link: String,
     #[serde(rename = "feed_url")]
     pub source: Option<String>,
+    /// In minutes, from `<ttl>` or the syndication module
     pub ttl: Option<u32>,
-    pub items: Vec<Item>,
+    /// Like `en-US`, the language of the items without their own
+    #[serde(default)]
+    pub language: Option<String>,
+    #[serde(skip)]
+    pub skip: SkipHints,
+    /// WebSub hubs that announce the updates of the feed
+    #[serde(default, deserialize_with = "deserialize_hubs")]
+    pub hubs: Vec<String>,
+    /// The software that made the feed
+    #[serde(skip)]
+    pub generator: Option<String>,
 }
 
-impl FromXml for Rss {
+/// `<skipHours>` and `<skipDays>` of RSS, when the feed says it isn't updated
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SkipHints {
+    /// Hours of the day in UTC, sorted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hours: Vec<u8>,
+    /// Days of the week in UTC from 0 for Monday, sorted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<u8>,
+}
+
+impl SkipHints {
+    pub fn is_empty(&self) -> bool {
+        self.hours.is_empty() && self.days.is_empty()
+    }
+
+    /// Whether a fetch at `time` can be skipped. Skipping every hour or every day
+    /// is ignored, the feed would never be fetched again
+    pub fn skips(&self, time: DateTime<Utc>) -> bool {
+        let hour = time.hour() as u8;
+        let day = time.weekday().num_days_from_monday() as u8;
+        (self.hours.len() < 24 && self.hours.contains(&hour))
+            || (self.days.len() < 7 && self.days.contains(&day))
+    }
+}
+
+/// The `url`s of the `hubs` of a JSON Feed
+fn deserialize_hubs<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Hub {
+        url: String,
+    }
+    let hubs: Option<Vec<Hub>> = Deserialize::deserialize(deserializer)?;
+    Ok(hubs.into_iter().flatten().map(|hub| hub.url).collect())
+}
+
+/// The text of the child elements, like the `<hour>`s of `<skipHours>`
+struct Values(Vec<String>);
+
+impl FromXml for Values {
     fn from_xml<B: std::io::BufRead>(
-        bufs: &BufPool,
+        cx: &Context,
         reader: &mut XmlReader<B>,
         _start: &BytesStart,
-    ) -> quick_xml::Result<Self> {
-        let mut buf = bufs.pop();
+    ) -> Result<Self, ParseError> {
+        let mut buf = cx.bufs.pop();
+        let mut values = Vec::new();
+        loop {
+            match cx.read_event(reader, &mut buf)? {
+                XmlEvent::Start(ref e) => {
+                    if let Some(value) = <Option<String> as FromXml>::from_xml(cx, reader, e)? {
+                        values.push(value);
+                    }
+                }
+                XmlEvent::End(_) | XmlEvent::Eof => break,
+                _ => (),
+            }
+            buf.clear();
+        }
+        Ok(Values(values))
+    }
+}
+
+fn parse_skip_hours(values: Values) -> Vec<u8> {
+    let mut hours: Vec<u8> = values
+        .0
+        .iter()
+        .filter_map(|hour| hour.trim().parse().ok())
+        // Older RSS counts from 1 to 24
+        .filter(|hour| *hour <= 24)
+        .map(|hour| hour % 24)
+        .collect();
+    hours.sort_unstable();
+    hours.dedup();
+    hours
+}
+
+fn parse_skip_days(values: Values) -> Vec<u8> {
+    const DAYS: [&str; 7] = [
+        "monday",
+        "tuesday",
+        "wednesday",
+        "thursday",
+        "friday",
+        "saturday",
+        "sunday",
+    ];
+    let mut days: Vec<u8> = values
+        .0
+        .iter()
+        .filter_map(|day| DAYS.iter().position(|d| day.trim().eq_ignore_ascii_case(d)))
+        .map(|day| day as u8)
+        .collect();
+    days.sort_unstable();
+    days.dedup();
+    days
+}
+
+impl FromXml for Rss {
+    fn from_xml<B: std::io::BufRead>(
+        cx: &Context,
+        reader: &mut XmlReader<B>,
+        start: &BytesStart,
+    ) -> Result<Self, ParseError> {
+        let mut buf = cx.bufs.pop();
         let mut rss = Rss::default();
+        rss.meta.language = attribute_value(reader, start, "xml:lang")?;
+        let mut links = LinkPicker::default();
         let mut reading_rss_1_0_head = false;
+        let mut skipped_items = 0;
 
         // http://purl.org/rss/1.0/modules/syndication/
         let mut sy_period: Option<SyPeriod> = None;
         let mut sy_freq: Option<u32> = None;
 
         loop {
-            match reader.read_event(&mut buf)? {
+            match cx.read_event(reader, &mut buf)? {
                 XmlEvent::Empty(ref e) => {
                     if reader.decode(e.local_name()) == "link" {
                         match parse_atom_link(reader, e.attributes())? {
-                            Some(AtomLink::Alternate(link)) => rss.link = link,
-                            Some(AtomLink::Source(link)) => rss.source = Some(link),
-                            _ => {}
+                            Some(AtomLink::Source(link)) => rss.meta.source = Some(link),
+                            Some(AtomLink::Hub(link)) => rss.meta.hubs.push(link),
+                            Some(link) => links.offer_atom(link),
+                            None => {}
                         }
                     }
                 }
@@ -188,40 +414,66 @@ impl FromXml for Rss {
                         }
                         "title" => {
                             if let Some(title) =
-                                <Option<String> as FromXml>::from_xml(bufs, reader, e)?
+                                <Option<String> as FromXml>::from_xml(cx, reader, e)?
                             {
-                                rss.title = title;
+                                rss.meta.title = title;
                             }
                         }
                         "link" => {
                             if let Some(link) =
-                                <Option<String> as FromXml>::from_xml(bufs, reader, e)?
+                                <Option<String> as FromXml>::from_xml(cx, reader, e)?
                             {
                                 // RSS
-                                rss.link = link;
+                                links.offer_text(link);
                             } else {
                                 // ATOM
                                 match parse_atom_link(reader, e.attributes())? {
-                                    Some(AtomLink::Alternate(link)) => rss.link = link,
-                                    Some(AtomLink::Source(link)) => rss.source = Some(link),
-                                    _ => {}
+                                    Some(AtomLink::Source(link)) => rss.meta.source = Some(link),
+                                    Some(AtomLink::Hub(link)) => rss.meta.hubs.push(link),
+                                    Some(link) => links.offer_atom(link),
+                                    None => {}
                                 }
                             }
                         }
+                        "item" | "entry" if rss.items.len() < MAX_ITEMS => {
+                            rss.items.push(Item::from_xml(cx, reader, e)?);
+                        }
                         "item" | "entry" => {
-                            rss.items.push(Item::from_xml(bufs, reader, e)?);
+                            SkipThisElement::from_xml(cx, reader, e)?;
+                            skipped_items += 1;
                         }
                         "ttl" => {
-                            rss.ttl = <Option<u32> as FromXml>::from_xml(bufs, reader, e)?;
+                            rss.meta.ttl = <Option<u32> as FromXml>::from_xml(cx, reader, e)?;
+                        }
+                        // RSS, and dc:language of RSS 1.0
+                        "language" => {
+                            let language = <Option<String> as FromXml>::from_xml(cx, reader, e)?;
+                            if language.is_some() {
+                                rss.meta.language = language;
+                            }
+                        }
+                        // RSS and Atom, the version and URI attributes are left out
+                        "generator" => {
+                            rss.meta.generator =
+                                <Option<String> as FromXml>::from_xml(cx, reader, e)?;
+                        }
+                        // Some feeds get the case wrong
+                        name if name.eq_ignore_ascii_case("skipHours") => {
+                            let hours = Values::from_xml(cx, reader, e)?;
+                            rss.meta.skip.hours = parse_skip_hours(hours);
+                        }
+                        name if name.eq_ignore_ascii_case("skipDays") => {
+                            let days = Values::from_xml(cx, reader, e)?;
+                            rss.meta.skip.days = parse_skip_days(days);
                         }
                         "updatePeriod" => {
-                            sy_period = <Option<SyPeriod> as FromXml>::from_xml(bufs, reader, e)?;
+                            sy_period = <Option<SyPeriod> as FromXml>::from_xml(cx, reader, e)?;
                         }
                         "updateFrequency" => {
-                            sy_freq = <Option<u32> as FromXml>::from_xml(bufs, reader, e)?;
+                            sy_freq = <Option<u32> as FromXml>::from_xml(cx, reader, e)?;
                         }
                         _ => {
-                            SkipThisElement::from_xml(bufs, reader, e)?;
+                            SkipThisElement::from_xml(cx, reader, e)?;
                         }
                     }
                 }
@@ -234,9 +486,20 @@ impl FromXml for Rss {
             }
             buf.clear();
         }
-        if rss.ttl.is_none() {
+        if skipped_items > 0 {
+            eprintln!(
+                "{}: ignored {} items after the first {}",
+                crate::fetcher::current_feed().unwrap_or_default(),
+                skipped_items,
+                MAX_ITEMS
+            );
+        }
+        if let Some(link) = links.into_link() {
+            rss.meta.link = link;
+        }
+        if rss.meta.ttl.is_none() {
             let freq = sy_freq.unwrap_or(1); // 1 is the default value
-            rss.ttl = match sy_period {
+            rss.meta.ttl = match sy_period {
                 Some(SyPeriod::Hourly) => Some(60 / freq),
                 Some(SyPeriod::Daily) => Some((60 * 24) / freq),
                 Some(SyPeriod::Weekly) => Some((60 * 24 * 7) / freq),
@@ -255,50 +518,166 @@ pub struct Item {
     #[serde(rename = "url")]
     pub link: Option<String>,
     pub id: Option<String>,
+    #[serde(
+        rename = "date_published",
+        default,
+        deserialize_with = "deserialize_date"
+    )]
+    pub published: Option<DateTime<FixedOffset>>,
+    #[serde(default, deserialize_with = "deserialize_author")]
+    pub author: Option<String>,
+    /// The first attached media
+    #[serde(
+        rename = "attachments",
+        default,
+        deserialize_with = "deserialize_attachment"
+    )]
+    pub enclosure: Option<String>,
+    /// HTML or text of the content, or of the summary without one.
+    /// Only read by the filters, it's never sent
+    #[serde(rename = "content_html", default)]
+    pub description: Option<String>,
+    /// Its own, or the one of the feed after `inherit_language`
+    #[serde(default)]
+    pub language: Option<String>,
+}
+
+fn deserialize_attachment<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Attachment {
+        url: String,
+    }
+    let attachments: Option<Vec<Attachment>> = Option::deserialize(deserializer)?;
+    Ok(attachments.and_then(|attachments| attachments.into_iter().next().map(|a| a.url)))
+}
+
+fn deserialize_author<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct JsonAuthor {
+        name: Option<String>,
+    }
+    let author: Option<JsonAuthor> = Option::deserialize(deserializer)?;
+    Ok(author.and_then(|author| author.name))
+}
+
+fn deserialize_date<'de, D>(deserializer: D) -> Result<Option<DateTime<FixedOffset>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: Option<String> = Option::deserialize(deserializer)?;
+    Ok(s.as_deref().and_then(parse_date))
+}
+
+/// Parse RFC 2822 (RSS) and RFC 3339 (Atom, Dublin Core) dates
+pub fn parse_date(s: &str) -> Option<DateTime<FixedOffset>> {
+    let s = s.trim();
+    DateTime::parse_from_rfc3339(s)
+        .or_else(|_| DateTime::parse_from_rfc2822(s))
+        .ok()
+        .or_else(|| {
+            // Lots of feeds get the day of week wrong, it's optional anyway
+            let (_, rest) = s.split_once(',')?;
+            DateTime::parse_from_rfc2822(rest.trim()).ok()
+        })
 }
 
 impl FromXml for Item {
     fn from_xml<B: std::io::BufRead>(
-        bufs: &BufPool,
+        cx: &Context,
         reader: &mut XmlReader<B>,
-        _start: &BytesStart,
-    ) -> quick_xml::Result<Self> {
-        let mut buf = bufs.pop();
-        let mut item = Item::default();
+        start: &BytesStart,
+    ) -> Result<Self, ParseError> {
+        let mut buf = cx.bufs.pop();
+        let mut item = Item {
+            language: attribute_value(reader, start, "xml:lang")?,
+            ..Item::default()
+        };
+        let mut links = LinkPicker::default();
+        let mut updated = None;
         loop {
-            match reader.read_event(&mut buf)? {
-                XmlEvent::Empty(ref e) => {
-                    if reader.decode(e.name()) == "link" {
-                        if let Some(AtomLink::Alternate(link)) =
-                            parse_atom_link(reader, e.attributes())?
-                        {
-                            item.link = Some(link);
+            match cx.read_event(reader, &mut buf)? {
+                XmlEvent::Empty(ref e) => match &*reader.decode(e.name()) {
+                    "link" => {
+                        if let Some(link) = parse_atom_link(reader, e.attributes())? {
+                            item.add_atom_link(link, &mut links);
                         }
                     }
-                }
+                    "enclosure" => {
+                        // RSS
+                        if item.enclosure.is_none() {
+                            item.enclosure = attribute_value(reader, e, "url")?;
+                        }
+                    }
+                    _ => (),
+                },
                 XmlEvent::Start(ref e) => {
                     match &*reader.decode(e.name()) {
                         "title" => {
-                            item.title = <Option<String> as FromXml>::from_xml(bufs, reader, e)?;
+                            item.title = <Option<String> as FromXml>::from_xml(cx, reader, e)?;
                         }
                         "link" => {
                             if let Some(link) =
-                                <Option<String> as FromXml>::from_xml(bufs, reader, e)?
+                                <Option<String> as FromXml>::from_xml(cx, reader, e)?
                             {
                                 // RSS
-                                item.link = Some(link);
-                            } else if let Some(AtomLink::Alternate(link)) =
-                                parse_atom_link(reader, e.attributes())?
-                            {
+                                links.offer_text(link);
+                            } else if let Some(link) = parse_atom_link(reader, e.attributes())? {
                                 // ATOM
-                                item.link = Some(link);
+                                item.add_atom_link(link, &mut links);
+                            }
+                        }
+                        "enclosure" => {
+                            if item.enclosure.is_none() {
+                                item.enclosure = attribute_value(reader, e, "url")?;
                             }
+                            SkipThisElement::from_xml(cx, reader, e)?;
                         }
                         "id" | "guid" => {
-                            item.id = <Option<String> as FromXml>::from_xml(bufs, reader, e)?;
+                            item.id = <Option<String> as FromXml>::from_xml(cx, reader, e)?;
+                        }
+                        "description" | "summary" => {
+                            let summary = <Option<String> as FromXml>::from_xml(cx, reader, e)?;
+                            if item.description.is_none() {
+                                item.description = summary;
+                            }
+                        }
+                        // Atom XHTML content is nested elements, it's skipped
+                        "content:encoded" | "content" => {
+                            if let Some(content) =
+                                <Option<String> as FromXml>::from_xml(cx, reader, e)?
+                            {
+                                item.description = Some(content);
+                            }
+                        }
+                        "pubDate" | "published" | "issued" | "dc:date" => {
+                            item.published = <Option<String> as FromXml>::from_xml(cx, reader, e)?
+                                .as_deref()
+                                .and_then(parse_date);
+                        }
+                        "author" | "dc:creator" => {
+                            if let Author(Some(author)) = Author::from_xml(cx, reader, e)? {
+                                item.author = Some(author);
+                            }
+                        }
+                        "dc:language" => {
+                            let language = <Option<String> as FromXml>::from_xml(cx, reader, e)?;
+                            if language.is_some() {
+                                item.language = language;
+                            }
+                        }
+                        "updated" | "modified" => {
+                            updated = <Option<String> as FromXml>::from_xml(cx, reader, e)?
+                                .as_deref()
+                                .and_then(parse_date);
                         }
                         _ => {
-                            SkipThisElement::from_xml(bufs, reader, e)?;
+                            SkipThisElement::from_xml(cx, reader, e)?;
                         }
                     }
                 }
@@ -307,10 +686,58 @@ impl FromXml for Item {
             }
             buf.clear();
         }
+        item.link = links.into_link();
+        if item.published.is_none() {
+            item.published = updated;
+        }
         Ok(item)
     }
 }
 
+impl Item {
+    fn add_atom_link(&mut self, link: AtomLink, links: &mut LinkPicker) {
+        if let AtomLink::Enclosure(href) = &link {
+            self.enclosure.get_or_insert_with(|| href.clone());
+        }
+        links.offer_atom(link);
+    }
+}
+
+/// RSS puts the name in the text, Atom in the `name` element
+struct Author(Option<String>);
+
+impl FromXml for Author {
+    fn from_xml<B: std::io::BufRead>(
+        cx: &Context,
+        reader: &mut XmlReader<B>,
+        _start: &BytesStart,
+    ) -> Result<Self, ParseError> {
+        let mut buf = cx.bufs.pop();
+        let mut name = None;
+        loop {
+            match cx.read_event(reader, &mut buf)? {
+                XmlEvent::Start(ref e) => {
+                    if reader.decode(e.local_name()) == "name" {
+                        name = <Option<String> as FromXml>::from_xml(cx, reader, e)?;
+                    } else {
+                        SkipThisElement::from_xml(cx, reader, e)?;
+                    }
+                }
+                XmlEvent::Text(ref e) => {
+                    name = Some(e.unescape_and_decode(reader)?);
+                }
+                XmlEvent::CData(ref e) => {
+                    name = Some(reader.decode(e).to_string());
+                }
+                XmlEvent::End(_) | XmlEvent::Eof => break,
+                _ => (),
+            }
+            buf.clear();
+        }
+        Ok(Author(name))
+    }
+}
+
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 enum SyPeriod {
     Hourly,
@@ -322,16 +749,16 @@ enum SyPeriod {
 
 impl FromXml for Option<SyPeriod> {
     fn from_xml<B: std::io::BufRead>(
-        bufs: &BufPool,
+        cx: &Context,
         reader: &mut XmlReader<B>,
         _start: &BytesStart,
-    ) -> quick_xml::Result<Self> {
-        let mut buf = bufs.pop();
+    ) -> Result<Self, ParseError> {
+        let mut buf = cx.bufs.pop();
         let mut output = None;
         loop {
-            match reader.read_event(&mut buf)? {
+            match cx.read_event(reader, &mut buf)? {
                 XmlEvent::Start(ref e) => {
-                    SkipThisElement::from_xml(bufs, reader, e)?;
+                    SkipThisElement::from_xml(cx, reader, e)?;
                 }
                 XmlEvent::Text(ref e) => {
                     let period = match &*reader.decode(e) {
@@ -354,29 +781,132 @@ impl FromXml for Option<SyPeriod> {
 }
 
 /// NOTE: This function doesn't check the syntax of feed, it only cares about performance
-pub fn parse<B: std::io::BufRead>(reader: B) -> quick_xml::Result<Rss> {
+pub fn parse<B: std::io::BufRead>(reader: B) -> Result<Rss, ParseError> {
+    parse_until(reader, &AtomicBool::new(false))
+}
+
+/// Like `parse`, but gives up once `stop` is set, see `PARSE_BUDGET`
+pub fn parse_until<B: std::io::BufRead>(reader: B, stop: &AtomicBool) -> Result<Rss, ParseError> {
     let mut reader = XmlReader::from_reader(reader);
     reader.trim_text(true);
-    let bufs = BufPool::new(4, 512);
-    let mut buf = bufs.pop();
+    let cx = Context {
+        bufs: BufPool::new(4, 512),
+        stop,
+        depth: Cell::new(0),
+        events: Cell::new(0),
+    };
+    let mut buf = cx.bufs.pop();
     loop {
-        match reader.read_event(&mut buf)? {
+        match cx.read_event(&mut reader, &mut buf)? {
             XmlEvent::Start(ref e) => match &*reader.decode(e.name()) {
                 "rss" => continue,
                 "channel" | "feed" | "rdf:RDF" => {
-                    return Rss::from_xml(&bufs, &mut reader, e);
+                    return Rss::from_xml(&cx, &mut reader, e);
                 }
                 _ => {
-                    SkipThisElement::from_xml(&bufs, &mut reader, e)?;
+                    SkipThisElement::from_xml(&cx, &mut reader, e)?;
                 }
             },
-            XmlEvent::Eof => return Err(quick_xml::Error::UnexpectedEof("feed".to_string())),
+            XmlEvent::Eof => {
+                let eof = quick_xml::Error::UnexpectedEof("feed".to_string());
+                return Err(ParseError::Xml(eof));
+            }
             _ => (),
         }
         buf.clear();
     }
 }
 
+/// A part of a body that is parsed while it's downloaded
+pub type Chunk = Box<dyn AsRef<[u8]> + Send>;
+
+/// Reads the chunks as they come, the body is never in a single buffer
+pub struct Chunks<I> {
+    chunks: I,
+    current: Option<Chunk>,
+    pos: usize,
+}
+
+impl<I: Iterator<Item = Chunk>> Chunks<I> {
+    pub fn new(chunks: I) -> Self {
+        Chunks {
+            chunks,
+            current: None,
+            pos: 0,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Chunk>> std::io::Read for Chunks<I> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        use std::io::BufRead;
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<I: Iterator<Item = Chunk>> std::io::BufRead for Chunks<I> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        while self
+            .current
+            .as_ref()
+            .is_none_or(|chunk| self.pos >= (**chunk).as_ref().len())
+        {
+            match self.chunks.next() {
+                Some(chunk) => {
+                    self.current = Some(chunk);
+                    self.pos = 0;
+                }
+                None => return Ok(&[]),
+            }
+        }
+        let chunk = self.current.as_ref().unwrap();
+        Ok(&(**chunk).as_ref()[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+/// Login pages are often served with the content type of the feed they replaced,
+/// so look at the body instead
+pub fn looks_like_html(body: &[u8]) -> bool {
+    let mut rest = body.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(body);
+    loop {
+        rest = &rest[rest
+            .iter()
+            .position(|b| !b.is_ascii_whitespace())
+            .unwrap_or(rest.len())..];
+        // XHTML pages may start with the same prolog as a feed
+        let end = if rest.starts_with(b"<?") {
+            find(rest, b"?>").map(|i| i + 2)
+        } else if rest.starts_with(b"<!--") {
+            find(rest, b"-->").map(|i| i + 3)
+        } else {
+            break;
+        };
+        match end {
+            Some(end) => rest = &rest[end..],
+            None => return false,
+        }
+    }
+    let head = &rest[..rest.len().min(14)];
+    head.eq_ignore_ascii_case(b"<!doctype html")
+        || head.len() >= 5
+            && head[..5].eq_ignore_ascii_case(b"<html")
+            && head.get(5).is_none_or(|b| *b == b'>' || b.is_ascii_whitespace())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
 fn url_relative_to_absolute(link: &mut String, host: &str) {
     match link.as_str() {
         _ if link.starts_with("//") => {
@@ -398,19 +928,85 @@ pub fn fix_relative_url(mut rss: Rss, rss_link: &str) -> Rss {
     let rss_host = HOST
         .captures(rss_link)
         .map_or(rss_link, |r| r.get(0).unwrap().as_str());
-    match rss.link.as_str() {
-        "" | "/" => rss.link = rss_host.to_owned(),
-        _ => url_relative_to_absolute(&mut rss.link, rss_host),
+    match rss.meta.link.as_str() {
+        "" | "/" => rss.meta.link = rss_host.to_owned(),
+        _ => url_relative_to_absolute(&mut rss.meta.link, rss_host),
     }
     for item in &mut rss.items {
         if let Some(link) = item.link.as_mut() {
             url_relative_to_absolute(link, rss_host);
         }
+        if let Some(enclosure) = item.enclosure.as_mut() {
+            url_relative_to_absolute(enclosure, rss_host);
+        }
     }
 
     rss
 }
 
+/// Items without a language of their own have the one of the feed
+pub fn inherit_language(mut rss: Rss) -> Rss {
+    if let Some(language) = &rss.meta.language {
+        for item in &mut rss.items {
+            if item.language.is_none() {
+                item.language = Some(language.clone());
+            }
+        }
+    }
+    rss
+}
+
+/// Shared by the parsers of the elements, every event is read through it
+struct Context<'a> {
+    bufs: BufPool,
+    stop: &'a AtomicBool,
+    /// Elements open at the current event
+    depth: Cell<usize>,
+    events: Cell<u64>,
+}
+
+impl Context<'_> {
+    fn read_event<'b, B: std::io::BufRead>(
+        &self,
+        reader: &mut XmlReader<B>,
+        buf: &'b mut Vec<u8>,
+    ) -> Result<XmlEvent<'b>, ParseError> {
+        let event = reader.read_event(buf)?;
+        let events = self.events.get() + 1;
+        self.events.set(events);
+        if events % STOP_CHECK_EVENTS == 0 && self.stop.load(Ordering::Relaxed) {
+            return Err(ParseError::TooComplex("took too long to parse"));
+        }
+        match &event {
+            XmlEvent::Start(e) => {
+                let depth = self.depth.get() + 1;
+                if depth > MAX_DEPTH {
+                    return Err(ParseError::TooComplex("nested too deeply"));
+                }
+                self.depth.set(depth);
+                check_attributes(e)?;
+            }
+            XmlEvent::Empty(e) => check_attributes(e)?,
+            XmlEvent::End(_) => self.depth.set(self.depth.get().saturating_sub(1)),
+            _ => (),
+        }
+        Ok(event)
+    }
+}
+
+/// Without the checks for duplicates, they take quadratic time
+fn check_attributes(element: &BytesStart) -> Result<(), ParseError> {
+    let count = element
+        .attributes()
+        .with_checks(false)
+        .take(MAX_ATTRIBUTES + 1)
+        .count();
+    if count > MAX_ATTRIBUTES {
+        return Err(ParseError::TooComplex("too many attributes"));
+    }
+    Ok(())
+}
+
 struct BufPool {
     pool: Rc<RefCell<Vec<Vec<u8>>>>,
     capacity: usize,
@@ -466,11 +1062,23 @@ mod test {
 
     use super::*;
 
+    fn date(s: &str) -> Option<DateTime<FixedOffset>> {
+        Some(DateTime::parse_from_rfc3339(s).unwrap())
+    }
+
+    /// The RSS fixtures skip every day, and every hour but 22
+    fn fixture_skip() -> SkipHints {
+        SkipHints {
+            hours: (0..22).chain([23]).collect(),
+            days: (0..7).collect(),
+        }
+    }
+
     #[test]
     fn encoding() {
         let s: &[u8] = &*include_bytes!("../tests/data/encoding.xml");
         let r = parse(Cursor::new(s)).unwrap();
-        assert_eq!(r.title, "虎扑足球新闻")
+        assert_eq!(r.meta.title, "虎扑足球新闻")
     }
 
     #[test]
@@ -480,21 +1088,34 @@ mod test {
         assert_eq!(
             r,
             Rss {
-                title: "atom_0.3.feed.title".into(),
-                link: "atom_0.3.feed.link^href".into(),
+                meta: FeedMeta {
+                    title: "atom_0.3.feed.title".into(),
+                    link: "atom_0.3.feed.link^href".into(),
+                    generator: Some("atom_0.3.feed.generator".into()),
+                    ..FeedMeta::default()
+                },
                 items: vec![
                     Item {
                         title: Some("atom_0.3.feed.entry[0].title".into()),
                         link: Some("atom_0.3.feed.entry[0].link^href".into()),
                         id: Some("atom_0.3.feed.entry[0]^id".into()),
+                        published: date("2000-01-01T01:00:00Z"),
+                        author: Some("atom_0.3.feed.entry[0].author.name".into()),
+                        enclosure: None,
+                        description: Some("atom_0.3.feed.entry[0].content[1]".into()),
+                        language: None,
                     },
                     Item {
                         title: Some("atom_0.3.feed.entry[1].title".into()),
                         link: Some("atom_0.3.feed.entry[1].link^href".into()),
                         id: Some("atom_0.3.feed.entry[1]^id".into()),
+                        published: date("2000-02-01T01:00:00Z"),
+                        author: Some("atom_0.3.feed.entry[1].author.name".into()),
+                        enclosure: None,
+                        description: Some("atom_0.3.feed.entry[1].content[1]".into()),
+                        language: None,
                     },
                 ],
-                ..Rss::default()
             }
         );
     }
@@ -506,26 +1127,157 @@ mod test {
         assert_eq!(
             r,
             Rss {
-                title: "atom_1.0.feed.title".into(),
-                link: "http://example.com/blog_plain".into(),
-                source: Some("http://example.com/blog/atom_1.0.xml".into()),
+                meta: FeedMeta {
+                    title: "atom_1.0.feed.title".into(),
+                    link: "http://example.com/blog".into(),
+                    language: Some("en-us".into()),
+                    source: Some("http://example.com/blog/atom_1.0.xml".into()),
+                    generator: Some("atom_1.0.feed.generator".into()),
+                    ..FeedMeta::default()
+                },
                 items: vec![
                     Item {
                         title: Some("atom_1.0.feed.entry[0].title".into()),
-                        link: Some("http://example.com/blog/entry1_plain".into()),
+                        link: Some("http://example.com/blog/entry1".into()),
                         id: Some("atom_1.0.feed.entry[0]^id".into()),
+                        published: date("2000-01-01T01:00:00Z"),
+                        author: Some("atom_1.0.feed.entry[0].author.name".into()),
+                        enclosure: Some("http://example.com/blog/enclosure1.gif".into()),
+                        description: Some("atom_1.0.feed.entry[0].content[0]".into()),
+                        language: None,
                     },
                     Item {
                         title: Some("atom_1.0.feed.entry[1].title".into()),
                         link: Some("http://example.com/blog/entry2".into()),
                         id: Some("atom_1.0.feed.entry[1]^id".into()),
+                        published: date("2000-02-01T01:00:00Z"),
+                        author: Some("atom_1.0.feed.entry[1].author.name".into()),
+                        enclosure: Some("http://example.com/blog/enclosure2.gif".into()),
+                        description: Some("atom_1.0.feed.entry[1].content[0]".into()),
+                        language: None,
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn github_releases() {
+        let s = include_str!("../tests/data/github_releases.xml");
+        let r = parse(Cursor::new(s)).unwrap();
+        assert_eq!(
+            r,
+            Rss {
+                meta: FeedMeta {
+                    title: "Release notes from rssbot".into(),
+                    link: "https://github.com/iovxw/rssbot/releases".into(),
+                    language: Some("en-US".into()),
+                    source: Some("https://github.com/iovxw/rssbot/releases.atom".into()),
+                    ..FeedMeta::default()
+                },
+                items: vec![
+                    Item {
+                        title: Some("v2.0.0-alpha.12".into()),
+                        link: Some(
+                            "https://github.com/iovxw/rssbot/releases/tag/v2.0.0-alpha.12".into()
+                        ),
+                        id: Some(
+                            "tag:github.com,2008:Repository/62155754/v2.0.0-alpha.12".into()
+                        ),
+                        published: date("2023-09-21T08:02:31Z"),
+                        author: Some("iovxw".into()),
+                        enclosure: None,
+                        description: Some("<ul>\n<li>Update dependencies</li>\n</ul>".into()),
+                        language: None,
+                    },
+                    Item {
+                        title: Some("v2.0.0-alpha.11".into()),
+                        link: Some(
+                            "https://github.com/iovxw/rssbot/releases/tag/v2.0.0-alpha.11".into()
+                        ),
+                        id: Some(
+                            "tag:github.com,2008:Repository/62155754/v2.0.0-alpha.11".into()
+                        ),
+                        published: date("2022-02-06T10:53:20Z"),
+                        author: Some("iovxw".into()),
+                        enclosure: None,
+                        description: Some("<p>Fix the build</p>".into()),
+                        language: None,
                     },
                 ],
-                ..Rss::default()
             }
         );
     }
 
+    #[test]
+    fn youtube() {
+        let s = include_str!("../tests/data/youtube.xml");
+        let r = parse(Cursor::new(s)).unwrap();
+        assert_eq!(
+            r,
+            Rss {
+                meta: FeedMeta {
+                    title: "Google for Developers".into(),
+                    link: "https://www.youtube.com/channel/UC_x5XG1OV2P6uZZ5FSM9Ttw".into(),
+                    source: Some(
+                        "http://www.youtube.com/feeds/videos.xml?channel_id=UC_x5XG1OV2P6uZZ5FSM9Ttw"
+                            .into()
+                    ),
+                    ..FeedMeta::default()
+                },
+                items: vec![Item {
+                    title: Some("What's new in Jetpack Compose".into()),
+                    link: Some("https://www.youtube.com/watch?v=8x3j0D5nU-k".into()),
+                    id: Some("yt:video:8x3j0D5nU-k".into()),
+                    published: date("2024-05-15T17:00:06Z"),
+                    author: Some("Google for Developers".into()),
+                    enclosure: None,
+                    description: None,
+                    language: None,
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn atom_link_precedence() {
+        let input = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+<link rel="self" href="http://example.com/feed.xml"/>
+<link rel="replies" type="text/html" href="http://example.com/comments"/>
+<link href="http://example.com/"/>
+<entry>
+<link rel="replies" type="text/html" href="http://example.com/1#comments"/>
+<link rel="enclosure" type="audio/mpeg" href="http://example.com/1.mp3"/>
+<link rel="alternate" type="application/atom+xml" href="http://example.com/1.xml"/>
+<link rel="alternate" type="text/html" href="http://example.com/1"/>
+</entry>
+<entry>
+<link rel="replies" type="text/html" href="http://example.com/2#comments"/>
+<link rel="alternate" href="http://example.com/2.txt"/>
+<link href="http://example.com/2"/>
+</entry>
+<entry>
+<link rel="replies" type="text/html" href="http://example.com/3#comments"/>
+<link rel="edit" href="http://example.com/3/edit"/>
+</entry>
+</feed>"#;
+        let r = parse(Cursor::new(input)).unwrap();
+        assert_eq!(r.meta.link, "http://example.com/");
+        assert_eq!(r.meta.source.as_deref(), Some("http://example.com/feed.xml"));
+        let links: Vec<_> = r.items.iter().map(|item| item.link.as_deref()).collect();
+        assert_eq!(
+            links,
+            [
+                Some("http://example.com/1"),
+                Some("http://example.com/2"),
+                Some("http://example.com/3#comments"),
+            ]
+        );
+        assert_eq!(r.items[0].enclosure.as_deref(), Some("http://example.com/1.mp3"));
+        assert_eq!(r.items[1].enclosure, None);
+    }
+
     #[test]
     fn rss09() {
         let s = include_str!("../tests/data/rss_0.9.xml");
@@ -533,21 +1285,33 @@ mod test {
         assert_eq!(
             r,
             Rss {
-                title: "rss_0.9.channel.title".into(),
-                link: "rss_0.9.channel.link".into(),
+                meta: FeedMeta {
+                    title: "rss_0.9.channel.title".into(),
+                    link: "rss_0.9.channel.link".into(),
+                    ..FeedMeta::default()
+                },
                 items: vec![
                     Item {
                         title: Some("rss_0.9.item[0].title".into()),
                         link: Some("rss_0.9.item[0].link".into()),
                         id: None,
+                        published: None,
+                        author: None,
+                        enclosure: None,
+                        description: None,
+                        language: None,
                     },
                     Item {
                         title: Some("rss_0.9.item[1].title".into()),
                         link: Some("rss_0.9.item[1].link".into()),
                         id: None,
+                        published: None,
+                        author: None,
+                        enclosure: None,
+                        description: None,
+                        language: None,
                     },
                 ],
-                ..Rss::default()
             }
         );
     }
@@ -559,21 +1323,35 @@ mod test {
         assert_eq!(
             r,
             Rss {
-                title: "rss_0.91.channel.title".into(),
-                link: "rss_0.91.channel.link".into(),
+                meta: FeedMeta {
+                    title: "rss_0.91.channel.title".into(),
+                    link: "rss_0.91.channel.link".into(),
+                    language: Some("rss_0.91.channel.language".into()),
+                    skip: fixture_skip(),
+                    ..FeedMeta::default()
+                },
                 items: vec![
                     Item {
                         title: Some("rss_0.91.channel.item[0].title".into()),
                         link: Some("rss_0.91.channel.item[0].link".into()),
                         id: None,
+                        published: None,
+                        author: None,
+                        enclosure: None,
+                        description: Some("rss_0.91.channel.item[0].description".into()),
+                        language: None,
                     },
                     Item {
                         title: Some("rss_0.91.channel.item[1].title".into()),
                         link: Some("rss_0.91.channel.item[1].link".into()),
                         id: None,
+                        published: None,
+                        author: None,
+                        enclosure: None,
+                        description: Some("rss_0.91.channel.item[1].description".into()),
+                        language: None,
                     },
                 ],
-                ..Rss::default()
             }
         );
     }
@@ -585,21 +1363,35 @@ mod test {
         assert_eq!(
             r,
             Rss {
-                title: "rss_0.92.channel.title".into(),
-                link: "rss_0.92.channel.link".into(),
+                meta: FeedMeta {
+                    title: "rss_0.92.channel.title".into(),
+                    link: "rss_0.92.channel.link".into(),
+                    language: Some("rss_0.92.channel.language".into()),
+                    skip: fixture_skip(),
+                    ..FeedMeta::default()
+                },
                 items: vec![
                     Item {
                         title: Some("rss_0.92.channel.item[0].title".into()),
                         link: Some("rss_0.92.channel.item[0].link".into()),
                         id: None,
+                        published: None,
+                        author: None,
+                        enclosure: Some("rss_0.92.channel.item[0].enclousure[0]^url".into()),
+                        description: Some("rss_0.92.channel.item[0].description".into()),
+                        language: None,
                     },
                     Item {
                         title: Some("rss_0.92.channel.item[1].title".into()),
                         link: Some("rss_0.92.channel.item[1].link".into()),
                         id: None,
+                        published: None,
+                        author: None,
+                        enclosure: Some("rss_0.92.channel.item[1].enclousure[0]^url".into()),
+                        description: Some("rss_0.92.channel.item[1].description".into()),
+                        language: None,
                     },
                 ],
-                ..Rss::default()
             }
         );
     }
@@ -611,21 +1403,35 @@ mod test {
         assert_eq!(
             r,
             Rss {
-                title: "rss_0.93.channel.title".into(),
-                link: "rss_0.93.channel.link".into(),
+                meta: FeedMeta {
+                    title: "rss_0.93.channel.title".into(),
+                    link: "rss_0.93.channel.link".into(),
+                    language: Some("rss_0.93.channel.language".into()),
+                    skip: fixture_skip(),
+                    ..FeedMeta::default()
+                },
                 items: vec![
                     Item {
                         title: Some("rss_0.93.channel.item[0].title".into()),
                         link: Some("rss_0.93.channel.item[0].link".into()),
                         id: None,
+                        published: date("2001-01-01T00:00:00Z"),
+                        author: None,
+                        enclosure: Some("rss_0.93.channel.item[0].enclousure[0]^url".into()),
+                        description: Some("rss_0.93.channel.item[0].description".into()),
+                        language: None,
                     },
                     Item {
                         title: Some("rss_0.93.channel.item[1].title".into()),
                         link: Some("rss_0.93.channel.item[1].link".into()),
                         id: None,
+                        published: date("2001-01-02T00:00:00Z"),
+                        author: None,
+                        enclosure: Some("rss_0.93.channel.item[1].enclousure[0]^url".into()),
+                        description: Some("rss_0.93.channel.item[1].description".into()),
+                        language: None,
                     },
                 ],
-                ..Rss::default()
             }
         );
     }
@@ -637,22 +1443,37 @@ mod test {
         assert_eq!(
             r,
             Rss {
-                title: "rss_0.94.channel.title".into(),
-                link: "rss_0.94.channel.link".into(),
-                ttl: Some(100),
+                meta: FeedMeta {
+                    title: "rss_0.94.channel.title".into(),
+                    link: "rss_0.94.channel.link".into(),
+                    language: Some("rss_0.94.channel.language".into()),
+                    ttl: Some(100),
+                    skip: fixture_skip(),
+                    generator: Some("rss_0.94.channel.generator".into()),
+                    ..FeedMeta::default()
+                },
                 items: vec![
                     Item {
                         title: Some("rss_0.94.channel.item[0].title".into()),
                         link: Some("rss_0.94.channel.item[0].link".into()),
                         id: Some("rss_0.94.channel.item[0].guid".into()),
+                        published: date("2001-01-01T00:00:00Z"),
+                        author: Some("rss_0.94.channel.item[0].author".into()),
+                        enclosure: Some("rss_0.94.channel.item[0].enclousure[0]^url".into()),
+                        description: Some("rss_0.94.channel.item[0].description".into()),
+                        language: None,
                     },
                     Item {
                         title: Some("rss_0.94.channel.item[1].title".into()),
                         link: Some("rss_0.94.channel.item[1].link".into()),
                         id: Some("rss_0.94.channel.item[1].guid".into()),
+                        published: date("2001-01-02T00:00:00Z"),
+                        author: Some("rss_0.94.channel.item[1].author".into()),
+                        enclosure: Some("rss_0.94.channel.item[1].enclousure[0]^url".into()),
+                        description: Some("rss_0.94.channel.item[1].description".into()),
+                        language: None,
                     },
                 ],
-                ..Rss::default()
             }
         );
     }
@@ -664,21 +1485,33 @@ mod test {
         assert_eq!(
             r,
             Rss {
-                title: "rss_1.0.channel.title".into(),
-                link: "rss_1.0.channel.link".into(),
+                meta: FeedMeta {
+                    title: "rss_1.0.channel.title".into(),
+                    link: "rss_1.0.channel.link".into(),
+                    ..FeedMeta::default()
+                },
                 items: vec![
                     Item {
                         title: Some("rss_1.0.item[0].title".into()),
                         link: Some("rss_1.0.item[0].link".into()),
                         id: None,
+                        published: None,
+                        author: None,
+                        enclosure: None,
+                        description: Some("rss_1.0.item[0].content".into()),
+                        language: None,
                     },
                     Item {
                         title: Some("rss_1.0.item[1].title".into()),
                         link: Some("rss_1.0.item[1].link".into()),
                         id: None,
+                        published: None,
+                        author: None,
+                        enclosure: None,
+                        description: Some("rss_1.0.item[1].content".into()),
+                        language: None,
                     },
                 ],
-                ..Rss::default()
             }
         );
     }
@@ -690,22 +1523,37 @@ mod test {
         assert_eq!(
             r,
             Rss {
-                title: "rss_2.0.channel.title".into(),
-                link: "rss_2.0.channel.link".into(),
-                ttl: Some(100),
+                meta: FeedMeta {
+                    title: "rss_2.0.channel.title".into(),
+                    link: "rss_2.0.channel.link".into(),
+                    language: Some("rss_2.0.channel.language".into()),
+                    ttl: Some(100),
+                    skip: fixture_skip(),
+                    generator: Some("rss_2.0.channel.generator".into()),
+                    ..FeedMeta::default()
+                },
                 items: vec![
                     Item {
                         title: Some("rss_2.0.channel.item[0].title".into()),
                         link: Some("rss_2.0.channel.item[0].link".into()),
                         id: Some("rss_2.0.channel.item[0].guid".into()),
+                        published: date("2001-01-01T00:00:00Z"),
+                        author: Some("rss_2.0.channel.item[0].author".into()),
+                        enclosure: Some("rss_2.0.channel.item[0].enclousure[0]^url".into()),
+                        description: Some("rss_2.0.channel.item[0].content".into()),
+                        language: None,
                     },
                     Item {
                         title: Some("rss_2.0.channel.item[1].title".into()),
                         link: Some("rss_2.0.channel.item[1].link".into()),
                         id: Some("rss_2.0.channel.item[1].guid".into()),
+                        published: date("2001-01-02T00:00:00Z"),
+                        author: Some("rss_2.0.channel.item[1].author".into()),
+                        enclosure: Some("rss_2.0.channel.item[1].enclousure[0]^url".into()),
+                        description: Some("rss_2.0.channel.item[1].content".into()),
+                        language: None,
                     },
                 ],
-                ..Rss::default()
             }
         );
     }
@@ -719,7 +1567,7 @@ mod test {
 </channel>
 </rss>"#;
         let r = parse(Cursor::new(s)).unwrap();
-        assert_eq!(r.source, Some("self link".into()));
+        assert_eq!(r.meta.source, Some("self link".into()));
     }
 
     #[test]
@@ -733,8 +1581,11 @@ mod test {
             r#"<link />"#,
         ];
         let results = vec![
-            Some(AtomLink::Alternate("alternate href".into())),
-            Some(AtomLink::Alternate("alternate href".into())),
+            Some(AtomLink::Unspecified("alternate href".into())),
+            Some(AtomLink::Alternate {
+                href: "alternate href".into(),
+                html: false,
+            }),
             Some(AtomLink::Source("self href".into())),
             Some(AtomLink::Hub("hub href".into())),
             Some(AtomLink::Other(
@@ -753,10 +1604,141 @@ mod test {
         }
     }
 
+    #[test]
+    fn html_detection() {
+        assert!(looks_like_html(b"<!DOCTYPE html>\n<html><head>"));
+        assert!(looks_like_html(b"\xEF\xBB\xBF  <html lang=\"en\">"));
+        assert!(looks_like_html(b"<HTML>"));
+        assert!(looks_like_html(
+            br#"<?xml version="1.0"?><!-- login --><!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.0 Strict//EN"><html>"#
+        ));
+        assert!(!looks_like_html(b"<?xml version=\"1.0\"?><rss version=\"2.0\">"));
+        assert!(!looks_like_html(b"<htmlfeed>"));
+        assert!(!looks_like_html(b"<!-- unterminated"));
+        assert!(!looks_like_html(b""));
+        assert!(!looks_like_html(include_bytes!("../tests/data/atom_1.0.xml")));
+    }
+
+    #[test]
+    fn date_parsing() {
+        let expected = date("2001-01-02T00:00:00Z");
+        assert_eq!(parse_date("Tue, 02 Jan 2001 00:00:00 GMT"), expected);
+        // wrong day of week
+        assert_eq!(parse_date("Mon, 02 Jan 2001 00:00:00 GMT"), expected);
+        assert_eq!(parse_date("02 Jan 2001 00:00:00 +0000"), expected);
+        assert_eq!(parse_date(" 2001-01-02T08:00:00+08:00 "), expected);
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[test]
+    fn updated_as_fallback() {
+        let input = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+<entry><updated>2001-01-02T00:00:00Z</updated></entry>
+<entry><updated>2001-01-02T00:00:00Z</updated><published>2001-01-01T00:00:00Z</published></entry>
+</feed>"#;
+        let r = parse(Cursor::new(input)).unwrap();
+        assert_eq!(r.items[0].published, date("2001-01-02T00:00:00Z"));
+        assert_eq!(r.items[1].published, date("2001-01-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn streamed_like_buffered() {
+        let fixtures: [&[u8]; 13] = [
+            include_bytes!("../tests/data/atom_0.3.xml"),
+            include_bytes!("../tests/data/atom_1.0.xml"),
+            include_bytes!("../tests/data/github_releases.xml"),
+            include_bytes!("../tests/data/youtube.xml"),
+            include_bytes!("../tests/data/rss_0.9.xml"),
+            include_bytes!("../tests/data/rss_0.91.xml"),
+            include_bytes!("../tests/data/rss_0.92.xml"),
+            include_bytes!("../tests/data/rss_0.93.xml"),
+            include_bytes!("../tests/data/rss_0.94.xml"),
+            include_bytes!("../tests/data/rss_1.0.xml"),
+            include_bytes!("../tests/data/rss_2.0.xml"),
+            include_bytes!("../tests/data/encoding.xml"),
+            include_bytes!("../tests/data/encoding_items.xml"),
+        ];
+        for fixture in fixtures {
+            let buffered = parse(Cursor::new(fixture)).unwrap();
+            // Boundaries inside of tags, entities and multi-byte characters
+            for size in [1, 7, 64, 4096] {
+                let chunks = fixture.chunks(size).map(|chunk| Box::new(chunk.to_vec()) as Chunk);
+                assert_eq!(parse(Chunks::new(chunks)).unwrap(), buffered, "{}", size);
+            }
+        }
+    }
+
+    #[test]
+    fn item_languages() {
+        let input = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xml:lang="en">
+<entry><id>1</id></entry>
+<entry xml:lang="zh-CN"><id>2</id></entry>
+</feed>"#;
+        let r = inherit_language(parse(Cursor::new(input)).unwrap());
+        assert_eq!(r.items[0].language.as_deref(), Some("en"));
+        assert_eq!(r.items[1].language.as_deref(), Some("zh-CN"));
+
+        let input = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:dc="http://purl.org/dc/elements/1.1/">
+<channel>
+<item><guid>1</guid><dc:language>de</dc:language></item>
+<item><guid>2</guid></item>
+</channel>
+</rss>"#;
+        let r = inherit_language(parse(Cursor::new(input)).unwrap());
+        assert_eq!(r.meta.language, None);
+        assert_eq!(r.items[0].language.as_deref(), Some("de"));
+        assert_eq!(r.items[1].language, None);
+    }
+
     #[test]
     fn empty_input() {
         let r = parse(Cursor::new(&[])).unwrap_err();
-        assert!(matches!(r, quick_xml::Error::UnexpectedEof(s) if s == "feed" ))
+        assert!(matches!(r, ParseError::Xml(quick_xml::Error::UnexpectedEof(s)) if s == "feed" ))
+    }
+
+    #[test]
+    fn adversarial_feeds() {
+        let start = std::time::Instant::now();
+        let fixtures: [&[u8]; 2] = [
+            include_bytes!("../tests/data/deep_nesting.xml"),
+            include_bytes!("../tests/data/many_attributes.xml"),
+        ];
+        for fixture in fixtures {
+            let r = parse(Cursor::new(fixture)).unwrap_err();
+            assert!(matches!(r, ParseError::TooComplex(_)), "{:?}", r);
+        }
+        assert!(start.elapsed() < PARSE_BUDGET);
+        // Real feeds nest a bit
+        let nested = "<rss><channel><item><description><div><p><b><i>x</i></b></p></div>\
+                      </description></item></channel></rss>";
+        assert_eq!(parse(Cursor::new(nested)).unwrap().items.len(), 1);
+    }
+
+    #[test]
+    fn item_limit() {
+        let mut input = String::from("<rss><channel>");
+        for i in 0..MAX_ITEMS + 10 {
+            input.push_str(&format!("<item><guid>{}</guid></item>", i));
+        }
+        input.push_str("<title>after the items</title></channel></rss>");
+        let r = parse(Cursor::new(input)).unwrap();
+        assert_eq!(r.items.len(), MAX_ITEMS);
+        assert_eq!(r.items.last().unwrap().id.as_deref(), Some("999"));
+        assert_eq!(r.meta.title, "after the items");
+    }
+
+    #[test]
+    fn stopped() {
+        let mut input = String::from("<rss><channel>");
+        for i in 0..STOP_CHECK_EVENTS {
+            input.push_str(&format!("<item><guid>{}</guid></item>", i));
+        }
+        input.push_str("</channel></rss>");
+        let r = parse_until(Cursor::new(input), &AtomicBool::new(true)).unwrap_err();
+        assert!(matches!(r, ParseError::TooComplex(_)), "{:?}", r);
     }
 
     #[test]
@@ -769,7 +1751,7 @@ mod test {
 </channel>
 </rss>"#;
         let sy_output = parse(Cursor::new(sy_input)).unwrap();
-        assert_eq!(sy_output.ttl, Some(10));
+        assert_eq!(sy_output.meta.ttl, Some(10));
     }
 
     #[test]
@@ -781,7 +1763,7 @@ mod test {
 </channel>
 </rss>"#;
         let sy_output = parse(Cursor::new(sy_input)).unwrap();
-        assert_eq!(sy_output.ttl, Some(60 * 24));
+        assert_eq!(sy_output.meta.ttl, Some(60 * 24));
     }
 
     #[test]
@@ -795,7 +1777,7 @@ mod test {
 </channel>
 </rss>"#;
         let output = parse(Cursor::new(input)).unwrap();
-        assert_eq!(output.ttl, Some(42));
+        assert_eq!(output.meta.ttl, Some(42));
     }
 
     #[test]
@@ -809,7 +1791,7 @@ mod test {
 </channel>
 </rss>"#;
         let output = parse(Cursor::new(input)).unwrap();
-        assert_eq!(output.ttl, Some(42));
+        assert_eq!(output.meta.ttl, Some(42));
     }
 
     // https://github.com/tafia/quick-xml/issues/311
@@ -829,12 +1811,64 @@ mod test {
         assert_eq!(
             r,
             Rss {
-                title: CHARACTERS.into(),
-                link: "".into(),
-                ttl: None,
-                source: None,
+                meta: FeedMeta {
+                    title: CHARACTERS.into(),
+                    link: "".into(),
+                    ttl: None,
+                    source: None,
+                    language: None,
+                    ..FeedMeta::default()
+                },
                 items: vec![],
             }
         );
     }
+
+    #[test]
+    fn json_feed_meta() {
+        let json = r#"{
+            "version": "https://jsonfeed.org/version/1.1",
+            "title": "json",
+            "home_page_url": "http://example.com/",
+            "feed_url": "http://example.com/feed.json",
+            "language": "en",
+            "hubs": [{"type": "WebSub", "url": "http://hub.example.com/"}],
+            "items": []
+        }"#;
+        let r: Rss = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            r.meta,
+            FeedMeta {
+                title: "json".into(),
+                link: "http://example.com/".into(),
+                source: Some("http://example.com/feed.json".into()),
+                language: Some("en".into()),
+                hubs: vec!["http://hub.example.com/".into()],
+                ..FeedMeta::default()
+            }
+        );
+    }
+
+    #[test]
+    fn skip_hints() {
+        let input = r#"<rss version="2.0"><channel>
+<skipHours><hour>24</hour><hour>3</hour><hour>25</hour><hour>3</hour></skipHours>
+<SKIPDAYS><day>saturday</day><day>Sunday</day><day>someday</day></SKIPDAYS>
+</channel></rss>"#;
+        let skip = parse(Cursor::new(input)).unwrap().meta.skip;
+        assert_eq!(skip.hours, [0, 3]);
+        assert_eq!(skip.days, [5, 6]);
+
+        let time = |s| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        // A Friday
+        assert!(skip.skips(time("2021-01-01T03:30:00Z")));
+        assert!(!skip.skips(time("2021-01-01T04:00:00Z")));
+        assert!(skip.skips(time("2021-01-02T12:00:00Z")));
+        // Skipping all the time means nothing
+        let always = SkipHints {
+            hours: (0..24).collect(),
+            days: (0..7).collect(),
+        };
+        assert!(!always.skips(time("2021-01-01T03:30:00Z")));
+    }
 }
@@ -1,187 +1,1431 @@
-use std::cmp;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::time::SystemTime;
 use std::sync::{
     atomic::{AtomicUsize, Ordering},
-    Arc,
+    Arc, LazyLock, Mutex, OnceLock,
 };
 
-use futures::{future::FutureExt, select_biased};
-use tbot::{types::parameters, Bot};
+use futures::{
+    future::{join_all, FutureExt},
+    select_biased,
+};
+use regex::{Regex, RegexBuilder};
+use tbot::Bot;
 use tokio::{
     self,
-    sync::{Mutex, Notify},
+    sync::Notify,
+    task::JoinHandle,
     time::{self, Duration, Instant},
 };
 use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 use tokio_util::time::DelayQueue;
 
-use crate::client::pull_feed;
-use crate::data::{Database, Feed, FeedUpdate};
-use crate::messages::{format_large_msg, Escape};
+use crate::client::{fetch_feed_if_modified, FeedError};
+use crate::data::{
+    chat_display, Database, Discussion, Execution, Feed, FeedReset, FeedUpdate, ItemFilter,
+    LanguageFilter, LinkCleaning, MinWords, Quirks, ReplyTo, Settings,
+};
+use crate::feed::{Item, Rss};
+use crate::health::{HEARTBEATS, METRICS};
+use crate::messages::{
+    classify_error, clean_item_links, date, delivery_latency, firehose, fit_html, format_digest,
+    format_duration, format_large_msg, is_link_only, sanitize_title, send_html, strip_html,
+    with_footer, word_count, ApiError, Escape, Limit, MessageSink, Title, TITLE_UNITS,
+};
+use crate::{interval, translate, webhook};
+
+// Feeds fetched at the same time by `fetch_once`
+const ONCE_CONCURRENCY: usize = 8;
+// Titles are cut in digests, so more of them fit
+const DIGEST_TITLE_UNITS: usize = 80;
+// How often the items held for their publish date are checked, they'd wait for the
+// next fetch otherwise
+const EMBARGO_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// The most active feeds named in the weekly report
+const REPORT_TOP_FEEDS: usize = 3;
+// Feeds without new items for this long are suggested for unsubscribing
+const SILENT_FEED_DAYS: i64 = 30;
+// Compiled size of a /filter regex, every item of the feed is matched against it
+const FILTER_REGEX_SIZE: usize = 256 * 1024;
+
+static INTERVAL_BOUNDS: OnceLock<(u32, u32)> = OnceLock::new();
+static TRUNCATION_THRESHOLD: OnceLock<f64> = OnceLock::new();
+// `--cross-dedup-window`
+static CROSS_DEDUP_WINDOW: OnceLock<u64> = OnceLock::new();
+// `--backfill-grace`
+static BACKFILL_GRACE: OnceLock<u64> = OnceLock::new();
+// `--error-notice-window`
+static ERROR_NOTICE_WINDOW: OnceLock<u64> = OnceLock::new();
+// Links of the feeds being fetched, /fetchnow may race with the scheduler
+static FETCHING: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+// Chats a weekly report is being sent to, a slow one isn't sent another by the next tick
+static SENDING_REPORTS: Mutex<BTreeSet<i64>> = Mutex::new(BTreeSet::new());
+// The fetches and deliveries started by `start`, `wait_started` waits for them
+static STARTED: LazyLock<TaskTracker> = LazyLock::new(TaskTracker::new);
+
+tokio::task_local! {
+    // The feed being fetched and delivered, a panic in there only fails that feed
+    static FEED_TASK: String;
+}
+
+/// What happened to a fetch, reported by /fetchnow
+pub struct FetchOutcome {
+    pub status: Option<reqwest::StatusCode>,
+    pub bytes: Option<usize>,
+    /// Items in the response
+    pub result: Result<usize, FeedError>,
+    /// The response looked truncated and was skipped
+    pub skipped: bool,
+    /// Items that were new to the subscribers
+    pub delivered: usize,
+}
+
+/// Totals of a single pass over all feeds, printed by `--once`
+#[derive(Debug, Default)]
+pub struct CycleSummary {
+    pub fetched: usize,
+    pub failed: usize,
+    /// Skipped, still backing off from the failures of earlier runs
+    pub backing_off: usize,
+    pub delivered: usize,
+}
+
+impl CycleSummary {
+    pub fn failure_ratio(&self) -> f64 {
+        if self.fetched == 0 {
+            0.0
+        } else {
+            self.failed as f64 / self.fetched as f64
+        }
+    }
+}
+
+/// The `init` functions keep what the first run of the process set
+pub fn init(min_interval: u32, max_interval: u32, truncation_threshold: f64) {
+    let _ = INTERVAL_BOUNDS.set((min_interval, max_interval));
+    let _ = TRUNCATION_THRESHOLD.set(truncation_threshold);
+}
+
+/// For the chats with `/settings crossdedup on`, 48 hours if not called
+pub fn init_cross_dedup(window: u64) {
+    let _ = CROSS_DEDUP_WINDOW.set(window);
+}
+
+/// Items published this long before a chat subscribed are not sent to it,
+/// 1 hour if not called
+pub fn init_backfill_grace(grace: u64) {
+    let _ = BACKFILL_GRACE.set(grace);
+}
+
+/// Subscribers are told about a kind of errors of a feed at most once in this long,
+/// 24 hours if not called
+pub fn init_error_notice_window(window: u64) {
+    let _ = ERROR_NOTICE_WINDOW.set(window);
+}
 
-pub fn start(bot: Bot, db: Arc<Mutex<Database>>, min_interval: u32, max_interval: u32) {
+pub fn start(
+    bot: Bot,
+    db: Arc<Database>,
+    min_interval: u32,
+    max_interval: u32,
+    truncation_threshold: f64,
+    shutdown: CancellationToken,
+) -> JoinHandle<()> {
+    init(min_interval, max_interval, truncation_threshold);
     let mut queue = FetchQueue::new();
     // TODO: Don't use interval, it can accumulate ticks
     // replace it with delay_until
     let mut interval = time::interval_at(Instant::now(), Duration::from_secs(min_interval as u64));
     let throttle = Throttle::new(min_interval as usize);
+    let mut embargo_check = time::interval(EMBARGO_CHECK_INTERVAL);
+    // Aborting it only stops scheduling, the fetches started are separate tasks,
+    // see `wait_started`
     tokio::spawn(async move {
         loop {
             select_biased! {
                 feed = queue.next().fuse() => {
                     let feed = feed.expect("unreachable");
+                    // The feed said it isn't updated now, a later cycle fetches it
+                    if feed.skip.skips(chrono::Utc::now()) {
+                        crate::print_debug(&format!("{}: skipped by its skip hints", feed.link));
+                    } else {
+                        let bot = bot.clone();
+                        let db = db.clone();
+                        let opportunity = throttle.acquire();
+                        let progress = HEARTBEATS.fetch_started();
+                        let shutdown = shutdown.clone();
+                        STARTED.spawn(async move {
+                            let _progress = progress;
+                            select_biased! {
+                                _ = shutdown.cancelled().fuse() => return,
+                                _ = opportunity.wait().fuse() => (),
+                            }
+                            if let Err(e) = fetch(&bot, db, feed).await {
+                                crate::print_error(e);
+                            }
+                        });
+                    }
+                }
+                _ = interval.tick().fuse() => {
+                    let feeds = db.all_feeds();
+                    for feed in feeds {
+                        let delay = match feed.next_fetch {
+                            // backing off
+                            Some(t) => t.duration_since(SystemTime::now()).unwrap_or_default(),
+                            // after -1, we can stagger with `interval`
+                            None => {
+                                let inputs = interval_inputs(&feed);
+                                let interval = interval::normal(&inputs, bounds());
+                                crate::print_debug(&format!(
+                                    "{}: interval {}s from {:?}",
+                                    feed.link, interval, inputs
+                                ));
+                                Duration::from_secs(interval as u64 - 1)
+                            }
+                        };
+                        queue.enqueue(feed, delay);
+                    }
+                    HEARTBEATS.fetch_cycle();
+                }
+                _ = embargo_check.tick().fuse() => {
                     let bot = bot.clone();
                     let db = db.clone();
-                    let opportunity = throttle.acquire();
-                    tokio::spawn(async move {
-                        opportunity.wait().await;
-                        if let Err(e) = fetch_and_push_updates(bot, db, feed).await {
-                            crate::print_error(e);
-                        }
+                    STARTED.spawn(async move {
+                        deliver_due_items(&bot, &db).await;
+                        send_weekly_reports(&bot, &db, &chrono::Local::now()).await;
+                        send_digests(&bot, &db, &chrono::Local::now()).await;
                     });
                 }
-                _ = interval.tick().fuse() => {
-                    let feeds = db.lock().await.all_feeds();
-                    for feed in feeds {
-                        let feed_interval = cmp::min(
-                            cmp::max(
-                                feed.ttl.map(|ttl| ttl * 60).unwrap_or_default(),
-                                min_interval,
-                            ),
-                            max_interval,
-                        ) as u64 - 1; // after -1, we can stagger with `interval`
-                        queue.enqueue(feed, Duration::from_secs(feed_interval));
+            }
+        }
+    })
+}
+
+/// Wait for the fetches and deliveries started by `start`, the ones still waiting
+/// for their turn give up once the `shutdown` given to it is cancelled
+pub async fn wait_started() {
+    STARTED.close();
+    STARTED.wait().await;
+    // For the next run
+    STARTED.reopen();
+}
+
+/// Deliver the items whose publish date has come since they were fetched
+async fn deliver_due_items(sink: &dyn MessageSink, db: &Arc<Database>) {
+    let now = chrono::Utc::now().timestamp();
+    for (feed, items) in db.take_due_items(now) {
+        let updates = vec![FeedUpdate::Items(items)];
+        let pushing = push_feed_updates(sink, db, &feed, updates, None);
+        match catch_feed_panic(&feed.link, pushing).await {
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => crate::print_error(e),
+            Err(message) => {
+                exit_if_poisoned(db);
+                eprintln!("{}: panicked: {}", feed.link, message);
+            }
+        }
+    }
+}
+
+/// Send the weekly reports whose time has come, only reads the counters of the feeds
+async fn send_weekly_reports<Tz: chrono::TimeZone>(
+    sink: &dyn MessageSink,
+    db: &Database,
+    now: &chrono::DateTime<Tz>,
+) {
+    let due: Vec<i64> = {
+        let mut sending = SENDING_REPORTS.lock().unwrap();
+        db.weekly_reports()
+            .into_iter()
+            .filter(|(chat_id, report)| report.is_due(now) && sending.insert(*chat_id))
+            .map(|(chat_id, _)| chat_id)
+            .collect()
+    };
+    if due.is_empty() {
+        return;
+    }
+    let _sending = SendingReports(&due);
+    let now = now.timestamp();
+    let mut sent = Vec::new();
+    for &chat_id in &due {
+        let feeds = db.subscribed_feeds(chat_id).unwrap_or_default();
+        if feeds.is_empty() || send_weekly_report(sink, chat_id, &feeds, now).await {
+            sent.push(chat_id);
+        }
+    }
+    if !sent.is_empty() {
+        db.record_weekly_reports(&sent, now);
+    }
+}
+
+/// Removes the chats from `SENDING_REPORTS` once they were tried
+struct SendingReports<'a>(&'a [i64]);
+
+impl Drop for SendingReports<'_> {
+    fn drop(&mut self) {
+        let mut sending = SENDING_REPORTS.lock().unwrap();
+        for chat_id in self.0 {
+            sending.remove(chat_id);
+        }
+    }
+}
+
+/// Return `false` if it should be tried again, nothing of it was sent
+async fn send_weekly_report(
+    sink: &dyn MessageSink,
+    chat_id: i64,
+    feeds: &[Feed],
+    now: i64,
+) -> bool {
+    let mut sent = false;
+    for msg in format_weekly_report(feeds, chat_id, now) {
+        match sink.send_html(chat_id, &msg, None).await {
+            Ok(_) => sent = true,
+            // Removed from the chat, the gardener takes care of the subscriptions
+            Err(e) if classify_error(&e) == ApiError::ChatUnavailable => return true,
+            Err(e) => {
+                crate::print_error(e);
+                break;
+            }
+        }
+    }
+    sent
+}
+
+/// Send the digests whose time has come, see `Subscription::digest`
+async fn send_digests<Tz: chrono::TimeZone>(
+    sink: &dyn MessageSink,
+    db: &Arc<Database>,
+    now: &chrono::DateTime<Tz>,
+) {
+    for (feed, subscriber, items) in db.take_due_digests(now) {
+        let sending = send_digest(sink, db, &feed, subscriber, &items);
+        let sent = match catch_feed_panic(&feed.link, sending).await {
+            Ok(Ok(())) => true,
+            Ok(Err(e)) => {
+                crate::print_error(e);
+                false
+            }
+            Err(message) => {
+                exit_if_poisoned(db);
+                eprintln!("{}: panicked: {}", feed.link, message);
+                false
+            }
+        };
+        // The items of one that failed are sent with the next one
+        db.digest_sent(&feed.link, subscriber, sent.then_some(&items[..]));
+    }
+}
+
+/// The items kept for the digest in a single message, the newest `max_items` of them
+/// with the note of the others
+async fn send_digest(
+    sink: &dyn MessageSink,
+    db: &Database,
+    feed: &Feed,
+    subscriber: i64,
+    items: &[Item],
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat = db.chat(subscriber);
+    let settings = Settings {
+        digest_above: Some(0),
+        ..feed.subscription(subscriber).settings.resolve(&chat.defaults)
+    };
+    let stale_skipped = feed
+        .activity(subscriber)
+        .map_or(0, |activity| activity.stale_skipped);
+    let (msgs, items) = render_items(feed, &settings, items, stale_skipped).await;
+    if let Some(webhook) = chat.webhook {
+        webhook::deliver(webhook, feed, &items);
+    }
+    let delivered_to = push_to_chat(sink, db, &feed.link, subscriber, &msgs, true).await?;
+    let sent = SystemTime::now();
+    if delivered_to.is_some() {
+        let latencies: Vec<u64> = items
+            .iter()
+            .filter_map(|item| delivery_latency(item, sent))
+            .collect();
+        METRICS.delivery_latencies(&feed.link, &latencies);
+        firehose::mirror(&feed.title, &feed.link, &items);
+    }
+    let delivered_to: Vec<i64> = delivered_to.into_iter().collect();
+    db.record_delivery(&feed.link, &delivered_to, &items, sent);
+    Ok(())
+}
+
+fn format_weekly_report(feeds: &[Feed], subscriber: i64, now: i64) -> Vec<String> {
+    let counts: Vec<(&Feed, u32)> = feeds
+        .iter()
+        .map(|feed| (feed, feed.items_this_week(now)))
+        .collect();
+    let total: u32 = counts.iter().map(|(_, count)| count).sum();
+    let mut active: Vec<&(&Feed, u32)> = counts.iter().filter(|(_, count)| *count > 0).collect();
+    active.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.link.cmp(&b.link)));
+    let mut msg = tr!("weekly_report", feeds = feeds.len(), items = total);
+    if !active.is_empty() {
+        let top: Vec<String> = active
+            .iter()
+            .take(REPORT_TOP_FEEDS)
+            .map(|(feed, count)| format!("{} ({})", Title(&feed.title), count))
+            .collect();
+        msg.push('\n');
+        msg.push_str(&tr!("weekly_report_most_active", feeds = top.join(", ")));
+    }
+    msg.push('\n');
+    msg.push_str(&tr!("weekly_report_silent", count = feeds.len() - active.len()));
+
+    let silent_for = SILENT_FEED_DAYS * 24 * 60 * 60;
+    let stale: Vec<&Feed> = feeds
+        .iter()
+        .filter(|feed| {
+            // Feeds without new items since they were subscribed count from then
+            let since = feed.last_new_items().or_else(|| {
+                let activity = feed.activity(subscriber)?;
+                let subscribed_at = activity.subscribed_at?.duration_since(SystemTime::UNIX_EPOCH);
+                Some(subscribed_at.ok()?.as_secs() as i64)
+            });
+            since.is_some_and(|since| now - since > silent_for)
+        })
+        .collect();
+    if stale.is_empty() {
+        return vec![msg];
+    }
+    msg.push_str("\n\n");
+    msg.push_str(&tr!("weekly_report_stale", days = SILENT_FEED_DAYS));
+    format_large_msg(msg, &stale, |feed| {
+        format!("<a href=\"{}\">{}</a>", Escape(&feed.link), Title(&feed.title))
+    })
+}
+
+/// Fetch all feeds once, `init` must be called first.
+///
+/// The backoff of failing feeds is stored in the database like the scheduler does,
+/// so running it from cron doesn't retry them more often than the daemon would.
+pub async fn fetch_once(sink: &dyn MessageSink, db: Arc<Database>) -> CycleSummary {
+    let mut summary = CycleSummary::default();
+    let now = SystemTime::now();
+    let (feeds, backing_off): (Vec<Feed>, Vec<Feed>) = db
+        .all_feeds()
+        .into_iter()
+        .partition(|feed| feed.next_fetch.is_none_or(|t| t <= now));
+    summary.backing_off = backing_off.len();
+    for chunk in feeds.chunks(ONCE_CONCURRENCY) {
+        let fetches = chunk
+            .iter()
+            .map(|feed| fetch(sink, db.clone(), feed.clone()));
+        for result in futures::future::join_all(fetches).await {
+            summary.fetched += 1;
+            match result {
+                Ok(Some(outcome)) => {
+                    if outcome.result.is_err() {
+                        summary.failed += 1;
                     }
+                    summary.delivered += outcome.delivered;
+                }
+                Ok(None) => unreachable!("nothing else is fetching"),
+                Err(e) => {
+                    summary.failed += 1;
+                    crate::print_error(e);
                 }
             }
         }
-    });
+    }
+    HEARTBEATS.fetch_cycle();
+    summary
+}
+
+/// `--min-interval` and `--max-interval`
+pub fn interval_bounds() -> (u32, u32) {
+    *INTERVAL_BOUNDS.get().expect("fetcher not started")
+}
+
+fn bounds() -> interval::Bounds {
+    let (min, max) = interval_bounds();
+    interval::Bounds { min, max }
+}
+
+/// What the interval of the feed is decided from, as if its last fetch worked
+fn interval_inputs(feed: &Feed) -> interval::Inputs<'_> {
+    interval::Inputs {
+        item_dates: feed.item_dates(),
+        ttl: feed.ttl,
+        ..Default::default()
+    }
+}
+
+/// How often the feed is fetched while it works, in seconds, see `interval::normal`
+pub fn feed_interval(feed: &Feed) -> u32 {
+    interval::normal(&interval_inputs(feed), bounds())
+}
+
+fn random() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    // Every `RandomState` comes with random keys
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    hasher.finish()
+}
+
+/// Fetch the feed and deliver the new items, return `None` if it's being fetched already
+pub async fn fetch(
+    sink: &dyn MessageSink,
+    db: Arc<Database>,
+    feed: Feed,
+) -> Result<Option<FetchOutcome>, tbot::errors::MethodCall> {
+    let _guard = match FetchGuard::acquire(&feed.link) {
+        Some(guard) => guard,
+        None => return Ok(None),
+    };
+    let fetching = fetch_and_push_updates(sink, db.clone(), feed.clone());
+    match catch_feed_panic(&feed.link, fetching).await {
+        Ok(result) => result.map(Some),
+        Err(message) => {
+            exit_if_poisoned(&db);
+            eprintln!("{}: panicked: {}", feed.link, message);
+            record_failure(sink, &db, &feed, FeedError::Panicked(message))
+                .await
+                .map(Some)
+        }
+    }
+}
+
+/// Whether this thread is running the task of a feed, where panics are caught
+pub fn in_feed_task() -> bool {
+    FEED_TASK.try_with(|_| ()).is_ok()
+}
+
+/// The link of the feed whose task this is, for logging
+pub fn current_feed() -> Option<String> {
+    FEED_TASK.try_with(Clone::clone).ok()
+}
+
+/// Run `f` on a blocking thread as a part of the current feed task,
+/// a panic in there is still a failure of the feed and not of the bot
+pub fn spawn_blocking_in_feed<F, R>(f: F) -> tokio::task::JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let feed_link = FEED_TASK.try_with(Clone::clone).ok();
+    tokio::task::spawn_blocking(move || match feed_link {
+        Some(feed_link) => FEED_TASK.sync_scope(feed_link, f),
+        None => f(),
+    })
+}
+
+/// Run the work of a single feed, a panic in there is returned with its message
+async fn catch_feed_panic<F: Future>(feed_link: &str, task: F) -> Result<F::Output, String> {
+    FEED_TASK
+        .scope(feed_link.to_string(), AssertUnwindSafe(task).catch_unwind())
+        .await
+        .map_err(|panic| {
+            panic
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string())
+        })
+}
+
+/// The state may be half updated if a lock was held while panicking, that's not
+/// something to keep running with
+fn exit_if_poisoned(db: &Database) {
+    if db.is_poisoned() {
+        eprintln!("The database was poisoned by a panic, exiting");
+        std::process::exit(101);
+    }
+}
+
+/// When the feed will be fetched by the scheduler, roughly
+pub fn next_fetch(feed: &Feed) -> SystemTime {
+    feed.next_fetch
+        .unwrap_or_else(|| SystemTime::now() + Duration::from_secs(feed_interval(feed) as u64))
+}
+
+struct FetchGuard(String);
+
+impl FetchGuard {
+    fn acquire(link: &str) -> Option<Self> {
+        FETCHING
+            .lock()
+            .unwrap()
+            .insert(link.to_string())
+            .then(|| FetchGuard(link.to_string()))
+    }
+}
+
+impl Drop for FetchGuard {
+    fn drop(&mut self) {
+        FETCHING.lock().unwrap().remove(&self.0);
+    }
 }
 
 async fn fetch_and_push_updates(
-    bot: Bot,
-    db: Arc<Mutex<Database>>,
+    sink: &dyn MessageSink,
+    db: Arc<Database>,
     feed: Feed,
-) -> Result<(), tbot::errors::MethodCall> {
-    let new_feed = match pull_feed(&feed.link).await {
-        Ok(feed) => feed,
+) -> Result<FetchOutcome, tbot::errors::MethodCall> {
+    let fetching = fetch_feed_if_modified(
+        &feed.link,
+        feed.cookie(),
+        feed.max_size,
+        feed.quirks,
+        &feed.validators,
+    );
+    let fetched = match fetching.await {
+        Ok(Some(fetched)) => fetched,
+        // Nothing new to see, the items are as they were
+        Ok(None) => {
+            db.record_not_modified(&feed.link);
+            return Ok(FetchOutcome {
+                status: Some(reqwest::StatusCode::NOT_MODIFIED),
+                bytes: Some(0),
+                result: Ok(feed.item_count),
+                skipped: false,
+                delivered: 0,
+            });
+        }
         Err(e) => {
-            let down_time = db.lock().await.get_or_update_down_time(&feed.link);
-            if down_time.is_none() {
-                // user unsubscribed while fetching the feed
-                return Ok(());
+            // Maybe the workaround is what the server rejects now, it's learned again if not
+            if feed.quirks.accept_any && e.status().is_some() {
+                let quirks = Quirks {
+                    accept_any: false,
+                    ..feed.quirks
+                };
+                db.set_quirks(&feed.link, quirks);
             }
-            // 5 days
-            if down_time.unwrap().as_secs() > 5 * 24 * 60 * 60 {
-                db.lock().await.reset_down_time(&feed.link);
-                let msg = tr!(
-                    "continuous_fetch_error",
-                    link = Escape(&feed.link),
-                    title = Escape(&feed.title),
-                    error = Escape(&e.to_user_friendly())
-                );
-                push_updates(
-                    &bot,
-                    &db,
-                    feed.subscribers,
-                    parameters::Text::with_html(&msg),
-                )
-                .await?;
-            }
-            return Ok(());
+            return record_failure(sink, &db, &feed, e).await;
         }
     };
+    db.set_quirks(&feed.link, fetched.quirks);
+    let validators = fetched.validators;
+    let new_feed = fetched.rss;
+    let mut outcome = FetchOutcome {
+        status: Some(fetched.status),
+        bytes: Some(fetched.bytes),
+        result: Ok(new_feed.items.len()),
+        skipped: false,
+        delivered: 0,
+    };
+
+    let threshold = *TRUNCATION_THRESHOLD.get().unwrap();
+    if !accept_response(&db, &feed.link, &new_feed, threshold) {
+        outcome.skipped = true;
+        return Ok(outcome);
+    }
+    // Nothing in common with the last response, f.e. the site moved to another system
+    let reset = db.seems_reset(&feed.link, &new_feed.items);
+    // Items are compared and marked as seen under the same lock,
+    // a concurrent fetch of the same feed never gets them as new again.
+    // The validators are only of an accepted response, a skipped one is fetched in full again
+    let updates = db.update_validated(&feed.link, new_feed, Some(validators));
+    outcome.delivered = push_feed_updates(sink, &db, &feed, updates, reset).await?;
+    Ok(outcome)
+}
 
-    let updates = db.lock().await.update(&feed.link, new_feed);
+/// Back off from the failed fetch, and tell the subscribers if it keeps failing
+async fn record_failure(
+    sink: &dyn MessageSink,
+    db: &Arc<Database>,
+    feed: &Feed,
+    e: FeedError,
+) -> Result<FetchOutcome, tbot::errors::MethodCall> {
+    let blocked = matches!(e, FeedError::Blocked(_));
+    let failures = db.record_failure(&feed.link, blocked, |failures| {
+        let inputs = interval::Inputs {
+            failures,
+            blocked,
+            retry_after: e.retry_after(),
+            ..interval_inputs(feed)
+        };
+        let delay = interval::next(&inputs, bounds(), random());
+        crate::print_debug(&format!(
+            "{}: next fetch in {}s from {:?}",
+            feed.link, delay, inputs
+        ));
+        SystemTime::now() + Duration::from_secs(delay as u64)
+    });
+    if let Some(status) = e.status() {
+        let kind = if blocked {
+            "blocked by an anti-bot challenge"
+        } else if status.is_client_error() {
+            "client error"
+        } else {
+            "server error"
+        };
+        eprintln!(
+            "{}: HTTP {} ({}), failed {} times in a row",
+            feed.link,
+            status,
+            kind,
+            failures.unwrap_or_default()
+        );
+    }
+    let outcome = FetchOutcome {
+        status: e.status(),
+        bytes: None,
+        result: Err(e),
+        skipped: false,
+        delivered: 0,
+    };
+    let e = outcome.result.as_ref().unwrap_err();
+    let down_time = db.get_or_update_down_time(&feed.link);
+    if down_time.is_none() {
+        // user unsubscribed while fetching the feed
+        return Ok(outcome);
+    }
+    // 5 days
+    if down_time.unwrap().as_secs() > 5 * 24 * 60 * 60 {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let window = *ERROR_NOTICE_WINDOW.get_or_init(|| 24 * 60 * 60);
+        // The same message for everyone who missed as many notices
+        let notices = db.error_notices(&feed.link, e.class(), now, window);
+        let mut groups: BTreeMap<u32, Vec<i64>> = BTreeMap::new();
+        for (subscriber, suppressed) in notices {
+            groups.entry(suppressed).or_default().push(subscriber);
+        }
+        for (suppressed, subscribers) in groups {
+            let mut msg = tr!(
+                "continuous_fetch_error",
+                link = Escape(&feed.link),
+                title = Title(&feed.title),
+                error = Escape(&e.to_user_friendly())
+            );
+            if suppressed > 0 {
+                msg.push('\n');
+                msg.push_str(&tr!("fetch_errors_suppressed", count = suppressed));
+            }
+            push_updates(sink, db, &feed.link, subscribers, &[msg], false).await?;
+        }
+    }
+    Ok(outcome)
+}
+
+/// Send what `Database::update` found, return how many items were new. `reset` is how
+/// many items the feed had, if none of them are in the response
+async fn push_feed_updates(
+    sink: &dyn MessageSink,
+    db: &Arc<Database>,
+    feed: &Feed,
+    updates: Vec<FeedUpdate>,
+    reset: Option<usize>,
+) -> Result<usize, tbot::errors::MethodCall> {
+    let mut delivered = 0;
     for update in updates {
         match update {
+            FeedUpdate::Recovered { down_for, missed } => {
+                let msg = tr!(
+                    "feed_recovered",
+                    link = Escape(&feed.link),
+                    title = Title(&feed.title),
+                    time = format_duration(down_for.as_secs()),
+                    count = missed
+                );
+                let subscribers = feed.subscribers.iter().copied();
+                push_updates(sink, db, &feed.link, subscribers, &[msg], false).await?;
+            }
             FeedUpdate::Items(items) => {
-                let msgs =
-                    format_large_msg(format!("<b>{}</b>", Escape(&feed.title)), &items, |item| {
-                        let title = item.title.as_deref().unwrap_or_else(|| &feed.title);
-                        let link = item.link.as_deref().unwrap_or_else(|| &feed.link);
-                        format!("<a href=\"{}\">{}</a>", Escape(link), Escape(title))
-                    });
-                for msg in msgs {
-                    push_updates(
-                        &bot,
-                        &db,
-                        feed.subscribers.iter().copied(),
-                        parameters::Text::with_html(&msg),
-                    )
-                    .await?;
+                delivered += items.len();
+                // When the undated items were found, for `max_queue_age`
+                let found_at = SystemTime::now();
+                // Group the subscribers by their settings, so the same messages
+                // are only formatted once. Chats deduplicating across feeds get their own.
+                let grace = *BACKFILL_GRACE.get_or_init(|| 60 * 60);
+                let mut groups: HashMap<_, Vec<i64>> = HashMap::new();
+                for &subscriber in &feed.subscribers {
+                    let chat = db.chat(subscriber);
+                    let subscription = feed.subscription(subscriber);
+                    let key = GroupKey {
+                        settings: subscription.settings.resolve(&chat.defaults),
+                        filter: subscription.filter,
+                        digest: subscription.digest.is_some(),
+                        dedup_chat: chat.cross_dedup.then_some(subscriber),
+                        cutoff: backfill_cutoff(feed, subscriber, grace)
+                            .filter(|&cutoff| items.iter().any(|item| predates(item, cutoff))),
+                        stale_skipped: feed
+                            .activity(subscriber)
+                            .map_or(0, |activity| activity.stale_skipped),
+                    };
+                    groups.entry(key).or_default().push(subscriber);
+                }
+                // Across the groups, channels with other settings can share a discussion group
+                let mut mirrored = HashSet::new();
+                // The first time any of the groups got them
+                let mut first_sent = None;
+                for (key, subscribers) in groups {
+                    let reset = reset.filter(|&had| had >= key.settings.reset_threshold());
+                    if reset.is_some() && key.settings.reset_policy() == FeedReset::Resync {
+                        continue;
+                    }
+                    let recent;
+                    let items = match key.cutoff {
+                        Some(cutoff) => {
+                            recent = items
+                                .iter()
+                                .filter(|item| !predates(item, cutoff))
+                                .cloned()
+                                .collect::<Vec<_>>();
+                            if recent.is_empty() {
+                                continue;
+                            }
+                            &recent[..]
+                        }
+                        None => &items[..],
+                    };
+                    let long_enough;
+                    let items = match key.settings.min_words {
+                        Some(min_words) => {
+                            long_enough = items
+                                .iter()
+                                .filter(|item| is_long_enough(item, min_words))
+                                .cloned()
+                                .collect::<Vec<_>>();
+                            if long_enough.is_empty() {
+                                continue;
+                            }
+                            &long_enough[..]
+                        }
+                        None => items,
+                    };
+                    let in_language;
+                    let items = match &key.settings.lang {
+                        Some(filter) => {
+                            in_language = items
+                                .iter()
+                                .filter(|item| is_in_language(item, filter))
+                                .cloned()
+                                .collect::<Vec<_>>();
+                            if in_language.is_empty() {
+                                continue;
+                            }
+                            &in_language[..]
+                        }
+                        None => items,
+                    };
+                    // Before anything is recorded, the items filtered out are neither
+                    // counted as stale nor kept from the chat's other feeds
+                    let matching;
+                    let items = match ItemMatcher::new(&key.filter) {
+                        Some(matcher) => {
+                            matching = items
+                                .iter()
+                                .filter(|item| matcher.matches(item))
+                                .cloned()
+                                .collect::<Vec<_>>();
+                            if matching.is_empty() {
+                                continue;
+                            }
+                            &matching[..]
+                        }
+                        None => items,
+                    };
+                    // Checked last, the deliveries before this one may have waited
+                    let fresh;
+                    let mut stale_skipped = key.stale_skipped;
+                    let items = match key.settings.max_queue_age {
+                        Some(max_age) => {
+                            let now = SystemTime::now();
+                            fresh = items
+                                .iter()
+                                .filter(|item| !is_stale(item, found_at, now, max_age))
+                                .cloned()
+                                .collect::<Vec<_>>();
+                            let stale = (items.len() - fresh.len()) as u32;
+                            if fresh.is_empty() {
+                                db.record_stale_items(&feed.link, &subscribers, stale);
+                                continue;
+                            }
+                            stale_skipped += stale;
+                            &fresh[..]
+                        }
+                        None => items,
+                    };
+                    // After the filters, so the latest ones that are delivered are shown
+                    let mut reset_note = None;
+                    let newest;
+                    let items = match (reset, key.settings.reset_policy()) {
+                        (Some(_), FeedReset::Latest(latest)) if items.len() > latest => {
+                            reset_note = Some(tr!(
+                                "feed_reset",
+                                link = Escape(&feed.link),
+                                title = Title(&feed.title),
+                                count = latest,
+                                total = items.len()
+                            ));
+                            newest = newest_first(items);
+                            cap_items(&newest, Some(latest)).0
+                        }
+                        _ => items,
+                    };
+                    // After the cap, an item it leaves out doesn't hide a later duplicate
+                    let unique = |items: &[Item]| {
+                        key.dedup_chat.map(|chat_id| {
+                            let window = *CROSS_DEDUP_WINDOW.get_or_init(|| 48 * 60 * 60);
+                            let now = chrono::Utc::now().timestamp();
+                            db.filter_cross_duplicates(
+                                chat_id,
+                                &feed.link,
+                                items,
+                                now,
+                                window as i64,
+                            )
+                        })
+                    };
+                    let record_stale = || {
+                        if stale_skipped > key.stale_skipped {
+                            let stale = stale_skipped - key.stale_skipped;
+                            db.record_stale_items(&feed.link, &subscribers, stale);
+                        }
+                    };
+                    if key.digest {
+                        // Sent by `send_digests` when it's time, with the stale ones told.
+                        // All of them wait, `max_items` caps the digest when it's sent
+                        record_stale();
+                        let unique = unique(items);
+                        let items = unique.as_deref().unwrap_or(items);
+                        if !items.is_empty() {
+                            db.queue_digest_items(&feed.link, &subscribers, items);
+                        }
+                        continue;
+                    }
+                    let mut selection = select_items(&key.settings, items);
+                    if let Some(unique) = unique(&selection.items) {
+                        if unique.is_empty() {
+                            record_stale();
+                            continue;
+                        }
+                        selection.items = Cow::Owned(unique);
+                    }
+                    let (mut msgs, items) =
+                        render_selection(feed, &key.settings, selection, stale_skipped).await;
+                    if let Some(note) = reset_note {
+                        msgs.insert(0, note);
+                    }
+                    for &subscriber in &subscribers {
+                        if let Some(webhook) = db.chat(subscriber).webhook {
+                            webhook::deliver(webhook, feed, &items);
+                        }
+                    }
+                    let mut discussions = discussion_targets(feed, &subscribers, &mut mirrored);
+                    let subscribers = subscribers.iter().copied();
+                    let mut delivered_to =
+                        push_updates(sink, db, &feed.link, subscribers, &msgs, true).await?;
+                    for msg in &msgs {
+                        push_to_discussions(sink, db, feed, &mut discussions, msg).await;
+                    }
+                    delivered_to.sort_unstable();
+                    let sent = SystemTime::now();
+                    if !delivered_to.is_empty() {
+                        first_sent.get_or_insert(sent);
+                        firehose::mirror(&feed.title, &feed.link, &items);
+                    }
+                    db.record_delivery(&feed.link, &delivered_to, &items, sent);
+                }
+                // Once for the update, not again for every group that got its items
+                if let Some(sent) = first_sent {
+                    let latencies: Vec<u64> = items
+                        .iter()
+                        .filter_map(|item| delivery_latency(item, sent))
+                        .collect();
+                    METRICS.delivery_latencies(&feed.link, &latencies);
                 }
             }
             FeedUpdate::Title(new_title) => {
                 let msg = tr!(
                     "feed_renamed",
                     link = Escape(&feed.link),
-                    title = Escape(&feed.title),
-                    new_title = Escape(&new_title)
+                    title = Title(&feed.title),
+                    new_title = Title(&new_title)
                 );
-                push_updates(
-                    &bot,
-                    &db,
-                    feed.subscribers.iter().copied(),
-                    parameters::Text::with_html(&msg),
-                )
-                .await?;
+                let subscribers = feed.subscribers.iter().copied();
+                push_updates(sink, db, &feed.link, subscribers, &[msg], false).await?;
+            }
+            FeedUpdate::HomePage(new_home_page) => {
+                let msg = tr!(
+                    "feed_home_page_changed",
+                    link = Escape(&feed.link),
+                    title = Title(&feed.title),
+                    home_page = Escape(&new_home_page)
+                );
+                let subscribers = feed.subscribers.iter().copied();
+                push_updates(sink, db, &feed.link, subscribers, &[msg], false).await?;
             }
         }
     }
-    Ok(())
+    Ok(delivered)
+}
+
+/// The messages for the subscribers with `settings`, after the filters, and the items
+/// in them. /preview goes through here too, so it looks like a delivery.
+pub async fn render_items(
+    feed: &Feed,
+    settings: &Settings,
+    items: &[Item],
+    stale_skipped: u32,
+) -> (Vec<String>, Vec<Item>) {
+    let selection = select_items(settings, items);
+    render_selection(feed, settings, selection, stale_skipped).await
+}
+
+/// The items of a delivery to the subscribers with some settings, see `select_items`
+struct Selection<'a> {
+    items: Cow<'a, [Item]>,
+    /// Left out by `max_items`
+    skipped: usize,
+    /// All of them in a single message
+    digest: bool,
+}
+
+fn select_items<'a>(settings: &Settings, items: &'a [Item]) -> Selection<'a> {
+    // Counted after the filters and before `max_items`, the digest gets the capped set too
+    let digest = settings.digest_above.is_some_and(|max| items.len() > max);
+    let (items, skipped) = match newest_first(items) {
+        Cow::Borrowed(items) => {
+            let (items, skipped) = cap_items(items, settings.max_items);
+            (Cow::Borrowed(items), skipped)
+        }
+        Cow::Owned(mut items) => {
+            let skipped = cap_items(&items, settings.max_items).1;
+            items.truncate(items.len() - skipped);
+            (Cow::Owned(items), skipped)
+        }
+    };
+    Selection {
+        items,
+        skipped,
+        digest,
+    }
 }
 
+async fn render_selection(
+    feed: &Feed,
+    settings: &Settings,
+    selection: Selection<'_>,
+    stale_skipped: u32,
+) -> (Vec<String>, Vec<Item>) {
+    let Selection {
+        items,
+        skipped,
+        digest,
+    } = selection;
+    let items = match settings.clean_links {
+        Some(cleaning) => {
+            let resolve = cleaning == LinkCleaning::Resolve;
+            clean_item_links(&items, resolve).await
+        }
+        None => items.into_owned(),
+    };
+    let translations = match &settings.translate {
+        Some(language) => translate_titles(&items, language).await,
+        None => HashMap::new(),
+    };
+    let line = |item: &Item, title_limit: usize| {
+        let title = item.title.as_deref().unwrap_or_else(|| &feed.title);
+        let link = item.link.as_deref().unwrap_or_else(|| &feed.link);
+        let mut line = format!(
+            "<a href=\"{}\">{}</a>",
+            Escape(link),
+            Escape(&sanitize_title(title, title_limit))
+        );
+        if let Some(translated) = translations.get(title) {
+            let translated = sanitize_title(translated, title_limit);
+            line.push_str(&format!(" · {}", Escape(&translated)));
+        }
+        if let (Some(format), Some(published)) = (&settings.date, &item.published) {
+            let published = published.with_timezone(&chrono::Local);
+            let date = date::format(&published, format, chrono::Utc::now());
+            line.push_str(&format!(" ({})", Escape(&date)));
+        }
+        line
+    };
+    let mut msgs = if digest {
+        let head = tr!("digest_head", title = Title(&feed.title), count = items.len());
+        vec![format_digest(head, &items, |item| line(item, DIGEST_TITLE_UNITS))]
+    } else {
+        let head = format!("<b>{}</b>", Title(&feed.title));
+        format_large_msg(head, &items, |item| line(item, TITLE_UNITS))
+    };
+    if let Some(last) = msgs.last_mut().filter(|_| stale_skipped > 0) {
+        let stale = tr!("stale_items_skipped", count = stale_skipped);
+        let joined = format!("{}\n\n{}", last, stale);
+        // Cut by `fit_html` the line would be lost, it goes on its own then
+        match fit_html(&joined, Limit::Text) {
+            Cow::Borrowed(_) => *last = joined,
+            Cow::Owned(_) => msgs.push(stale),
+        }
+    }
+    if skipped > 0 {
+        msgs.push(tr!(
+            "items_skipped",
+            count = skipped,
+            link = Escape(&feed.link),
+            title = Title(&feed.title)
+        ));
+    }
+    (msgs, items)
+}
+
+/// Send the item to the subscriber the way it would be delivered, after a line saying
+/// it's a preview. The filters don't apply, and it's not marked as seen.
+pub async fn send_preview(
+    sink: &dyn MessageSink,
+    db: &Database,
+    feed: &Feed,
+    subscriber: i64,
+    item: Item,
+) -> Result<bool, tbot::errors::MethodCall> {
+    let settings = feed
+        .subscription(subscriber)
+        .settings
+        .resolve(&db.chat(subscriber).defaults);
+    let (mut msgs, _) = render_items(feed, &settings, &[item], 0).await;
+    if let Some(first) = msgs.first_mut() {
+        first.insert_str(0, &format!("{}\n", tr!("preview_head")));
+    }
+    let delivered = push_to_chat(sink, db, &feed.link, subscriber, &msgs, true).await?;
+    Ok(delivered.is_some())
+}
+
+/// Maintenance pages and truncated caches may still parse, and the next full response
+/// would be delivered as new again. Skip a response that shrank below `threshold` of
+/// the last one and has nothing in common with it, unless it happens twice in a row.
+fn accept_response(db: &Database, rss_link: &str, rss: &Rss, threshold: f64) -> bool {
+    let (item_count, known) = match db.compare_items(rss_link, &rss.items) {
+        Some(r) => r,
+        None => return true,
+    };
+    if known > 0 || rss.items.len() as f64 >= item_count as f64 * threshold {
+        return true;
+    }
+    if db.mark_suspect(rss_link) {
+        return true;
+    }
+    eprintln!(
+        "{}: only {} items returned (previously {}), none of them seen before, skipped",
+        rss_link,
+        rss.items.len(),
+        item_count
+    );
+    false
+}
+
+/// Translations of the item titles, by the original title
+async fn translate_titles(items: &[Item], language: &str) -> HashMap<String, String> {
+    let titles: Vec<&str> = items.iter().filter_map(|item| item.title.as_deref()).collect();
+    let translations = translate::translate_all(&titles, language).await;
+    titles
+        .into_iter()
+        .zip(translations)
+        .filter_map(|(title, translated)| Some((title.to_string(), translated?)))
+        .collect()
+}
+
+/// What the subscribers in a group have in common, they all get the same messages
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct GroupKey {
+    /// Resolved, `None` is off
+    settings: Settings,
+    filter: ItemFilter,
+    /// The items are kept for `Subscription::digest` instead
+    digest: bool,
+    /// The chat, if it skips the items delivered by other feeds
+    dedup_chat: Option<i64>,
+    /// Items published before this are not sent, see `backfill_cutoff`
+    cutoff: Option<SystemTime>,
+    /// Told with the next delivery, see `Activity::stale_skipped`
+    stale_skipped: u32,
+}
+
+/// Older than `max_age` seconds, from the publish date or when it was found
+fn is_stale(item: &Item, found_at: SystemTime, now: SystemTime, max_age: u64) -> bool {
+    let since = item.published.map_or(found_at, SystemTime::from);
+    now.duration_since(since).unwrap_or_default() > Duration::from_secs(max_age)
+}
+
+/// The feed state is shared by all subscribers, so items that are old news can still
+/// be new to it, e.g. when the feed recovers right after a chat subscribed.
+/// Items published before the returned time are not sent to the chat.
+fn backfill_cutoff(feed: &Feed, subscriber: i64, grace: u64) -> Option<SystemTime> {
+    let subscribed_at = feed.activity(subscriber)?.subscribed_at?;
+    subscribed_at.checked_sub(Duration::from_secs(grace))
+}
+
+/// Items without a date are never considered old
+fn predates(item: &Item, cutoff: SystemTime) -> bool {
+    item.published
+        .is_some_and(|published| SystemTime::from(published) < cutoff)
+}
+
+/// `/settings min_words`, items without a description can't be judged and are kept
+fn is_long_enough(item: &Item, min_words: MinWords) -> bool {
+    let description = match item.description.as_deref() {
+        Some(description) if !description.trim().is_empty() => description,
+        _ => return true,
+    };
+    match min_words {
+        MinWords::Off => true,
+        MinWords::Links => !is_link_only(description),
+        MinWords::Count(min) => word_count(description) >= min,
+    }
+}
+
+/// `/settings lang`, compared by the primary subtag
+fn is_in_language(item: &Item, filter: &LanguageFilter) -> bool {
+    match &item.language {
+        Some(language) => {
+            let primary = language.trim().split(['-', '_']).next().unwrap_or_default();
+            filter
+                .languages
+                .iter()
+                .any(|code| code.eq_ignore_ascii_case(primary))
+        }
+        None => filter.untagged,
+    }
+}
+
+/// A regex of /filter, like it's matched when delivering
+pub fn filter_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .size_limit(FILTER_REGEX_SIZE)
+        .build()
+}
+
+/// Compiled once for every group and fetch, `None` for the ones that don't compile
+static FILTER_REGEXES: Mutex<BTreeMap<String, Option<Regex>>> = Mutex::new(BTreeMap::new());
+
+/// Started over when there are more patterns than this
+const MAX_FILTER_REGEXES: usize = 1024;
+
+fn cached_filter_regex(pattern: &str) -> Option<Regex> {
+    let mut cache = FILTER_REGEXES.lock().unwrap();
+    if let Some(regex) = cache.get(pattern) {
+        return regex.clone();
+    }
+    let regex = filter_regex(pattern)
+        .map_err(|e| eprintln!("Invalid filter {}: {}", pattern, e))
+        .ok();
+    if cache.len() >= MAX_FILTER_REGEXES {
+        cache.clear();
+    }
+    cache.insert(pattern.to_string(), regex.clone());
+    regex
+}
+
+/// The compiled regexes of an `ItemFilter`
+struct ItemMatcher {
+    include: Option<Regex>,
+    exclude: Option<Regex>,
+    /// The include doesn't compile anymore, nothing matches rather than everything
+    broken_include: bool,
+}
+
+impl ItemMatcher {
+    /// `None` if the filter is empty. A regex that doesn't compile anymore, f.e. after
+    /// `FILTER_REGEX_SIZE` changed, is left out if it's the exclude
+    fn new(filter: &ItemFilter) -> Option<Self> {
+        if filter.is_empty() {
+            return None;
+        }
+        let include = filter.include.as_deref().map(cached_filter_regex);
+        Some(ItemMatcher {
+            broken_include: matches!(include, Some(None)),
+            include: include.flatten(),
+            exclude: filter.exclude.as_deref().and_then(cached_filter_regex),
+        })
+    }
+
+    fn matches(&self, item: &Item) -> bool {
+        if self.broken_include {
+            return false;
+        }
+        let title = item.title.as_deref().unwrap_or_default();
+        let content = item.description.as_deref().map(strip_html).unwrap_or_default();
+        let found = |regex: &Regex| regex.is_match(title) || regex.is_match(&content);
+        self.include.as_ref().is_none_or(found) && !self.exclude.as_ref().is_some_and(found)
+    }
+}
+
+/// Sorted by the publish date when all `items` have one, most feeds list the newest
+/// first but not all of them. The order of the feed is kept otherwise.
+fn newest_first(items: &[Item]) -> Cow<'_, [Item]> {
+    let sorted = items.windows(2).all(|pair| pair[0].published >= pair[1].published);
+    if sorted || items.iter().any(|item| item.published.is_none()) {
+        return Cow::Borrowed(items);
+    }
+    let mut items = items.to_vec();
+    items.sort_by(|a, b| b.published.cmp(&a.published));
+    Cow::Owned(items)
+}
+
+/// Keep the first `max_items` of the `items` sorted by `newest_first`.
+/// The rest were already marked as seen by `Database::update`.
+fn cap_items<T>(items: &[T], max_items: Option<usize>) -> (&[T], usize) {
+    match max_items {
+        Some(max) if items.len() > max => (&items[..max], items.len() - max),
+        _ => (items, 0),
+    }
+}
+
+/// Linked discussion groups of the channels, skip the groups subscribed by themselves
+/// and those in `mirrored` already, which the new ones are added to
+fn discussion_targets(
+    feed: &Feed,
+    subscribers: &[i64],
+    mirrored: &mut HashSet<i64>,
+) -> Vec<(i64, Discussion)> {
+    subscribers
+        .iter()
+        .filter_map(|&subscriber| {
+            feed.subscription(subscriber)
+                .discussion
+                .map(|discussion| (subscriber, discussion))
+        })
+        .filter(|(_, discussion)| {
+            !feed.subscribers.contains(&discussion.chat_id) && mirrored.insert(discussion.chat_id)
+        })
+        .collect()
+}
+
+/// Failures never affect the channels, the mirroring is disabled instead
+async fn push_to_discussions(
+    sink: &dyn MessageSink,
+    db: &Arc<Database>,
+    feed: &Feed,
+    discussions: &mut Vec<(i64, Discussion)>,
+    msg: &str,
+) {
+    use tbot::errors::MethodCall;
+    let mut failed = Vec::new();
+    for &(channel, discussion) in discussions.iter() {
+        for _ in 0..3 {
+            let result = send_html(sink, discussion.chat_id, msg, &feed.link, None).await;
+            match result {
+                Err(e) => match classify_error(&e) {
+                    ApiError::RetryAfter(delay) => {
+                        time::sleep(Duration::from_secs(delay)).await;
+                        continue;
+                    }
+                    _ if matches!(e, MethodCall::RequestError { .. }) => {
+                        eprintln!(
+                            "Failed to mirror {} to discussion group {}: {}",
+                            feed.link,
+                            chat_display(discussion.chat_id),
+                            e
+                        );
+                        failed.push((channel, discussion));
+                    }
+                    _ => crate::print_error(e),
+                },
+                Ok(_) => (),
+            }
+            break;
+        }
+    }
+    for (channel, discussion) in failed {
+        discussions.retain(|(c, _)| *c != channel);
+        db.update_subscription(channel, &feed.link, |s| s.discussion = None);
+        let msg = tr!(
+            "discussion_mirroring_disabled",
+            link = Escape(&feed.link),
+            title = Title(&feed.title),
+            channel = channel
+        );
+        let _ignore_result = sink.send_html(discussion.enabled_by, &msg, None).await;
+    }
+}
+
+/// Send the messages to the subscribers, the chats concurrently and the messages
+/// to each chat in order. Return the subscribers who received them,
+/// by their new IDs if migrated.
+/// `footer` is for the messages of items, the chats that didn't opt out get the /footer.
 async fn push_updates<I: IntoIterator<Item = i64>>(
-    bot: &Bot,
-    db: &Arc<Mutex<Database>>,
+    sink: &dyn MessageSink,
+    db: &Arc<Database>,
+    feed_link: &str,
     subscribers: I,
-    msg: parameters::Text,
-) -> Result<(), tbot::errors::MethodCall> {
-    use tbot::errors::MethodCall;
-    for mut subscriber in subscribers {
+    msgs: &[String],
+    footer: bool,
+) -> Result<Vec<i64>, tbot::errors::MethodCall> {
+    // The send scheduler limits how many are sent at once
+    let chats = subscribers
+        .into_iter()
+        .map(|subscriber| push_to_chat(sink, db, feed_link, subscriber, msgs, footer));
+    let results = join_all(chats).await;
+    let mut delivered = Vec::new();
+    for result in results {
+        delivered.extend(result?);
+    }
+    Ok(delivered)
+}
+
+/// Return the ID of the chat if it received any of the messages
+async fn push_to_chat(
+    sink: &dyn MessageSink,
+    db: &Database,
+    feed_link: &str,
+    mut subscriber: i64,
+    msgs: &[String],
+    footer: bool,
+) -> Result<Option<i64>, tbot::errors::MethodCall> {
+    let mut delivered = false;
+    let footer = footer && !db.chat(subscriber).hide_footer;
+    for msg in msgs {
+        let msg = if footer {
+            with_footer(msg, Limit::Text)
+        } else {
+            Cow::Borrowed(msg.as_str())
+        };
+        let msg = &*msg;
         'retry: for _ in 0..3 {
-            match bot
-                .send_message(tbot::types::chat::Id(subscriber), msg.clone())
-                .is_web_page_preview_disabled(true)
-                .call()
-                .await
-            {
-                Err(MethodCall::RequestError { description, .. })
-                    if chat_is_unavailable(&description) =>
-                {
-                    db.lock().await.delete_subscriber(subscriber);
+            let reply_to = db.chat(subscriber).reply_to;
+            let result = send_html(sink, subscriber, msg, feed_link, reply_to).await;
+            match result.as_ref().err().map(classify_error) {
+                Some(ApiError::ChatUnavailable) => {
+                    // Told once, the subscriptions are gone after this
+                    let creators = db.subscribed_by(subscriber);
+                    db.delete_subscriber(subscriber, Execution::Apply);
+                    let msg = tr!(
+                        "chat_unavailable_removed",
+                        chat = Escape(&chat_display(subscriber)),
+                        error = Escape(&result.unwrap_err().to_string())
+                    );
+                    for creator in creators {
+                        let _ignore_result = sink.send_html(creator, &msg, None).await;
+                    }
+                    return Ok(delivered.then_some(subscriber));
                 }
-                Err(MethodCall::RequestError {
-                    migrate_to_chat_id: Some(new_chat_id),
-                    ..
-                }) => {
-                    db.lock().await.update_subscriber(subscriber, new_chat_id.0);
-                    subscriber = new_chat_id.0;
+                Some(ApiError::Migrated(new_chat_id)) => {
+                    db.update_subscriber(subscriber, new_chat_id);
+                    subscriber = new_chat_id;
                     continue 'retry;
                 }
-                Err(MethodCall::RequestError {
-                    retry_after: Some(delay),
-                    ..
-                }) => {
+                Some(ApiError::RetryAfter(delay)) => {
                     time::sleep(Duration::from_secs(delay)).await;
                     continue 'retry;
                 }
-                other => {
-                    other?;
+                _ => {
+                    if result?.reply_lost && matches!(reply_to, Some(ReplyTo::Message(_))) {
+                        db.update_chat(subscriber, |chat| chat.reply_to = None);
+                        let msg = tr!("reply_to_message_gone");
+                        let _ignore_result = sink.send_html(subscriber, &msg, None).await;
+                    }
+                    delivered = true;
                 }
             }
             break 'retry;
         }
     }
-    Ok(())
-}
-
-pub fn chat_is_unavailable(s: &str) -> bool {
-    s.contains("Forbidden")
-        || s.contains("chat not found")
-        || s.contains("have no rights")
-        || s.contains("need administrator rights")
+    Ok(delivered.then_some(subscriber))
 }
 
 #[derive(Default)]
@@ -256,3 +1500,816 @@ impl Drop for Opportunity {
         self.counter.fetch_sub(1, Ordering::SeqCst);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::data::Subscription;
+    use crate::testing::temp_database;
+
+    #[test]
+    fn cap_items_limit() {
+        let items = [1, 2, 3, 4, 5];
+        assert_eq!(cap_items(&items, None), (&items[..], 0));
+        assert_eq!(cap_items(&items, Some(10)), (&items[..], 0));
+        assert_eq!(cap_items(&items, Some(5)), (&items[..], 0));
+        assert_eq!(cap_items(&items, Some(2)), (&[1, 2][..], 3));
+    }
+
+    #[test]
+    fn newest_items_first() {
+        let item = |title: &str, published: Option<&str>| Item {
+            title: Some(title.into()),
+            published: published.map(|date| chrono::DateTime::parse_from_rfc3339(date).unwrap()),
+            ..Default::default()
+        };
+        let titles = |items: &[Item]| -> Vec<String> {
+            items.iter().filter_map(|item| item.title.clone()).collect()
+        };
+        let items = [
+            item("old", Some("2000-01-01T00:00:00Z")),
+            item("new", Some("2000-03-01T00:00:00Z")),
+            item("middle", Some("2000-02-01T00:00:00Z")),
+        ];
+        assert_eq!(titles(&newest_first(&items)), ["new", "middle", "old"]);
+        assert_eq!(titles(cap_items(&newest_first(&items), Some(1)).0), ["new"]);
+        let items = [item("old", Some("2000-01-01T00:00:00Z")), item("undated", None)];
+        assert!(matches!(newest_first(&items), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn language_filter() {
+        let item = |language: Option<&str>| Item {
+            language: language.map(String::from),
+            ..Default::default()
+        };
+        let mut filter = LanguageFilter {
+            languages: vec!["en".into(), "zh".into()],
+            untagged: true,
+        };
+        assert!(is_in_language(&item(Some("en-US")), &filter));
+        assert!(is_in_language(&item(Some("ZH_cn")), &filter));
+        assert!(!is_in_language(&item(Some("de")), &filter));
+        assert!(!is_in_language(&item(Some("eng")), &filter));
+        assert!(is_in_language(&item(None), &filter));
+        filter.untagged = false;
+        assert!(!is_in_language(&item(None), &filter));
+    }
+
+    #[test]
+    fn item_filter() {
+        let item = |title: &str, description: &str| Item {
+            title: Some(title.into()),
+            description: Some(description.into()),
+            ..Default::default()
+        };
+        assert!(ItemMatcher::new(&ItemFilter::default()).is_none());
+        let mut filter = ItemFilter {
+            include: Some(r"\brust\b".into()),
+            exclude: None,
+        };
+        let matcher = ItemMatcher::new(&filter).unwrap();
+        assert!(matcher.matches(&item("Rust 2.0 released", "")));
+        assert!(matcher.matches(&item("Released", "<p>A new <b>rust</b> version</p>")));
+        assert!(!matcher.matches(&item("Trust issues", "")));
+        // Only the text of the content
+        assert!(!matcher.matches(&item("Released", "<a class=\"rust\">link</a>")));
+
+        filter.exclude = Some("sponsored".into());
+        let matcher = ItemMatcher::new(&filter).unwrap();
+        assert!(!matcher.matches(&item("Rust tips", "SPONSORED")));
+        filter.include = None;
+        let matcher = ItemMatcher::new(&filter).unwrap();
+        assert!(matcher.matches(&item("Go tips", "")));
+        assert!(!matcher.matches(&item("Sponsored: Go tips", "")));
+        assert!(filter_regex("(unclosed").is_err());
+
+        // A broken include delivers nothing, a broken exclude is left out
+        filter.exclude = Some("(unclosed".into());
+        assert!(ItemMatcher::new(&filter).unwrap().matches(&item("Go tips", "")));
+        filter.include = Some("(unclosed".into());
+        assert!(!ItemMatcher::new(&filter).unwrap().matches(&item("Go tips", "")));
+    }
+
+    #[test]
+    fn discussion_dedup() {
+        let mut feed = Feed::default();
+        feed.subscribers.extend([1, 2, 3, 4]);
+        // 1 and 2 share the same group, 4 is subscribed by itself
+        for (channel, group) in [(1, 10), (2, 10), (3, 4)] {
+            let discussion = Discussion {
+                chat_id: group,
+                enabled_by: 42,
+            };
+            let subscription = Subscription {
+                discussion: Some(discussion),
+                ..Default::default()
+            };
+            feed.subscriptions.insert(channel, subscription);
+        }
+        let mut mirrored = HashSet::new();
+        let targets = discussion_targets(&feed, &[1, 2, 3, 4], &mut mirrored);
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].1.chat_id, 10);
+
+        // 1 and 2 in different groups of settings
+        let mut mirrored = HashSet::new();
+        assert_eq!(discussion_targets(&feed, &[1], &mut mirrored).len(), 1);
+        assert!(discussion_targets(&feed, &[2], &mut mirrored).is_empty());
+    }
+
+    #[test]
+    fn truncated_response() {
+        let (path, db) = temp_database("truncated");
+        let link = "http://example.com/feed.xml";
+        let rss = |items: std::ops::Range<usize>| Rss {
+            items: items
+                .map(|i| crate::feed::Item {
+                    id: Some(i.to_string()),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        db.subscribe(1, link, &rss(0..20));
+
+        // (response, accepted, new items)
+        let script = [
+            // maintenance page, skipped once
+            (rss(100..101), false, 0),
+            // back to normal, nothing is delivered again
+            (rss(1..21), true, 1),
+            // the feed really shrank, accepted the second time
+            (rss(200..201), false, 0),
+            (rss(200..201), true, 1),
+            (rss(201..202), true, 1),
+            // an empty response, but the feed only had 1 item
+            (rss(0..0), false, 0),
+        ];
+        for (i, (response, accepted, new_items)) in script.into_iter().enumerate() {
+            assert_eq!(accept_response(&db, link, &response, 0.1), accepted, "{}", i);
+            if accepted {
+                let updates = db.update(link, response);
+                let items = match updates.first() {
+                    Some(FeedUpdate::Items(items)) => items.len(),
+                    _ => 0,
+                };
+                assert_eq!(items, new_items, "{}", i);
+            }
+        }
+        // 0 disables it
+        assert!(accept_response(&db, link, &rss(0..0), 0.0));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn fetch_guard() {
+        let link = "http://example.com/fetch_guard.xml";
+        let guard = FetchGuard::acquire(link).unwrap();
+        assert!(FetchGuard::acquire(link).is_none());
+        assert!(FetchGuard::acquire("http://example.com/other.xml").is_some());
+        drop(guard);
+        assert!(FetchGuard::acquire(link).is_some());
+    }
+
+    mod pipeline {
+        use super::*;
+        use crate::client::pull_feed;
+        use crate::testing::{self, rss, rss_with, FeedServer, MockSink, Response};
+
+        fn links(items: std::ops::Range<usize>) -> Vec<String> {
+            items
+                .rev()
+                .map(|i| format!("http://example.com/{}", i))
+                .collect()
+        }
+
+        fn feed(db: &Database, link: &str) -> Feed {
+            db.all_feeds()
+                .into_iter()
+                .find(|feed| feed.link == link)
+                .unwrap()
+        }
+
+        /// Serve `responses` after the one used to subscribe chat 1
+        async fn subscribed(name: &str, responses: Vec<Response>) -> Setup {
+            testing::init();
+            let server = FeedServer::start().await;
+            server.script("/feed.xml", responses);
+            let (path, db) = temp_database(name);
+            let db = Arc::new(db);
+            let link = server.url("/feed.xml");
+            db.subscribe(1, &link, &pull_feed(&link, None, None).await.unwrap());
+            Setup {
+                _server: server,
+                path,
+                db,
+                link,
+                sink: MockSink::default(),
+            }
+        }
+
+        struct Setup {
+            _server: FeedServer,
+            path: std::path::PathBuf,
+            db: Arc<Database>,
+            link: String,
+            sink: MockSink,
+        }
+
+        impl Setup {
+            /// Fetch once, return the links sent to chat 1
+            async fn fetch(&self) -> Vec<String> {
+                let feed = feed(&self.db, &self.link);
+                fetch(&self.sink, self.db.clone(), feed)
+                    .await
+                    .unwrap()
+                    .unwrap();
+                self.sink.take_links(1)
+            }
+        }
+
+        impl Drop for Setup {
+            fn drop(&mut self) {
+                let _ = std::fs::remove_file(&self.path);
+            }
+        }
+
+        #[tokio::test]
+        async fn updates() {
+            let setup = subscribed(
+                "pipeline-updates",
+                vec![
+                    Response::ok(rss(0..3)),
+                    Response::ok(rss(0..5)),
+                    Response::ok(rss(0..5)),
+                    Response::ok(rss(3..6)),
+                ],
+            )
+            .await;
+            assert_eq!(setup.fetch().await, links(3..5));
+            assert!(setup.fetch().await.is_empty());
+            assert_eq!(setup.fetch().await, links(5..6));
+        }
+
+        #[tokio::test]
+        async fn feed_reset() {
+            let setup = subscribed(
+                "pipeline-feed-reset",
+                vec![
+                    Response::ok(rss(0..10)),
+                    Response::ok(rss(100..110)),
+                    Response::ok(rss(200..210)),
+                    Response::ok(rss(300..310)),
+                    Response::ok(rss(300..312)),
+                ],
+            )
+            .await;
+            // The note links the feed
+            let mut expected = vec![setup.link.clone()];
+            expected.extend(links(107..110));
+            assert_eq!(setup.fetch().await, expected);
+
+            setup.db.update_subscription(1, &setup.link, |s| {
+                s.settings.reset = Some(FeedReset::All);
+            });
+            assert_eq!(setup.fetch().await, links(200..210));
+
+            setup.db.update_subscription(1, &setup.link, |s| {
+                s.settings.reset = Some(FeedReset::Resync);
+            });
+            assert!(setup.fetch().await.is_empty());
+            // Seen even though nothing was sent
+            assert_eq!(setup.fetch().await, links(310..312));
+        }
+
+        #[tokio::test]
+        async fn redirects() {
+            testing::init();
+            let server = FeedServer::start().await;
+            server.script("/old.xml", vec![Response::redirect("/feed.xml")]);
+            server.script(
+                "/feed.xml",
+                vec![Response::ok(rss(0..2)), Response::ok(rss(0..4))],
+            );
+            let (path, db) = temp_database("pipeline-redirects");
+            let db = Arc::new(db);
+            let link = server.url("/old.xml");
+            db.subscribe(1, &link, &pull_feed(&link, None, None).await.unwrap());
+            let sink = MockSink::default();
+            fetch(&sink, db.clone(), feed(&db, &link))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(sink.take_links(1), links(2..4));
+            std::fs::remove_file(path).unwrap();
+        }
+
+        #[tokio::test]
+        async fn failures_back_off() {
+            let setup = subscribed(
+                "pipeline-failures",
+                vec![
+                    Response::ok(rss(0..2)),
+                    // Never asked for, the response had no validators
+                    Response::status(304),
+                    Response::status(500),
+                    Response::truncated(rss(0..4)),
+                    Response::ok(rss(0..3)),
+                ],
+            )
+            .await;
+            for failures in 1..=3 {
+                assert!(setup.fetch().await.is_empty());
+                let feed = feed(&setup.db, &setup.link);
+                assert_eq!(feed.failures, failures);
+                assert!(feed.next_fetch.is_some());
+            }
+            // Only what's new since the last good response
+            assert_eq!(setup.fetch().await, links(2..3));
+            let feed = feed(&setup.db, &setup.link);
+            assert_eq!(feed.failures, 0);
+            assert_eq!(feed.next_fetch, None);
+        }
+
+        #[tokio::test]
+        async fn not_modified() {
+            let setup = subscribed(
+                "pipeline-not-modified",
+                vec![
+                    Response::ok(rss(0..2)),
+                    Response::ok(rss(0..3)).header("ETag", "\"3\""),
+                    Response::status(304),
+                    Response::ok(rss(0..4)).header("ETag", "\"4\""),
+                ],
+            )
+            .await;
+            assert_eq!(setup.fetch().await, links(2..3));
+            let validators = feed(&setup.db, &setup.link).validators;
+            assert_eq!(validators.etag.as_deref(), Some("\"3\""));
+            setup.db.mark_suspect(&setup.link);
+            assert!(setup.fetch().await.is_empty());
+            let feed_after = feed(&setup.db, &setup.link);
+            assert_eq!(feed_after.failures, 0);
+            // The same as the last accepted response
+            assert!(!setup.db.mark_suspect(&setup.link));
+            assert_eq!(feed_after.validators, validators);
+            assert_eq!(setup.fetch().await, links(3..4));
+            // Fetched in full by /refresh
+            setup.db.forget_responses(&setup.link);
+            assert!(feed(&setup.db, &setup.link).validators.is_empty());
+        }
+
+        #[tokio::test]
+        async fn blocked_chats_are_dropped() {
+            let setup = subscribed(
+                "pipeline-blocked",
+                vec![Response::ok(rss(0..1)), Response::ok(rss(0..2))],
+            )
+            .await;
+            setup.db.subscribe(2, &setup.link, &crate::feed::Rss::default());
+            setup.sink.block(2);
+            assert_eq!(setup.fetch().await, links(1..2));
+            assert!(setup.db.subscribed_feeds(2).is_none());
+            assert!(setup.db.subscribed_feeds(1).is_some());
+        }
+
+        #[tokio::test]
+        async fn panics_only_fail_their_feed() {
+            let setup = subscribed(
+                "pipeline-panics",
+                vec![Response::ok(rss(0..1)), Response::ok(rss(0..2))],
+            )
+            .await;
+            setup.sink.panic_on(1);
+            setup._server.script("/other.xml", vec![Response::ok(rss(0..3))]);
+            let other = setup._server.url("/other.xml");
+            let seen = crate::feed::parse(std::io::Cursor::new(rss(0..1))).unwrap();
+            setup.db.subscribe(2, &other, &seen);
+            let summary = fetch_once(&setup.sink, setup.db.clone()).await;
+            assert_eq!((summary.fetched, summary.failed), (2, 1));
+            assert_eq!(setup.sink.take_links(2), links(1..3));
+            let feed = feed(&setup.db, &setup.link);
+            assert_eq!(feed.failures, 1);
+            assert!(feed.next_fetch.is_some());
+            assert!(!setup.db.is_poisoned());
+        }
+
+        #[tokio::test]
+        async fn creators_are_told() {
+            let setup = subscribed(
+                "pipeline-creators",
+                vec![Response::ok(rss(0..1)), Response::ok(rss(0..2))],
+            )
+            .await;
+            setup.db.subscribe(-100, &setup.link, &crate::feed::Rss::default());
+            setup.db.set_subscribed_by(-100, &setup.link, 5);
+            setup.sink.block(-100);
+            let subscribed = feed(&setup.db, &setup.link);
+            fetch(&setup.sink, setup.db.clone(), subscribed)
+                .await
+                .unwrap()
+                .unwrap();
+            let sent = setup.sink.take();
+            let notices: Vec<_> = sent.iter().filter(|(chat, _)| *chat == 5).collect();
+            assert_eq!(notices.len(), 1);
+            assert!(notices[0].1.contains("-100"), "{}", notices[0].1);
+            assert!(setup.db.subscribed_feeds(-100).is_none());
+        }
+
+        #[tokio::test]
+        async fn chats_in_parallel() {
+            let setup = subscribed("pipeline-parallel", vec![Response::ok(rss(0..1))]).await;
+            let chats = [1, 2, 3];
+            for &chat in &chats[1..] {
+                setup.db.subscribe(chat, &setup.link, &crate::feed::Rss::default());
+            }
+            setup.sink.slow_down(Duration::from_millis(20));
+            let msgs: Vec<String> = (0..3).map(|i| format!("message {}", i)).collect();
+            let mut delivered =
+                push_updates(&setup.sink, &setup.db, &setup.link, chats, &msgs, false)
+                    .await
+                    .unwrap();
+            delivered.sort_unstable();
+            assert_eq!(delivered, chats);
+            let sent = setup.sink.take();
+            // Every chat got its first message before any of them got the second one
+            let first: HashSet<i64> = sent[..3].iter().map(|(chat, _)| *chat).collect();
+            assert_eq!(first.len(), 3);
+            for chat in chats {
+                let received: Vec<&str> = sent
+                    .iter()
+                    .filter(|(to, _)| *to == chat)
+                    .map(|(_, msg)| msg.as_str())
+                    .collect();
+                assert_eq!(received, ["message 0", "message 1", "message 2"]);
+            }
+        }
+
+        #[tokio::test]
+        async fn backfill_after_subscription() {
+            let now = chrono::Utc::now();
+            let item = |i: usize, published: Option<chrono::Duration>| {
+                let date = published
+                    .map(|age| format!("<pubDate>{}</pubDate>", (now - age).to_rfc2822()))
+                    .unwrap_or_default();
+                format!(
+                    "<item><title>item {0}</title><link>http://example.com/{0}</link>\
+                     <guid>{0}</guid>{1}</item>",
+                    i, date
+                )
+            };
+            let recovered = format!(
+                "<rss version=\"2.0\"><channel><title>test</title>{}{}{}{}</channel></rss>",
+                item(4, None),
+                item(3, Some(chrono::Duration::minutes(30))),
+                item(2, Some(chrono::Duration::days(365))),
+                item(0, None)
+            );
+            let setup = subscribed(
+                "pipeline-backfill",
+                vec![
+                    Response::ok(rss(0..1)),
+                    Response::status(500),
+                    Response::ok(recovered.into_bytes()),
+                ],
+            )
+            .await;
+            // Chat 2 subscribes while the feed is failing
+            setup.db.subscribe(2, &setup.link, &crate::feed::Rss::default());
+            assert!(setup.fetch().await.is_empty());
+
+            let mut subscribed = feed(&setup.db, &setup.link);
+            // Chat 1 has been around for long
+            let activity = subscribed.activity.get_mut(&1).unwrap();
+            activity.subscribed_at = Some(SystemTime::now() - Duration::from_secs(2 * 365 * 86400));
+            fetch(&setup.sink, setup.db.clone(), subscribed)
+                .await
+                .unwrap()
+                .unwrap();
+            let sent = setup.sink.take();
+            let links_to = |chat_id| {
+                sent.iter()
+                    .filter(|(chat, _)| *chat == chat_id)
+                    .flat_map(|(_, html)| crate::messages::html_links(html))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(links_to(1), [4, 3, 2].map(|i| format!("http://example.com/{}", i)));
+            // The year old item is only new to the shared feed state
+            assert_eq!(links_to(2), [4, 3].map(|i| format!("http://example.com/{}", i)));
+        }
+
+        #[tokio::test]
+        async fn digest_above() {
+            let setup = subscribed(
+                "pipeline-digest",
+                vec![
+                    Response::ok(rss(0..1)),
+                    Response::ok(rss(0..6)),
+                    Response::ok(rss(0..8)),
+                ],
+            )
+            .await;
+            setup.db.update_subscription(1, &setup.link, |s| {
+                s.settings.digest_above = Some(3);
+                s.settings.max_items = Some(2);
+            });
+            // A single message with the newest of them, and the note of the others
+            let subscribed = feed(&setup.db, &setup.link);
+            fetch(&setup.sink, setup.db.clone(), subscribed)
+                .await
+                .unwrap()
+                .unwrap();
+            let sent = setup.sink.take();
+            assert_eq!(sent.len(), 2);
+            assert_eq!(crate::messages::html_links(&sent[0].1), links(4..6));
+            let skipped = tr!(
+                "items_skipped",
+                count = 3,
+                link = Escape(&setup.link),
+                title = Title("test")
+            );
+            assert!(sent[1].1.starts_with(&skipped), "{}", sent[1].1);
+            // Back to normal for the next fetch
+            assert_eq!(setup.fetch().await, links(6..8));
+        }
+
+        #[tokio::test]
+        async fn reordered_items() {
+            testing::init();
+            // The same 30 items in another order every time
+            let shuffled = |k: usize| {
+                let order: Vec<usize> = (0..30).map(|i| (i * 7 + k * 11) % 30).collect();
+                Response::ok(rss_with(&order))
+            };
+            // Then two new ones, among the same items shuffled again
+            let mut order: Vec<usize> = (0..30).map(|i| (i * 13 + 5) % 30).collect();
+            order.insert(0, 31);
+            order.insert(10, 30);
+            let mut responses: Vec<Response> = (0..6).map(shuffled).collect();
+            responses.push(Response::ok(rss_with(&order)));
+            responses.push(shuffled(6));
+            let server = FeedServer::start().await;
+            server.script("/feed.xml", responses);
+            let (path, _) = temp_database("pipeline-reordered");
+            let db = Database::create(path.clone())
+                .unwrap()
+                .with_max_hashes(Some(20))
+                .unwrap();
+            let link = server.url("/feed.xml");
+            db.subscribe(1, &link, &pull_feed(&link, None, None).await.unwrap());
+            let setup = Setup {
+                _server: server,
+                path,
+                db: Arc::new(db),
+                link,
+                sink: MockSink::default(),
+            };
+            for _ in 1..6 {
+                assert!(setup.fetch().await.is_empty());
+            }
+            // Only the new ones, in the order of the feed
+            assert_eq!(
+                setup.fetch().await,
+                ["http://example.com/31", "http://example.com/30"]
+            );
+            assert!(setup.fetch().await.is_empty());
+        }
+
+        #[tokio::test]
+        async fn chat_defaults() {
+            let setup = subscribed(
+                "pipeline-defaults",
+                vec![
+                    Response::ok(rss(0..1)),
+                    Response::ok(rss(0..4)),
+                    Response::ok(rss(0..7)),
+                ],
+            )
+            .await;
+            setup.db.update_chat(1, |chat| chat.defaults.digest_above = Some(2));
+            let subscribed = feed(&setup.db, &setup.link);
+            fetch(&setup.sink, setup.db.clone(), subscribed)
+                .await
+                .unwrap()
+                .unwrap();
+            let sent = setup.sink.take();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(crate::messages::html_links(&sent[0].1), links(1..4));
+            // Unless the subscription overrides it
+            setup.db.update_subscription(1, &setup.link, |s| {
+                s.settings.digest_above = Some(0)
+            });
+            assert_eq!(setup.fetch().await, links(4..7));
+        }
+
+        #[tokio::test]
+        async fn max_queue_age() {
+            // Not old enough to be left out as a backfill
+            let recent = (chrono::Utc::now() - chrono::Duration::minutes(30)).to_rfc2822();
+            let xml = |items: &[(usize, bool)]| {
+                let mut xml = String::from("<rss><channel><title>test</title>");
+                for &(i, dated) in items.iter().rev() {
+                    xml.push_str(&format!(
+                        "<item><link>http://example.com/{0}</link><guid>{0}</guid>{1}</item>",
+                        i,
+                        if dated { format!("<pubDate>{}</pubDate>", recent) } else { String::new() }
+                    ));
+                }
+                xml.push_str("</channel></rss>");
+                Response::ok(xml.into_bytes())
+            };
+            let setup = subscribed(
+                "pipeline-max-queue-age",
+                vec![
+                    xml(&[(0, false)]),
+                    xml(&[(0, false), (1, true), (2, false)]),
+                    xml(&[(0, false), (1, true), (2, false), (3, true)]),
+                    xml(&[(0, false), (1, true), (2, false), (3, true), (4, false)]),
+                ],
+            )
+            .await;
+            setup.db.update_chat(1, |chat| chat.defaults.max_queue_age = Some(10 * 60));
+            let stale_line = tr!("stale_items_skipped", count = 1);
+            let subscribed = || feed(&setup.db, &setup.link);
+            fetch(&setup.sink, setup.db.clone(), subscribed()).await.unwrap().unwrap();
+            let sent = setup.sink.take();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(crate::messages::html_links(&sent[0].1), links(2..3));
+            assert!(sent[0].1.ends_with(&stale_line), "{}", sent[0].1);
+            // Nothing left to send, it's told with the next delivery
+            fetch(&setup.sink, setup.db.clone(), subscribed()).await.unwrap().unwrap();
+            assert!(setup.sink.take().is_empty());
+            assert_eq!(subscribed().activity(1).unwrap().stale_skipped, 1);
+            fetch(&setup.sink, setup.db.clone(), subscribed()).await.unwrap().unwrap();
+            let sent = setup.sink.take();
+            assert_eq!(crate::messages::html_links(&sent[0].1), links(4..5));
+            assert!(sent[0].1.ends_with(&stale_line), "{}", sent[0].1);
+            assert_eq!(subscribed().activity(1).unwrap().stale_skipped, 0);
+        }
+
+        #[tokio::test]
+        async fn preview() {
+            let setup = subscribed(
+                "pipeline-preview",
+                vec![Response::ok(rss(0..2)), Response::ok(rss(0..3))],
+            )
+            .await;
+            setup.db.update_chat(1, |chat| chat.defaults.clean_links = Some(LinkCleaning::Strip));
+            let item = Item {
+                title: Some("item 2".into()),
+                link: Some("http://example.com/2?utm_source=rss".into()),
+                id: Some("2".into()),
+                ..Default::default()
+            };
+            let subscribed = feed(&setup.db, &setup.link);
+            assert!(send_preview(&setup.sink, &setup.db, &subscribed, 1, item).await.unwrap());
+            let preview = setup.sink.take();
+            // Not marked as seen, and delivered the same way
+            fetch(&setup.sink, setup.db.clone(), subscribed).await.unwrap().unwrap();
+            let delivered = setup.sink.take();
+            assert_eq!(crate::messages::html_links(&delivered[0].1), links(2..3));
+            assert_eq!(preview.len(), 1);
+            assert_eq!(preview[0].1, format!("{}\n{}", tr!("preview_head"), delivered[0].1));
+        }
+
+        #[tokio::test]
+        async fn replies() {
+            let setup = subscribed(
+                "pipeline-replies",
+                vec![
+                    Response::ok(rss(0..1)),
+                    Response::ok(rss(0..2)),
+                    Response::ok(rss(0..3)),
+                    Response::ok(rss(0..4)),
+                ],
+            )
+            .await;
+            setup.sink.pin(1, 10);
+            setup.db.update_chat(1, |chat| chat.reply_to = Some(ReplyTo::Pinned));
+            assert_eq!(setup.fetch().await, links(1..2));
+            assert_eq!(setup.sink.take_replies(), [(1, 10)]);
+
+            // Sent normally when the message is gone, and the setting is cleared
+            setup.db.update_chat(1, |chat| chat.reply_to = Some(ReplyTo::Message(10)));
+            setup.sink.delete(1, 10);
+            let subscribed = feed(&setup.db, &setup.link);
+            fetch(&setup.sink, setup.db.clone(), subscribed)
+                .await
+                .unwrap()
+                .unwrap();
+            let sent = setup.sink.take();
+            assert_eq!(sent.len(), 2);
+            assert!(sent[0].1.contains("http://example.com/2"));
+            assert_eq!(sent[1].1, tr!("reply_to_message_gone"));
+            assert_eq!(setup.db.chat(1).reply_to, None);
+
+            assert_eq!(setup.fetch().await, links(3..4));
+            assert!(setup.sink.take_replies().is_empty());
+        }
+
+        #[tokio::test]
+        async fn legacy_encoding() {
+            let setup = subscribed(
+                "pipeline-encoding",
+                vec![
+                    Response::ok(rss(0..0)),
+                    Response::ok(include_bytes!("../tests/data/encoding_items.xml").to_vec()),
+                ],
+            )
+            .await;
+            let subscribed = feed(&setup.db, &setup.link);
+            fetch(&setup.sink, setup.db.clone(), subscribed)
+                .await
+                .unwrap()
+                .unwrap();
+            let sent: String = setup.sink.take().into_iter().map(|(_, html)| html).collect();
+            assert!(sent.contains("第一条") && sent.contains("第二条"), "{}", sent);
+            assert_eq!(feed(&setup.db, &setup.link).title, "编码测试");
+        }
+
+        #[tokio::test]
+        async fn weekly_report() {
+            use chrono::Datelike;
+            use crate::data::WeeklyReport;
+            let setup = subscribed(
+                "pipeline-weekly-report",
+                vec![Response::ok(rss(0..2)), Response::ok(rss(0..5))],
+            )
+            .await;
+            assert_eq!(setup.fetch().await, links(2..5));
+            let now = chrono::Utc::now();
+            setup.db.update_chat(1, |chat| {
+                chat.weekly_report = Some(WeeklyReport {
+                    weekday: now.weekday().num_days_from_monday(),
+                    minute: 0,
+                    last_sent: now.timestamp() - 7 * 24 * 60 * 60,
+                })
+            });
+            send_weekly_reports(&setup.sink, &setup.db, &now).await;
+            let sent = setup.sink.take();
+            assert_eq!(sent.len(), 1);
+            let expected = tr!("weekly_report", feeds = 1, items = 3);
+            assert!(sent[0].1.starts_with(&expected), "{}", sent[0].1);
+            assert!(sent[0].1.contains("test (3)"), "{}", sent[0].1);
+            // Only once a week
+            send_weekly_reports(&setup.sink, &setup.db, &now).await;
+            assert!(setup.sink.take().is_empty());
+
+            // Not counted as sent when it failed
+            let next_week = now + chrono::Duration::weeks(1);
+            setup.sink.fail_on(1);
+            send_weekly_reports(&setup.sink, &setup.db, &next_week).await;
+            setup.sink.recover(1);
+            send_weekly_reports(&setup.sink, &setup.db, &next_week).await;
+            assert_eq!(setup.sink.take().len(), 1);
+        }
+
+        #[tokio::test]
+        async fn digest_schedule() {
+            use crate::data::{Digest, DigestSchedule};
+            let setup = subscribed(
+                "pipeline-digest-schedule",
+                vec![Response::ok(rss(0..3)), Response::ok(rss(0..5))],
+            )
+            .await;
+            let now = chrono::Utc::now();
+            setup.db.update_subscription(1, &setup.link, |s| {
+                s.digest = Some(Digest {
+                    schedule: DigestSchedule::Hourly,
+                    last_sent: now.timestamp(),
+                });
+                s.settings.max_items = Some(1);
+            });
+            // Kept until it's time
+            assert!(setup.fetch().await.is_empty());
+            assert!(setup.fetch().await.is_empty());
+            send_digests(&setup.sink, &setup.db, &now).await;
+            assert!(setup.sink.take().is_empty());
+
+            // Kept when it fails
+            let later = now + chrono::Duration::hours(1);
+            setup.sink.fail_on(1);
+            send_digests(&setup.sink, &setup.db, &later).await;
+            setup.sink.recover(1);
+            assert!(setup.sink.take().is_empty());
+            assert_eq!(feed(&setup.db, &setup.link).digest_items(1), 2);
+
+            let later = now + chrono::Duration::hours(2);
+            send_digests(&setup.sink, &setup.db, &later).await;
+            // Capped when it's sent, the others are gone too
+            let sent = setup.sink.take();
+            assert_eq!(sent.len(), 2);
+            assert_eq!(crate::messages::html_links(&sent[0].1), links(4..5));
+            assert!(sent[1].1.contains(&tr!(
+                "items_skipped",
+                count = 1,
+                link = Escape(&setup.link),
+                title = Title("test")
+            )));
+            assert_eq!(feed(&setup.db, &setup.link).digest_items(1), 0);
+            send_digests(&setup.sink, &setup.db, &later).await;
+            assert!(setup.sink.take().is_empty());
+        }
+    }
+}
@@ -1,44 +1,237 @@
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
 
+use futures::{future::FutureExt, select_biased};
 use tbot::Bot;
 use tokio::{
     self,
-    sync::Mutex,
+    sync::Notify,
+    task::JoinHandle,
     time::{self, Duration},
 };
 
-use crate::data::Database;
+use crate::chat_info;
+use crate::data::{chat_display, Database, Execution};
+use crate::messages::{classify_error, ApiError};
 use crate::BOT_ID;
 
-pub fn start_pruning(bot: Bot, db: Arc<Mutex<Database>>) {
-    let mut interval = time::interval(Duration::from_secs(1 * 24 * 60 * 60));
+// Chats checked before letting the others use the database
+const BATCH_SIZE: usize = 50;
+
+static SCHEDULE: OnceLock<Schedule> = OnceLock::new();
+static SWEEPS: Mutex<Sweeps> = Mutex::new(Sweeps {
+    last: None,
+    next: None,
+    running: false,
+});
+static SWEEP_NOW: Notify = Notify::const_new();
+// Chats the bot can't post in anymore, to when that was first noticed.
+// Only kept in memory, a restart starts their grace period over
+static UNAVAILABLE: Mutex<BTreeMap<i64, SystemTime>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy)]
+pub struct Schedule {
+    pub interval: Duration,
+    /// How long a chat has to stay unavailable before it's removed
+    pub grace: Duration,
+}
+
+/// Reported by /gardener
+#[derive(Debug, Clone, Copy)]
+pub struct Sweeps {
+    /// When the last sweep finished, and how many chats it removed
+    pub last: Option<(SystemTime, usize)>,
+    pub next: Option<SystemTime>,
+    pub running: bool,
+}
+
+pub fn start_pruning(bot: Bot, db: Arc<Database>, schedule: Schedule) -> JoinHandle<()> {
+    // A second run keeps the schedule of the first
+    let _ = SCHEDULE.set(schedule);
     tokio::spawn(async move {
         loop {
-            interval.tick().await;
-            if let Err(e) = prune(&bot, &db).await {
-                crate::print_error(e);
+            SWEEPS.lock().unwrap().running = true;
+            let removed = prune(&bot, &db, Execution::Apply).await;
+            *SWEEPS.lock().unwrap() = Sweeps {
+                last: Some((SystemTime::now(), removed.len())),
+                next: Some(SystemTime::now() + schedule.interval),
+                running: false,
+            };
+            select_biased! {
+                _ = SWEEP_NOW.notified().fuse() => (),
+                _ = time::sleep(schedule.interval).fuse() => (),
             }
         }
-    });
-}
-
-async fn prune(bot: &Bot, db: &Mutex<Database>) -> Result<(), tbot::errors::MethodCall> {
-    let subscribers = db.lock().await.all_subscribers();
-    for subscriber in subscribers {
-        let chat_id = tbot::types::chat::Id(subscriber);
-        let chat = bot.get_chat(chat_id).call().await?;
-        if chat.kind.is_group() || chat.kind.is_supergroup() || chat.kind.is_channel() {
-            let me = bot
-                .get_chat_member(chat_id, *BOT_ID.get().unwrap())
-                .call()
-                .await?;
-            // Bots can only be added as administrators in channel,
-            // so we don't need to check that.
-            // And just ignore `can_post_messages` or `can_send_messages`
-            if me.status.is_left() || me.status.is_kicked() {
-                db.lock().await.delete_subscriber(subscriber);
+    })
+}
+
+pub fn schedule() -> Option<Schedule> {
+    SCHEDULE.get().copied()
+}
+
+pub fn sweeps() -> Sweeps {
+    *SWEEPS.lock().unwrap()
+}
+
+/// Chats that will be removed if they are still unavailable after the grace period
+pub fn in_grace_period() -> Vec<i64> {
+    UNAVAILABLE.lock().unwrap().keys().copied().collect()
+}
+
+/// The bot was removed from the chat, its grace period starts now
+pub fn mark_unavailable(chat: i64) {
+    UNAVAILABLE
+        .lock()
+        .unwrap()
+        .entry(chat)
+        .or_insert_with(SystemTime::now);
+}
+
+/// The bot was added back before the chat was removed
+pub fn mark_available(chat: i64) {
+    UNAVAILABLE.lock().unwrap().remove(&chat);
+}
+
+/// Start a sweep now, false if one is running already
+pub fn sweep_now() -> bool {
+    if sweeps().running {
+        return false;
+    }
+    SWEEP_NOW.notify_one();
+    true
+}
+
+/// What a sweep would do now, without removing anything or starting grace periods
+pub async fn dry_run(bot: &Bot, db: &Database) -> Vec<(i64, usize)> {
+    prune(bot, db, Execution::DryRun).await
+}
+
+/// Return the removed chats, with how many subscriptions they had
+async fn prune(bot: &Bot, db: &Database, execution: Execution) -> Vec<(i64, usize)> {
+    let subscribers = db.all_subscribers();
+    let mut removed = Vec::new();
+    for batch in subscribers.chunks(BATCH_SIZE) {
+        for &subscriber in batch {
+            if let Some(subscriptions) = prune_subscriber(bot, db, subscriber, execution).await {
+                removed.push((subscriber, subscriptions));
             }
         }
+        tokio::task::yield_now().await;
+    }
+    if execution == Execution::Apply {
+        forget_removed(&mut UNAVAILABLE.lock().unwrap(), &subscribers);
+    }
+    removed
+}
+
+/// Removed by other means, f.e. when delivering to them failed
+fn forget_removed(unavailable: &mut BTreeMap<i64, SystemTime>, subscribers: &[i64]) {
+    // Not sorted, they come from a hash map
+    let subscribers: HashSet<i64> = subscribers.iter().copied().collect();
+    unavailable.retain(|chat, _| subscribers.contains(chat));
+}
+
+/// The subscriptions of the subscriber, if it was removed
+async fn prune_subscriber(
+    bot: &Bot,
+    db: &Database,
+    subscriber: i64,
+    execution: Execution,
+) -> Option<usize> {
+    let dry_run = execution == Execution::DryRun;
+    match check_chat(bot, subscriber).await {
+        Some(true) => {
+            if !dry_run {
+                UNAVAILABLE.lock().unwrap().remove(&subscriber);
+            }
+            None
+        }
+        Some(false) if grace_elapsed(subscriber, SystemTime::now(), execution) => {
+            if !dry_run {
+                UNAVAILABLE.lock().unwrap().remove(&subscriber);
+                eprintln!("Removing unavailable chat {}", chat_display(subscriber));
+            }
+            db.delete_subscriber(subscriber, execution)
+        }
+        Some(false) | None => None,
+    }
+}
+
+/// Whether the bot can still post in the chat, `None` if that's unknown
+async fn check_chat(bot: &Bot, subscriber: i64) -> Option<bool> {
+    for _ in 0..3 {
+        match prune_chat(bot, subscriber).await {
+            Ok(available) => return Some(available),
+            Err(e) => match classify_error(&e) {
+                ApiError::RetryAfter(delay) => {
+                    time::sleep(Duration::from_secs(delay)).await;
+                }
+                ApiError::ChatUnavailable => return Some(false),
+                // Chats migrated to supergroups are updated when delivering to them
+                ApiError::Migrated(_) => return Some(true),
+                // One broken chat doesn't stop the others from being checked
+                ApiError::BadMarkup | ApiError::Other => {
+                    crate::print_error(e);
+                    return None;
+                }
+            },
+        }
+    }
+    None
+}
+
+/// A dry run doesn't start the grace period
+fn grace_elapsed(subscriber: i64, now: SystemTime, execution: Execution) -> bool {
+    let grace = schedule().map(|s| s.grace).unwrap_or_default();
+    let mut unavailable = UNAVAILABLE.lock().unwrap();
+    let since = match execution {
+        Execution::Apply => *unavailable.entry(subscriber).or_insert(now),
+        Execution::DryRun => unavailable.get(&subscriber).copied().unwrap_or(now),
+    };
+    now.duration_since(since).unwrap_or_default() >= grace
+}
+
+/// Whether the bot is still in the chat
+async fn prune_chat(bot: &Bot, subscriber: i64) -> Result<bool, tbot::errors::MethodCall> {
+    let chat_id = tbot::types::chat::Id(subscriber);
+    let chat = chat_info::get_chat(bot, chat_id).await?;
+    // Channels never send commands, this is how their names are known
+    crate::commands::remember_chat(&chat);
+    if chat.kind.is_group() || chat.kind.is_supergroup() || chat.kind.is_channel() {
+        let me = chat_info::get_chat_member(bot, chat_id, *BOT_ID.get().unwrap()).await?;
+        // Bots can only be added as administrators in channel,
+        // so we don't need to check that.
+        // And just ignore `can_post_messages` or `can_send_messages`
+        if me.status.is_left() || me.status.is_kicked() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn removed_chats() {
+        let now = SystemTime::now();
+        let mut unavailable: BTreeMap<i64, SystemTime> =
+            [(-3, now), (1, now), (5, now), (7, now)].into_iter().collect();
+        forget_removed(&mut unavailable, &[7, -3, 2, 1]);
+        assert_eq!(unavailable.keys().copied().collect::<Vec<_>>(), [-3, 1, 7]);
+    }
+
+    #[test]
+    fn dry_run_grace() {
+        let subscriber = -1001;
+        let now = SystemTime::now();
+        assert!(grace_elapsed(subscriber, now, Execution::DryRun));
+        assert!(!in_grace_period().contains(&subscriber));
+        assert!(grace_elapsed(subscriber, now, Execution::Apply));
+        assert!(in_grace_period().contains(&subscriber));
+        mark_available(subscriber);
+        assert!(!in_grace_period().contains(&subscriber));
     }
-    Ok(())
 }
@@ -0,0 +1,458 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time,
+};
+
+/// When the major parts of the bot made progress, in unix seconds
+///
+/// These are plain atomics, so reading them never waits for the database.
+pub static HEARTBEATS: Heartbeats = Heartbeats::new();
+
+pub static METRICS: Metrics = Metrics::new();
+
+// If polling reported no error for this long, it's considered working
+const POLLING_ERROR_GRACE: u64 = 60;
+// Recent items per feed the latency percentiles are taken from
+const LATENCY_WINDOW: usize = 100;
+// Fewer than this say nothing about how fresh a feed is
+const MIN_LATENCY_SAMPLES: usize = 5;
+// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [u64; 6] = [60, 5 * 60, 15 * 60, 60 * 60, 6 * 60 * 60, 24 * 60 * 60];
+
+pub struct Heartbeats {
+    started: AtomicU64,
+    event_loop: AtomicU64,
+    polling_error: AtomicU64,
+    fetch_cycle: AtomicU64,
+    fetches_running: AtomicU64,
+    database_saved: AtomicU64,
+    database_failing_since: AtomicU64,
+}
+
+/// A fetch of the scheduler, see `Heartbeats::fetch_started`
+pub struct FetchProgress(&'static Heartbeats);
+
+/// Counters that are only reported, they don't affect the health
+pub struct Metrics {
+    plain_text_fallbacks: AtomicU64,
+    queued_sends: AtomicU64,
+    delayed_sends: AtomicU64,
+    chat_info_requests: AtomicU64,
+    chat_info_hits: AtomicU64,
+    latencies: Mutex<Latencies>,
+}
+
+/// Only kept in memory, a restart starts over
+struct Latencies {
+    /// By the feed link
+    feeds: BTreeMap<String, Window>,
+    /// Of all feeds, the items in each of `LATENCY_BUCKETS`, and above them
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+}
+
+/// The last `capacity` values
+struct Window {
+    samples: VecDeque<u64>,
+    capacity: usize,
+}
+
+impl Window {
+    fn new(capacity: usize) -> Self {
+        Window {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn push(&mut self, value: u64) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(value);
+    }
+
+    fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Nearest rank, `None` if there are no values
+    fn percentile(&self, percent: usize) -> Option<u64> {
+        let mut sorted: Vec<u64> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        let rank = (sorted.len() * percent).div_ceil(100);
+        sorted.get(rank.saturating_sub(1)).copied()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct LatencyBucket {
+    /// In seconds, `None` above the last bucket
+    le: Option<u64>,
+    /// Cumulative, like a Prometheus histogram
+    count: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    event_loop: u64,
+    fetch_cycle: u64,
+    database_saved: u64,
+    plain_text_fallbacks: u64,
+    /// Messages waiting for Telegram's rate limits now
+    queued_sends: u64,
+    /// Messages that had to wait for them
+    delayed_sends: u64,
+    /// Requests for chat info made to Telegram
+    chat_info_requests: u64,
+    /// Chat info that was known already, or requested by another caller at the same time
+    chat_info_hits: u64,
+    /// Items by how long after their publication they were delivered
+    delivery_latency: Vec<LatencyBucket>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            plain_text_fallbacks: AtomicU64::new(0),
+            queued_sends: AtomicU64::new(0),
+            delayed_sends: AtomicU64::new(0),
+            chat_info_requests: AtomicU64::new(0),
+            chat_info_hits: AtomicU64::new(0),
+            latencies: Mutex::new(Latencies {
+                feeds: BTreeMap::new(),
+                buckets: [0; LATENCY_BUCKETS.len() + 1],
+            }),
+        }
+    }
+
+    /// Telegram failed to parse a HTML message we sent, it's likely an escaping bug
+    pub fn plain_text_fallback(&self) {
+        self.plain_text_fallbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn queued_sends(&self, count: usize) {
+        self.queued_sends.store(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn delayed_send(&self) {
+        self.delayed_sends.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// See `chat_info`, `hit` if no request was made
+    pub fn chat_info(&self, hit: bool) {
+        let counter = if hit {
+            &self.chat_info_hits
+        } else {
+            &self.chat_info_requests
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Items of the feed were just delivered, see `messages::delivery_latency`
+    pub fn delivery_latencies(&self, feed_link: &str, latencies: &[u64]) {
+        if latencies.is_empty() {
+            return;
+        }
+        let stats = &mut *self.latencies.lock().unwrap();
+        let window = stats
+            .feeds
+            .entry(feed_link.to_string())
+            .or_insert_with(|| Window::new(LATENCY_WINDOW));
+        for &latency in latencies {
+            let bucket = LATENCY_BUCKETS
+                .iter()
+                .position(|&le| latency <= le)
+                .unwrap_or(LATENCY_BUCKETS.len());
+            stats.buckets[bucket] += 1;
+            window.push(latency);
+        }
+    }
+
+    /// Nobody is subscribed to the feed anymore
+    pub fn forget_feed(&self, feed_link: &str) {
+        self.latencies.lock().unwrap().feeds.remove(feed_link);
+    }
+
+    /// The median and the 95th percentile of the recent latencies of the feed,
+    /// `None` until enough dated items were delivered
+    pub fn delivery_latency(&self, feed_link: &str) -> Option<(u64, u64)> {
+        let stats = self.latencies.lock().unwrap();
+        let window = stats
+            .feeds
+            .get(feed_link)
+            .filter(|window| window.len() >= MIN_LATENCY_SAMPLES)?;
+        Some((window.percentile(50)?, window.percentile(95)?))
+    }
+
+    fn latency_histogram(&self) -> Vec<LatencyBucket> {
+        let buckets = self.latencies.lock().unwrap().buckets;
+        let mut count = 0;
+        buckets
+            .iter()
+            .enumerate()
+            .map(|(i, &n)| {
+                count += n;
+                LatencyBucket {
+                    le: LATENCY_BUCKETS.get(i).copied(),
+                    count,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Heartbeats {
+    const fn new() -> Self {
+        Heartbeats {
+            started: AtomicU64::new(0),
+            event_loop: AtomicU64::new(0),
+            polling_error: AtomicU64::new(0),
+            fetch_cycle: AtomicU64::new(0),
+            fetches_running: AtomicU64::new(0),
+            database_saved: AtomicU64::new(0),
+            database_failing_since: AtomicU64::new(0),
+        }
+    }
+
+    /// Received an update from Telegram
+    pub fn event_loop(&self) {
+        self.event_loop.store(now(), Ordering::Relaxed);
+    }
+
+    /// `getUpdates` failed
+    pub fn polling_error(&self) {
+        self.polling_error.store(now(), Ordering::Relaxed);
+    }
+
+    /// Seconds since `start_heartbeats`
+    pub fn uptime(&self) -> u64 {
+        now().saturating_sub(self.started.load(Ordering::Relaxed))
+    }
+
+    /// The fetcher went through all feeds again. Only counts when no fetch is running,
+    /// otherwise the scheduler is making progress only when they complete.
+    pub fn fetch_cycle(&self) {
+        if self.fetches_running.load(Ordering::Relaxed) == 0 {
+            self.fetch_cycle.store(now(), Ordering::Relaxed);
+        }
+    }
+
+    /// The fetcher started a fetch, it counts as progress once the returned value is dropped
+    pub fn fetch_started(&'static self) -> FetchProgress {
+        self.fetches_running.fetch_add(1, Ordering::Relaxed);
+        FetchProgress(self)
+    }
+
+    fn fetch_finished(&self) {
+        self.fetches_running.fetch_sub(1, Ordering::Relaxed);
+        self.fetch_cycle.store(now(), Ordering::Relaxed);
+    }
+
+    pub fn database_saved(&self, succeeded: bool) {
+        let now = now();
+        if succeeded {
+            self.database_saved.store(now, Ordering::Relaxed);
+            self.database_failing_since.store(0, Ordering::Relaxed);
+        } else {
+            let _ = self.database_failing_since.compare_exchange(
+                0,
+                now,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    fn report(&self) -> Report {
+        Report {
+            event_loop: self.event_loop.load(Ordering::Relaxed),
+            fetch_cycle: self.fetch_cycle.load(Ordering::Relaxed),
+            database_saved: self.database_saved.load(Ordering::Relaxed),
+            plain_text_fallbacks: METRICS.plain_text_fallbacks.load(Ordering::Relaxed),
+            queued_sends: METRICS.queued_sends.load(Ordering::Relaxed),
+            delayed_sends: METRICS.delayed_sends.load(Ordering::Relaxed),
+            chat_info_requests: METRICS.chat_info_requests.load(Ordering::Relaxed),
+            chat_info_hits: METRICS.chat_info_hits.load(Ordering::Relaxed),
+            delivery_latency: METRICS.latency_histogram(),
+        }
+    }
+
+    /// Nothing changes if there are no new items, so the database is only
+    /// stale if saving keeps failing.
+    /// Long polling doesn't return anything when there are no updates, so the event loop
+    /// is alive while it's not reporting errors.
+    fn is_healthy(&self, now: u64, staleness: u64) -> bool {
+        let report = self.report();
+        let failing_since = self.database_failing_since.load(Ordering::Relaxed);
+        let last_error = self.polling_error.load(Ordering::Relaxed);
+        let polling = now.saturating_sub(last_error) > POLLING_ERROR_GRACE;
+        (polling || now.saturating_sub(report.event_loop) <= staleness)
+            && now.saturating_sub(report.fetch_cycle) <= staleness
+            && (failing_since == 0 || now.saturating_sub(failing_since) <= staleness)
+    }
+
+    /// Checked by the health check endpoint and the systemd watchdog
+    pub fn is_alive(&self, staleness: Duration) -> bool {
+        self.is_healthy(now(), staleness.as_secs())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub fn start_heartbeats() {
+    let startup = now();
+    HEARTBEATS.started.store(startup, Ordering::Relaxed);
+    HEARTBEATS.event_loop.store(startup, Ordering::Relaxed);
+    HEARTBEATS.fetch_cycle.store(startup, Ordering::Relaxed);
+}
+
+impl Drop for FetchProgress {
+    fn drop(&mut self) {
+        self.0.fetch_finished();
+    }
+}
+
+/// Serve the health check on `addr`, reply 503 if anything is older than `staleness`
+pub async fn serve(addr: SocketAddr, staleness: Duration) -> std::io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr).await?;
+    let staleness = staleness.as_secs();
+    let server = tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(async move {
+                        let _ = time::timeout(
+                            Duration::from_secs(5),
+                            handle_connection(stream, staleness),
+                        )
+                        .await;
+                    });
+                }
+                Err(e) => eprintln!("Health check: {}", e),
+            }
+        }
+    });
+    Ok(server)
+}
+
+async fn handle_connection(mut stream: TcpStream, staleness: u64) -> std::io::Result<()> {
+    // We answer every request the same way, only wait for the request head
+    let mut buf = [0u8; 1024];
+    let mut len = 0;
+    while len < buf.len() && !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buf[len..]).await? {
+            0 => break,
+            n => len += n,
+        }
+    }
+
+    let healthy = HEARTBEATS.is_healthy(now(), staleness);
+    let body = serde_json::to_string(&HEARTBEATS.report()).unwrap();
+    let status = if healthy {
+        "200 OK"
+    } else {
+        "503 Service Unavailable"
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn staleness() {
+        let heartbeats = Heartbeats::new();
+        heartbeats.event_loop.store(1000, Ordering::Relaxed);
+        heartbeats.fetch_cycle.store(1000, Ordering::Relaxed);
+        assert!(heartbeats.is_healthy(1100, 100));
+        assert!(!heartbeats.is_healthy(1101, 100));
+
+        heartbeats.fetch_cycle.store(1100, Ordering::Relaxed);
+        heartbeats.event_loop.store(1100, Ordering::Relaxed);
+        heartbeats.database_failing_since.store(1000, Ordering::Relaxed);
+        assert!(!heartbeats.is_healthy(1101, 100));
+        heartbeats.database_saved(true);
+        assert!(heartbeats.is_healthy(1101, 100));
+    }
+
+    #[test]
+    fn polling_errors() {
+        let heartbeats = Heartbeats::new();
+        heartbeats.event_loop.store(1000, Ordering::Relaxed);
+        heartbeats.fetch_cycle.store(1200, Ordering::Relaxed);
+        assert!(heartbeats.is_healthy(1200, 100));
+        heartbeats.polling_error.store(1190, Ordering::Relaxed);
+        assert!(!heartbeats.is_healthy(1200, 100));
+        heartbeats.event_loop.store(1195, Ordering::Relaxed);
+        assert!(heartbeats.is_healthy(1200, 100));
+    }
+
+    #[test]
+    fn fetch_progress() {
+        static HEARTBEATS: Heartbeats = Heartbeats::new();
+        HEARTBEATS.fetch_cycle.store(1000, Ordering::Relaxed);
+        let progress = HEARTBEATS.fetch_started();
+        HEARTBEATS.fetch_cycle();
+        assert_eq!(HEARTBEATS.fetch_cycle.load(Ordering::Relaxed), 1000);
+        drop(progress);
+        assert!(HEARTBEATS.fetch_cycle.load(Ordering::Relaxed) > 1000);
+        assert_eq!(HEARTBEATS.fetches_running.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn latency_window() {
+        let mut window = Window::new(10);
+        assert_eq!(window.percentile(50), None);
+        for minutes in 1..=20 {
+            window.push(minutes * 60);
+        }
+        // Only the last 10
+        assert_eq!(window.len(), 10);
+        assert_eq!(window.percentile(50), Some(15 * 60));
+        assert_eq!(window.percentile(95), Some(20 * 60));
+        assert_eq!(window.percentile(100), Some(20 * 60));
+    }
+
+    #[test]
+    fn delivery_latencies() {
+        let metrics = Metrics::new();
+        metrics.delivery_latencies("a", &[30, 90, 600]);
+        metrics.delivery_latencies("b", &[]);
+        assert_eq!(metrics.delivery_latency("a"), None);
+        assert_eq!(metrics.delivery_latency("b"), None);
+        metrics.delivery_latencies("a", &[120, 7200]);
+        assert_eq!(metrics.delivery_latency("a"), Some((120, 7200)));
+        metrics.forget_feed("a");
+        assert!(metrics.latencies.lock().unwrap().feeds.is_empty());
+
+        let counts: Vec<u64> = metrics
+            .latency_histogram()
+            .iter()
+            .map(|bucket| bucket.count)
+            .collect();
+        assert_eq!(counts, [1, 3, 4, 4, 5, 5, 5]);
+        assert_eq!(metrics.latency_histogram().last().unwrap().le, None);
+    }
+}
@@ -0,0 +1,254 @@
+//! How long to wait before fetching a feed again
+//!
+//! Only decides from what it's given, `fetcher` gathers the history of the feed
+//! and the hints of the server, so every case can be tested here.
+
+use std::cmp;
+
+// Backing off from anti-bot challenges, in seconds
+const BLOCKED_INTERVAL: u32 = 3 * 60 * 60;
+const BLOCKED_MAX_INTERVAL: u32 = 24 * 60 * 60;
+
+/// `--min-interval` and `--max-interval`, in seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bounds {
+    pub min: u32,
+    pub max: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Inputs<'a> {
+    /// Publication times of the recent items, Unix timestamps from the newest
+    pub item_dates: &'a [i64],
+    /// `<ttl>` of the feed, in minutes
+    pub ttl: Option<u32>,
+    /// Failed fetches in a row, 0 if the last one worked
+    pub failures: u32,
+    /// The last failure was an anti-bot challenge
+    pub blocked: bool,
+    /// `Retry-After` of the last failed response, in seconds
+    pub retry_after: Option<u32>,
+}
+
+/// Median gap between the items, `None` if there are fewer than 3 of them
+pub fn median_gap(item_dates: &[i64]) -> Option<u32> {
+    if item_dates.len() < 3 {
+        return None;
+    }
+    let mut gaps: Vec<i64> = item_dates.windows(2).map(|w| w[0] - w[1]).collect();
+    gaps.sort_unstable();
+    let median = gaps[gaps.len() / 2];
+    Some(median.clamp(0, u32::MAX as i64) as u32)
+}
+
+/// The interval while the feed works.
+///
+/// Poll twice as often as the feed usually posts, never more often than its `ttl`.
+pub fn normal(inputs: &Inputs, bounds: Bounds) -> u32 {
+    let ttl = inputs.ttl.map(|ttl| ttl.saturating_mul(60)).unwrap_or_default();
+    let interval = match median_gap(inputs.item_dates) {
+        Some(gap) => cmp::max(gap / 2, ttl),
+        None => ttl,
+    };
+    interval.clamp(bounds.min, cmp::max(bounds.min, bounds.max))
+}
+
+/// The interval after the last fetch, backing off from failures.
+/// `random` is any random number, for the jitter.
+pub fn next(inputs: &Inputs, bounds: Bounds, random: u64) -> u32 {
+    let mut interval = normal(inputs, bounds);
+    if inputs.failures == 0 {
+        return interval;
+    }
+    let mut max_interval = cmp::max(bounds.min, bounds.max);
+    // Trying again soon only makes the blocking worse
+    if inputs.blocked {
+        interval = interval.max(BLOCKED_INTERVAL);
+        max_interval = max_interval.max(BLOCKED_MAX_INTERVAL);
+    }
+    let delay = backoff(interval, inputs.failures, max_interval, random);
+    // The server knows best, as long as it's not longer than the backoff could get
+    match inputs.retry_after {
+        Some(retry_after) => delay.max(retry_after.min(max_interval)),
+        None => delay,
+    }
+}
+
+/// Double the interval per failure, capped at `max_interval`, with full jitter.
+fn backoff(interval: u32, failures: u32, max_interval: u32, random: u64) -> u32 {
+    let backoff = (interval as u64)
+        .saturating_mul(1u64.checked_shl(failures).unwrap_or(u64::MAX))
+        .min(max_interval as u64);
+    let interval = cmp::min(interval as u64, backoff);
+    // Never faster than the normal interval
+    (interval + random % (backoff - interval + 1)) as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BOUNDS: Bounds = Bounds {
+        min: 300,
+        max: 43200,
+    };
+    const HOUR: i64 = 60 * 60;
+
+    fn dates(gaps: &[i64]) -> Vec<i64> {
+        let mut date = 1_000_000_000;
+        let mut dates = vec![date];
+        for gap in gaps {
+            date -= gap;
+            dates.push(date);
+        }
+        dates
+    }
+
+    #[test]
+    fn empty_and_single_item_feeds() {
+        assert_eq!(normal(&Inputs::default(), BOUNDS), 300);
+        let single = [1_000_000_000];
+        let inputs = Inputs {
+            item_dates: &single,
+            ..Default::default()
+        };
+        assert_eq!(median_gap(&single), None);
+        assert_eq!(normal(&inputs, BOUNDS), 300);
+        // Two items are not enough to tell either
+        let two = dates(&[10 * HOUR]);
+        let inputs = Inputs {
+            item_dates: &two,
+            ..Default::default()
+        };
+        assert_eq!(normal(&inputs, BOUNDS), 300);
+        // Only the ttl is known
+        let inputs = Inputs {
+            ttl: Some(60),
+            ..Default::default()
+        };
+        assert_eq!(normal(&inputs, BOUNDS), 3600);
+    }
+
+    #[test]
+    fn regular_and_bursty_feeds() {
+        let daily = dates(&[24 * HOUR; 9]);
+        let inputs = Inputs {
+            item_dates: &daily,
+            ..Default::default()
+        };
+        assert_eq!(normal(&inputs, BOUNDS), 12 * 60 * 60);
+
+        // A burst of 5 items in a minute, then one a day
+        let bursty = dates(&[10, 10, 10, 10, 24 * HOUR, 24 * HOUR, 24 * HOUR]);
+        let inputs = Inputs {
+            item_dates: &bursty,
+            ..Default::default()
+        };
+        assert_eq!(median_gap(&bursty), Some(10));
+        assert_eq!(normal(&inputs, BOUNDS), 300);
+        // Mostly daily with one burst
+        let mostly_daily = dates(&[10, 10, 24 * HOUR, 24 * HOUR, 24 * HOUR, 24 * HOUR]);
+        assert_eq!(median_gap(&mostly_daily), Some(24 * HOUR as u32));
+
+        // The ttl is a lower bound
+        let hourly = dates(&[HOUR; 5]);
+        let inputs = Inputs {
+            item_dates: &hourly,
+            ttl: Some(120),
+            ..Default::default()
+        };
+        assert_eq!(normal(&inputs, BOUNDS), 2 * 60 * 60);
+    }
+
+    #[test]
+    fn clamping() {
+        let rare = dates(&[30 * 24 * HOUR; 4]);
+        let inputs = Inputs {
+            item_dates: &rare,
+            ..Default::default()
+        };
+        assert_eq!(normal(&inputs, BOUNDS), 43200);
+        let inputs = Inputs {
+            ttl: Some(u32::MAX),
+            ..Default::default()
+        };
+        assert_eq!(normal(&inputs, BOUNDS), 43200);
+        // Items out of order, or all at once
+        let odd = [0, 100, 0, 100];
+        let inputs = Inputs {
+            item_dates: &odd,
+            ..Default::default()
+        };
+        assert_eq!(normal(&inputs, BOUNDS), 300);
+        // Bounds the wrong way around stay at the minimum
+        let bounds = Bounds { min: 600, max: 300 };
+        assert_eq!(normal(&Inputs::default(), bounds), 600);
+        let inputs = Inputs {
+            failures: 5,
+            ..Default::default()
+        };
+        assert_eq!(next(&inputs, bounds, u64::MAX), 600);
+    }
+
+    #[test]
+    fn backoff_from_failures() {
+        assert_eq!(backoff(300, 1, 43200, 0), 300);
+        assert_eq!(backoff(300, 1, 43200, 300), 600);
+        assert_eq!(backoff(300, 3, 43200, 2100), 2400);
+        assert_eq!(backoff(300, 3, 43200, 2101), 300);
+        // capped
+        assert_eq!(backoff(300, 10, 43200, 43200 - 300), 43200);
+        assert_eq!(backoff(300, 100, 43200, 43200 - 300), 43200);
+        for random in 0..1000 {
+            let interval = backoff(300, 2, 43200, random);
+            assert!((300..=1200).contains(&interval));
+        }
+
+        let failing = Inputs {
+            failures: 2,
+            ..Default::default()
+        };
+        assert_eq!(next(&failing, BOUNDS, 0), 300);
+        assert_eq!(next(&failing, BOUNDS, 900), 1200);
+        let working = Inputs {
+            failures: 0,
+            ..failing
+        };
+        assert_eq!(next(&working, BOUNDS, 900), 300);
+    }
+
+    #[test]
+    fn server_hints() {
+        let blocked = Inputs {
+            failures: 1,
+            blocked: true,
+            ..Default::default()
+        };
+        assert_eq!(next(&blocked, BOUNDS, 0), BLOCKED_INTERVAL);
+        let blocked = Inputs {
+            failures: 10,
+            ..blocked
+        };
+        let random = (BLOCKED_MAX_INTERVAL - BLOCKED_INTERVAL) as u64;
+        assert_eq!(next(&blocked, BOUNDS, random), BLOCKED_MAX_INTERVAL);
+
+        let throttled = Inputs {
+            failures: 1,
+            retry_after: Some(3600),
+            ..Default::default()
+        };
+        assert_eq!(next(&throttled, BOUNDS, 0), 3600);
+        // Not shorter than the backoff
+        let throttled = Inputs {
+            retry_after: Some(1),
+            ..throttled
+        };
+        assert_eq!(next(&throttled, BOUNDS, 300), 600);
+        // Nor longer than the maximum
+        let throttled = Inputs {
+            retry_after: Some(u32::MAX),
+            ..throttled
+        };
+        assert_eq!(next(&throttled, BOUNDS, 0), 43200);
+    }
+}
@@ -0,0 +1,751 @@
+//! A simple Telegram RSS bot.
+//!
+//! The `rssbot` binary is a thin wrapper over [`RssBot`], which can also run inside
+//! another service, on its tokio runtime. See `examples/embedded.rs`.
+
+#![feature(error_reporter)]
+#![recursion_limit = "256"]
+
+use std::convert::TryInto;
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use futures::{future::FutureExt, select_biased};
+use hyper_proxy::{Intercept, Proxy};
+use std::sync::{LazyLock, OnceLock};
+use structopt::StructOpt;
+use tbot::bot::Uri;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+// Include the tr! macro and localizations
+include!(concat!(env!("OUT_DIR"), "/ctl10n_macros.rs"));
+
+mod admins;
+mod audit;
+mod chat_info;
+mod client;
+mod commands;
+mod data;
+mod export;
+mod feed;
+mod fetcher;
+mod gardener;
+mod health;
+mod interval;
+mod messages;
+mod opml;
+#[cfg(test)]
+mod testing;
+mod translate;
+mod webhook;
+
+pub use crate::admins::Admin;
+use crate::data::{Database, Execution, Location};
+use crate::messages::{parse_duration, DurationError};
+
+static BOT_NAME: OnceLock<String> = OnceLock::new();
+static BOT_ID: OnceLock<tbot::types::user::Id> = OnceLock::new();
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    about = "A simple Telegram RSS bot.",
+    after_help = "NOTE: You can get <user id> using bots like @userinfobot @getidsbot"
+)]
+pub struct Opt {
+    /// Telegram bot token
+    pub token: String,
+    /// Path to database, a JSON file, or a SQLite database with sqlite:path
+    #[structopt(
+        short = "d",
+        long,
+        value_name = "path",
+        default_value = "./rssbot.json"
+    )]
+    pub database: Location,
+    /// Copy the feeds, chats and settings of this JSON database into the empty SQLite
+    /// database of --database and exit
+    #[structopt(long, value_name = "path")]
+    pub import_json: Option<PathBuf>,
+    /// Refuse to start if the database needs migrating, instead of migrating it
+    /// after making a copy
+    #[structopt(long)]
+    pub no_migrate: bool,
+    /// Minimum fetch interval, in seconds or like 5m
+    #[structopt(
+        long,
+        value_name = "duration",
+        default_value = "5m",
+        parse(try_from_str = parse_interval)
+    )]
+    pub min_interval: u32,
+    /// Maximum fetch interval, in seconds or like 12h
+    #[structopt(
+        long,
+        value_name = "duration",
+        default_value = "12h",
+        parse(try_from_str = parse_interval)
+    )]
+    pub max_interval: u32,
+    /// Skip a response once if it has fewer items than this fraction of the last one
+    /// and none of them were seen before, 0 disables it
+    #[structopt(
+        long,
+        value_name = "fraction",
+        default_value = "0.1",
+        validator(check_fraction)
+    )]
+    pub truncation_threshold: f64,
+    /// Seen items remembered per feed to avoid delivering them again,
+    /// twice the items of the last response by default
+    #[structopt(long, value_name = "count")]
+    pub max_hashes_per_feed: Option<usize>,
+    /// Fetches to hold back new items that only link to the website, or nowhere,
+    /// waiting for their own link. 0 delivers them at once. 3 fetches are at least 15
+    /// minutes with the default `--min-interval`, the sites fill the link in within minutes
+    #[structopt(long, value_name = "count", default_value = "3")]
+    pub pending_cycles: u32,
+    /// Maximum feed size, 0 is unlimited
+    #[structopt(long, value_name = "bytes", default_value = "2M")]
+    pub max_feed_size: String,
+    /// Private mode, only specified user can use this bot, by ID or @username.
+    /// This argument can be passed multiple times to allow multiple admins
+    #[structopt(
+        long,
+        value_name = "user id",
+        number_of_values = 1,
+        alias = "single_user" // For compatibility
+    )]
+    pub admin: Vec<Admin>,
+    /// Make bot commands only accessible for group admins.
+    #[structopt(long)]
+    pub restricted: bool,
+    /// Like --restricted, and only the user who subscribed a feed, or a bot admin,
+    /// can change or remove the subscription
+    #[structopt(long)]
+    pub restricted_strict: bool,
+    /// Custom telegram api URI
+    #[structopt(
+        long,
+        value_name = "tgapi-uri",
+        default_value = "https://api.telegram.org/"
+    )]
+    pub api_uri: Uri,
+    /// DANGER: Insecure mode, accept invalid TLS certificates
+    #[structopt(long)]
+    pub insecure: bool,
+    /// Maximum time to cache DNS results of feed hosts
+    #[structopt(
+        long,
+        value_name = "duration",
+        default_value = "5m",
+        parse(try_from_str = parse_duration)
+    )]
+    pub dns_cache_ttl: u64,
+    /// Connect to feed hosts over IPv4 first, IPv6 is only tried if that is slow
+    #[structopt(long)]
+    pub prefer_ipv4: bool,
+    /// Never use HTTP/2 for feeds, for proxies that mishandle it
+    #[structopt(long = "http1-only")]
+    pub http1_only: bool,
+    /// Fetch .onion feeds through this SOCKS5 proxy, 127.0.0.1:9050 if only the flag is given.
+    /// They are refused without it
+    #[structopt(long, value_name = "addr")]
+    pub onion_proxy: Option<Option<SocketAddr>>,
+    /// Serve a health check endpoint on this address
+    #[structopt(long, value_name = "addr")]
+    pub health_addr: Option<SocketAddr>,
+    /// Report unhealthy if there is no progress for this long
+    #[structopt(
+        long,
+        value_name = "duration",
+        default_value = "15m",
+        parse(try_from_str = parse_duration)
+    )]
+    pub health_staleness: u64,
+    /// RSSHub instance that rsshub:// feeds are fetched from, can be changed by /rsshub
+    #[structopt(
+        long,
+        value_name = "url",
+        default_value = "https://rsshub.app",
+        validator(check_rsshub_base)
+    )]
+    pub rsshub_base: String,
+    /// Query parameters dropped by the clean_links setting, a trailing * matches any suffix
+    #[structopt(
+        long,
+        value_name = "names",
+        default_value(messages::DEFAULT_TRACKING_PARAMS),
+        use_delimiter = true
+    )]
+    pub tracking_params: Vec<String>,
+    /// With /settings crossdedup on, skip a link another feed delivered to the chat
+    /// in this long
+    #[structopt(
+        long,
+        value_name = "duration",
+        default_value = "48h",
+        parse(try_from_str = parse_duration)
+    )]
+    pub cross_dedup_window: u64,
+    /// Don't send a chat the items published this long before it subscribed,
+    /// when they are only new to the feed's other subscribers
+    #[structopt(
+        long,
+        value_name = "duration",
+        default_value = "1h",
+        parse(try_from_str = parse_duration)
+    )]
+    pub backfill_grace: u64,
+    /// Tell a chat about the same kind of errors of a feed at most once in this long
+    #[structopt(
+        long,
+        value_name = "duration",
+        default_value = "24h",
+        parse(try_from_str = parse_duration)
+    )]
+    pub error_notice_window: u64,
+    /// How often to check for chats the bot was removed from, /gardener can start it sooner
+    #[structopt(
+        long,
+        value_name = "duration",
+        default_value = "1d",
+        parse(try_from_str = parse_duration)
+    )]
+    pub prune_interval: u64,
+    /// Only remove a chat after it stayed unavailable this long, checked at every sweep
+    #[structopt(
+        long,
+        value_name = "duration",
+        default_value = "0",
+        parse(try_from_str = parse_duration)
+    )]
+    pub prune_grace: u64,
+    /// Also send every delivered item to this chat, once however many chats got it
+    #[structopt(long, value_name = "chat id", allow_hyphen_values = true)]
+    pub firehose_chat_id: Option<i64>,
+    /// Append a JSON line for every item sent to a chat to this file, rotated daily
+    #[structopt(long, value_name = "path")]
+    pub audit_log: Option<PathBuf>,
+    /// LibreTranslate compatible endpoint for /settings translate,
+    /// like https://libretranslate.com/translate
+    #[structopt(long, value_name = "url")]
+    pub translate_api_url: Option<reqwest::Url>,
+    /// API key sent to --translate-api-url
+    #[structopt(long, value_name = "key")]
+    pub translate_api_key: Option<String>,
+    /// Fetch all feeds once, deliver the new items and exit, for running from cron
+    #[structopt(long)]
+    pub once: bool,
+    /// With --once, exit with an error if more than this fraction of the fetches failed
+    #[structopt(
+        long,
+        value_name = "fraction",
+        default_value = "0.5",
+        validator(check_fraction)
+    )]
+    pub once_max_failures: f64,
+}
+
+impl Opt {
+    /// The defaults of the command line, with only the token given
+    pub fn new(token: &str) -> Self {
+        Opt::from_iter(["rssbot", token])
+    }
+
+    /// What's wrong with the options beyond what each of their validators checks,
+    /// all of it at once
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.min_interval > self.max_interval {
+            errors.push(format!(
+                "--min-interval {} is greater than --max-interval {}",
+                self.min_interval, self.max_interval
+            ));
+        }
+        if let Err(e) = parse_human_size(&self.max_feed_size) {
+            errors.push(format!("--max-feed-size {}: {}", self.max_feed_size, e));
+        }
+        if self.import_json.is_some() && !matches!(self.database, Location::Sqlite(_)) {
+            errors.push("--import-json needs a --database sqlite:path to import into".into());
+        }
+        if self.translate_api_key.is_some() && self.translate_api_url.is_none() {
+            errors.push("--translate-api-key is given without --translate-api-url".into());
+        }
+        for admin in &self.admin {
+            if *admin == Admin::Id(0) {
+                errors.push("--admin 0 is not a user ID".into());
+            }
+        }
+        errors
+    }
+
+    /// `validate` as a single error listing the problems
+    pub fn check(&self) -> anyhow::Result<()> {
+        match &*self.validate() {
+            [] => Ok(()),
+            errors => Err(anyhow!("Invalid options:\n  {}", errors.join("\n  "))),
+        }
+    }
+}
+
+fn parse_interval(s: &str) -> Result<u32, String> {
+    match parse_duration(s) {
+        Ok(0) => Err("must be at least 1s".into()),
+        Ok(secs) => u32::try_from(secs).map_err(|_| DurationError::Overflow.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn check_fraction(s: String) -> Result<(), String> {
+    s.parse::<f64>().map_err(|e| e.to_string()).and_then(|r| {
+        if (0.0..=1.0).contains(&r) {
+            Ok(())
+        } else {
+            Err("must be between 0 and 1".into())
+        }
+    })
+}
+
+fn check_rsshub_base(s: String) -> Result<(), String> {
+    match reqwest::Url::parse(&s) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(()),
+        Ok(_) => Err("must be a http or https URL".into()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Parse human readable size into bytes.
+fn parse_human_size(s: &str) -> anyhow::Result<u64> {
+    const BASE: u64 = 1024;
+    let s = s.trim().trim_end_matches(|x| x == 'B' || x == 'b');
+    match s.chars().last().map(|x| x.to_ascii_lowercase()) {
+        Some('b') => Ok(s[..s.len() - 1].parse()?),
+        Some('k') => Ok(s[..s.len() - 1].parse::<u64>()? * BASE),
+        Some('m') => Ok(s[..s.len() - 1].parse::<u64>()? * BASE.pow(2)),
+        Some('g') => Ok(s[..s.len() - 1].parse::<u64>()? * BASE.pow(3)),
+        Some('t') => Ok(s[..s.len() - 1].parse::<u64>()? * BASE.pow(4)),
+        Some(x) if x.is_ascii_digit() => Ok(s.parse()?),
+        Some(x) => Err(anyhow!("invalid size character: {}", x)),
+        None => Err(anyhow!("empty size")),
+    }
+}
+
+/// Whether a panic on this thread is caught, it's in the task of a single feed
+/// and only fails that fetch. A panic hook exiting the process should let these go.
+pub fn panic_is_caught() -> bool {
+    fetcher::in_feed_task()
+}
+
+/// The bot, running on the tokio runtime of the caller.
+///
+/// Only one can run at a time in a process, settings like the HTTP client are global.
+/// A second `run` after `shutdown` keeps the ones of the first.
+pub struct RssBot {
+    opt: Arc<Opt>,
+    shutdown: CancellationToken,
+}
+
+impl RssBot {
+    pub fn new(opt: Opt) -> Self {
+        RssBot {
+            opt: Arc::new(opt),
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// Make `run` stop receiving updates and fetching feeds. It returns once the
+    /// fetches already started are done, the webhooks and the audit log are written
+    /// and the database is saved.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Start the event loop, the fetcher and the gardener, until `shutdown`.
+    /// With `--once`, returns after fetching all feeds once instead.
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let opt = &*self.opt;
+        opt.check()?;
+        let migrations = if opt.no_migrate {
+            Execution::DryRun
+        } else {
+            Execution::Apply
+        };
+        let db = Database::open_exclusive(opt.database.clone(), migrations)?
+            .with_max_hashes(opt.max_hashes_per_feed)?
+            .with_pending_cycles(opt.pending_cycles);
+        if let Some(path) = &opt.import_json {
+            let (feeds, chats) = db
+                .import_json(path)
+                .with_context(|| format!("Failed to import {}", path.display()))?;
+            eprintln!("Imported {} feeds and {} chats from {}", feeds, chats, path.display());
+            return Ok(());
+        }
+        let db = Arc::new(db);
+        let bot_builder = tbot::bot::Builder::with_string_token(opt.token.clone())
+            .server_uri(opt.api_uri.clone());
+        let bot = if let Some(proxy) = init_proxy(&opt.api_uri) {
+            bot_builder.proxy(proxy).build()
+        } else {
+            bot_builder.build()
+        };
+        let me = bot
+            .get_me()
+            .call()
+            .await
+            .context("Initialization failed, check your network and Telegram token")?;
+
+        let bot_name = me.user.username.clone().unwrap();
+        crate::client::init_client(
+            &bot_name,
+            opt.insecure,
+            parse_human_size(&opt.max_feed_size).context("Invalid max_feed_size")?,
+            opt.dns_cache_ttl,
+            opt.prefer_ipv4,
+            opt.http1_only,
+            opt.onion_proxy
+                .map(|proxy| proxy.unwrap_or(crate::client::DEFAULT_ONION_PROXY)),
+        );
+        crate::client::init_rsshub(opt.rsshub_base.clone(), db.global().rsshub_base);
+        crate::messages::init_tracking_params(opt.tracking_params.clone());
+        crate::messages::set_footer(db.global().footer);
+        fetcher::init_cross_dedup(opt.cross_dedup_window);
+        fetcher::init_backfill_grace(opt.backfill_grace);
+        fetcher::init_error_notice_window(opt.error_notice_window);
+        if let Some(url) = &opt.translate_api_url {
+            let api_key = opt.translate_api_key.clone();
+            translate::init(Box::new(translate::LibreTranslate::new(url.clone(), api_key)));
+        }
+
+        // The same token in a second run
+        let _ = BOT_NAME.set(bot_name);
+        let _ = BOT_ID.set(me.user.id);
+        admins::verify(&bot, &opt.admin).await;
+        if let Some(path) = &opt.audit_log {
+            audit::init(path.clone());
+        }
+        if let Some(chat_id) = opt.firehose_chat_id {
+            crate::messages::firehose::init(Arc::new(bot.clone()), chat_id);
+        }
+
+        if opt.once {
+            return run_once(bot, db, opt).await;
+        }
+
+        health::start_heartbeats();
+        let mut tasks = Vec::new();
+        if let Some(addr) = opt.health_addr {
+            let server = health::serve(addr, Duration::from_secs(opt.health_staleness))
+                .await
+                .context("Failed to start the health check endpoint")?;
+            tasks.push(server);
+        }
+
+        tasks.push(gardener::start_pruning(
+            bot.clone(),
+            db.clone(),
+            gardener::Schedule {
+                interval: Duration::from_secs(opt.prune_interval),
+                grace: Duration::from_secs(opt.prune_grace),
+            },
+        ));
+        tasks.push(fetcher::start(
+            bot.clone(),
+            db.clone(),
+            opt.min_interval,
+            opt.max_interval,
+            opt.truncation_threshold,
+            self.shutdown.clone(),
+        ));
+
+        let mut event_loop = bot.event_loop();
+        event_loop.username(me.user.username.unwrap());
+        tasks.extend(start_sd_watchdog(Duration::from_secs(opt.health_staleness)));
+        commands::register_commands(&mut event_loop, self.opt.clone(), db.clone());
+
+        if let Err(e) = sd_notify("READY=1") {
+            eprintln!("Failed to notify systemd: {}", e);
+        }
+        // Another instance polling the token would get half of the updates, stop instead
+        let conflict = Arc::new(AtomicBool::new(false));
+        let polling = event_loop
+            .polling()
+            .error_handler({
+                let conflict = conflict.clone();
+                let shutdown = self.shutdown.clone();
+                move |err| {
+                    let conflict = conflict.clone();
+                    let shutdown = shutdown.clone();
+                    async move {
+                        health::HEARTBEATS.polling_error();
+                        if is_polling_conflict(&err) {
+                            conflict.store(true, Ordering::Relaxed);
+                            shutdown.cancel();
+                            return;
+                        }
+                        eprintln!("Polling error: {:?}", err);
+                    }
+                }
+            })
+            .start();
+        select_biased! {
+            _ = self.shutdown.cancelled().fuse() => (),
+            result = polling.fuse() => {
+                result.map_err(|e| anyhow!("Failed to start polling: {:?}", e))?;
+            }
+        }
+
+        if let Err(e) = sd_notify("STOPPING=1") {
+            eprintln!("Failed to notify systemd: {}", e);
+        }
+        for task in tasks {
+            task.abort();
+        }
+        fetcher::wait_started().await;
+        webhook::wait_pending().await;
+        audit::wait_written().await;
+        // Saved first, the copies may take long and the bot be killed meanwhile
+        db.save().context("Failed to save the database")?;
+        crate::messages::firehose::wait_sent().await;
+        if conflict.load(Ordering::Relaxed) {
+            return Err(anyhow!(
+                "Another instance is polling this token, only one bot can run with it"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Telegram ends a `getUpdates` when another one for the same token comes in
+fn is_polling_conflict(err: &tbot::errors::Polling) -> bool {
+    use tbot::errors::{MethodCall, Polling};
+    match err {
+        Polling::Fetching(MethodCall::RequestError {
+            error_code: 409,
+            description,
+            ..
+        }) => description.contains("other getUpdates"),
+        _ => false,
+    }
+}
+
+/// `--once`, no updates are received from Telegram
+async fn run_once(bot: tbot::Bot, db: Arc<Database>, opt: &Opt) -> anyhow::Result<()> {
+    let started = std::time::Instant::now();
+    fetcher::init(opt.min_interval, opt.max_interval, opt.truncation_threshold);
+    let summary = fetcher::fetch_once(&bot, db.clone()).await;
+    webhook::wait_pending().await;
+    audit::wait_written().await;
+    db.save().context("Failed to save the database")?;
+    crate::messages::firehose::wait_sent().await;
+    println!(
+        "Fetched {} feeds, {} failed, {} backing off, {} items delivered in {:.1}s",
+        summary.fetched,
+        summary.failed,
+        summary.backing_off,
+        summary.delivered,
+        started.elapsed().as_secs_f64()
+    );
+    if summary.failure_ratio() > opt.once_max_failures {
+        return Err(anyhow!(
+            "{} of {} fetches failed",
+            summary.failed,
+            summary.fetched
+        ));
+    }
+    Ok(())
+}
+
+fn init_proxy(api_uri: &Uri) -> Option<Proxy> {
+    // api.telegram.org only uses https, but a local Bot API server may be http
+    let name = if api_uri.scheme_str() == Some("http") {
+        "HTTP_PROXY"
+    } else {
+        "HTTPS_PROXY"
+    };
+    env::var(name)
+        .or_else(|_| env::var(name.to_lowercase()))
+        .map(|uri| {
+            let uri = uri
+                .try_into()
+                .unwrap_or_else(|e| panic!("Illegal {}: {}", name, e));
+            Proxy::new(Intercept::All, uri)
+        })
+        .ok()
+}
+
+/// Send `state` to systemd, does nothing if we are not started by systemd
+fn sd_notify(state: &str) -> std::io::Result<()> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+
+        let socket = UnixDatagram::unbound()?;
+        let path = path.as_bytes();
+        match path.first() {
+            #[cfg(target_os = "linux")]
+            Some(b'@') => {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(&path[1..])?;
+                socket.send_to_addr(state.as_bytes(), &addr)?;
+            }
+            _ => {
+                socket.send_to(state.as_bytes(), std::ffi::OsStr::from_bytes(path))?;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (path, state);
+    Ok(())
+}
+
+/// Ping the systemd watchdog, but only while the bot is actually making progress
+fn start_sd_watchdog(staleness: Duration) -> Option<JoinHandle<()>> {
+    let usec = match env::var("WATCHDOG_USEC").ok().and_then(|s| s.parse::<u64>().ok()) {
+        Some(usec) if usec > 0 => usec,
+        _ => return None,
+    };
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(process::id()) {
+            return None;
+        }
+    }
+    // systemd recommends pinging at half of the timeout
+    let mut interval = tokio::time::interval(Duration::from_micros(usec / 2));
+    let watchdog = tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+            if health::HEARTBEATS.is_alive(staleness) {
+                if let Err(e) = sd_notify("WATCHDOG=1") {
+                    eprintln!("Failed to notify systemd: {}", e);
+                }
+            }
+        }
+    });
+    Some(watchdog)
+}
+
+/// Too noisy to print by default, set `RSSBOT_DEBUG=1` to see them
+fn print_debug(msg: &str) {
+    static ENABLED: LazyLock<bool> = LazyLock::new(|| env::var_os("RSSBOT_DEBUG").is_some());
+    if *ENABLED {
+        eprintln!("Debug: {}", msg);
+    }
+}
+
+fn print_error<E: std::error::Error>(err: E) {
+    eprintln!(
+        "Error: {}",
+        std::error::Report::new(err)
+            .pretty(true)
+            .show_backtrace(true)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opt(args: &[&str]) -> Opt {
+        Opt::from_iter(["rssbot", "token"].iter().chain(args))
+    }
+
+    #[test]
+    fn valid_options() {
+        assert!(opt(&[]).validate().is_empty());
+        assert!(opt(&["--min-interval", "600", "--max-interval", "600"]).validate().is_empty());
+        assert!(opt(&["--admin", "1", "--admin", "@username"]).validate().is_empty());
+        assert!(opt(&["--max-feed-size", "0"]).check().is_ok());
+    }
+
+    #[test]
+    fn invalid_options() {
+        assert_eq!(
+            opt(&["--min-interval", "9999", "--max-interval", "300"]).validate(),
+            ["--min-interval 9999 is greater than --max-interval 300"]
+        );
+        assert_eq!(
+            opt(&["--max-feed-size", "2X"]).validate(),
+            ["--max-feed-size 2X: invalid size character: x"]
+        );
+        assert_eq!(
+            opt(&["--translate-api-key", "key"]).validate(),
+            ["--translate-api-key is given without --translate-api-url"]
+        );
+        assert_eq!(
+            opt(&["--admin", "1", "--admin", "0"]).validate(),
+            ["--admin 0 is not a user ID"]
+        );
+    }
+
+    #[test]
+    fn polling_conflict() {
+        use tbot::errors::{MethodCall, Polling};
+        let error = |error_code, description: &str| {
+            Polling::Fetching(MethodCall::RequestError {
+                description: description.into(),
+                error_code,
+                migrate_to_chat_id: None,
+                retry_after: None,
+            })
+        };
+        assert!(is_polling_conflict(&error(
+            409,
+            "Conflict: terminated by other getUpdates request; \
+             make sure that only one bot instance is running"
+        )));
+        // Not another instance, a webhook is set
+        assert!(!is_polling_conflict(&error(
+            409,
+            "Conflict: can't use getUpdates method while webhook is active"
+        )));
+        assert!(!is_polling_conflict(&error(502, "Bad Gateway")));
+    }
+
+    #[test]
+    fn durations() {
+        let intervals = opt(&["--min-interval", "10m", "--max-interval", "1d6h"]);
+        assert_eq!((intervals.min_interval, intervals.max_interval), (600, 30 * 60 * 60));
+        assert_eq!(opt(&["--prune-interval", "3600"]).prune_interval, 3600);
+        assert_eq!(opt(&[]).cross_dedup_window, 48 * 60 * 60);
+
+        let error = |args: &[&str]| {
+            let args = ["rssbot", "token"].iter().chain(args);
+            Opt::from_iter_safe(args).unwrap_err().message
+        };
+        assert!(error(&["--min-interval", "0"]).contains("must be at least 1s"));
+        assert!(error(&["--max-interval", "50000d"]).contains("too long"));
+        assert!(error(&["--backfill-grace", "-1h"]).contains("30s, 15m, 6h, 1d or 1h30m"));
+    }
+
+    #[test]
+    fn invalid_options_together() {
+        let error = opt(&["--min-interval", "9999", "--max-interval", "300", "--admin", "0"])
+            .check()
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid options:\n  \
+             --min-interval 9999 is greater than --max-interval 300\n  \
+             --admin 0 is not a user ID"
+        );
+    }
+
+    #[test]
+    fn test_parse_human_size() {
+        assert_eq!(parse_human_size("2M").unwrap(), 2_097_152);
+        assert_eq!(parse_human_size("2G").unwrap(), 2_147_483_648);
+        assert_eq!(parse_human_size("2mb").unwrap(), 2_097_152);
+        assert_eq!(parse_human_size("2097152").unwrap(), 2_097_152);
+    }
+}
@@ -1,25 +1,1303 @@
-use std::fmt;
+use std::borrow::Cow;
+use std::fmt::{self, Write};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
-pub const TELEGRAM_MAX_MSG_LEN: usize = 4096;
+use futures::{
+    future::{BoxFuture, FutureExt},
+    select_biased,
+};
+use tbot::{errors::MethodCall, types::parameters, Bot};
+use tokio::sync::{Notify, Semaphore};
+
+use crate::audit;
+use crate::client::resolve_redirect;
+use crate::data::{QueueSnapshot, ReplyTo};
+use crate::feed::Item;
+use crate::health::METRICS;
+
+pub mod date;
+pub mod firehose;
+
+pub const DEFAULT_TRACKING_PARAMS: &str = "utm_*,fbclid,gclid,ref";
+
+// Items published longer ago than this were backdated, or their dates are wrong
+const MAX_LATENCY: u64 = 7 * 24 * 60 * 60;
+// How long the pinned message of a chat is remembered for `ReplyTo::Pinned`
+const PINNED_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+static TRACKING_PARAMS: OnceLock<Vec<String>> = OnceLock::new();
+// Set by /footer
+static FOOTER: RwLock<Option<String>> = RwLock::new(None);
+// Chat IDs to their pinned messages, and when they were looked up
+static PINNED: Mutex<BTreeMap<i64, (Option<i64>, Instant)>> = Mutex::new(BTreeMap::new());
+
+/// A trailing `*` matches any parameter starting with the rest. A second run keeps
+/// the parameters of the first.
+pub fn init_tracking_params(params: Vec<String>) {
+    let _ = TRACKING_PARAMS.set(params);
+}
+
+fn is_tracking_param(name: &str) -> bool {
+    let params = TRACKING_PARAMS.get_or_init(|| {
+        DEFAULT_TRACKING_PARAMS
+            .split(',')
+            .map(ToString::to_string)
+            .collect()
+    });
+    params.iter().any(|param| match param.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == param,
+    })
+}
+
+/// Drop the tracking parameters from the query of the link,
+/// the rest is kept as is, the `?` too if nothing is left
+pub fn strip_tracking_params(link: &str) -> Cow<'_, str> {
+    let (head, fragment) = match link.find('#') {
+        Some(i) => link.split_at(i),
+        None => (link, ""),
+    };
+    let (base, query) = match head.split_once('?') {
+        Some(r) => r,
+        None => return Cow::Borrowed(link),
+    };
+    let is_kept = |pair: &&str| {
+        let name = pair.split_once('=').map_or(*pair, |(name, _)| name);
+        !is_tracking_param(name)
+    };
+    if query.split('&').all(|pair| is_kept(&pair)) {
+        return Cow::Borrowed(link);
+    }
+    let kept: Vec<&str> = query.split('&').filter(is_kept).collect();
+    let mut cleaned = base.to_owned();
+    if !kept.is_empty() {
+        cleaned.push('?');
+        cleaned.push_str(&kept.join("&"));
+    }
+    cleaned.push_str(fragment);
+    Cow::Owned(cleaned)
+}
+
+/// Copy of the items with the tracking parameters stripped, and with the
+/// redirector links resolved if `resolve`, which falls back to the original link
+pub async fn clean_item_links(items: &[Item], resolve: bool) -> Vec<Item> {
+    let mut cleaned = Vec::with_capacity(items.len());
+    for item in items {
+        let mut item = item.clone();
+        if let Some(link) = item.link.take() {
+            let link = if resolve {
+                resolve_redirect(&link).await.unwrap_or(link)
+            } else {
+                link
+            };
+            item.link = Some(strip_tracking_params(&link).into_owned());
+        }
+        cleaned.push(item);
+    }
+    cleaned
+}
+
+/// Length limits of Telegram, counted in UTF-16 code units of the text
+/// left after parsing the entities
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Text,
+    // For sending the items with their media
+    #[cfg_attr(not(test), allow(dead_code))]
+    Caption,
+}
+
+impl Limit {
+    pub fn units(self) -> usize {
+        match self {
+            Limit::Text => 4096,
+            Limit::Caption => 1024,
+        }
+    }
+}
+
+enum Token<'a> {
+    Text(&'a str),
+    Tag(&'a str),
+}
+
+fn tokens(html: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        if start > 0 {
+            tokens.push(Token::Text(&rest[..start]));
+        }
+        tokens.push(Token::Tag(&rest[start..=end]));
+        rest = &rest[end + 1..];
+    }
+    if !rest.is_empty() {
+        tokens.push(Token::Text(rest));
+    }
+    tokens
+}
+
+fn utf16_len(s: &str) -> usize {
+    s.encode_utf16().count()
+}
+
+/// Length of our HTML message as Telegram counts it
+pub fn visible_len(html: &str) -> usize {
+    tokens(html)
+        .iter()
+        .map(|token| match token {
+            Token::Text(text) => utf16_len(&unescape(text)),
+            Token::Tag(_) => 0,
+        })
+        .sum()
+}
+
+/// Cut the text of our HTML message to fit in `limit`, with an ellipsis.
+/// Tags are never cut and the open ones are closed, so a link that is cut
+/// still points to the item.
+pub fn fit_html(html: &str, limit: Limit) -> Cow<'_, str> {
+    if visible_len(html) <= limit.units() {
+        return Cow::Borrowed(html);
+    }
+    // Leave room for the ellipsis
+    let mut budget = limit.units() - 1;
+    let mut fitted = String::with_capacity(html.len());
+    let mut open = Vec::new();
+    for token in tokens(html) {
+        match token {
+            Token::Tag(tag) => {
+                if tag.starts_with("</") {
+                    open.pop();
+                } else {
+                    open.push(tag_name(tag));
+                }
+                fitted.push_str(tag);
+            }
+            Token::Text(text) => {
+                let unescaped = unescape(text);
+                let len = utf16_len(&unescaped);
+                if len <= budget {
+                    fitted.push_str(text);
+                    budget -= len;
+                    continue;
+                }
+                let end = grapheme_prefix(&unescaped, budget);
+                write!(fitted, "{}…", Escape(&unescaped[..end])).unwrap();
+                for name in open.iter().rev() {
+                    write!(fitted, "</{}>", name).unwrap();
+                }
+                break;
+            }
+        }
+    }
+    Cow::Owned(fitted)
+}
+
+/// Footers longer than this are cut, in UTF-16 code units
+pub const FOOTER_UNITS: usize = 200;
+
+pub fn set_footer(footer: Option<String>) {
+    *FOOTER.write().unwrap() = footer;
+}
+
+pub fn footer() -> Option<String> {
+    FOOTER.read().unwrap().clone()
+}
+
+/// Append the footer to a message of items, unless it wouldn't fit in `limit` anymore
+pub fn with_footer(html: &str, limit: Limit) -> Cow<'_, str> {
+    match &*FOOTER.read().unwrap() {
+        Some(footer) => append_footer(html, footer, limit),
+        None => Cow::Borrowed(html),
+    }
+}
+
+fn append_footer<'a>(html: &'a str, footer: &str, limit: Limit) -> Cow<'a, str> {
+    // Two line breaks before it
+    if visible_len(html) + 2 + utf16_len(footer) > limit.units() {
+        return Cow::Borrowed(html);
+    }
+    Cow::Owned(format!("{}\n\n{}", html, Escape(footer)))
+}
+
+/// Cut plain text to fit in `limit`, with an ellipsis
+pub fn fit_text(text: &str, limit: Limit) -> Cow<'_, str> {
+    shorten(text, limit.units())
+}
+
+/// Cut plain text to `max_units` UTF-16 code units, with an ellipsis
+pub fn shorten(text: &str, max_units: usize) -> Cow<'_, str> {
+    if utf16_len(text) <= max_units {
+        return Cow::Borrowed(text);
+    }
+    let end = grapheme_prefix(text, max_units.saturating_sub(1));
+    Cow::Owned(format!("{}…", &text[..end]))
+}
+
+/// Feed and item titles put in our messages are cut to this, in UTF-16 code units
+pub const TITLE_UNITS: usize = 256;
+
+/// Make a title from a feed safe to show: control characters, bidi overrides and
+/// invisible characters are removed, whitespace is collapsed, and it's cut to `max_units`.
+///
+/// Zero width joiners and non-joiners are kept, emoji sequences and some scripts need them.
+pub fn sanitize_title(title: &str, max_units: usize) -> Cow<'_, str> {
+    let title = title.trim();
+    let dirty = title.contains("  ")
+        || title
+            .chars()
+            .any(|c| is_hidden(c) || c.is_control() || (c.is_whitespace() && c != ' '));
+    let title = if !dirty {
+        Cow::Borrowed(title)
+    } else {
+        let mut sanitized = String::with_capacity(title.len());
+        for c in title.chars().filter(|&c| !is_hidden(c)) {
+            if c.is_whitespace() {
+                if !sanitized.is_empty() && !sanitized.ends_with(' ') {
+                    sanitized.push(' ');
+                }
+            } else if !c.is_control() {
+                sanitized.push(c);
+            }
+        }
+        // Trailing hidden characters may leave a space behind
+        let len = sanitized.trim_end().len();
+        sanitized.truncate(len);
+        Cow::Owned(sanitized)
+    };
+    if utf16_len(&title) <= max_units {
+        return title;
+    }
+    Cow::Owned(shorten(&title, max_units).into_owned())
+}
+
+/// Bidi overrides and isolates, directional marks and zero width spaces
+pub fn is_hidden(c: char) -> bool {
+    matches!(
+        c,
+        '\u{202A}'..='\u{202E}'
+            | '\u{2066}'..='\u{2069}'
+            | '\u{200E}'
+            | '\u{200F}'
+            | '\u{061C}'
+            | '\u{200B}'
+            | '\u{2060}'
+            | '\u{FEFF}'
+    )
+}
+
+fn tag_name(tag: &str) -> &str {
+    let tag = tag.trim_start_matches('<').trim_end_matches('>');
+    tag.split_whitespace().next().unwrap_or_default()
+}
+
+/// The longest prefix of `s` within `max_units` that doesn't split a grapheme,
+/// returned as its length in bytes.
+///
+/// Close enough to the Unicode rules for titles: combining marks, variation selectors,
+/// skin tones and zero width joiner sequences stay with the character before them,
+/// and flags are kept in pairs of regional indicators.
+fn grapheme_prefix(s: &str, max_units: usize) -> usize {
+    let mut units = 0;
+    let mut end = 0;
+    let mut prev = None;
+    let mut regional_indicators = 0;
+    for (i, c) in s.char_indices() {
+        let regional = ('\u{1F1E6}'..='\u{1F1FF}').contains(&c);
+        let boundary = match prev {
+            None => true,
+            Some(prev) => {
+                prev != '\u{200D}'
+                    && !extends_grapheme(c)
+                    && !(regional && regional_indicators % 2 == 1)
+            }
+        };
+        if boundary {
+            if units > max_units {
+                return end;
+            }
+            end = i;
+        }
+        regional_indicators = if regional { regional_indicators + 1 } else { 0 };
+        units += c.len_utf16();
+        prev = Some(c);
+    }
+    if units <= max_units {
+        s.len()
+    } else {
+        end
+    }
+}
+
+fn extends_grapheme(c: char) -> bool {
+    matches!(c,
+        // Combining marks
+        '\u{0300}'..='\u{036F}'
+        | '\u{1AB0}'..='\u{1AFF}'
+        | '\u{1DC0}'..='\u{1DFF}'
+        | '\u{20D0}'..='\u{20FF}'
+        | '\u{3099}'..='\u{309A}'
+        | '\u{FE20}'..='\u{FE2F}'
+        // Zero width joiner, variation selectors, skin tones and emoji tags
+        | '\u{200D}'
+        | '\u{FE00}'..='\u{FE0F}'
+        | '\u{1F3FB}'..='\u{1F3FF}'
+        | '\u{E0020}'..='\u{E007F}'
+    )
+}
 
 pub fn format_large_msg<T, F>(head: String, data: &[T], line_format_fn: F) -> Vec<String>
 where
     F: Fn(&T) -> String,
 {
+    let limit = Limit::Text.units();
+    let mut len = visible_len(&head);
     let mut msgs = vec![head];
     for item in data {
         let line = line_format_fn(item);
-        if msgs.last_mut().unwrap().len() + line.len() > TELEGRAM_MAX_MSG_LEN {
+        let line_len = visible_len(&line);
+        if len + 1 + line_len > limit {
+            // A line too long by itself is cut by `fit_html` when sending
             msgs.push(line);
+            len = line_len;
         } else {
             let msg = msgs.last_mut().unwrap();
             msg.push('\n');
             msg.push_str(&line);
+            len += 1 + line_len;
         }
     }
     msgs
 }
 
+/// A single message with as many of the lines as fit, and the number of the others
+pub fn format_digest<T, F>(head: String, data: &[T], line_format_fn: F) -> String
+where
+    F: Fn(&T) -> String,
+{
+    let lines: Vec<String> = data.iter().map(line_format_fn).collect();
+    let total: usize = lines.iter().map(|line| 1 + visible_len(line)).sum();
+    let mut len = visible_len(&head);
+    // Leave room for the count of the others if they don't all fit
+    let room = if len + total > Limit::Text.units() {
+        Limit::Text.units() - 64
+    } else {
+        Limit::Text.units()
+    };
+    let mut msg = head;
+    for (i, line) in lines.iter().enumerate() {
+        let line_len = visible_len(line);
+        if len + 1 + line_len > room {
+            msg.push('\n');
+            msg.push_str(&tr!("digest_more", count = lines.len() - i));
+            break;
+        }
+        msg.push('\n');
+        msg.push_str(line);
+        len += 1 + line_len;
+    }
+    msg
+}
+
+/// Seconds from the publication of the item to `sent`, `None` if it has no date,
+/// or one in the future because of clock skew
+pub fn delivery_latency(item: &Item, sent: SystemTime) -> Option<u64> {
+    let published = SystemTime::from(item.published?);
+    let latency = sent.duration_since(published).ok()?.as_secs();
+    Some(latency).filter(|&latency| latency <= MAX_LATENCY)
+}
+
+/// Format seconds like `1d 2h`, only the two most significant units are kept
+pub fn format_duration(secs: u64) -> String {
+    const UNITS: [(u64, &str); 4] = [(24 * 60 * 60, "d"), (60 * 60, "h"), (60, "m"), (1, "s")];
+    if secs == 0 {
+        return "0s".into();
+    }
+    let mut rest = secs;
+    let mut parts = Vec::with_capacity(2);
+    for (unit, suffix) in UNITS {
+        if rest >= unit {
+            parts.push(format!("{}{}", rest / unit, suffix));
+            rest %= unit;
+        } else if !parts.is_empty() {
+            // Skip `1d 0h 5m`
+            break;
+        }
+        if parts.len() == 2 {
+            break;
+        }
+    }
+    parts.join(" ")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum DurationError {
+    #[error("not a duration, use seconds or units like 30s, 15m, 6h, 1d or 1h30m")]
+    Invalid,
+    #[error("the duration is too long")]
+    Overflow,
+}
+
+/// Seconds, or numbers with the units `s`, `m`, `h` and `d` from the largest to the
+/// smallest, like `6h` or `1h30m`. Reads what `format_duration` writes
+pub fn parse_duration(s: &str) -> Result<u64, DurationError> {
+    const UNITS: [(char, u64); 4] = [('d', 24 * 60 * 60), ('h', 60 * 60), ('m', 60), ('s', 1)];
+    let s = s.trim();
+    if s.is_empty() {
+        return Err(DurationError::Invalid);
+    }
+    if s.bytes().all(|b| b.is_ascii_digit()) {
+        return s.parse().map_err(|_| DurationError::Overflow);
+    }
+    let mut rest = s;
+    let mut units = &UNITS[..];
+    let mut secs: u64 = 0;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        if digits == 0 {
+            return Err(DurationError::Invalid);
+        }
+        let number: u64 = rest[..digits].parse().map_err(|_| DurationError::Overflow)?;
+        let suffix = rest[digits..].chars().next().ok_or(DurationError::Invalid)?;
+        // `30m1h` and `1h1h` are likely typos
+        let i = units
+            .iter()
+            .position(|&(unit, _)| unit == suffix)
+            .ok_or(DurationError::Invalid)?;
+        secs = number
+            .checked_mul(units[i].1)
+            .and_then(|n| n.checked_add(secs))
+            .ok_or(DurationError::Overflow)?;
+        units = &units[i + 1..];
+        rest = rest[digits + suffix.len_utf8()..].trim_start();
+    }
+    Ok(secs)
+}
+
+/// Where the fetcher sends the messages, a mock in the tests
+pub trait MessageSink: Send + Sync {
+    /// Send a HTML message without the web page preview, return the ID of it
+    fn send_html<'a>(
+        &'a self,
+        chat_id: i64,
+        html: &'a str,
+        reply_to: Option<i64>,
+    ) -> BoxFuture<'a, Result<i64, MethodCall>>;
+
+    /// Like `send_html`, after the messages of the subscribers
+    fn send_firehose<'a>(
+        &'a self,
+        chat_id: i64,
+        html: &'a str,
+    ) -> BoxFuture<'a, Result<i64, MethodCall>> {
+        self.send_html(chat_id, html, None)
+    }
+
+    /// ID of the pinned message of the chat
+    fn pinned_message(&self, chat_id: i64) -> BoxFuture<'_, Result<Option<i64>, MethodCall>>;
+}
+
+impl MessageSink for Bot {
+    fn send_html<'a>(
+        &'a self,
+        chat_id: i64,
+        html: &'a str,
+        reply_to: Option<i64>,
+    ) -> BoxFuture<'a, Result<i64, MethodCall>> {
+        Box::pin(send_with_priority(self, chat_id, html, reply_to, Priority::Delivery))
+    }
+
+    fn send_firehose<'a>(
+        &'a self,
+        chat_id: i64,
+        html: &'a str,
+    ) -> BoxFuture<'a, Result<i64, MethodCall>> {
+        Box::pin(send_with_priority(self, chat_id, html, None, Priority::Firehose))
+    }
+
+    fn pinned_message(&self, chat_id: i64) -> BoxFuture<'_, Result<Option<i64>, MethodCall>> {
+        Box::pin(async move {
+            let chat = crate::chat_info::get_chat(self, tbot::types::chat::Id(chat_id)).await?;
+            Ok(chat.pinned_message.map(|message| i64::from(message.id.0)))
+        })
+    }
+}
+
+async fn send_with_priority(
+    bot: &Bot,
+    chat_id: i64,
+    html: &str,
+    reply_to: Option<i64>,
+    priority: Priority,
+) -> Result<i64, MethodCall> {
+    let html = fit_html(html, Limit::Text);
+    wait_send_turn(chat_id, priority).await;
+    // Taken after the turn, the ones still waiting for it don't hold up the others
+    let _permit = match priority {
+        Priority::Reply => None,
+        _ => Some(SENDING.acquire().await.unwrap()),
+    };
+    let chat_id = tbot::types::chat::Id(chat_id);
+    let message = send_html_or_plain(bot, chat_id, &html, reply_to).await?;
+    Ok(i64::from(message.id.0))
+}
+
+/// Which messages are sent first when Telegram's limits are reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Replies to commands, so the bot stays responsive while delivering
+    Reply,
+    /// Items and notifications
+    Delivery,
+    /// Copies for `--firehose-chat-id`, only when the others can't go
+    Firehose,
+}
+
+/// Wait until a message to the chat can be sent without hitting Telegram's limits,
+/// about 30 messages per second overall and 20 per minute in a group.
+///
+/// Replies go first, deliveries take turns by chat, so a feed with many subscribers
+/// doesn't hold up the others. A delivery still goes after `MAX_REPLY_STREAK` replies
+/// in a row. The firehose only gets the budget neither of them can use.
+pub async fn wait_send_turn(chat_id: i64, priority: Priority) {
+    let scheduler = &*SEND_SCHEDULER;
+    let wakeup = Arc::new(Notify::new());
+    let ticket = {
+        let mut queue = scheduler.queue.lock().unwrap();
+        let ticket = queue.enqueue(chat_id, priority, Instant::now());
+        METRICS.queued_sends(queue.len());
+        scheduler.waiters.lock().unwrap().insert(ticket, wakeup.clone());
+        ticket
+    };
+    // Its turn is given to the next one even if this is cancelled
+    let _ticket = TicketGuard(ticket);
+    let mut delayed = false;
+    loop {
+        let mut queue = scheduler.queue.lock().unwrap();
+        let result = queue.try_send(ticket, Instant::now());
+        if result == Err(None) {
+            // The one whose turn it is may be asleep since it waited for the limits
+            scheduler.wake_next(&mut queue);
+        }
+        drop(queue);
+        match result {
+            Ok(()) => break,
+            Err(Some(until)) => {
+                let until = tokio::time::Instant::from_std(until);
+                select_biased! {
+                    _ = wakeup.notified().fuse() => (),
+                    _ = tokio::time::sleep_until(until).fuse() => (),
+                }
+            }
+            Err(None) => wakeup.notified().await,
+        }
+        delayed = true;
+    }
+    if delayed {
+        METRICS.delayed_send();
+    }
+}
+
+// https://core.telegram.org/bots/faq#my-bot-is-hitting-limits-how-do-i-avoid-this
+const GLOBAL_SENDS_PER_SECOND: f64 = 30.0;
+const GROUP_SENDS_PER_MINUTE: f64 = 20.0;
+// Replies sent in a row while deliveries are ready, so a flood of commands can't stop them
+const MAX_REPLY_STREAK: u32 = 10;
+// Deliveries sent at once across all the feeds, replies don't count
+const DELIVERY_CONCURRENCY: usize = 16;
+
+static SENDING: Semaphore = Semaphore::const_new(DELIVERY_CONCURRENCY);
+
+static SEND_SCHEDULER: LazyLock<SendScheduler> = LazyLock::new(|| SendScheduler {
+    queue: Mutex::new(SendQueue::new(Instant::now())),
+    waiters: Mutex::new(BTreeMap::new()),
+});
+
+struct SendScheduler {
+    queue: Mutex<SendQueue>,
+    /// Notified one at a time when it may be their turn, locked after `queue`
+    waiters: Mutex<BTreeMap<u64, Arc<Notify>>>,
+}
+
+impl SendScheduler {
+    /// Wake the ticket whose turn it is, the others keep sleeping
+    fn wake_next(&self, queue: &mut SendQueue) {
+        let waiters = self.waiters.lock().unwrap();
+        let waiter = match queue.next(Instant::now()) {
+            Ok(next) => waiters.get(&next),
+            // The limits hold all of them back, the oldest one waits for them
+            Err(_) => waiters.values().next(),
+        };
+        if let Some(waiter) = waiter {
+            waiter.notify_one();
+        }
+    }
+}
+
+struct TicketGuard(u64);
+
+impl Drop for TicketGuard {
+    fn drop(&mut self) {
+        let mut queue = SEND_SCHEDULER.queue.lock().unwrap();
+        queue.cancel(self.0);
+        METRICS.queued_sends(queue.len());
+        SEND_SCHEDULER.waiters.lock().unwrap().remove(&self.0);
+        SEND_SCHEDULER.wake_next(&mut queue);
+    }
+}
+
+/// Groups and channels have negative IDs
+fn is_group(chat_id: i64) -> bool {
+    chat_id < 0
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(capacity: f64, now: Instant) -> Self {
+        Bucket {
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, capacity: f64, per_second: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second).min(capacity);
+        self.updated = now;
+    }
+
+    /// When there is a token for one more message, after `refill`
+    fn ready_at(&self, per_second: f64) -> Instant {
+        let missing = (1.0 - self.tokens).max(0.0);
+        self.updated + Duration::from_secs_f64(missing / per_second)
+    }
+}
+
+/// The messages waiting to be sent, and the budget left for them
+struct SendQueue {
+    next_ticket: u64,
+    /// Tickets to their chats, priorities and when they were queued
+    tickets: BTreeMap<u64, (i64, Priority, Instant)>,
+    replies: VecDeque<u64>,
+    /// Deliveries by chat, chats with any queued take turns in `turns`
+    deliveries: BTreeMap<i64, VecDeque<u64>>,
+    turns: VecDeque<i64>,
+    firehose: VecDeque<u64>,
+    global: Bucket,
+    /// Only groups sent to recently, the full buckets are dropped
+    groups: BTreeMap<i64, Bucket>,
+    /// Replies sent since the last delivery
+    reply_streak: u32,
+}
+
+impl SendQueue {
+    fn new(now: Instant) -> Self {
+        SendQueue {
+            next_ticket: 0,
+            tickets: BTreeMap::new(),
+            replies: VecDeque::new(),
+            deliveries: BTreeMap::new(),
+            turns: VecDeque::new(),
+            firehose: VecDeque::new(),
+            global: Bucket::full(GLOBAL_SENDS_PER_SECOND, now),
+            groups: BTreeMap::new(),
+            reply_streak: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.tickets.len()
+    }
+
+    fn enqueue(&mut self, chat_id: i64, priority: Priority, now: Instant) -> u64 {
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        self.tickets.insert(ticket, (chat_id, priority, now));
+        match priority {
+            Priority::Reply => self.replies.push_back(ticket),
+            Priority::Delivery => {
+                let queued = self.deliveries.entry(chat_id).or_default();
+                if queued.is_empty() {
+                    self.turns.push_back(chat_id);
+                }
+                queued.push_back(ticket);
+            }
+            Priority::Firehose => self.firehose.push_back(ticket),
+        }
+        ticket
+    }
+
+    /// Forget the ticket, it's a no-op if it was sent already
+    fn cancel(&mut self, ticket: u64) {
+        let (chat_id, priority, _) = match self.tickets.remove(&ticket) {
+            Some(queued) => queued,
+            None => return,
+        };
+        match priority {
+            Priority::Reply => self.replies.retain(|&t| t != ticket),
+            Priority::Delivery => {
+                let queued = self.deliveries.get_mut(&chat_id).unwrap();
+                queued.retain(|&t| t != ticket);
+                if queued.is_empty() {
+                    self.deliveries.remove(&chat_id);
+                    self.turns.retain(|&chat| chat != chat_id);
+                }
+            }
+            Priority::Firehose => self.firehose.retain(|&t| t != ticket),
+        }
+    }
+
+    /// The ticket whose turn it is, or when to look again, `None` if nothing is queued
+    fn next(&mut self, now: Instant) -> Result<u64, Option<Instant>> {
+        self.global.refill(GLOBAL_SENDS_PER_SECOND, GLOBAL_SENDS_PER_SECOND, now);
+        if self.global.tokens < 1.0 {
+            return Err(Some(self.global.ready_at(GLOBAL_SENDS_PER_SECOND)));
+        }
+        let delivery = self.next_delivery(now);
+        match (self.replies.front().copied(), delivery) {
+            (Some(_), Ok(delivery)) if self.reply_streak >= MAX_REPLY_STREAK => Ok(delivery),
+            // Not held up by the limit of the group, the deliveries there wait longer instead
+            (Some(reply), _) => Ok(reply),
+            (None, Ok(delivery)) => Ok(delivery),
+            (None, Err(ready_at)) => match self.next_firehose(now) {
+                Ok(firehose) => Ok(firehose),
+                Err(firehose_at) => Err(earliest(ready_at, firehose_at)),
+            },
+        }
+    }
+
+    /// Take the budget for the ticket if it's its turn. Otherwise return when to try again,
+    /// `None` if it has to wait for another message to be sent first.
+    fn try_send(&mut self, ticket: u64, now: Instant) -> Result<(), Option<Instant>> {
+        let group_rate = GROUP_SENDS_PER_MINUTE / 60.0;
+        if self.next(now)? != ticket {
+            return Err(None);
+        }
+
+        let (chat_id, priority, _) = self.tickets.remove(&ticket).unwrap();
+        match priority {
+            Priority::Reply => {
+                self.replies.pop_front();
+                self.reply_streak += 1;
+            }
+            Priority::Delivery => {
+                self.reply_streak = 0;
+                let queued = self.deliveries.get_mut(&chat_id).unwrap();
+                queued.pop_front();
+                // To the end of the line
+                self.turns.retain(|&chat| chat != chat_id);
+                if queued.is_empty() {
+                    self.deliveries.remove(&chat_id);
+                } else {
+                    self.turns.push_back(chat_id);
+                }
+            }
+            Priority::Firehose => {
+                self.firehose.pop_front();
+            }
+        }
+        self.global.tokens -= 1.0;
+        if is_group(chat_id) {
+            let bucket = self
+                .groups
+                .entry(chat_id)
+                .or_insert_with(|| Bucket::full(GROUP_SENDS_PER_MINUTE, now));
+            bucket.refill(GROUP_SENDS_PER_MINUTE, group_rate, now);
+            bucket.tokens -= 1.0;
+        }
+        let deliveries = &self.deliveries;
+        self.groups.retain(|chat_id, bucket| {
+            bucket.refill(GROUP_SENDS_PER_MINUTE, group_rate, now);
+            bucket.tokens < GROUP_SENDS_PER_MINUTE || deliveries.contains_key(chat_id)
+        });
+        Ok(())
+    }
+
+    /// The delivery whose turn it is, or when the limit of a group allows one
+    fn next_delivery(&mut self, now: Instant) -> Result<u64, Option<Instant>> {
+        let group_rate = GROUP_SENDS_PER_MINUTE / 60.0;
+        let mut ready_at: Option<Instant> = None;
+        for &chat_id in &self.turns {
+            let bucket = match self.groups.get_mut(&chat_id) {
+                Some(bucket) => bucket,
+                None => return Ok(self.deliveries[&chat_id][0]),
+            };
+            bucket.refill(GROUP_SENDS_PER_MINUTE, group_rate, now);
+            if bucket.tokens >= 1.0 {
+                return Ok(self.deliveries[&chat_id][0]);
+            }
+            let at = bucket.ready_at(group_rate);
+            ready_at = Some(ready_at.map_or(at, |ready_at| ready_at.min(at)));
+        }
+        Err(ready_at)
+    }
+
+    /// The first firehose message, or when the limit of its group allows it
+    fn next_firehose(&mut self, now: Instant) -> Result<u64, Option<Instant>> {
+        let group_rate = GROUP_SENDS_PER_MINUTE / 60.0;
+        let ticket = *self.firehose.front().ok_or(None)?;
+        let chat_id = self.tickets[&ticket].0;
+        match self.groups.get_mut(&chat_id) {
+            Some(bucket) => {
+                bucket.refill(GROUP_SENDS_PER_MINUTE, group_rate, now);
+                if bucket.tokens >= 1.0 {
+                    Ok(ticket)
+                } else {
+                    Err(Some(bucket.ready_at(group_rate)))
+                }
+            }
+            None => Ok(ticket),
+        }
+    }
+
+    /// The deliveries queued for the chat, or all of them, when the first of them was
+    /// queued and when the next may go as far as the limits tell
+    fn queued_deliveries(
+        &mut self,
+        chat_id: Option<i64>,
+        now: Instant,
+    ) -> (usize, Option<Instant>, Option<Instant>) {
+        let group_rate = GROUP_SENDS_PER_MINUTE / 60.0;
+        self.global.refill(GLOBAL_SENDS_PER_SECOND, GLOBAL_SENDS_PER_SECOND, now);
+        let global = self.global.ready_at(GLOBAL_SENDS_PER_SECOND);
+        let (mut queued, mut oldest, mut next) = (0, None::<Instant>, None::<Instant>);
+        for (chat, tickets) in &self.deliveries {
+            if chat_id.is_some_and(|chat_id| chat_id != *chat) {
+                continue;
+            }
+            queued += tickets.len();
+            let queued_at = self.tickets[&tickets[0]].2;
+            oldest = Some(oldest.map_or(queued_at, |oldest| oldest.min(queued_at)));
+            let ready_at = match self.groups.get_mut(chat) {
+                Some(bucket) => {
+                    bucket.refill(GROUP_SENDS_PER_MINUTE, group_rate, now);
+                    bucket.ready_at(group_rate).max(global)
+                }
+                None => global,
+            };
+            next = Some(next.map_or(ready_at, |next| next.min(ready_at)));
+        }
+        (queued, oldest, next)
+    }
+}
+
+/// The sooner of the two, `None` only if both are
+fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// The deliveries waiting for Telegram's limits, of the chat or all of them, for /queue
+pub fn queued_deliveries(chat_id: Option<i64>) -> QueueSnapshot {
+    let (now, wall) = (Instant::now(), SystemTime::now());
+    let (queued, oldest, next) = SEND_SCHEDULER
+        .queue
+        .lock()
+        .unwrap()
+        .queued_deliveries(chat_id, now);
+    let wall_clock = |at: Instant| match at.checked_duration_since(now) {
+        Some(ahead) => wall + ahead,
+        None => wall - now.duration_since(at),
+    };
+    QueueSnapshot {
+        queued,
+        oldest: oldest.map(wall_clock),
+        next_flush: next.map(wall_clock),
+        // Only the chats are known, the messages are still with their senders
+        titles: Vec::new(),
+    }
+}
+
+/// Result of `send_html`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sent {
+    pub message_id: i64,
+    /// The message to reply to was gone, it was sent without replying
+    pub reply_lost: bool,
+}
+
+/// Send a message about the feed, and add it to the audit log.
+/// If the message to reply to is gone, it's sent again without replying.
+pub async fn send_html(
+    sink: &dyn MessageSink,
+    chat_id: i64,
+    html: &str,
+    feed_link: &str,
+    reply_to: Option<ReplyTo>,
+) -> Result<Sent, MethodCall> {
+    let reply_to_id = match reply_to {
+        Some(reply_to) => reply_target(sink, chat_id, reply_to).await,
+        None => None,
+    };
+    let mut result = sink.send_html(chat_id, html, reply_to_id).await;
+    let mut reply_lost = false;
+    if reply_to_id.is_some() {
+        if let Err(ApiError::ReplyNotFound) = result.as_ref().map_err(classify_error) {
+            PINNED.lock().unwrap().remove(&chat_id);
+            reply_lost = true;
+            result = sink.send_html(chat_id, html, None).await;
+        }
+    }
+    audit::record(chat_id, feed_link, html, &result);
+    result.map(|message_id| Sent {
+        message_id,
+        reply_lost,
+    })
+}
+
+/// The message to reply to, the pinned message is looked up at most every `PINNED_CACHE_TTL`.
+/// `None` if the chat has nothing pinned, or it can't be looked up.
+async fn reply_target(sink: &dyn MessageSink, chat_id: i64, reply_to: ReplyTo) -> Option<i64> {
+    match reply_to {
+        ReplyTo::Message(message_id) => return Some(message_id),
+        ReplyTo::Pinned => (),
+    }
+    if let Some((pinned, at)) = PINNED.lock().unwrap().get(&chat_id) {
+        if at.elapsed() < PINNED_CACHE_TTL {
+            return *pinned;
+        }
+    }
+    match sink.pinned_message(chat_id).await {
+        Ok(pinned) => {
+            PINNED
+                .lock()
+                .unwrap()
+                .insert(chat_id, (pinned, Instant::now()));
+            pinned
+        }
+        Err(e) => {
+            crate::print_error(e);
+            None
+        }
+    }
+}
+
+/// If Telegram can't parse the HTML message, send it again as plain text,
+/// so a formatting bug never loses the items
+async fn send_html_or_plain(
+    bot: &Bot,
+    chat_id: tbot::types::chat::Id,
+    html: &str,
+    reply_to: Option<i64>,
+) -> Result<tbot::types::Message, MethodCall> {
+    let reply_to = reply_to.map(|id| tbot::types::message::Id(id as u32));
+    let mut request = bot
+        .send_message(chat_id, parameters::Text::with_html(html))
+        .is_web_page_preview_disabled(true);
+    if let Some(reply_to) = reply_to {
+        request = request.in_reply_to(reply_to);
+    }
+    let result = request.call().await;
+    match result {
+        Err(ref e) if classify_error(e) == ApiError::BadMarkup => {
+            METRICS.plain_text_fallback();
+            crate::print_debug(&format!("Sending as plain text, {}: {:?}", e, html));
+            let plain = html_to_plain(html);
+            let plain = fit_text(&plain, Limit::Text);
+            let mut request = bot
+                .send_message(chat_id, parameters::Text::with_plain(&plain))
+                .is_web_page_preview_disabled(true);
+            if let Some(reply_to) = reply_to {
+                request = request.in_reply_to(reply_to);
+            }
+            request.call().await
+        }
+        other => other,
+    }
+}
+
+/// What a failed Bot API request means for us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiError {
+    /// The group was upgraded to a supergroup with this ID
+    Migrated(i64),
+    /// Flood control, in seconds
+    RetryAfter(u64),
+    /// Blocked, kicked, deleted, or not allowed to post anymore
+    ChatUnavailable,
+    BadMarkup,
+    /// The message to reply to was deleted
+    ReplyNotFound,
+    Other,
+}
+
+/// Decided by the error code and parameters first, only 400 needs the description.
+/// Local Bot API servers word some of them differently from api.telegram.org,
+/// so it's matched loosely.
+pub fn classify_error(error: &MethodCall) -> ApiError {
+    let (description, error_code, migrate_to_chat_id, retry_after) = match error {
+        MethodCall::RequestError {
+            description,
+            error_code,
+            migrate_to_chat_id,
+            retry_after,
+        } => (description, *error_code, *migrate_to_chat_id, *retry_after),
+        _ => return ApiError::Other,
+    };
+    if let Some(chat_id) = migrate_to_chat_id {
+        return ApiError::Migrated(chat_id.0);
+    }
+    if let Some(delay) = retry_after {
+        return ApiError::RetryAfter(delay);
+    }
+    let description = description.to_lowercase();
+    match error_code {
+        403 => ApiError::ChatUnavailable,
+        400 if description.contains("parse entities") => ApiError::BadMarkup,
+        400 if description.contains("replied message not found")
+            || description.contains("message to be replied not found") =>
+        {
+            ApiError::ReplyNotFound
+        }
+        400 if description.contains("chat not found")
+            || description.contains("no rights")
+            || description.contains("not enough rights")
+            || description.contains("administrator rights")
+            || description.contains("chat_write_forbidden")
+            || description.contains("peer_id_invalid") =>
+        {
+            ApiError::ChatUnavailable
+        }
+        _ => ApiError::Other,
+    }
+}
+
+/// Drop the tags of our HTML messages, the links are appended as raw URLs
+pub fn html_to_plain(html: &str) -> String {
+    let mut plain = String::with_capacity(html.len());
+    let mut href = None;
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        plain.push_str(&unescape(&rest[..start]));
+        let tag = &rest[start + 1..end];
+        if tag.starts_with("a ") {
+            href = parse_href(tag);
+        } else if tag == "/a" {
+            if let Some(href) = href.take() {
+                plain.push_str(" (");
+                plain.push_str(&href);
+                plain.push(')');
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    plain.push_str(&unescape(rest));
+    plain
+}
+
+/// The text of HTML from a feed, every tag is a space and the entities are decoded.
+/// It's sanitized like a title, without the cut.
+pub fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    for token in tokens(html) {
+        match token {
+            Token::Text(s) => text.push_str(&decode_entities(s)),
+            Token::Tag(_) => text.push(' '),
+        }
+    }
+    sanitize_title(&text, usize::MAX).into_owned()
+}
+
+/// Words in HTML from a feed. Chinese and Japanese are written without spaces,
+/// every 2 of their characters count as a word.
+pub fn word_count(html: &str) -> usize {
+    let mut words = 0;
+    let mut cjk_chars = 0;
+    for token in strip_html(html).split_whitespace() {
+        let mut in_word = false;
+        for c in token.chars() {
+            if is_cjk(c) {
+                cjk_chars += 1;
+                in_word = false;
+            } else if c.is_alphanumeric() && !in_word {
+                words += 1;
+                in_word = true;
+            }
+        }
+    }
+    words + cjk_chars.div_ceil(2)
+}
+
+/// Nothing but a link in HTML from a feed, maybe after a label like `Link:`
+pub fn is_link_only(html: &str) -> bool {
+    let mut text = String::new();
+    let mut links = 0;
+    let mut in_link = false;
+    for token in tokens(html) {
+        match token {
+            Token::Tag(tag) => {
+                let name = tag_name(tag);
+                if name.eq_ignore_ascii_case("a") {
+                    links += 1;
+                    in_link = true;
+                } else if name.eq_ignore_ascii_case("/a") {
+                    in_link = false;
+                }
+                text.push(' ');
+            }
+            // The text of a link is usually the URL again, or its title
+            Token::Text(_) if in_link => (),
+            Token::Text(s) => text.push_str(&decode_entities(s)),
+        }
+    }
+    let mut labeled = false;
+    for word in text.split_whitespace() {
+        // No space after a full-width colon
+        let label = match word.find("http://").or_else(|| word.find("https://")) {
+            Some(start) => {
+                links += 1;
+                &word[..start]
+            }
+            None => word,
+        };
+        if label.is_empty() {
+            continue;
+        }
+        if !labeled && (label.ends_with(':') || label.ends_with('：')) {
+            labeled = true;
+        } else if label.chars().any(char::is_alphanumeric) {
+            return false;
+        }
+    }
+    links > 0
+}
+
+/// Kana and the ideographs, Korean is written with spaces
+fn is_cjk(c: char) -> bool {
+    matches!(
+        c,
+        '\u{3040}'..='\u{30FF}'
+            | '\u{3400}'..='\u{4DBF}'
+            | '\u{4E00}'..='\u{9FFF}'
+            | '\u{F900}'..='\u{FAFF}'
+            | '\u{20000}'..='\u{2FA1F}'
+    )
+}
+
+/// Unlike `unescape` for our own messages, feeds use any entity
+fn decode_entities(s: &str) -> Cow<'_, str> {
+    if !s.contains('&') {
+        return Cow::Borrowed(s);
+    }
+    let mut decoded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let name = match rest[1..].find(';') {
+            Some(end) if end <= 32 => &rest[1..=end],
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let c = match name {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => name
+                .strip_prefix('#')
+                .and_then(|n| match n.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => n.parse().ok(),
+                })
+                .and_then(char::from_u32),
+        };
+        match c {
+            Some(c) => decoded.push(c),
+            // The other named ones are mostly punctuation, like &hellip;
+            None if name.chars().all(|c| c.is_ascii_alphanumeric()) => decoded.push(' '),
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        }
+        rest = &rest[name.len() + 2..];
+    }
+    decoded.push_str(rest);
+    Cow::Owned(decoded)
+}
+
+/// The links in our HTML messages
+pub fn html_links(html: &str) -> Vec<String> {
+    html.split('<')
+        .skip(1)
+        .filter_map(|s| s.split_once('>'))
+        .filter(|(tag, _)| tag.starts_with("a "))
+        .filter_map(|(tag, _)| parse_href(tag))
+        .collect()
+}
+
+/// The http(s) URLs in plain text, f.e. a /rss listing copied from another bot,
+/// in order and without duplicates
+pub fn find_urls(text: &str) -> Vec<String> {
+    let mut urls: Vec<String> = Vec::new();
+    for start in text.match_indices("http").map(|(i, _)| i) {
+        let rest = &text[start..];
+        if !rest.starts_with("http://") && !rest.starts_with("https://") {
+            continue;
+        }
+        // Markdown puts links in parentheses, and bold or code markers around them
+        let end = rest
+            .find(|c: char| c.is_whitespace() || "<>\"'`()[]{}".contains(c))
+            .unwrap_or(rest.len());
+        let url = rest[..end].trim_end_matches(['.', ',', ';', ':', '!', '?', '*', '_']);
+        if url.len() > "https://".len() && !urls.iter().any(|u| u == url) {
+            urls.push(url.to_string());
+        }
+    }
+    urls
+}
+
+fn parse_href(tag: &str) -> Option<String> {
+    tag.split_once("href=\"")
+        .and_then(|(_, s)| s.split_once('"'))
+        .map(|(url, _)| unescape(url).into_owned())
+}
+
+fn unescape(s: &str) -> Cow<'_, str> {
+    if !s.contains('&') {
+        return Cow::Borrowed(s);
+    }
+    Cow::Owned(
+        s.replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&amp;", "&"),
+    )
+}
+
+/// A title from a feed, sanitized and escaped for our HTML messages
+pub struct Title<'a>(pub &'a str);
+
+impl<'a> fmt::Display for Title<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        Escape(&sanitize_title(self.0, TITLE_UNITS)).fmt(fmt)
+    }
+}
+
 pub struct Escape<'a>(pub &'a str);
 
 impl<'a> fmt::Display for Escape<'a> {
@@ -52,3 +1330,495 @@ impl<'a> fmt::Display for Escape<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The order the waiting tickets are let through at `now`, until none can go
+    fn send_order(queue: &mut SendQueue, waiting: &mut Vec<u64>, now: Instant) -> Vec<u64> {
+        let mut sent = Vec::new();
+        while let Some(i) = waiting.iter().position(|&t| queue.try_send(t, now).is_ok()) {
+            sent.push(waiting.remove(i));
+        }
+        sent
+    }
+
+    #[test]
+    fn send_scheduling() {
+        let start = Instant::now();
+        let mut queue = SendQueue::new(start);
+        // A big group with 25 items, then a small one and a reply
+        let mut waiting: Vec<u64> = (0..25)
+            .map(|_| queue.enqueue(-1, Priority::Delivery, start))
+            .collect();
+        let small = queue.enqueue(-2, Priority::Delivery, start);
+        let private = queue.enqueue(1, Priority::Delivery, start);
+        let reply = queue.enqueue(-1, Priority::Reply, start);
+        waiting.extend([small, private, reply]);
+        let sent = send_order(&mut queue, &mut waiting, start);
+        // The reply first, then the chats take turns,
+        // until the big group used its budget for the minute
+        assert_eq!(sent[..4], [reply, 0, small, private]);
+        assert_eq!(sent.len(), 4 + 18);
+        assert_eq!(queue.len(), 25 - 19);
+        let next = queue.try_send(waiting[0], start).unwrap_err().unwrap();
+        assert_eq!(next, start + Duration::from_secs(3));
+        // Other chats aren't held up by it
+        let other = queue.enqueue(-3, Priority::Delivery, start);
+        assert!(queue.try_send(other, start).is_ok());
+        let refilled = start + Duration::from_millis(3001);
+        let sent = send_order(&mut queue, &mut waiting, refilled);
+        assert_eq!(sent.len(), 1);
+
+        // 30 per second overall
+        let start = start + Duration::from_secs(60);
+        let mut queue = SendQueue::new(start);
+        let mut waiting: Vec<u64> = (0..40)
+            .map(|i| queue.enqueue(i, Priority::Delivery, start))
+            .collect();
+        assert_eq!(send_order(&mut queue, &mut waiting, start), (0..30).collect::<Vec<_>>());
+        let next = queue.try_send(waiting[0], start).unwrap_err().unwrap();
+        assert_eq!(next, start + Duration::from_secs_f64(1.0 / 30.0));
+        let later = start + Duration::from_millis(100);
+        assert_eq!(send_order(&mut queue, &mut waiting, later), [30, 31, 32]);
+        // Cancelled tickets give up their turn
+        queue.cancel(waiting.remove(0));
+        let later = later + Duration::from_secs(1);
+        assert_eq!(send_order(&mut queue, &mut waiting, later).len(), 6);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn send_turns_handed_on() {
+        // More than the budget of a second, the ones woken by the limits hand the turn on
+        let sends = (0..40).map(|i| wait_send_turn(1000 + i, Priority::Delivery));
+        let all = futures::future::join_all(sends);
+        tokio::time::timeout(Duration::from_secs(5), all).await.unwrap();
+        assert!(SEND_SCHEDULER.waiters.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reply_priority() {
+        let start = Instant::now();
+        let mut queue = SendQueue::new(start);
+        let mut waiting: Vec<u64> = (0..1000)
+            .map(|i| queue.enqueue(i, Priority::Delivery, start))
+            .collect();
+        assert_eq!(send_order(&mut queue, &mut waiting, start).len(), 30);
+        // Overtakes the backlog as soon as there is budget again
+        let reply = queue.enqueue(1, Priority::Reply, start);
+        waiting.push(reply);
+        let later = start + Duration::from_millis(100);
+        assert_eq!(send_order(&mut queue, &mut waiting, later)[0], reply);
+
+        // A flood of replies doesn't stop the deliveries
+        let replies: Vec<u64> = (0..500)
+            .map(|i| queue.enqueue(i, Priority::Reply, start))
+            .collect();
+        waiting.extend(&replies);
+        let mut sent = Vec::new();
+        let mut now = later;
+        while !waiting.is_empty() {
+            now += Duration::from_secs(1);
+            sent.extend(send_order(&mut queue, &mut waiting, now));
+        }
+        let mut streak = 0;
+        for ticket in &sent {
+            if replies.contains(ticket) {
+                streak += 1;
+                assert!(streak <= MAX_REPLY_STREAK);
+            } else {
+                streak = 0;
+            }
+        }
+        // Every delivery was sent
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn firehose_last() {
+        let start = Instant::now();
+        let mut queue = SendQueue::new(start);
+        let firehose = queue.enqueue(-100, Priority::Firehose, start);
+        let mut waiting: Vec<u64> = (0..28)
+            .map(|i| queue.enqueue(i, Priority::Delivery, start))
+            .collect();
+        let reply = queue.enqueue(1, Priority::Reply, start);
+        waiting.extend([firehose, reply]);
+        let sent = send_order(&mut queue, &mut waiting, start);
+        assert_eq!(sent.len(), 30);
+        assert_eq!((sent[0], sent[29]), (reply, firehose));
+
+        // Goes while the deliveries wait for the limit of their group
+        let start = start + Duration::from_secs(60);
+        let mut queue = SendQueue::new(start);
+        let mut waiting: Vec<u64> = (0..21)
+            .map(|_| queue.enqueue(-1, Priority::Delivery, start))
+            .collect();
+        let firehose = queue.enqueue(-100, Priority::Firehose, start);
+        waiting.push(firehose);
+        let sent = send_order(&mut queue, &mut waiting, start);
+        assert_eq!(sent.len(), 21);
+        assert_eq!(sent[20], firehose);
+    }
+
+    #[test]
+    fn queued_deliveries() {
+        let start = Instant::now();
+        let mut queue = SendQueue::new(start);
+        assert_eq!(queue.queued_deliveries(None, start), (0, None, None));
+        let mut waiting: Vec<u64> = (0..25)
+            .map(|_| queue.enqueue(-1, Priority::Delivery, start))
+            .collect();
+        let later = start + Duration::from_secs(1);
+        waiting.push(queue.enqueue(1, Priority::Delivery, later));
+        waiting.push(queue.enqueue(1, Priority::Reply, later));
+        assert_eq!(queue.queued_deliveries(None, later), (26, Some(start), Some(later)));
+        assert_eq!(queue.queued_deliveries(Some(1), later), (1, Some(later), Some(later)));
+
+        // The group waits for its limit, the private chat was sent to
+        assert_eq!(send_order(&mut queue, &mut waiting, later).len(), 22);
+        assert_eq!(queue.len(), 5);
+        let next = later + Duration::from_secs(3);
+        assert_eq!(queue.queued_deliveries(Some(-1), later), (5, Some(start), Some(next)));
+        assert_eq!(queue.queued_deliveries(Some(1), later), (0, None, None));
+    }
+
+    #[test]
+    fn footer() {
+        let footer = "Powered by <@rssbot> & co";
+        let html = "<b>feed</b>\n<a href=\"http://example.com/?a=1&amp;b=2\">item</a>";
+        assert_eq!(
+            append_footer(html, footer, Limit::Text),
+            format!("{}\n\nPowered by &lt;@rssbot&gt; &amp; co", html)
+        );
+        // Only added if it still fits
+        let long = format!("<b>{}</b>", "a".repeat(1024 - 2 - footer.len()));
+        assert!(append_footer(&long, footer, Limit::Caption).ends_with("co"));
+        let long = format!("<b>{}</b>", "a".repeat(1024 - 1 - footer.len()));
+        assert_eq!(append_footer(&long, footer, Limit::Caption), long);
+        assert!(append_footer(&long, footer, Limit::Text).ends_with("co"));
+    }
+
+    #[test]
+    fn word_counting() {
+        assert_eq!(word_count("<p>Hello, <b>world</b>!</p>"), 2);
+        assert_eq!(word_count("Tom&apos;s caf&#233;&nbsp;au lait"), 4);
+        // Neither entities nor punctuation are words
+        assert_eq!(word_count("a &amp; b &mdash; c"), 3);
+        assert_eq!(word_count("Привет, мир"), 2);
+        assert_eq!(word_count("안녕하세요 세계"), 2);
+        assert_eq!(word_count("今天天气很好"), 3);
+        assert_eq!(word_count("日本語のテキスト"), 4);
+        assert_eq!(word_count("我用 Rust 写了一个机器人"), 6);
+        assert_eq!(word_count("中文English混合"), 3);
+        assert_eq!(word_count("<img src=\"a.png\">"), 0);
+        // Hidden characters don't split words
+        assert_eq!(word_count("zero\u{200B}width"), 1);
+        assert_eq!(strip_html("<p>a\u{200B}b</p>\n<p>c</p>"), "ab c");
+    }
+
+    #[test]
+    fn link_only() {
+        assert!(is_link_only("https://example.com/post"));
+        assert!(is_link_only(
+            "Link: <a href=\"https://example.com/\">https://example.com/</a>"
+        ));
+        assert!(is_link_only("<p><a href=\"https://example.com\">Example Post</a></p>"));
+        assert!(is_link_only("链接：https://example.com"));
+        assert!(!is_link_only("Read this: https://example.com"));
+        assert!(!is_link_only("A post about Rust <a href=\"https://example.com\">here</a>"));
+        assert!(!is_link_only("Link:"));
+        assert!(!is_link_only(""));
+    }
+
+    #[test]
+    fn title_sanitizing() {
+        let spoofed = "\u{202E}gpj.exe\u{202C} <b>invoice</b>\u{2066}\u{200F}";
+        assert_eq!(sanitize_title(spoofed, TITLE_UNITS), "gpj.exe <b>invoice</b>");
+        assert_eq!(Title(spoofed).to_string(), "gpj.exe &lt;b&gt;invoice&lt;/b&gt;");
+        let invisible = "\u{FEFF}zero\u{200B}\u{2060}width\u{061C}\r\n\tline\u{0007}";
+        assert_eq!(sanitize_title(invisible, TITLE_UNITS), "zerowidth line");
+        // Only an emoji sequence, the joiners stay
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        assert!(matches!(sanitize_title(family, TITLE_UNITS), Cow::Borrowed(_)));
+        assert_eq!(sanitize_title(" \u{200B} ", TITLE_UNITS), "");
+
+        let long = "\u{202E}&".repeat(1000);
+        let title = sanitize_title(&long, TITLE_UNITS);
+        assert!(title.chars().all(|c| c == '&' || c == '…'));
+        assert_eq!(utf16_len(&title), TITLE_UNITS);
+        assert_eq!(Title(&long).to_string(), title.replace('&', "&amp;"));
+    }
+
+    #[test]
+    fn url_extraction() {
+        let text = "Kosmos: https://example.com/feed.xml\n\
+                    **Blog**: <https://blog.example.com/rss>,\n\
+                    [News](http://news.example.com/?a=1)\n\
+                    `https://example.com/feed.xml` again, and https://\n\
+                    myhttps://example.org/x.";
+        assert_eq!(
+            find_urls(text),
+            [
+                "https://example.com/feed.xml",
+                "https://blog.example.com/rss",
+                "http://news.example.com/?a=1",
+                "https://example.org/x",
+            ]
+        );
+        assert!(find_urls("no links, just http and https").is_empty());
+    }
+
+    #[test]
+    fn duration_formatting() {
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(59), "59s");
+        assert_eq!(format_duration(300), "5m");
+        assert_eq!(format_duration(90 * 60), "1h 30m");
+        assert_eq!(format_duration(24 * 60 * 60 + 5 * 60), "1d");
+        assert_eq!(format_duration(26 * 60 * 60 + 61), "1d 2h");
+    }
+
+    #[test]
+    fn duration_parsing() {
+        assert_eq!(parse_duration("0"), Ok(0));
+        assert_eq!(parse_duration("300"), Ok(300));
+        assert_eq!(parse_duration("30s"), Ok(30));
+        assert_eq!(parse_duration("15m"), Ok(15 * 60));
+        assert_eq!(parse_duration("6h"), Ok(6 * 60 * 60));
+        assert_eq!(parse_duration("1d"), Ok(24 * 60 * 60));
+        assert_eq!(parse_duration("1h30m"), Ok(90 * 60));
+        assert_eq!(parse_duration(" 1d2h3m4s "), Ok(93784));
+        assert_eq!(parse_duration("0m"), Ok(0));
+        for secs in [0, 59, 300, 90 * 60, 26 * 60 * 60] {
+            assert_eq!(parse_duration(&format_duration(secs)), Ok(secs));
+        }
+
+        for s in ["", " ", "-5", "-5m", "+5", "5x", "h", "1.5h", "1h-30m", "5 m", "1m1h", "1h1h"] {
+            assert_eq!(parse_duration(s), Err(DurationError::Invalid), "{:?}", s);
+        }
+        assert_eq!(parse_duration("18446744073709551615"), Ok(u64::MAX));
+        assert_eq!(parse_duration("213503982334601d25215s"), Ok(u64::MAX));
+        for s in [
+            "18446744073709551616",
+            "18446744073709551615m",
+            "213503982334602d",
+            // Only the sum overflows
+            "213503982334601d25216s",
+        ] {
+            assert_eq!(parse_duration(s), Err(DurationError::Overflow), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn delivery_latencies() {
+        let sent = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let published = |secs: i64| Item {
+            published: chrono::DateTime::from_timestamp(secs, 0).map(|t| t.fixed_offset()),
+            ..Default::default()
+        };
+        assert_eq!(delivery_latency(&published(1_700_000_000 - 420), sent), Some(420));
+        assert_eq!(delivery_latency(&published(1_700_000_000), sent), Some(0));
+        // Clock skew
+        assert_eq!(delivery_latency(&published(1_700_000_000 + 60), sent), None);
+        assert_eq!(delivery_latency(&published(1_000_000_000), sent), None);
+        assert_eq!(delivery_latency(&Item::default(), sent), None);
+    }
+
+    #[test]
+    fn plain_text_fallback() {
+        let title = "a < b & \"c\"";
+        let link = "http://example.com/?a=1&b=2";
+        let html = format!(
+            "<b>{}</b>\n<a href=\"{}\">{}</a>",
+            Escape(title),
+            Escape(link),
+            Escape(title)
+        );
+        assert_eq!(
+            html_to_plain(&html),
+            format!("{}\n{} ({})", title, title, link)
+        );
+        // Broken markup is kept as is
+        assert_eq!(html_to_plain("1 < 2"), "1 < 2");
+        assert_eq!(html_links(&html), [link]);
+    }
+
+    #[test]
+    fn fit_limits() {
+        let link = |text: &str| {
+            let href = "http://example.com/?a=1&amp;b=2";
+            format!("<b>feed</b>\n<a href=\"{}\">{}</a>", href, text)
+        };
+        // 4 units in the head and the newline
+        let room = Limit::Text.units() - 5;
+        let cjk = "中".repeat(room);
+        assert!(matches!(fit_html(&link(&cjk), Limit::Text), Cow::Borrowed(_)));
+        let fitted = fit_html(&link(&format!("{}文", cjk)), Limit::Text);
+        assert_eq!(fitted, link(&format!("{}…", "中".repeat(room - 1))));
+        assert_eq!(visible_len(&fitted), Limit::Text.units());
+
+        // Surrogate pairs, 2 units each
+        let emoji = "👍".repeat(room / 2);
+        assert!(matches!(fit_html(&link(&emoji), Limit::Text), Cow::Borrowed(_)));
+        let fitted = fit_html(&link(&format!("{}👍", emoji)), Limit::Text);
+        assert_eq!(fitted, link(&format!("{}…", "👍".repeat(room / 2))));
+        assert_eq!(html_links(&fitted), ["http://example.com/?a=1&b=2"]);
+
+        // Escaped characters count as one
+        let escaped = format!("&amp;{}", "a".repeat(Limit::Caption.units() - 1));
+        assert_eq!(visible_len(&escaped), Limit::Caption.units());
+        assert_eq!(fit_html(&escaped, Limit::Caption), escaped);
+        assert_eq!(
+            fit_html(&format!("{}&lt;", escaped), Limit::Caption),
+            format!("&amp;{}…", "a".repeat(Limit::Caption.units() - 2))
+        );
+
+        // Only the tags still open are closed
+        let html = format!("<i>a</i> <b>{}</b>", "b".repeat(Limit::Caption.units()));
+        let fitted = fit_html(&html, Limit::Caption);
+        assert!(fitted.ends_with("b…</b>"));
+        assert_eq!(visible_len(&fitted), Limit::Caption.units());
+
+        let family = "👨‍👩‍👧";
+        assert_eq!(utf16_len(family), 8);
+        let text = family.repeat(Limit::Caption.units() / 8);
+        assert_eq!(fit_text(&text, Limit::Caption), text);
+        assert_eq!(
+            fit_text(&format!("{}a", text), Limit::Caption),
+            format!("{}…", family.repeat(Limit::Caption.units() / 8 - 1))
+        );
+    }
+
+    #[test]
+    fn grapheme_boundaries() {
+        // Flags are pairs of regional indicators
+        assert_eq!(grapheme_prefix("🇯🇵🇺🇸", 6), "🇯🇵".len());
+        assert_eq!(grapheme_prefix("🇯🇵🇺🇸", 8), "🇯🇵🇺🇸".len());
+        assert_eq!(grapheme_prefix("👍🏽👍", 3), 0);
+        assert_eq!(grapheme_prefix("👍🏽👍", 4), "👍🏽".len());
+        assert_eq!(grapheme_prefix("e\u{301}e\u{301}", 3), "e\u{301}".len());
+        assert_eq!(grapheme_prefix("❤️x", 1), 0);
+        assert_eq!(grapheme_prefix("中文", 1), "中".len());
+    }
+
+    #[test]
+    fn digest() {
+        let titles: Vec<String> = (0..1000).map(|i| format!("item {}", i)).collect();
+        let line = |t: &String| format!("<a href=\"http://example.com/\">{}</a>", Escape(t));
+        let msg = format_digest("head".into(), &titles[..3], line);
+        assert_eq!(msg.lines().count(), 4);
+        let msg = format_digest("head".into(), &titles, line);
+        assert!(visible_len(&msg) <= Limit::Text.units());
+        let listed = html_links(&msg).len();
+        let more = tr!("digest_more", count = titles.len() - listed);
+        assert!(msg.ends_with(&*more), "{}", msg);
+    }
+
+    #[test]
+    fn large_msg_splitting() {
+        // Counted like Telegram does, not in bytes
+        let titles = vec!["中".repeat(2000); 3];
+        let msgs = format_large_msg("head".into(), &titles, |t| t.clone());
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(visible_len(&msgs[0]), 4 + 2 * 2001);
+    }
+
+    fn request_error(
+        error_code: u16,
+        description: &str,
+        migrate_to_chat_id: Option<i64>,
+        retry_after: Option<u64>,
+    ) -> MethodCall {
+        MethodCall::RequestError {
+            description: description.into(),
+            error_code,
+            migrate_to_chat_id: migrate_to_chat_id.map(tbot::types::chat::Id),
+            retry_after,
+        }
+    }
+
+    #[test]
+    fn api_errors() {
+        let check = |cases: &[(u16, &str)], expected| {
+            for &(code, description) in cases {
+                let error = request_error(code, description, None, None);
+                assert_eq!(classify_error(&error), expected, "{}", description);
+            }
+        };
+        check(
+            &[
+                // api.telegram.org
+                (403, "Forbidden: bot was blocked by the user"),
+                (403, "Forbidden: bot was kicked from the supergroup chat"),
+                (400, "Bad Request: chat not found"),
+                (400, "Bad Request: have no rights to send a message"),
+                (400, "Bad Request: need administrator rights in the channel chat"),
+                // telegram-bot-api running locally
+                (403, "Forbidden: user is deactivated"),
+                (400, "Bad Request: CHAT_WRITE_FORBIDDEN"),
+                (400, "Bad Request: PEER_ID_INVALID"),
+                (400, "Bad Request: not enough rights to send text messages to the chat"),
+            ],
+            ApiError::ChatUnavailable,
+        );
+        check(
+            &[
+                (400, "Bad Request: can't parse entities: Unsupported start tag \"q\""),
+                (400, "Bad Request: Can't parse entities: can't find end tag"),
+            ],
+            ApiError::BadMarkup,
+        );
+        check(
+            &[
+                (400, "Bad Request: replied message not found"),
+                (400, "Bad Request: message to be replied not found"),
+            ],
+            ApiError::ReplyNotFound,
+        );
+        check(
+            &[
+                (400, "Bad Request: message is too long"),
+                (500, "Internal Server Error: restart"),
+            ],
+            ApiError::Other,
+        );
+
+        // The descriptions of these differ, the parameters don't
+        for description in [
+            "Bad Request: group chat was upgraded to a supergroup chat",
+            "Bad Request: group chat is migrated to a supergroup chat",
+        ] {
+            let error = request_error(400, description, Some(-1001), None);
+            assert_eq!(classify_error(&error), ApiError::Migrated(-1001));
+        }
+        for description in [
+            "Too Many Requests: retry after 5",
+            "Too Many Requests: retry later",
+        ] {
+            let error = request_error(429, description, None, Some(5));
+            assert_eq!(classify_error(&error), ApiError::RetryAfter(5));
+        }
+        assert_eq!(classify_error(&MethodCall::OutOfService), ApiError::Other);
+    }
+
+    #[test]
+    fn tracking_params() {
+        let link = "http://example.com/a";
+        assert!(matches!(strip_tracking_params(link), Cow::Borrowed(_)));
+        let link = "http://example.com/a?id=1&utm_source=rss&utm_medium=feed";
+        assert_eq!(strip_tracking_params(link), "http://example.com/a?id=1");
+        let link = "http://example.com/a?fbclid=x&ref=home#comments";
+        assert_eq!(strip_tracking_params(link), "http://example.com/a#comments");
+        // Only whole names, and parameters without a value
+        let link = "http://example.com/a?referrer=x&gclid";
+        assert_eq!(
+            strip_tracking_params(link),
+            "http://example.com/a?referrer=x"
+        );
+        let link = "http://example.com/a?id=%2F&p";
+        assert!(matches!(strip_tracking_params(link), Cow::Borrowed(_)));
+    }
+}
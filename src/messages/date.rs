@@ -0,0 +1,124 @@
+//! `/settings date`, how the publish dates of the items are shown
+//!
+//! Formats are checked with `is_valid` when they are set, formatting never fails.
+
+use std::fmt::Display;
+
+use chrono::{DateTime, TimeZone, Utc};
+
+/// Shows how long ago the item was published instead of the date
+pub const RELATIVE: &str = "relative";
+
+// The strftime specifiers allowed in a format
+const SPECIFIERS: &str = "YymdeHIMSpbBaAjzFRT%";
+// Needs more than a few dozen characters to say anything useful
+const MAX_FORMAT_LEN: usize = 64;
+
+/// `relative`, or a strftime format with only the specifiers in `SPECIFIERS`
+pub fn is_valid(format: &str) -> bool {
+    if format == RELATIVE {
+        return true;
+    }
+    if format.is_empty() || format.len() > MAX_FORMAT_LEN {
+        return false;
+    }
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c == '%' && !chars.next().is_some_and(|c| SPECIFIERS.contains(c)) {
+            return false;
+        }
+    }
+    true
+}
+
+/// `date` in its own timezone, `now` is only used by `relative`
+pub fn format<Tz>(date: &DateTime<Tz>, format: &str, now: DateTime<Utc>) -> String
+where
+    Tz: TimeZone,
+    Tz::Offset: Display,
+{
+    if format == RELATIVE {
+        return relative(now.signed_duration_since(date).num_seconds());
+    }
+    date.format(format).to_string()
+}
+
+/// Only the most significant unit, `ago` is in seconds and negative in the future
+fn relative(ago: i64) -> String {
+    const MINUTE: i64 = 60;
+    const HOUR: i64 = 60 * MINUTE;
+    const DAY: i64 = 24 * HOUR;
+    // Clocks of the feeds that are a few minutes ahead
+    const SKEW: i64 = 5 * MINUTE;
+    match ago {
+        _ if (-SKEW..MINUTE).contains(&ago) => tr!("date_just_now").to_string(),
+        _ if ago <= -DAY => tr!("date_in_days", count = -ago / DAY),
+        _ if ago <= -HOUR => tr!("date_in_hours", count = -ago / HOUR),
+        _ if ago < 0 => tr!("date_in_minutes", count = -ago / MINUTE),
+        _ if ago < HOUR => tr!("date_minutes_ago", count = ago / MINUTE),
+        _ if ago < DAY => tr!("date_hours_ago", count = ago / HOUR),
+        _ => tr!("date_days_ago", count = ago / DAY),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::FixedOffset;
+
+    fn date(s: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(s).unwrap()
+    }
+
+    #[test]
+    fn validation() {
+        for format in ["%Y-%m-%d", "%d.%m.%Y_%H:%M", "%F", "%b %e, %Y", "100%%", "relative"] {
+            assert!(is_valid(format), "{}", format);
+        }
+        for format in ["", "%", "%Y-%", "%Q", "%s", "%-d", &"%Y".repeat(40)] {
+            assert!(!is_valid(format), "{}", format);
+        }
+    }
+
+    #[test]
+    fn absolute() {
+        let now = Utc::now();
+        let published = date("2024-03-05T07:08:09+08:00");
+        assert_eq!(format(&published, "%Y-%m-%d %H:%M", now), "2024-03-05 07:08");
+        assert_eq!(format(&published, "%e %b %y", now), " 5 Mar 24");
+        assert_eq!(format(&published, "%T %z", now), "07:08:09 +0800");
+        assert_eq!(format(&published, "100%%", now), "100%");
+    }
+
+    #[test]
+    fn around_dst_transitions() {
+        // Central Europe switched to summer time at 2024-03-31T01:00:00Z,
+        // the dates keep the offset they were published with
+        let now = date("2024-03-31T12:00:00Z").with_timezone(&Utc);
+        let before = date("2024-03-31T01:59:00+01:00");
+        let after = date("2024-03-31T03:01:00+02:00");
+        assert_eq!(format(&before, "%H:%M", now), "01:59");
+        assert_eq!(format(&after, "%H:%M", now), "03:01");
+        // Two minutes apart, not an hour and two minutes
+        assert_eq!((after - before).num_minutes(), 2);
+        assert_eq!(format(&before, RELATIVE, now), tr!("date_hours_ago", count = 11));
+        assert_eq!(format(&after, RELATIVE, now), tr!("date_hours_ago", count = 10));
+    }
+
+    #[test]
+    fn relative_dates() {
+        let now = date("2024-01-10T00:00:00Z").with_timezone(&Utc);
+        let ago = |s: &str| format(&date(s), RELATIVE, now);
+        assert_eq!(ago("2024-01-09T23:59:30Z"), tr!("date_just_now"));
+        // Clock skew of the feed
+        assert_eq!(ago("2024-01-10T00:03:00Z"), tr!("date_just_now"));
+        assert_eq!(ago("2024-01-09T23:58:00Z"), tr!("date_minutes_ago", count = 2));
+        assert_eq!(ago("2024-01-09T22:00:00+00:00"), tr!("date_hours_ago", count = 2));
+        assert_eq!(ago("2024-01-10T06:00:00+08:00"), tr!("date_hours_ago", count = 2));
+        assert_eq!(ago("2024-01-07T00:00:00Z"), tr!("date_days_ago", count = 3));
+        // Scheduled posts, or a clock far off
+        assert_eq!(ago("2024-01-10T00:10:00Z"), tr!("date_in_minutes", count = 10));
+        assert_eq!(ago("2024-01-10T03:00:00Z"), tr!("date_in_hours", count = 3));
+        assert_eq!(ago("2024-01-12T00:00:00Z"), tr!("date_in_days", count = 2));
+    }
+}
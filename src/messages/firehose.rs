@@ -0,0 +1,242 @@
+//! `--firehose-chat-id`, a copy of every item delivered anywhere, for the operator
+//!
+//! An item is copied once in `WINDOW`, by its normalized link, however many chats got it.
+//! The copies are queued and sent in background after everything else, a failure
+//! or a backlog never holds up delivery.
+
+use std::collections::{hash_map::Entry, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc;
+
+use super::{
+    classify_error, format_large_msg, sanitize_title, ApiError, Escape, MessageSink, Title,
+    TITLE_UNITS,
+};
+use crate::data::normalize_link;
+use crate::feed::Item;
+
+// Items copied this long ago are copied again
+const WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+// Messages waiting to be sent, beyond this they are dropped
+const QUEUE_SIZE: usize = 1024;
+// A full queue takes close to an hour at the rate limit of the chat, the rest is dropped
+const SHUTDOWN_WAIT: Duration = Duration::from_secs(30);
+
+static FIREHOSE: OnceLock<Firehose> = OnceLock::new();
+// Queued but not sent yet
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+static DROP_REPORTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+struct Firehose {
+    queue: mpsc::Sender<String>,
+    copied: Mutex<Copied>,
+}
+
+pub fn init(sink: Arc<dyn MessageSink>, chat_id: i64) {
+    // A second run keeps sending with the first
+    if FIREHOSE.get().is_some() {
+        return;
+    }
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    let firehose = Firehose {
+        queue: sender,
+        copied: Mutex::new(Copied::default()),
+    };
+    let _ = FIREHOSE.set(firehose);
+    tokio::spawn(send_copies(sink, chat_id, receiver));
+}
+
+/// Copy the items delivered from the feed, leaving out the ones copied already.
+/// Does nothing without `--firehose-chat-id`.
+pub fn mirror(feed_title: &str, feed_link: &str, items: &[Item]) {
+    let firehose = match FIREHOSE.get() {
+        Some(firehose) => firehose,
+        None => return,
+    };
+    let fresh = firehose
+        .copied
+        .lock()
+        .unwrap()
+        .fresh(feed_link, items, Instant::now());
+    for msg in format_copies(feed_title, feed_link, &fresh) {
+        PENDING.fetch_add(1, Ordering::SeqCst);
+        if firehose.queue.try_send(msg).is_err() {
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+            if !DROP_REPORTED.swap(true, Ordering::Relaxed) {
+                eprintln!("Firehose can't keep up, dropping copies");
+            }
+        }
+    }
+}
+
+/// Wait for the queued copies to be sent, before exiting, for `SHUTDOWN_WAIT` at most
+pub async fn wait_sent() {
+    let sent = async {
+        while PENDING.load(Ordering::SeqCst) > 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    };
+    if tokio::time::timeout(SHUTDOWN_WAIT, sent).await.is_err() {
+        let pending = PENDING.load(Ordering::SeqCst);
+        eprintln!("Firehose didn't catch up, {} copies were not sent", pending);
+    }
+}
+
+/// Like the deliveries, under the title of the feed
+fn format_copies(feed_title: &str, feed_link: &str, items: &[&Item]) -> Vec<String> {
+    if items.is_empty() {
+        return Vec::new();
+    }
+    let head = format!("<b>{}</b>", Title(feed_title));
+    format_large_msg(head, items, |item| {
+        let title = item.title.as_deref().unwrap_or(feed_title);
+        let link = item.link.as_deref().unwrap_or(feed_link);
+        format!(
+            "<a href=\"{}\">{}</a>",
+            Escape(link),
+            Escape(&sanitize_title(title, TITLE_UNITS))
+        )
+    })
+}
+
+async fn send_copies(
+    sink: Arc<dyn MessageSink>,
+    mut chat_id: i64,
+    mut receiver: mpsc::Receiver<String>,
+) {
+    let mut failing = false;
+    while let Some(msg) = receiver.recv().await {
+        for _ in 0..3 {
+            let e = match sink.send_firehose(chat_id, &msg).await {
+                Ok(_) => {
+                    failing = false;
+                    break;
+                }
+                Err(e) => e,
+            };
+            match classify_error(&e) {
+                ApiError::RetryAfter(delay) => {
+                    tokio::time::sleep(Duration::from_secs(delay)).await;
+                    continue;
+                }
+                ApiError::Migrated(new_chat_id) => {
+                    chat_id = new_chat_id;
+                    continue;
+                }
+                // Only once, until it works again
+                _ if !failing => {
+                    failing = true;
+                    eprintln!("Failed to send to the firehose chat {}: {}", chat_id, e);
+                }
+                _ => (),
+            }
+            break;
+        }
+        PENDING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Keys of the items copied recently, to when
+#[derive(Debug, Default)]
+struct Copied(HashMap<String, Instant>);
+
+impl Copied {
+    /// The items not copied in `WINDOW`, they count as copied from `now`
+    fn fresh<'a>(&mut self, feed_link: &str, items: &'a [Item], now: Instant) -> Vec<&'a Item> {
+        self.0
+            .retain(|_, at| now.saturating_duration_since(*at) < WINDOW);
+        items
+            .iter()
+            .filter(|item| match copy_key(feed_link, item) {
+                Some(key) => match self.0.entry(key) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(now);
+                        true
+                    }
+                    Entry::Occupied(_) => false,
+                },
+                None => true,
+            })
+            .collect()
+    }
+}
+
+/// The normalized link, or the feed and the ID or title of an item without a link
+fn copy_key(feed_link: &str, item: &Item) -> Option<String> {
+    match (&item.link, item.id.as_ref().or(item.title.as_ref())) {
+        (Some(link), _) => Some(normalize_link(link.trim())),
+        (None, Some(id)) => Some(format!("{} {}", feed_link, id)),
+        (None, None) => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::MockSink;
+
+    fn item(link: &str) -> Item {
+        Item {
+            title: Some(format!("title of {}", link)),
+            link: Some(link.into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn copied_once() {
+        let mut copied = Copied::default();
+        let now = Instant::now();
+        let a = "http://a.example.com/feed.xml";
+        let items = vec![item("http://example.com/1"), item("http://example.com/2")];
+        assert_eq!(copied.fresh(a, &items, now).len(), 2);
+        // From another feed, or with tracking parameters
+        let again = vec![
+            item("http://example.com/1?utm_source=rss"),
+            item("http://example.com/2/"),
+            item("http://example.com/3"),
+        ];
+        let fresh = copied.fresh("http://b.example.com/feed.xml", &again, now);
+        assert_eq!(fresh, [&again[2]]);
+        let later = now + WINDOW;
+        assert_eq!(copied.fresh(a, &items, later).len(), 2);
+
+        // Without a link, by the feed
+        let untitled = Item {
+            id: Some("1".into()),
+            ..Default::default()
+        };
+        let untitled = [untitled];
+        assert_eq!(copied.fresh(a, &untitled, now).len(), 1);
+        assert!(copied.fresh(a, &untitled, now).is_empty());
+        assert_eq!(copied.fresh("http://b.example.com/", &untitled, now).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn copies_sent() {
+        let sink = Arc::new(MockSink::default());
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(send_copies(sink.clone(), -100, receiver));
+        let items = [item("http://example.com/1"), item("http://example.com/2")];
+        let items: Vec<&Item> = items.iter().collect();
+        for msg in format_copies("feed & co", "http://example.com/feed.xml", &items) {
+            PENDING.fetch_add(1, Ordering::SeqCst);
+            sender.send(msg).await.unwrap();
+        }
+        wait_sent().await;
+        assert_eq!(
+            sink.take(),
+            [(
+                -100,
+                "<b>feed &amp; co</b>\n\
+                 <a href=\"http://example.com/1\">title of http://example.com/1</a>\n\
+                 <a href=\"http://example.com/2\">title of http://example.com/2</a>"
+                    .to_string()
+            )]
+        );
+    }
+}
@@ -1,14 +1,21 @@
+use std::borrow::Cow;
+use std::fmt;
 use std::io::Cursor;
 use std::io::Write;
 
-use chrono::Local;
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
 use quick_xml::events::attributes::Attribute;
 use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
-use quick_xml::Writer;
+use quick_xml::{Reader, Writer};
 
+use crate::client::expand_url;
 use crate::data::Feed;
 
-pub fn into_opml(feeds: Vec<Feed>) -> String {
+// Characters of the chat name kept in the name of the exported file
+const MAX_FILENAME_CHARS: usize = 64;
+
+/// `subscriber` is whose subscriptions `feeds` are, `chat` is its name or ID
+pub fn into_opml(feeds: Vec<Feed>, subscriber: i64, chat: &str) -> String {
     let mut writer = Writer::new(Cursor::new(Vec::new()));
     let decl = BytesDecl::new(b"1.0", Some(b"UTF-8"), None);
     writer.write_event(Event::Decl(decl)).unwrap();
@@ -20,13 +27,15 @@ pub fn into_opml(feeds: Vec<Feed>) -> String {
         |writer| {
             with_tag(writer, b"head", &mut [], |writer| {
                 with_tag(writer, b"title", &mut [], |writer| {
-                    let text = BytesText::from_plain(b"Exported from RSSBot");
+                    // Control characters aren't allowed in XML
+                    let chat: String = chat.chars().filter(|c| !c.is_control()).collect();
+                    let title = format!("{} - Exported from RSSBot", chat);
+                    let text = BytesText::from_plain_str(&title);
                     writer.write_event(Event::Text(text))?;
                     Ok(())
                 })?;
                 with_tag(writer, b"dateCreated", &mut [], |writer| {
-                    // e.g. Thu, 02 Nov 2017 18:08:24 CST
-                    let time = Local::now().format("%a, %d %b %Y %T %Z").to_string();
+                    let time = format_date(Local::now());
                     let text = BytesText::from_plain_str(&time);
                     writer.write_event(Event::Text(text))?;
                     Ok(())
@@ -41,8 +50,24 @@ pub fn into_opml(feeds: Vec<Feed>) -> String {
                 for feed in feeds {
                     let mut outline = BytesStart::borrowed(b"outline", 7);
                     outline.push_attribute(Attribute::from(("type", "rss")));
-                    outline.push_attribute(Attribute::from(("text", feed.title.as_str())));
-                    outline.push_attribute(Attribute::from(("xmlUrl", feed.link.as_str())));
+                    outline.push_attribute(attribute("text", &feed.title));
+                    // Other readers don't know rsshub://
+                    let link = expand_url(&feed.link);
+                    outline.push_attribute(attribute("xmlUrl", &link));
+                    if !feed.home_page.is_empty() {
+                        outline.push_attribute(attribute("htmlUrl", &feed.home_page));
+                    }
+                    if let Some(language) = &feed.language {
+                        outline.push_attribute(attribute("language", language));
+                    }
+                    // Unknown for the subscriptions from before it was recorded
+                    let subscribed_at = feed
+                        .activity(subscriber)
+                        .and_then(|activity| activity.subscribed_at);
+                    if let Some(time) = subscribed_at {
+                        let time = format_date(DateTime::<Local>::from(time));
+                        outline.push_attribute(Attribute::from(("dateCreated", &*time)));
+                    }
                     writer.write_event(Event::Empty(outline))?;
                 }
                 Ok(())
@@ -54,6 +79,102 @@ pub fn into_opml(feeds: Vec<Feed>) -> String {
     unsafe { String::from_utf8_unchecked(writer.into_inner().into_inner()) }
 }
 
+/// `<chat>-<date>.<extension>`, with what file systems don't allow in names replaced
+pub fn export_filename(chat: &str, date: NaiveDate, extension: &str) -> String {
+    let mut name: String = chat
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() || c.is_whitespace() => '_',
+            c => c,
+        })
+        .take(MAX_FILENAME_CHARS)
+        .collect();
+    // Hidden files on Unix, and not allowed at the end on Windows
+    name = name.trim_matches('.').to_string();
+    if name.is_empty() {
+        name.push_str("feeds");
+    }
+    format!("{}-{}.{}", name, date.format("%Y-%m-%d"), extension)
+}
+
+/// A feed from an OPML file, categories are flattened
+#[derive(Debug, PartialEq, Eq)]
+pub struct Outline {
+    pub xml_url: String,
+    pub title: String,
+}
+
+pub fn parse_opml(opml: &[u8]) -> quick_xml::Result<Vec<Outline>> {
+    let mut reader = Reader::from_reader(opml);
+    reader.trim_text(true);
+    let mut buf = Vec::new();
+    let mut outlines = Vec::new();
+    loop {
+        match reader.read_event(&mut buf)? {
+            Event::Start(ref e) | Event::Empty(ref e) if e.name() == b"outline" => {
+                let (mut xml_url, mut title, mut text) = (None, None, None);
+                for attr in e.attributes() {
+                    let attr = attr?;
+                    let value = || attr.unescape_and_decode_value(&reader);
+                    match attr.key {
+                        b"xmlUrl" => xml_url = Some(value()?),
+                        b"title" => title = Some(value()?),
+                        b"text" => text = Some(value()?),
+                        _ => (),
+                    }
+                }
+                // Outlines without `xmlUrl` are categories
+                if let Some(xml_url) = xml_url {
+                    outlines.push(Outline {
+                        xml_url,
+                        title: title.or(text).unwrap_or_default(),
+                    });
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(outlines)
+}
+
+/// An attribute with a value from a feed, escaped by hand:
+/// `Attribute::from` keeps line breaks, which parsers turn into spaces,
+/// and characters that aren't allowed in XML at all would make the file unreadable
+fn attribute<'a>(key: &'a str, value: &str) -> Attribute<'a> {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' => escaped.push_str("&#10;"),
+            '\r' => escaped.push_str("&#13;"),
+            '\t' => escaped.push_str("&#9;"),
+            // https://www.w3.org/TR/xml/#charsets
+            '\u{0}'..='\u{1F}' | '\u{FFFE}' | '\u{FFFF}' => (),
+            c => escaped.push(c),
+        }
+    }
+    Attribute {
+        key: key.as_bytes(),
+        value: Cow::Owned(escaped.into_bytes()),
+    }
+}
+
+/// RFC 822, e.g. Thu, 02 Nov 2017 18:08:24 +0800
+fn format_date<Tz: TimeZone>(time: DateTime<Tz>) -> String
+where
+    Tz::Offset: fmt::Display,
+{
+    time.format("%a, %d %b %Y %T %z").to_string()
+}
+
 // type of `attrs` is for zero allocation
 fn with_tag<W, F>(
     writer: &mut Writer<W>,
@@ -78,27 +199,140 @@ where
 
 #[test]
 fn test_to_opml() {
+    use std::time::{Duration, SystemTime};
+
+    use crate::data::Activity;
+
     let mut feed1 = Feed::default();
     feed1.title = "title1".into();
     feed1.link = "link1".into();
     let mut feed2 = Feed::default();
-    feed2.title = "title2".into();
+    feed2.title = "title2 & <3".into();
     feed2.link = "link2".into();
+    feed2.home_page = "http://example.com/?a=1&b=2".into();
+    feed2.language = Some("en-US".into());
+    let subscribed_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+    feed2.activity.insert(
+        1,
+        Activity {
+            subscribed_at: Some(subscribed_at),
+            ..Default::default()
+        },
+    );
     let feeds = vec![feed1, feed2];
     let r = format!(
         "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
          <opml version=\"2.0\">\
          <head>\
-         <title>Exported from RSSBot</title>\
+         <title>Some &lt;Group&gt; - Exported from RSSBot</title>\
          <dateCreated>{}</dateCreated>\
          <docs>http://www.opml.org/spec2</docs>\
          </head>\
          <body>\
          <outline type=\"rss\" text=\"title1\" xmlUrl=\"link1\"/>\
-         <outline type=\"rss\" text=\"title2\" xmlUrl=\"link2\"/>\
+         <outline type=\"rss\" text=\"title2 &amp; &lt;3\" xmlUrl=\"link2\" \
+         htmlUrl=\"http://example.com/?a=1&amp;b=2\" language=\"en-US\" dateCreated=\"{}\"/>\
          </body>\
          </opml>",
-        Local::now().format("%a, %d %b %Y %T %Z")
+        format_date(Local::now()),
+        format_date(DateTime::<Local>::from(subscribed_at)),
+    );
+    assert_eq!(into_opml(feeds, 1, "Some <Group>\u{7}"), r);
+}
+
+#[test]
+fn test_opml_round_trip() {
+    let mut feed = Feed::default();
+    feed.title = "title & <1>".into();
+    feed.link = "http://example.com/feed.xml?a=1&b=2".into();
+    feed.home_page = "http://example.com/".into();
+    let opml = into_opml(vec![feed], 1, "1");
+    assert_eq!(
+        parse_opml(opml.as_bytes()).unwrap(),
+        [Outline {
+            xml_url: "http://example.com/feed.xml?a=1&b=2".into(),
+            title: "title & <1>".into(),
+        }]
+    );
+}
+
+#[test]
+fn test_opml_escaping() {
+    let titles = [
+        "\"/><outline type=\"rss\" text=\"injected\" xmlUrl=\"http://evil.example/",
+        "]]> & <b>bold</b> 'quoted'",
+        "line one\nline two\r\n\ttabbed",
+        "bell\u{7} and nul\u{0}",
+    ];
+    let feeds = titles
+        .iter()
+        .enumerate()
+        .map(|(i, title)| {
+            let mut feed = Feed::default();
+            feed.title = title.to_string();
+            feed.link = format!("http://example.com/{}.xml?q=\"<{}>\"&x='y'", i, i);
+            feed
+        })
+        .collect();
+    let opml = into_opml(feeds, 1, "1");
+    assert!(opml.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>"));
+    assert!(!opml.contains("injected\""), "{}", opml);
+    let outlines = parse_opml(opml.as_bytes()).unwrap();
+    assert_eq!(outlines.len(), titles.len());
+    for (i, outline) in outlines.iter().enumerate() {
+        let link = format!("http://example.com/{}.xml?q=\"<{}>\"&x='y'", i, i);
+        assert_eq!(outline.xml_url, link);
+        if i < 3 {
+            assert_eq!(outline.title, titles[i]);
+        }
+    }
+    // Not allowed in XML, dropped
+    assert_eq!(outlines[3].title, "bell and nul");
+
+    // The header date can be read back as RFC 822
+    let head = opml.split("<dateCreated>").nth(1).unwrap();
+    let date = head.split("</dateCreated>").next().unwrap();
+    assert!(DateTime::parse_from_rfc2822(date).is_ok(), "{}", date);
+}
+
+#[test]
+fn test_parse_opml_categories() {
+    let opml = br#"<?xml version="1.0" encoding="UTF-8"?>
+<opml version="1.0">
+<head><title>Subscriptions</title></head>
+<body>
+<outline text="News" title="News">
+    <outline type="rss" text="Example" title="Example &amp; Co" xmlUrl="http://example.com/feed.xml" htmlUrl="http://example.com/"/>
+</outline>
+<outline type="rss" text="Untitled" xmlUrl="http://example.com/other.xml"></outline>
+</body>
+</opml>"#;
+    assert_eq!(
+        parse_opml(opml).unwrap(),
+        [
+            Outline {
+                xml_url: "http://example.com/feed.xml".into(),
+                title: "Example & Co".into(),
+            },
+            Outline {
+                xml_url: "http://example.com/other.xml".into(),
+                title: "Untitled".into(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_export_filename() {
+    let date = NaiveDate::from_ymd_opt(2024, 3, 5).unwrap();
+    assert_eq!(export_filename("-1001", date, "opml"), "-1001-2024-03-05.opml");
+    assert_eq!(
+        export_filename(" My Blog: news/views? ", date, "opml"),
+        "My_Blog__news_views_-2024-03-05.opml"
     );
-    assert_eq!(into_opml(feeds), r);
+    assert_eq!(export_filename("..\\..\\etc", date, "json"), "_.._etc-2024-03-05.json");
+    assert_eq!(export_filename("...", date, "opml"), "feeds-2024-03-05.opml");
+    assert_eq!(export_filename("频道\n的名字", date, "opml"), "频道_的名字-2024-03-05.opml");
+    let long = export_filename(&"字".repeat(100), date, "opml");
+    assert_eq!(long.chars().count(), MAX_FILENAME_CHARS + "-2024-03-05.opml".len());
 }
@@ -0,0 +1,332 @@
+//! A local feed server with scripted responses and a mock Telegram,
+//! to test the fetcher end to end
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Once};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use tbot::errors::MethodCall;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::data::Database;
+use crate::messages::{html_links, MessageSink};
+
+/// The HTTP client and the fetcher only read their settings once
+pub fn init() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        crate::client::init_client("rssbot_test", false, 2 * 1024 * 1024, 300, false, false, None);
+        crate::fetcher::init(300, 43200, 0.1);
+    });
+}
+
+/// An empty database in the temporary directory, named after the test
+pub fn temp_database(name: &str) -> (PathBuf, Database) {
+    let path = std::env::temp_dir().join(format!(
+        "rssbot-test-{}-{}.json",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let db = Database::create(path.clone()).unwrap();
+    (path, db)
+}
+
+/// An RSS 2.0 feed with the items in `items`, newest first
+pub fn rss(items: Range<usize>) -> Vec<u8> {
+    rss_with(&items.rev().collect::<Vec<_>>())
+}
+
+/// An RSS 2.0 feed with the items in this order
+pub fn rss_with(items: &[usize]) -> Vec<u8> {
+    let mut xml = String::from("<rss version=\"2.0\"><channel><title>test</title>");
+    for i in items {
+        xml.push_str(&format!(
+            "<item><title>item {0}</title><link>http://example.com/{0}</link>\
+             <guid>{0}</guid></item>",
+            i
+        ));
+    }
+    xml.push_str("</channel></rss>");
+    xml.into_bytes()
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+    /// Announce a longer body than sent, and close the connection
+    truncated: bool,
+}
+
+impl Response {
+    pub fn ok(body: Vec<u8>) -> Self {
+        Response {
+            status: 200,
+            headers: vec![("Content-Type", "application/rss+xml".into())],
+            body,
+            truncated: false,
+        }
+    }
+
+    pub fn status(status: u16) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            truncated: false,
+        }
+    }
+
+    pub fn error_page(status: u16, html: &str) -> Self {
+        Response {
+            status,
+            headers: vec![("Content-Type", "text/html".into())],
+            body: html.as_bytes().to_vec(),
+            truncated: false,
+        }
+    }
+
+    pub fn redirect(location: &str) -> Self {
+        let mut response = Response::status(301);
+        response.headers.push(("Location", location.into()));
+        response
+    }
+
+    pub fn header(mut self, name: &'static str, value: &str) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn truncated(body: Vec<u8>) -> Self {
+        Response {
+            truncated: true,
+            ..Response::ok(body)
+        }
+    }
+}
+
+type Routes = HashMap<String, VecDeque<Response>>;
+
+/// Every request to a path gets the next of its scripted responses,
+/// the last one is repeated. Unknown paths get 404.
+pub struct FeedServer {
+    addr: SocketAddr,
+    routes: Arc<Mutex<Routes>>,
+    /// The heads of the requests, lowercase
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl FeedServer {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let routes = Arc::new(Mutex::new(Routes::new()));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let server_routes = routes.clone();
+        let server_requests = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let routes = server_routes.clone();
+                let requests = server_requests.clone();
+                tokio::spawn(async move {
+                    let _ = respond(stream, &routes, &requests).await;
+                });
+            }
+        });
+        FeedServer {
+            addr,
+            routes,
+            requests,
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    pub fn script(&self, path: &str, responses: Vec<Response>) {
+        self.routes
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), responses.into());
+    }
+
+    /// The requests since the last call
+    pub fn take_requests(&self) -> Vec<String> {
+        std::mem::take(&mut *self.requests.lock().unwrap())
+    }
+}
+
+async fn respond(
+    mut stream: TcpStream,
+    routes: &Mutex<Routes>,
+    requests: &Mutex<Vec<String>>,
+) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    requests.lock().unwrap().push(request.to_lowercase());
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+    let response = {
+        let mut routes = routes.lock().unwrap();
+        match routes.get_mut(path) {
+            Some(responses) if responses.len() > 1 => responses.pop_front().unwrap(),
+            Some(responses) => responses[0].clone(),
+            None => Response::status(404),
+        }
+    };
+    let content_length = if response.truncated {
+        response.body.len() * 2
+    } else {
+        response.body.len()
+    };
+    let mut head = format!(
+        "HTTP/1.1 {} Scripted\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status, content_length
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await
+}
+
+/// Records the messages instead of sending them
+#[derive(Default)]
+pub struct MockSink {
+    sent: Mutex<Vec<(i64, String)>>,
+    /// Chats and the messages replied to
+    replies: Mutex<Vec<(i64, i64)>>,
+    /// Chats that blocked the bot
+    blocked: Mutex<HashSet<i64>>,
+    /// Chats that Telegram fails to send to for now
+    failing: Mutex<HashSet<i64>>,
+    pinned: Mutex<HashMap<i64, i64>>,
+    /// Messages that can be replied to
+    existing: Mutex<HashSet<(i64, i64)>>,
+    /// How long sending a message takes
+    delay: Mutex<Option<Duration>>,
+    /// Chats that make sending panic, like a bug would
+    panicking: Mutex<HashSet<i64>>,
+}
+
+impl MockSink {
+    pub fn slow_down(&self, delay: Duration) {
+        *self.delay.lock().unwrap() = Some(delay);
+    }
+
+    pub fn panic_on(&self, chat_id: i64) {
+        self.panicking.lock().unwrap().insert(chat_id);
+    }
+
+    pub fn block(&self, chat_id: i64) {
+        self.blocked.lock().unwrap().insert(chat_id);
+    }
+
+    pub fn fail_on(&self, chat_id: i64) {
+        self.failing.lock().unwrap().insert(chat_id);
+    }
+
+    pub fn recover(&self, chat_id: i64) {
+        self.failing.lock().unwrap().remove(&chat_id);
+    }
+
+    pub fn pin(&self, chat_id: i64, message_id: i64) {
+        self.pinned.lock().unwrap().insert(chat_id, message_id);
+        self.existing.lock().unwrap().insert((chat_id, message_id));
+    }
+
+    pub fn delete(&self, chat_id: i64, message_id: i64) {
+        self.existing.lock().unwrap().remove(&(chat_id, message_id));
+        self.pinned
+            .lock()
+            .unwrap()
+            .retain(|chat, message| (*chat, *message) != (chat_id, message_id));
+    }
+
+    /// The replies sent since the last call
+    pub fn take_replies(&self) -> Vec<(i64, i64)> {
+        std::mem::take(&mut *self.replies.lock().unwrap())
+    }
+
+    /// The messages sent since the last call
+    pub fn take(&self) -> Vec<(i64, String)> {
+        std::mem::take(&mut *self.sent.lock().unwrap())
+    }
+
+    /// The links sent to the chat since the last call
+    pub fn take_links(&self, chat_id: i64) -> Vec<String> {
+        self.take()
+            .into_iter()
+            .filter(|(chat, _)| *chat == chat_id)
+            .flat_map(|(_, html)| html_links(&html))
+            .collect()
+    }
+}
+
+impl MessageSink for MockSink {
+    fn send_html<'a>(
+        &'a self,
+        chat_id: i64,
+        html: &'a str,
+        reply_to: Option<i64>,
+    ) -> BoxFuture<'a, Result<i64, MethodCall>> {
+        let delay = *self.delay.lock().unwrap();
+        let panicking = self.panicking.lock().unwrap().contains(&chat_id);
+        Box::pin(async move {
+            if panicking {
+                panic!("sending to {} panicked", chat_id);
+            }
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+            if self.blocked.lock().unwrap().contains(&chat_id) {
+                return Err(request_error(403, "Forbidden: bot was blocked by the user"));
+            }
+            if self.failing.lock().unwrap().contains(&chat_id) {
+                return Err(request_error(500, "Internal Server Error"));
+            }
+            if let Some(message_id) = reply_to {
+                if !self.existing.lock().unwrap().contains(&(chat_id, message_id)) {
+                    return Err(request_error(400, "Bad Request: replied message not found"));
+                }
+                self.replies.lock().unwrap().push((chat_id, message_id));
+            }
+            let mut sent = self.sent.lock().unwrap();
+            sent.push((chat_id, html.to_string()));
+            Ok(sent.len() as i64)
+        })
+    }
+
+    fn pinned_message(&self, chat_id: i64) -> BoxFuture<'_, Result<Option<i64>, MethodCall>> {
+        let pinned = self.pinned.lock().unwrap().get(&chat_id).copied();
+        Box::pin(async move { Ok(pinned) })
+    }
+}
+
+fn request_error(error_code: u16, description: &str) -> MethodCall {
+    MethodCall::RequestError {
+        description: description.into(),
+        error_code,
+        migrate_to_chat_id: None,
+        retry_after: None,
+    }
+}
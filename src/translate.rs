@@ -0,0 +1,279 @@
+//! `/settings translate`, item titles translated before delivery
+//!
+//! The backend is picked at startup, `--translate-api-url` sets up a LibreTranslate
+//! compatible API. Translations are cached, and a slow or failing backend only means
+//! the items are sent untranslated.
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{LazyLock, Mutex, OnceLock};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::stream::{self, StreamExt};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::time;
+
+/// Delivery waits for the translations at most this long
+const TIMEOUT: Duration = Duration::from_secs(5);
+const CACHE_SIZE: usize = 4096;
+// Bounds on what a fetch sends to the backend, the rest goes untranslated
+const MAX_TEXTS: usize = 100;
+const MAX_TEXT_CHARS: usize = 1000;
+const CONCURRENCY: usize = 8;
+
+static BACKEND: OnceLock<Box<dyn Translator>> = OnceLock::new();
+static CACHE: LazyLock<Mutex<Cache>> = LazyLock::new(Default::default);
+
+#[derive(Error, Debug)]
+pub enum TranslateError {
+    #[error("{0}")]
+    Network(#[from] reqwest::Error),
+    #[error("{0}")]
+    Api(String),
+}
+
+pub trait Translator: Send + Sync {
+    /// Translate `text` to the `target` language, the source is detected
+    fn translate<'a>(
+        &'a self,
+        text: &'a str,
+        target: &'a str,
+    ) -> BoxFuture<'a, Result<String, TranslateError>>;
+}
+
+/// https://libretranslate.com/docs/#/translate/post_translate
+pub struct LibreTranslate {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    api_key: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Request<'a> {
+    q: &'a str,
+    source: &'static str,
+    target: &'a str,
+    format: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_key: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    #[serde(rename = "translatedText")]
+    translated_text: Option<String>,
+    error: Option<String>,
+}
+
+impl LibreTranslate {
+    /// `url` is the endpoint itself, e.g. `https://libretranslate.com/translate`
+    pub fn new(url: reqwest::Url, api_key: Option<String>) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .expect("failed to build the translation client");
+        LibreTranslate {
+            client,
+            url,
+            api_key,
+        }
+    }
+}
+
+impl Translator for LibreTranslate {
+    fn translate<'a>(
+        &'a self,
+        text: &'a str,
+        target: &'a str,
+    ) -> BoxFuture<'a, Result<String, TranslateError>> {
+        Box::pin(async move {
+            let request = Request {
+                q: text,
+                source: "auto",
+                target,
+                format: "text",
+                api_key: self.api_key.as_deref(),
+            };
+            // Errors come with a JSON body too
+            let resp: Response = self
+                .client
+                .post(self.url.clone())
+                .json(&request)
+                .send()
+                .await?
+                .json()
+                .await?;
+            match (resp.translated_text, resp.error) {
+                (Some(translated), _) => Ok(translated),
+                (None, error) => Err(TranslateError::Api(error.unwrap_or_default())),
+            }
+        })
+    }
+}
+
+/// A second run keeps the backend of the first
+pub fn init(backend: Box<dyn Translator>) {
+    let _ = BACKEND.set(backend);
+}
+
+/// Whether `/settings translate` can be used
+pub fn enabled() -> bool {
+    BACKEND.get().is_some()
+}
+
+/// Like `en`, `zh` or `pt-BR`, not checked against what the backend supports
+pub fn is_language_code(code: &str) -> bool {
+    static RE: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"^[a-z]{2,3}([-_][A-Za-z]{2,4})?$").unwrap());
+    RE.is_match(code)
+}
+
+/// The translations of `texts` to `target`, `None` where it failed, timed out,
+/// or the text didn't change. Only the first `MAX_TEXTS` are translated, and none
+/// longer than `MAX_TEXT_CHARS`.
+pub async fn translate_all(texts: &[&str], target: &str) -> Vec<Option<String>> {
+    match BACKEND.get() {
+        Some(backend) => translate_bounded(&**backend, texts, target).await,
+        None => vec![None; texts.len()],
+    }
+}
+
+async fn translate_bounded(
+    backend: &dyn Translator,
+    texts: &[&str],
+    target: &str,
+) -> Vec<Option<String>> {
+    let mut translated: Vec<Option<String>> = stream::iter(texts.iter().take(MAX_TEXTS))
+        .map(|text| async move {
+            if text.chars().count() > MAX_TEXT_CHARS {
+                return None;
+            }
+            translate_cached(backend, text, target).await
+        })
+        .buffered(CONCURRENCY)
+        .collect()
+        .await;
+    translated.resize(texts.len(), None);
+    translated
+}
+
+async fn translate_cached(backend: &dyn Translator, text: &str, target: &str) -> Option<String> {
+    let key = cache_key(text, target);
+    let cached = CACHE.lock().unwrap().translations.get(&key).cloned();
+    if let Some(translated) = cached {
+        return useful(text, translated);
+    }
+    let translated = match time::timeout(TIMEOUT, backend.translate(text, target)).await {
+        Ok(Ok(translated)) => translated.trim().to_string(),
+        Ok(Err(e)) => {
+            eprintln!("Failed to translate to {}: {}", target, e);
+            return None;
+        }
+        Err(_) => return None,
+    };
+    CACHE.lock().unwrap().insert(key, translated.clone());
+    useful(text, translated)
+}
+
+fn useful(text: &str, translated: String) -> Option<String> {
+    Some(translated).filter(|translated| !translated.is_empty() && translated != text)
+}
+
+fn cache_key(text: &str, target: &str) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::default();
+    (text, target).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The oldest translation is dropped when it's full
+#[derive(Default)]
+struct Cache {
+    translations: HashMap<u64, String>,
+    order: VecDeque<u64>,
+}
+
+impl Cache {
+    fn insert(&mut self, key: u64, translated: String) {
+        if self.translations.insert(key, translated).is_none() {
+            self.order.push_back(key);
+        }
+        if self.order.len() > CACHE_SIZE {
+            let oldest = self.order.pop_front().unwrap();
+            self.translations.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Upper(AtomicUsize);
+
+    impl Translator for Upper {
+        fn translate<'a>(
+            &'a self,
+            text: &'a str,
+            target: &'a str,
+        ) -> BoxFuture<'a, Result<String, TranslateError>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                match text {
+                    "broken" => Err(TranslateError::Api("unsupported".into())),
+                    "same" => Ok(" same ".into()),
+                    _ => Ok(format!("{} {}", target, text.to_uppercase())),
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn fallback_and_cache() {
+        let backend = Upper(AtomicUsize::new(0));
+        let texts = ["hello", "same", "broken", "hello"];
+        let mut translated = Vec::new();
+        for text in texts {
+            translated.push(translate_cached(&backend, text, "xx").await);
+        }
+        assert_eq!(
+            translated,
+            [Some("xx HELLO".to_string()), None, None, Some("xx HELLO".to_string())]
+        );
+        // The second hello came from the cache
+        assert_eq!(backend.0.load(Ordering::SeqCst), 3);
+        // Failures are not cached
+        assert!(translate_cached(&backend, "broken", "xx").await.is_none());
+        assert_eq!(backend.0.load(Ordering::SeqCst), 4);
+        assert_eq!(translate_cached(&backend, "hello", "yy").await.unwrap(), "yy HELLO");
+    }
+
+    #[tokio::test]
+    async fn bounded_input() {
+        let backend = Upper(AtomicUsize::new(0));
+        let long = "a".repeat(MAX_TEXT_CHARS + 1);
+        let mut texts = vec!["first", &long];
+        texts.resize(MAX_TEXTS + 10, "other");
+        let translated = translate_bounded(&backend, &texts, "zz").await;
+        assert_eq!(translated.len(), texts.len());
+        assert_eq!(translated[0].as_deref(), Some("zz FIRST"));
+        assert_eq!(translated[1], None);
+        assert_eq!(translated[MAX_TEXTS - 1].as_deref(), Some("zz OTHER"));
+        assert!(translated[MAX_TEXTS..].iter().all(Option::is_none));
+        // The long one never reached the backend, the other ones came from the cache
+        assert_eq!(backend.0.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn language_codes() {
+        for code in ["en", "zh", "pt-BR", "zh-Hant", "fil"] {
+            assert!(is_language_code(code), "{}", code);
+        }
+        for code in ["", "EN", "english", "en-", "../x"] {
+            assert!(!is_language_code(code), "{}", code);
+        }
+    }
+}
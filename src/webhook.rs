@@ -0,0 +1,170 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use reqwest::header::CONTENT_TYPE;
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::Serialize;
+use thiserror::Error;
+use tokio::time;
+
+use crate::client::{check_public_url, public_client, UrlError};
+use crate::data::{Feed, Webhook};
+use crate::feed::Item;
+
+pub const SIGNATURE_HEADER: &str = "X-Rssbot-Signature";
+const ATTEMPTS: u32 = 4;
+
+// Deliveries still retrying in background
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Error, Debug)]
+enum WebhookError {
+    #[error("{0}")]
+    Url(#[from] UrlError),
+    #[error("{0}")]
+    Network(#[from] reqwest::Error),
+}
+
+#[derive(Debug, Serialize)]
+struct WebhookItem<'a> {
+    feed_url: &'a str,
+    feed_title: &'a str,
+    item_title: Option<&'a str>,
+    link: Option<&'a str>,
+    published: Option<String>,
+    author: Option<&'a str>,
+}
+
+fn payload(feed: &Feed, items: &[Item]) -> Vec<u8> {
+    let items: Vec<WebhookItem> = items
+        .iter()
+        .map(|item| WebhookItem {
+            feed_url: &feed.link,
+            feed_title: &feed.title,
+            item_title: item.title.as_deref(),
+            link: item.link.as_deref(),
+            published: item.published.map(|t| t.to_rfc3339()),
+            author: item.author.as_deref(),
+        })
+        .collect();
+    serde_json::to_vec(&items).unwrap()
+}
+
+/// `sha256=` followed by the hex encoded HMAC-SHA256 of the body
+fn sign(secret: &str, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    format!("sha256={}", hex(hmac::sign(&key, body).as_ref()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn gen_secret() -> String {
+    let mut secret = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut secret)
+        .expect("no system random source");
+    hex(&secret)
+}
+
+/// POST the items in background, Telegram delivery never waits for it
+pub fn deliver(webhook: Webhook, feed: &Feed, items: &[Item]) {
+    let body = payload(feed, items);
+    let feed_link = feed.link.clone();
+    PENDING.fetch_add(1, Ordering::SeqCst);
+    tokio::spawn(async move {
+        let _pending = Pending;
+        let signature = sign(&webhook.secret, &body);
+        let mut error = None;
+        for attempt in 0..ATTEMPTS {
+            if attempt > 0 {
+                time::sleep(Duration::from_secs(1 << attempt)).await;
+            }
+            match post(&webhook.url, &signature, body.clone()).await {
+                Ok(()) => return,
+                Err(e) => error = Some(e),
+            }
+        }
+        eprintln!(
+            "Webhook {} failed {} times, dropped the items of {}: {}",
+            webhook.url,
+            ATTEMPTS,
+            feed_link,
+            error.unwrap()
+        );
+    });
+}
+
+struct Pending;
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        PENDING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Wait for the deliveries in background, before exiting in `--once` mode
+pub async fn wait_pending() {
+    while PENDING.load(Ordering::SeqCst) > 0 {
+        time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+async fn post(url: &str, signature: &str, body: Vec<u8>) -> Result<(), WebhookError> {
+    // Checked again every time, the host may resolve to somewhere else now
+    let url = check_public_url(url).await?;
+    public_client()
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .header(SIGNATURE_HEADER, signature)
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn signature() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn payload_fields() {
+        let feed = Feed {
+            link: "http://example.com/feed.xml".into(),
+            title: "feed".into(),
+            ..Default::default()
+        };
+        let items = [Item {
+            title: Some("item".into()),
+            link: Some("http://example.com/item".into()),
+            published: crate::feed::parse_date("2001-01-01T00:00:00Z"),
+            ..Default::default()
+        }];
+        let json: serde_json::Value = serde_json::from_slice(&payload(&feed, &items)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([{
+                "feed_url": "http://example.com/feed.xml",
+                "feed_title": "feed",
+                "item_title": "item",
+                "link": "http://example.com/item",
+                "published": "2001-01-01T00:00:00+00:00",
+                "author": null,
+            }])
+        );
+    }
+}